@@ -20,9 +20,34 @@ Scroll_bar_mode = true
 Compile_command = "cargo build"
 Max_minibuffer_height = 30
 Emacs_scrolling = false
+Search_smart_case = true
+Lsp_servers = { rust = "rust-analyzer" }
+Completion_auto = true
+Completion_delay = 150 -- Milliseconds after typing before the popup opens
+Completion_min_prefix = 2
+Formatters = { rust = "rustfmt --edition 2021", lua = "stylua -" }
+Format_on_save = false
+Dired_sort = "name" -- name, size, time or extension
+Dired_directories_first = true
+Dired_show_hidden = true
+Dired_preview = false -- P toggles it
+Dired_preview_width = 50 -- Percent of the screen
+Dired_icons = true
+Fzy_icons = true
+Icons_nerd_font = true -- false draws files as - and directories as +
+-- On top of the built-in icons, the color is optional
+Icons = {
+   extensions = { rs = { "", "#DEA584" } },
+   filenames = { ["Cargo.lock"] = { "󰌾" } },
+   directories = { [".git"] = { "", "#F54D27" } },
+}
 
 
 -- TODO message in lua
+-- Pick something with the fuzzy finder, on_select gets the chosen item
+-- redit.completing_read("Cursor color: ", { "#10B1FE", "#9F7EFE" }, function(color)
+--    Normal_cursor_color = color
+-- end)
 -- TODO error in lua and rust
 
 function c()
@@ -0,0 +1,179 @@
+// A language server for the LspClient tests: it keeps the documents it is
+// sent, in sync incrementally, and answers from them. Hover gives the whole
+// text, definition the first line mentioning the word, rename every match.
+// Given a file as its argument, it logs the method of each message there.
+use std::io::{self, BufRead, BufReader, Write};
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+
+#[allow(dead_code)]
+#[path = "../src/json.rs"]
+mod json;
+
+use json::Json;
+
+fn read_message(reader: &mut impl BufRead) -> Option<Json> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let mut body = vec![0; length?];
+    reader.read_exact(&mut body).ok()?;
+    Json::parse(&String::from_utf8_lossy(&body)).ok()
+}
+
+fn write_message(message: Json) {
+    let body = message.to_string();
+    let mut stdout = io::stdout();
+    let _ = write!(stdout, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+    let _ = stdout.flush();
+}
+
+// Char index of a UTF-16 position in the line
+fn column(line: &str, character: usize) -> usize {
+    let mut units = 0;
+    for (column, c) in line.chars().enumerate() {
+        if units >= character {
+            return column;
+        }
+        units += c.len_utf16();
+    }
+    line.chars().count()
+}
+
+// Char offset of a position in the text
+fn offset(text: &str, position: &Json) -> usize {
+    let line = position.get("line").as_usize();
+    let lines: Vec<&str> = text.split('\n').collect();
+    let before: usize = lines.iter().take(line).map(|line| line.chars().count() + 1).sum();
+    before + lines.get(line).map_or(0, |chars| column(chars, position.get("character").as_usize()))
+}
+
+fn apply_change(text: &mut String, change: &Json) {
+    let range = change.get("range");
+    let new_text = change.get("text").as_str().unwrap_or("");
+    if range.is_null() {
+        *text = new_text.to_string();
+        return;
+    }
+    let chars: Vec<char> = text.chars().collect();
+    let start = offset(text, range.get("start")).min(chars.len());
+    let end = offset(text, range.get("end")).clamp(start, chars.len());
+    *text = chars[..start].iter().collect::<String>() + new_text + &chars[end..].iter().collect::<String>();
+}
+
+fn word_at(text: &str, position: &Json) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let is_word = |c: &char| c.is_alphanumeric() || *c == '_';
+    let at = offset(text, position).min(chars.len());
+    let start = chars[..at].iter().rposition(|c| !is_word(c)).map_or(0, |i| i + 1);
+    let end = chars[at..].iter().position(|c| !is_word(c)).map_or(chars.len(), |i| at + i);
+    chars[start..end].iter().collect()
+}
+
+fn range(line: usize, start: usize, end: usize) -> Json {
+    let position = |character: usize| Json::object(vec![("line", line.into()), ("character", character.into())]);
+    Json::object(vec![("start", position(start)), ("end", position(end))])
+}
+
+// Where the word appears as a whole word, as (line, start, end) in UTF-16 units
+fn occurrences(text: &str, word: &str) -> Vec<(usize, usize, usize)> {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let mut found = Vec::new();
+    for (line, chars) in text.split('\n').enumerate() {
+        for (start, _) in chars.match_indices(word) {
+            let before = chars[..start].chars().next_back();
+            let after = chars[start + word.len()..].chars().next();
+            if before.is_some_and(is_word) || after.is_some_and(is_word) {
+                continue;
+            }
+            let units = chars[..start].encode_utf16().count();
+            found.push((line, units, units + word.encode_utf16().count()));
+        }
+    }
+    found
+}
+
+fn main() {
+    let mut log = std::env::args().nth(1)
+        .and_then(|path| OpenOptions::new().create(true).append(true).open(path).ok());
+    let mut documents: HashMap<String, String> = HashMap::new();
+    let mut shutdown = false;
+    let mut reader = BufReader::new(io::stdin());
+
+    while let Some(message) = read_message(&mut reader) {
+        let method = message.get("method").as_str().unwrap_or("").to_string();
+        if let Some(log) = &mut log {
+            let _ = writeln!(log, "{}", method);
+        }
+        let params = message.get("params");
+        let uri = params.get("textDocument").get("uri").as_str().unwrap_or("").to_string();
+        let text = documents.get(&uri).cloned().unwrap_or_default();
+        let position = params.get("position");
+
+        let result = match method.as_str() {
+            "initialize" => Json::parse(r#"{"capabilities": {
+                "textDocumentSync": { "openClose": true, "change": 2 },
+                "hoverProvider": true,
+                "definitionProvider": true,
+                "renameProvider": true
+            }}"#).unwrap_or(Json::Null),
+            "textDocument/didOpen" => {
+                documents.insert(uri, params.get("textDocument").get("text").as_str().unwrap_or("").to_string());
+                continue;
+            },
+            "textDocument/didChange" => {
+                if let Some(text) = documents.get_mut(&uri) {
+                    for change in params.get("contentChanges").as_array() {
+                        apply_change(text, change);
+                    }
+                }
+                continue;
+            },
+            "textDocument/didClose" => {
+                documents.remove(&uri);
+                continue;
+            },
+            "textDocument/hover" => Json::object(vec![("contents", Json::object(vec![
+                ("kind", "markdown".into()),
+                ("value", format!("```\n{}\n```", text).into()),
+            ]))]),
+            "textDocument/definition" => {
+                let word = word_at(&text, position);
+                match occurrences(&text, &word).first() {
+                    Some(&(line, start, end)) => Json::object(vec![("uri", uri.into()), ("range", range(line, start, end))]),
+                    None => Json::Null,
+                }
+            },
+            "textDocument/rename" => {
+                let new_name = params.get("newName").as_str().unwrap_or("");
+                let edits: Vec<Json> = occurrences(&text, &word_at(&text, position)).into_iter()
+                    .map(|(line, start, end)| Json::object(vec![("range", range(line, start, end)), ("newText", new_name.into())]))
+                    .collect();
+                Json::object(vec![("changes", Json::Object(vec![(uri, edits.into())]))])
+            },
+            "shutdown" => {
+                shutdown = true;
+                Json::Null
+            },
+            "exit" => std::process::exit(if shutdown { 0 } else { 1 }),
+            _ => {
+                if message.get("id").is_null() {
+                    continue;
+                }
+                Json::Null
+            },
+        };
+        write_message(Json::object(vec![("jsonrpc", "2.0".into()), ("id", message.get("id").clone()), ("result", result)]));
+    }
+}
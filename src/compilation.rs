@@ -0,0 +1,744 @@
+use super::*;
+
+// Declared from worst to mildest, so min() is the one to show
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub(crate) enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl Severity {
+    fn label(&self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+        }
+    }
+
+    pub(crate) fn color(&self, theme: &Theme) -> Color {
+        match self {
+            Severity::Error => theme.error_color,
+            Severity::Warning => theme.warning_color,
+            Severity::Note => theme.comment_color,
+        }
+    }
+}
+
+// A place the compiler complained about
+pub(crate) struct CompileError {
+    output_line: usize, // Row of the *compilation* buffer it was read from
+    path: PathBuf,
+    canonical: PathBuf, // To compare with the open file
+    line: usize, // 1 based
+    column: usize, // 1 based, 0 when the tool doesn't say
+    severity: Severity,
+    message: String,
+}
+
+// A line of output without its ANSI escapes, and the colors they asked for
+struct CompilationLine {
+    text: String,
+    spans: Vec<(usize, usize, Option<Color>, bool)>, // char range, foreground, bold
+}
+
+impl CompilationLine {
+    fn parse(raw: &str) -> Self {
+        let mut text = String::new();
+        let mut spans = Vec::new();
+        let (mut foreground, mut bold) = (None, false);
+        let (mut span_start, mut count) = (0, 0);
+
+        let mut chars = raw.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\x1b' => {
+                    if chars.peek() != Some(&'[') {
+                        continue;
+                    }
+                    chars.next();
+                    let mut params = String::new();
+                    let mut command = None;
+                    for c in chars.by_ref() {
+                        if c.is_ascii_digit() || c == ';' {
+                            params.push(c);
+                        } else {
+                            command = Some(c);
+                            break;
+                        }
+                    }
+                    if command == Some('m') {
+                        if count > span_start && (foreground.is_some() || bold) {
+                            spans.push((span_start, count, foreground, bold));
+                        }
+                        span_start = count;
+                        CompilationLine::apply_sgr(&params, &mut foreground, &mut bold);
+                    }
+                },
+                '\r' => {},
+                '\t' => {
+                    let width = 8 - count % 8;
+                    text.push_str(&" ".repeat(width));
+                    count += width;
+                },
+                _ => {
+                    text.push(c);
+                    count += 1;
+                },
+            }
+        }
+        if count > span_start && (foreground.is_some() || bold) {
+            spans.push((span_start, count, foreground, bold));
+        }
+        CompilationLine { text, spans }
+    }
+
+    // Select Graphic Rendition, only what compilers use: bold and the foreground
+    fn apply_sgr(params: &str, foreground: &mut Option<Color>, bold: &mut bool) {
+        let codes: Vec<u16> = params.split(';').map(|code| code.parse().unwrap_or(0)).collect();
+        let mut i = 0;
+        while i < codes.len() {
+            match codes[i] {
+                0 => {
+                    *foreground = None;
+                    *bold = false;
+                },
+                1 => *bold = true,
+                22 => *bold = false,
+                code @ 30..=37 => *foreground = Some(Color::AnsiValue((code - 30) as u8)),
+                code @ 90..=97 => *foreground = Some(Color::AnsiValue((code - 90 + 8) as u8)),
+                39 => *foreground = None,
+                38 => match codes.get(i + 1) {
+                    Some(5) if i + 2 < codes.len() => {
+                        *foreground = Some(Color::AnsiValue(codes[i + 2] as u8));
+                        i += 2;
+                    },
+                    Some(2) if i + 4 < codes.len() => {
+                        *foreground = Some(Color::Rgb { r: codes[i + 2] as u8, g: codes[i + 3] as u8, b: codes[i + 4] as u8 });
+                        i += 4;
+                    },
+                    _ => {},
+                },
+                _ => {},
+            }
+            i += 1;
+        }
+    }
+}
+
+// Send each line of `stream` until it closes
+fn forward_lines(stream: impl Read + Send + 'static, sender: Sender<String>) {
+    std::thread::spawn(move || {
+        let mut reader = BufReader::new(stream);
+        let mut line = Vec::new();
+        while reader.read_until(b'\n', &mut line).is_ok_and(|read| read > 0) {
+            let text = String::from_utf8_lossy(&line).trim_end_matches(['\n', '\r']).to_string();
+            if sender.send(text).is_err() {
+                break;
+            }
+            line.clear();
+        }
+    });
+}
+
+// A compile command running in the background, its output streams into
+// the *compilation* buffer
+pub(crate) struct Compilation {
+    pub(crate) title: &'static str, // "Compilation", or what else runs in the buffer
+    pub(crate) back: Mode, // Where q goes
+    command: String,
+    directory: PathBuf,
+    lines: Vec<CompilationLine>,
+    pub(crate) errors: Vec<CompileError>,
+    current_error: Option<usize>, // Last one visited with next-error
+    pub(crate) selected: usize, // Cursor row
+    pub(crate) offset: usize, // First row on screen
+    follow: bool, // Keep the last line selected while output comes in
+    started: std::time::Instant,
+    pub(crate) finished: Option<(ExitStatus, Duration)>,
+    child: Child,
+    receiver: Receiver<String>,
+    pending: Option<(Severity, String)>, // rustc header waiting for its --> line
+    header_re: Regex,
+    location_re: Regex,
+    generic_re: Regex,
+}
+
+impl Compilation {
+    pub(crate) fn start(command: &str, shell: &str, directory: PathBuf) -> io::Result<Self> {
+        // Its own process group, so kill reaches whatever the shell started
+        let mut child = Command::new(shell)
+            .arg("-c")
+            .arg(command)
+            .current_dir(&directory)
+            .env("CARGO_TERM_COLOR", "always")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0)
+            .spawn()?;
+
+        let (sender, receiver) = mpsc::channel();
+        if let Some(stdout) = child.stdout.take() {
+            forward_lines(stdout, sender.clone());
+        }
+        if let Some(stderr) = child.stderr.take() {
+            forward_lines(stderr, sender);
+        }
+
+        Ok(Compilation {
+            title: "Compilation",
+            back: Mode::Normal,
+            command: command.to_string(),
+            directory,
+            lines: Vec::new(),
+            errors: Vec::new(),
+            current_error: None,
+            selected: 0,
+            offset: 0,
+            follow: true,
+            started: std::time::Instant::now(),
+            finished: None,
+            child,
+            receiver,
+            pending: None,
+            header_re: Regex::new(r"^(error|warning)(\[\w+\])?: (.*)$").unwrap(),
+            location_re: Regex::new(r"^\s*--> (.+?):(\d+):(\d+)$").unwrap(),
+            generic_re: Regex::new(r"^([^\s:][^:]*):(\d+):(?:(\d+):)?\s*(?:(fatal error|error|warning|note):)?\s*(.*)$").unwrap(),
+        })
+    }
+
+    // Take the output that came in since last time, true if anything changed
+    pub(crate) fn poll(&mut self) -> bool {
+        let mut changed = false;
+        loop {
+            match self.receiver.try_recv() {
+                Ok(raw) => {
+                    self.lines.push(CompilationLine::parse(&raw));
+                    self.parse_error(self.lines.len() - 1);
+                    changed = true;
+                },
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    if self.finished.is_none() {
+                        if let Ok(Some(status)) = self.child.try_wait() {
+                            self.finished = Some((status, self.started.elapsed()));
+                            changed = true;
+                        }
+                    }
+                    break;
+                },
+            }
+        }
+        if self.follow {
+            self.selected = self.lines.len().saturating_sub(1);
+        }
+        changed
+    }
+
+    // rustc puts the message and the location on different lines, gcc and
+    // most other tools use file:line:col: message
+    fn parse_error(&mut self, output_line: usize) {
+        let text = &self.lines[output_line].text;
+        let (path, line, column, severity, message) = if let Some(caps) = self.header_re.captures(text) {
+            let severity = if &caps[1] == "error" { Severity::Error } else { Severity::Warning };
+            self.pending = Some((severity, caps[3].to_string()));
+            return;
+        } else if let Some(caps) = self.location_re.captures(text) {
+            let (severity, message) = self.pending.take().unwrap_or((Severity::Note, String::new()));
+            (caps[1].to_string(), caps[2].to_string(), caps[3].to_string(), severity, message)
+        } else if let Some(caps) = self.generic_re.captures(text) {
+            let severity = match caps.get(4).map(|m| m.as_str()) {
+                Some("warning") => Severity::Warning,
+                Some("note") => Severity::Note,
+                _ => Severity::Error,
+            };
+            let column = caps.get(3).map_or(String::new(), |m| m.as_str().to_string());
+            (caps[1].to_string(), caps[2].to_string(), column, severity, caps[5].to_string())
+        } else {
+            return;
+        };
+
+        // file:line: also matches plenty of lines that aren't errors
+        let path = self.directory.join(path);
+        if !path.is_file() {
+            return;
+        }
+        self.errors.push(CompileError {
+            output_line,
+            canonical: fs::canonicalize(&path).unwrap_or_else(|_| path.clone()),
+            path,
+            line: line.parse().unwrap_or(1),
+            column: column.parse().unwrap_or(0),
+            severity,
+            message,
+        });
+    }
+
+    // The error the row belongs to, the closest one at or above it
+    fn error_at(&self, row: usize) -> Option<usize> {
+        self.errors.iter().rposition(|error| error.output_line <= row)
+    }
+
+    fn count(&self, severity: Severity) -> usize {
+        self.errors.iter().filter(|error| error.severity == severity).count()
+    }
+
+    pub(crate) fn status(&self) -> String {
+        let errors = format!("{} errors, {} warnings", self.count(Severity::Error), self.count(Severity::Warning));
+        match &self.finished {
+            None => format!("running, {}", errors),
+            Some((status, elapsed)) => {
+                let end = match status.code() {
+                    Some(0) => "finished".to_string(),
+                    Some(code) => format!("exited abnormally with code {}", code),
+                    None => "killed".to_string(),
+                };
+                format!("{} in {:.1}s, {}", end, elapsed.as_secs_f64(), errors)
+            },
+        }
+    }
+
+    fn kill(&mut self) {
+        if self.finished.is_some() {
+            return;
+        }
+        let group = format!("-{}", self.child.id());
+        let killed = Command::new("kill").args(["-TERM", "--", &group]).status().is_ok_and(|status| status.success());
+        if !killed {
+            let _ = self.child.kill();
+        }
+    }
+
+    pub(crate) fn draw(&mut self, stdout: &mut Stdout, height: u16, theme: &Theme) -> io::Result<()> {
+        let (width, _) = terminal::size()?;
+        let header = format!("{} \"{}\" in {}: {}", self.title, self.command, self.directory.display(), self.status());
+        execute!(
+            stdout,
+            MoveTo(3, 0),
+            SetForegroundColor(theme.dired_path_color),
+            SetBackgroundColor(theme.background_color),
+            Print(header),
+            ResetColor
+        )?;
+
+        let visible = height.saturating_sub(2) as usize;
+        if self.selected < self.offset {
+            self.offset = self.selected;
+        } else if visible > 0 && self.selected >= self.offset + visible {
+            self.offset = self.selected + 1 - visible;
+        }
+
+        let room = (width as usize).saturating_sub(3);
+        for (row, line) in self.lines.iter().enumerate().skip(self.offset).take(visible) {
+            let y = (row - self.offset) as u16 + 2;
+            let background = if row == self.selected { theme.hl_line_color } else { theme.background_color };
+            execute!(stdout, MoveTo(0, y), SetBackgroundColor(background), Print(" ".repeat(width as usize)), MoveTo(3, y))?;
+
+            // Tools that don't color their output get the severity color on their errors
+            let plain = match self.errors.binary_search_by_key(&row, |error| error.output_line) {
+                Ok(index) if line.spans.is_empty() => self.errors[index].severity.color(theme),
+                _ => theme.text_color,
+            };
+            let chars: Vec<char> = line.text.chars().take(room).collect();
+            let mut column = 0;
+            for &(start, end, foreground, bold) in line.spans.iter().chain(std::iter::once(&(chars.len(), chars.len(), None, false))) {
+                let (start, end) = (start.min(chars.len()), end.min(chars.len()));
+                execute!(
+                    stdout,
+                    SetForegroundColor(plain),
+                    Print(chars[column.min(start)..start].iter().collect::<String>()),
+                    SetForegroundColor(foreground.unwrap_or(plain)),
+                    SetAttribute(if bold { Attribute::Bold } else { Attribute::NormalIntensity }),
+                    Print(chars[start..end].iter().collect::<String>()),
+                    SetAttribute(Attribute::NormalIntensity)
+                )?;
+                column = end;
+            }
+        }
+        execute!(stdout, ResetColor)?;
+
+        Ok(())
+    }
+}
+
+impl Drop for Compilation {
+    fn drop(&mut self) {
+        self.kill();
+        let _ = self.child.try_wait();
+    }
+}
+
+// The diagnostics of `path` after `old` became `new`: those below the edit
+// move with their lines, those on edited lines are dropped
+pub(crate) fn shift_diagnostics(diagnostics: &mut Vec<Diagnostic>, path: &Path, old: &[Vec<char>], new: &[Vec<char>]) {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let room = old.len().min(new.len()) - prefix;
+    let suffix = old.iter().rev().zip(new.iter().rev()).take(room).take_while(|(a, b)| a == b).count();
+    let edited = prefix..old.len() - suffix;
+    diagnostics.retain_mut(|diagnostic| {
+        if diagnostic.path != path || diagnostic.line < edited.start {
+            return true;
+        }
+        if edited.contains(&diagnostic.line) {
+            return false;
+        }
+        diagnostic.line = diagnostic.line + new.len() - old.len();
+        true
+    });
+}
+
+impl Editor {
+    // TODO if the minibuffer is active
+    // write M-j and M-k keybind in modeline
+    pub fn compile(&mut self) {
+        let command = self.config.compile_command.clone();
+        self.compile_start(&command);
+    }
+
+    // Run the last command again, the configured one if there wasn't any
+    pub fn recompile(&mut self) {
+        match self.compilation.as_ref().map(|compilation| compilation.command.clone()) {
+            Some(command) => self.compile_start(&command),
+            None => self.compile(),
+        }
+    }
+
+    fn compile_start(&mut self, command: &str) {
+        // Dropping the previous run kills it if it is still going
+        self.compilation = None;
+        self.sync_compile_diagnostics();
+        let directory = project_root(&self.current_file_path);
+        match Compilation::start(command, &self.config.shell, directory) {
+            Ok(compilation) => {
+                self.compilation = Some(compilation);
+                self.mode = Mode::Compilation;
+                self.message(&format!("Compiling: {}", command));
+            },
+            Err(e) => self.error(&format!("Failed to execute compile command: {}", e)),
+        }
+    }
+
+    // The compiler's errors as diagnostics, replacing those of the last run
+    pub(crate) fn sync_compile_diagnostics(&mut self) {
+        self.diagnostics.retain(|diagnostic| diagnostic.source != DiagnosticSource::Compilation);
+        self.diagnostic_echoed = None;
+        let Some(compilation) = &self.compilation else { return };
+        for error in &compilation.errors {
+            self.diagnostics.push(Diagnostic {
+                source: DiagnosticSource::Compilation,
+                path: error.canonical.clone(),
+                line: error.line.saturating_sub(1),
+                column: error.column.checked_sub(1),
+                end: None,
+                severity: error.severity,
+                message: error.message.clone(),
+            });
+        }
+    }
+
+    // Diagnostics of the open file
+    pub(crate) fn buffer_diagnostics(&self) -> Vec<&Diagnostic> {
+        if self.diagnostics.is_empty() {
+            return Vec::new();
+        }
+        self.diagnostics.iter().filter(|diagnostic| diagnostic.path == self.current_file_canonical).collect()
+    }
+
+    pub(crate) fn set_current_file(&mut self, path: PathBuf) {
+        self.current_file_canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        self.current_file_path = path;
+        self.diagnostics_lines = None;
+    }
+
+    // After every key: move the open file's diagnostics with the lines they
+    // are about, forgetting those of the lines that were edited
+    pub(crate) fn diagnostics_follow_edits(&mut self) {
+        if !self.diagnostics.iter().any(|diagnostic| diagnostic.path == self.current_file_canonical) {
+            self.diagnostics_lines = None;
+            return;
+        }
+        match &self.diagnostics_lines {
+            Some(lines) if *lines == self.buffer => {},
+            Some(lines) => {
+                let path = self.current_file_canonical.clone();
+                shift_diagnostics(&mut self.diagnostics, &path, lines, &self.buffer);
+                self.diagnostics_lines = Some(self.buffer.to_vec());
+                self.diagnostic_echoed = None;
+            },
+            None => self.diagnostics_lines = Some(self.buffer.to_vec()),
+        }
+    }
+
+    // Show what is wrong with the line the cursor just moved to
+    pub(crate) fn echo_diagnostic(&mut self) {
+        if !matches!(self.mode, Mode::Normal | Mode::Insert | Mode::Visual)
+            || self.minibuffer_active
+            || self.fzy.as_ref().is_some_and(|fzy| fzy.active) {
+            return;
+        }
+        let line = self.cursor_pos.1 as usize;
+        let message = self.buffer_diagnostics().into_iter()
+            .filter(|diagnostic| diagnostic.line == line)
+            .min_by_key(|diagnostic| diagnostic.severity)
+            .map(|diagnostic| format!("{}: {}", diagnostic.severity.label(), diagnostic.message));
+
+        match message {
+            Some(message) if self.diagnostic_echoed != Some(line) => {
+                self.diagnostic_echoed = Some(line);
+                self.message(&message);
+            },
+            Some(_) => {},
+            None => self.diagnostic_echoed = None,
+        }
+    }
+
+    pub fn kill_compilation(&mut self) {
+        match &mut self.compilation {
+            Some(compilation) if compilation.finished.is_none() => {
+                compilation.kill();
+                self.message("Compilation killed");
+            },
+            _ => self.message("No compilation running"),
+        }
+    }
+
+    pub fn next_error(&mut self) {
+        self.error_step(true);
+    }
+
+    pub fn previous_error(&mut self) {
+        self.error_step(false);
+    }
+
+    // M-g n / M-g p, visit the next (or previous) error of the last compilation
+    fn error_step(&mut self, forward: bool) {
+        let Some(compilation) = &mut self.compilation else {
+            self.message("No compilation");
+            return;
+        };
+        let count = compilation.errors.len();
+        let next = match (compilation.current_error, forward) {
+            (None, true) if count > 0 => Some(0),
+            (Some(i), true) if i + 1 < count => Some(i + 1),
+            (Some(i), false) => i.checked_sub(1),
+            _ => None,
+        };
+        match next {
+            Some(index) => {
+                compilation.current_error = Some(index);
+                compilation.selected = compilation.errors[index].output_line;
+                compilation.follow = false;
+                self.compilation_visit(index);
+            },
+            None => self.message(if forward { "No more errors" } else { "No previous error" }),
+        }
+    }
+
+    fn compilation_visit(&mut self, index: usize) {
+        let Some(error) = self.compilation.as_ref().and_then(|compilation| compilation.errors.get(index)) else { return };
+        let (path, line, column) = (error.path.clone(), error.line, error.column);
+        let message = format!("{}: {}", error.severity.label(), error.message);
+
+        if let Err(e) = self.open(&path, None) {
+            self.error(&format!("Failed to open {}: {}", path.display(), e));
+            return;
+        }
+        self.cursor_pos = (column.saturating_sub(1) as u16, line.saturating_sub(1) as u16);
+        self.request_recenter();
+        self.message(&message);
+    }
+
+    pub(crate) fn handle_compilation_mode(&mut self, key: KeyEvent) -> Result<()> {
+        if key.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) {
+            return Ok(());
+        }
+        match key.code {
+            KeyCode::Char('q') => {
+                self.mode = self.compilation.as_ref().map_or(Mode::Normal, |compilation| compilation.back); // TODO to the preferred base mode instead
+            },
+            KeyCode::Char('j') | KeyCode::Down => {
+                if let Some(compilation) = &mut self.compilation {
+                    compilation.selected = (compilation.selected + 1).min(compilation.lines.len().saturating_sub(1));
+                    compilation.follow = compilation.selected + 1 == compilation.lines.len() && compilation.finished.is_none();
+                }
+            },
+            KeyCode::Char('k') | KeyCode::Up => {
+                if let Some(compilation) = &mut self.compilation {
+                    compilation.selected = compilation.selected.saturating_sub(1);
+                    compilation.follow = false;
+                }
+            },
+            KeyCode::Char('G') => {
+                if let Some(compilation) = &mut self.compilation {
+                    compilation.selected = compilation.lines.len().saturating_sub(1);
+                    compilation.follow = compilation.finished.is_none();
+                }
+            },
+            KeyCode::Char('n') | KeyCode::Char('p') => {
+                if let Some(compilation) = &mut self.compilation {
+                    let selected = compilation.selected;
+                    let target = if key.code == KeyCode::Char('n') {
+                        compilation.errors.iter().find(|error| error.output_line > selected)
+                    } else {
+                        compilation.errors.iter().rev().find(|error| error.output_line < selected)
+                    };
+                    match target.map(|error| error.output_line) {
+                        Some(row) => {
+                            compilation.selected = row;
+                            compilation.follow = false;
+                        },
+                        None => self.message("No more errors"),
+                    }
+                }
+            },
+            KeyCode::Char('g') => {
+                self.recompile();
+            },
+            KeyCode::Char('K') => {
+                self.kill_compilation();
+            },
+            KeyCode::Enter | KeyCode::Char('o') => {
+                let index = self.compilation.as_ref().and_then(|compilation| compilation.error_at(compilation.selected));
+                match index {
+                    Some(index) => {
+                        if let Some(compilation) = &mut self.compilation {
+                            compilation.current_error = Some(index);
+                        }
+                        self.compilation_visit(index);
+                    },
+                    None => self.message("No error here"),
+                }
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<Vec<char>> {
+        text.lines().map(|line| line.chars().collect()).collect()
+    }
+
+    fn diagnostic(path: &str, line: usize) -> Diagnostic {
+        Diagnostic {
+            source: DiagnosticSource::Compilation,
+            path: PathBuf::from(path),
+            line,
+            column: None,
+            end: None,
+            severity: Severity::Error,
+            message: String::new(),
+        }
+    }
+
+    fn shifted(old: &str, new: &str, at: &[usize]) -> Vec<usize> {
+        let mut diagnostics: Vec<Diagnostic> = at.iter().map(|&line| diagnostic("/a.rs", line)).collect();
+        shift_diagnostics(&mut diagnostics, Path::new("/a.rs"), &lines(old), &lines(new));
+        diagnostics.iter().map(|diagnostic| diagnostic.line).collect()
+    }
+
+    #[test]
+    fn diagnostics_move_with_inserted_and_deleted_lines() {
+        assert_eq!(shifted("a\nb\nc\nd", "a\nnew\nnew\nb\nc\nd", &[0, 1, 3]), vec![0, 3, 5]);
+        assert_eq!(shifted("a\nb\nc\nd", "a\nd", &[0, 3]), vec![0, 1]);
+        assert_eq!(shifted("a\nb", "a\nb\nc", &[0, 1]), vec![0, 1]);
+        assert_eq!(shifted("a\nb", "z\na\nb", &[0, 1]), vec![1, 2]);
+    }
+
+    #[test]
+    fn diagnostics_of_edited_lines_are_dropped() {
+        assert_eq!(shifted("a\nb\nc", "a\nB\nc", &[0, 1, 2]), vec![0, 2]);
+        assert_eq!(shifted("a\nb\nc\nd", "a\nd", &[1, 2, 3]), vec![1]);
+        assert_eq!(shifted("a\nb\nc", "", &[0, 2]), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn diagnostics_of_other_files_stay() {
+        let mut diagnostics = vec![diagnostic("/b.rs", 0), diagnostic("/b.rs", 4)];
+        shift_diagnostics(&mut diagnostics, Path::new("/a.rs"), &lines("a"), &lines("x\ny\na"));
+        assert_eq!(diagnostics.iter().map(|diagnostic| diagnostic.line).collect::<Vec<_>>(), vec![0, 4]);
+    }
+
+    type Parsed = (String, usize, usize, Severity, String);
+
+    // What the *compilation* buffer makes of `output`, printed in a directory
+    // with main.rs and lib/util.c in it
+    fn parsed(name: &str, output: &str) -> Vec<Parsed> {
+        let dir = crate::tests::temp_dir(name);
+        fs::create_dir(dir.join("lib")).unwrap();
+        for file in ["main.rs", "lib/util.c", "output"] {
+            fs::write(dir.join(file), if file == "output" { output } else { "" }).unwrap();
+        }
+        let mut compilation = Compilation::start("cat output", "sh", dir.clone()).unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while compilation.finished.is_none() {
+            assert!(std::time::Instant::now() < deadline, "cat didn't finish");
+            compilation.poll();
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        compilation.errors.iter().map(|error| (
+            error.path.strip_prefix(&dir).unwrap().display().to_string(),
+            error.line,
+            error.column,
+            error.severity,
+            error.message.clone(),
+        )).collect()
+    }
+
+    fn expected(errors: &[(&str, usize, usize, Severity, &str)]) -> Vec<Parsed> {
+        errors.iter().map(|&(path, line, column, severity, message)| (path.to_string(), line, column, severity, message.to_string())).collect()
+    }
+
+    #[test]
+    fn rustc_errors_take_the_location_from_the_next_line() {
+        let output = "   Compiling redit v0.1.0\n\
+            error[E0308]: mismatched types\n\
+            \x20--> main.rs:3:5\n\
+            \x20 |\n\
+            warning: unused variable: `x`\n\
+            \x20  --> main.rs:10:9\n\
+            error: can't find crate\n\
+            \x20--> missing.rs:1:1\n\
+            error: aborting due to 2 previous errors\n";
+        assert_eq!(parsed("compilation-rustc", output), expected(&[
+            ("main.rs", 3, 5, Severity::Error, "mismatched types"),
+            ("main.rs", 10, 9, Severity::Warning, "unused variable: `x`"),
+        ]));
+    }
+
+    #[test]
+    fn gcc_errors_are_on_one_line() {
+        let output = "In file included from lib/util.c:1:\n\
+            lib/util.c:12:7: error: expected ';' before '}' token\n\
+            lib/util.c:3:1: warning: unused function 'f'\n\
+            lib/util.c:4:2: note: declared here\n\
+            lib/util.c:5:10: fatal error: x.h: No such file or directory\n\
+            lib/gone.c:1:1: error: not there\n\
+            gcc -c lib/util.c\n";
+        assert_eq!(parsed("compilation-gcc", output), expected(&[
+            ("lib/util.c", 12, 7, Severity::Error, "expected ';' before '}' token"),
+            ("lib/util.c", 3, 1, Severity::Warning, "unused function 'f'"),
+            ("lib/util.c", 4, 2, Severity::Note, "declared here"),
+            ("lib/util.c", 5, 10, Severity::Error, "x.h: No such file or directory"),
+        ]));
+    }
+
+    #[test]
+    fn other_tools_give_file_and_line() {
+        let output = "main.rs:7: something is off\n\
+            lib/util.c:9:2:no space after the colon\n\
+            12:30:45 build started\n\
+            see https://example.com:443/docs\n\
+            main.rs: no line number\n";
+        assert_eq!(parsed("compilation-generic", output), expected(&[
+            ("main.rs", 7, 0, Severity::Error, "something is off"),
+            ("lib/util.c", 9, 2, Severity::Error, "no space after the colon"),
+        ]));
+    }
+}
@@ -0,0 +1,500 @@
+use super::*;
+
+const COMPLETION_MAX_ITEMS: usize = 10;
+const COMPLETION_MAX_LABEL: usize = 40;
+const COMPLETION_DOC_WIDTH: usize = 50;
+const COMPLETION_DOC_LINES: usize = 12;
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum CompletionSource {
+    Lsp,
+    Buffer,
+    Path,
+    Lua,
+}
+
+impl CompletionSource {
+    fn label(&self) -> &'static str {
+        match self {
+            CompletionSource::Lsp => "lsp",
+            CompletionSource::Buffer => "buf",
+            CompletionSource::Path => "file",
+            CompletionSource::Lua => "lua",
+        }
+    }
+}
+
+pub(crate) struct CompletionItem {
+    pub(crate) label: String,
+    insert: String,
+    pub(crate) source: CompletionSource,
+    pub(crate) detail: String,
+    pub(crate) documentation: String,
+    pub(crate) additional_edits: Vec<Json>, // Like the import of what gets completed
+    unresolved: Option<Json>, // The server's item, until it tells us more about it
+}
+
+impl CompletionItem {
+    fn new(label: String, source: CompletionSource) -> Self {
+        CompletionItem {
+            insert: label.clone(),
+            label,
+            source,
+            detail: String::new(),
+            documentation: String::new(),
+            additional_edits: Vec::new(),
+            unresolved: None,
+        }
+    }
+
+    pub(crate) fn from_lsp(item: &Json) -> Self {
+        let label = item.get("label").as_str().unwrap_or("").to_string();
+        let insert = item.get("textEdit").get("newText").as_str()
+            .or_else(|| item.get("insertText").as_str())
+            .unwrap_or(&label)
+            .to_string();
+        // Snippets: keep the placeholders' text, drop the tab stops
+        let insert = if item.get("insertTextFormat").as_i64() == Some(2) {
+            let placeholder = Regex::new(r"\$\{\d+:([^}]*)\}").unwrap();
+            let tab_stop = Regex::new(r"\$\{\d+\}|\$\d+").unwrap();
+            tab_stop.replace_all(&placeholder.replace_all(&insert, "$1"), "").into_owned()
+        } else {
+            insert
+        };
+        CompletionItem {
+            label,
+            insert,
+            source: CompletionSource::Lsp,
+            detail: item.get("detail").as_str().unwrap_or("").to_string(),
+            documentation: lsp_markup_text(item.get("documentation")),
+            additional_edits: item.get("additionalTextEdits").as_array().to_vec(),
+            unresolved: Some(item.clone()),
+        }
+    }
+
+    fn has_documentation(&self) -> bool {
+        !self.detail.is_empty() || !self.documentation.is_empty()
+    }
+}
+
+// The insert mode completion popup, anchored where the completed word starts
+pub(crate) struct CompletionPopup {
+    pub(crate) line: usize,
+    pub(crate) start: usize,
+    path: bool, // Completing a file name, which can have dots and dashes
+    pub(crate) items: Vec<CompletionItem>,
+    pub(crate) matches: Vec<(usize, Vec<usize>)>, // Items that fit what is typed, best first, with the matched chars
+    selected: usize,
+    offset: usize,
+    pub(crate) waiting: bool, // For the language server's items
+}
+
+impl CompletionPopup {
+    fn new(line: usize, start: usize, path: bool, items: Vec<CompletionItem>) -> Self {
+        CompletionPopup { line, start, path, items, matches: Vec::new(), selected: 0, offset: 0, waiting: false }
+    }
+
+    fn accepts(&self, c: char) -> bool {
+        if self.path {
+            !c.is_whitespace() && c != '/'
+        } else {
+            c.is_alphanumeric() || c == '_'
+        }
+    }
+
+    pub(crate) fn filter(&mut self, typed: &str) {
+        let needle: Vec<char> = typed.to_lowercase().chars().collect();
+        let mut scratch = FzyScratch::default();
+        let mut matches: Vec<(f64, usize, Vec<usize>)> = self.items.iter().enumerate().filter_map(|(index, item)| {
+            if needle.is_empty() {
+                return Some((0.0, index, Vec::new()));
+            }
+            // A word already typed out isn't worth offering
+            if item.label == typed && item.source == CompletionSource::Buffer {
+                return None;
+            }
+            let (score, positions) = fzy_score(&needle, &item.label, &mut scratch)?;
+            Some((score, index, positions))
+        }).collect();
+        matches.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal).then(a.1.cmp(&b.1)));
+        self.matches = matches.into_iter().map(|(_, index, positions)| (index, positions)).collect();
+        self.selected = 0;
+        self.offset = 0;
+    }
+
+    fn selected_item(&self) -> Option<&CompletionItem> {
+        self.matches.get(self.selected).map(|(index, _)| &self.items[*index])
+    }
+
+    fn select(&mut self, delta: isize) {
+        if self.matches.is_empty() {
+            return;
+        }
+        let count = self.matches.len() as isize;
+        self.selected = (self.selected as isize + delta).rem_euclid(count) as usize;
+        if self.selected < self.offset {
+            self.offset = self.selected;
+        } else if self.selected >= self.offset + COMPLETION_MAX_ITEMS {
+            self.offset = self.selected + 1 - COMPLETION_MAX_ITEMS;
+        }
+    }
+
+    // `anchor` is the screen cell of the word start, the popup goes below it
+    // unless it would cross `bottom`, with the documentation beside it
+    pub(crate) fn draw(&self, stdout: &mut Stdout, anchor: (u16, u16), bottom: u16, width: u16, theme: &Theme) -> io::Result<()> {
+        let rows = self.matches.len().min(COMPLETION_MAX_ITEMS);
+        if rows == 0 {
+            return Ok(());
+        }
+        let label_width = self.matches.iter()
+            .map(|(index, _)| self.items[*index].label.chars().count())
+            .max()
+            .unwrap_or(0)
+            .min(COMPLETION_MAX_LABEL);
+        let popup_width = label_width + 7;
+        let below = anchor.1 as usize + 1 + rows <= bottom as usize;
+        let top = if below { anchor.1 + 1 } else { anchor.1.saturating_sub(rows as u16) };
+        let x = (anchor.0 as usize).min((width as usize).saturating_sub(popup_width)) as u16;
+
+        for (row, (index, positions)) in self.matches.iter().enumerate().skip(self.offset).take(rows) {
+            let item = &self.items[*index];
+            let selected = row == self.selected;
+            let (background, foreground, match_color) = if selected {
+                (theme.normal_cursor_color, Color::Black, Color::Black)
+            } else {
+                (theme.modeline_color, theme.text_color, theme.normal_cursor_color)
+            };
+            execute!(stdout, MoveTo(x, top + (row - self.offset) as u16), SetBackgroundColor(background), Print(" "))?;
+            let label: Vec<char> = item.label.chars().take(label_width).collect();
+            for (char_index, c) in label.iter().enumerate() {
+                if positions.contains(&char_index) {
+                    execute!(stdout, SetForegroundColor(match_color), SetAttribute(Attribute::Bold), Print(c), SetAttribute(Attribute::NormalIntensity))?;
+                } else {
+                    execute!(stdout, SetForegroundColor(foreground), Print(c))?;
+                }
+            }
+            let source_color = if selected { Color::Black } else { theme.comment_color };
+            execute!(
+                stdout,
+                Print(" ".repeat(label_width - label.len() + 1)),
+                SetForegroundColor(source_color),
+                Print(format!("{:>4} ", item.source.label())),
+            )?;
+        }
+
+        let Some(item) = self.selected_item().filter(|item| item.has_documentation()) else {
+            return Ok(());
+        };
+        let doc_x = if x as usize + popup_width + COMPLETION_DOC_WIDTH <= width as usize {
+            x as usize + popup_width
+        } else if x as usize >= COMPLETION_DOC_WIDTH {
+            x as usize - COMPLETION_DOC_WIDTH
+        } else {
+            return Ok(());
+        };
+        let max_lines = if below { (bottom - top) as usize } else { rows }.min(COMPLETION_DOC_LINES);
+        let text = [item.detail.as_str(), item.documentation.as_str()]
+            .iter()
+            .filter(|part| !part.is_empty())
+            .copied()
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        let mut lines = Vec::new();
+        for line in text.lines() {
+            let chars: Vec<char> = line.chars().collect();
+            if chars.is_empty() {
+                lines.push(String::new());
+            }
+            lines.extend(chars.chunks(COMPLETION_DOC_WIDTH - 2).map(|chunk| chunk.iter().collect::<String>()));
+        }
+        for (row, line) in lines.iter().take(max_lines).enumerate() {
+            execute!(
+                stdout,
+                MoveTo(doc_x as u16, top + row as u16),
+                SetBackgroundColor(theme.hl_line_color),
+                SetForegroundColor(theme.text_color),
+                Print(format!(" {:<width$} ", line, width = COMPLETION_DOC_WIDTH - 2)),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// The words of a line, in order and not yet in `seen`. Not the one around
+// column `skip`, which is being typed, nor numbers
+fn collect_words(line: &[char], skip: Option<usize>, seen: &mut HashSet<String>, words: &mut Vec<String>) {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let mut start = None;
+    for (i, &c) in line.iter().chain(std::iter::once(&' ')).enumerate() {
+        match (start, is_word(c)) {
+            (None, true) => start = Some(i),
+            (Some(from), false) => {
+                start = None;
+                if i - from < 2 || skip.is_some_and(|x| (from..=i).contains(&x)) || line[from].is_numeric() {
+                    continue;
+                }
+                let word: String = line[from..i].iter().collect();
+                if seen.insert(word.clone()) {
+                    words.push(word);
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<std::time::SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+impl Editor {
+    // Keep the words of the buffer being left, so completing in the others
+    // doesn't read it back from disk
+    pub(crate) fn remember_buffer_words(&mut self) {
+        if !self.current_file_path.is_file() {
+            return;
+        }
+        let mut words = Vec::new();
+        let mut seen = HashSet::new();
+        for line in self.buffer.iter() {
+            collect_words(line, None, &mut seen, &mut words);
+        }
+        self.buffer_words.insert(self.current_file_path.clone(), (modified(&self.current_file_path), words));
+    }
+
+    // dabbrev: the words of this buffer, closest to the cursor first, then
+    // those of the other open buffers, read again only once their file changed
+    fn buffer_words(&mut self) -> Vec<String> {
+        let mut seen = HashSet::new();
+        let mut words = Vec::new();
+        let row = self.cursor_pos.1 as usize;
+        let mut rows: Vec<usize> = (0..self.buffer.len()).collect();
+        rows.sort_by_key(|&i| i.abs_diff(row));
+        for i in rows {
+            collect_words(&self.buffer[i], (i == row).then_some(self.cursor_pos.0 as usize), &mut seen, &mut words);
+        }
+        for path in &self.buffer_stack {
+            if *path == self.current_file_path {
+                continue;
+            }
+            let mtime = modified(path);
+            let cached = self.buffer_words.get(path).filter(|(when, _)| *when == mtime);
+            if cached.is_none() {
+                let mut file_words = Vec::new();
+                if let Ok(text) = fs::read_to_string(path) {
+                    let mut file_seen = HashSet::new();
+                    for line in text.lines() {
+                        collect_words(&line.chars().collect::<Vec<char>>(), None, &mut file_seen, &mut file_words);
+                    }
+                }
+                self.buffer_words.insert(path.clone(), (mtime, file_words));
+            }
+            for word in &self.buffer_words[path].1 {
+                if seen.insert(word.clone()) {
+                    words.push(word.clone());
+                }
+            }
+        }
+        words
+    }
+
+    // Open the popup for the word before the cursor. Automatically, only once
+    // enough of it is typed
+    pub(crate) fn completion_start(&mut self, manual: bool) {
+        self.completion_due = None;
+        let row = self.cursor_pos.1 as usize;
+        let line = &self.buffer[row];
+        let x = (self.cursor_pos.0 as usize).min(line.len());
+
+        // A file name when what is before the cursor is an existing directory
+        let token_start = line[..x].iter().rposition(|&c| c.is_whitespace() || "\"'`(".contains(c)).map_or(0, |i| i + 1);
+        let token: String = line[token_start..x].iter().collect();
+        let base = self.current_file_path.parent().filter(|dir| dir.is_dir()).map_or_else(|| env::current_dir().unwrap_or_default(), Path::to_path_buf);
+        let paths = match token.rfind('/') {
+            Some(slash) => {
+                let directory = &token[..=slash];
+                let names: Vec<String> = file_name_candidates(&base, directory).into_iter()
+                    .map(|candidate| candidate[directory.len()..].to_string())
+                    .collect();
+                (!names.is_empty()).then(|| (token_start + directory.chars().count(), names))
+            },
+            None => None,
+        };
+
+        let (start, path, mut items) = match paths {
+            Some((start, names)) => {
+                let items = names.into_iter().map(|name| CompletionItem::new(name, CompletionSource::Path)).collect();
+                (start, true, items)
+            },
+            None => {
+                let start = line[..x].iter().rposition(|&c| !(c.is_alphanumeric() || c == '_')).map_or(0, |i| i + 1);
+                let items: Vec<CompletionItem> = self.buffer_words().into_iter().map(|word| CompletionItem::new(word, CompletionSource::Buffer)).collect();
+                (start, false, items)
+            },
+        };
+        let typed: String = self.buffer[row][start..x].iter().collect();
+        if !manual && typed.chars().count() < self.config.completion_min_prefix {
+            return;
+        }
+
+        if !path && self.current_file_path.extension().is_some_and(|extension| extension == "lua") {
+            let mut names = self.lua_global_names();
+            names.sort();
+            items.extend(names.into_iter().map(|name| CompletionItem::new(name, CompletionSource::Lua)));
+        }
+        let lsp = !path && self.lsp_current().is_some_and(|(client, _)| !client.capabilities.get("completionProvider").is_null());
+
+        let mut popup = CompletionPopup::new(row, start, path, items);
+        popup.filter(&typed);
+        popup.waiting = lsp;
+        if popup.matches.is_empty() && !lsp {
+            if manual {
+                self.message("No completions");
+            }
+            return;
+        }
+        self.completion_popup = Some(popup);
+        if lsp {
+            self.lsp_request("textDocument/completion", Vec::new(), LspRequest::Completion);
+        }
+    }
+
+    pub fn completion_at_point(&mut self) {
+        if self.mode == Mode::Insert {
+            self.completion_start(true);
+        }
+    }
+
+    // After an insert mode key: narrow the popup to what is typed now, close
+    // it when the cursor left the word, or plan to open it
+    pub(crate) fn completion_after_key(&mut self, key: KeyEvent) {
+        let inserted = matches!(key.code, KeyCode::Char(_)) && (key.modifiers == KeyModifiers::NONE || key.modifiers == KeyModifiers::SHIFT);
+        if self.mode != Mode::Insert || !(inserted || key.code == KeyCode::Backspace) {
+            self.completion_popup = None;
+            self.completion_due = None;
+            return;
+        }
+
+        let row = self.cursor_pos.1 as usize;
+        let x = self.cursor_pos.0 as usize;
+        if let Some(popup) = &mut self.completion_popup {
+            let line = &self.buffer[row];
+            if row != popup.line || x < popup.start || x > line.len() || !line[popup.start..x].iter().all(|&c| popup.accepts(c)) {
+                self.completion_popup = None;
+            } else {
+                let typed: String = line[popup.start..x].iter().collect();
+                popup.filter(&typed);
+                if popup.matches.is_empty() && !popup.waiting {
+                    self.completion_popup = None;
+                }
+            }
+        }
+
+        if self.completion_popup.is_none() && self.config.completion_auto && inserted {
+            let line = &self.buffer[row];
+            let x = x.min(line.len());
+            let typed = line[..x].iter().rev().take_while(|&&c| c.is_alphanumeric() || c == '_').count();
+            self.completion_due = (typed >= self.config.completion_min_prefix)
+                .then(|| std::time::Instant::now() + Duration::from_millis(self.config.completion_delay));
+        }
+    }
+
+    // Popup keys come before the insert mode ones, false to let them through
+    pub(crate) fn handle_completion_keys(&mut self, key: KeyEvent) -> bool {
+        let Some(popup) = &mut self.completion_popup else { return false };
+        if popup.matches.is_empty() {
+            return false;
+        }
+        match (key.code, key.modifiers) {
+            (KeyCode::Tab, KeyModifiers::NONE) | (KeyCode::Down, KeyModifiers::NONE) | (KeyCode::Char('n'), KeyModifiers::CONTROL) => popup.select(1),
+            (KeyCode::BackTab, _) | (KeyCode::Up, KeyModifiers::NONE) | (KeyCode::Char('p'), KeyModifiers::CONTROL) => popup.select(-1),
+            (KeyCode::Enter, KeyModifiers::NONE) => {
+                self.completion_accept();
+                return true;
+            },
+            (KeyCode::Char('g'), KeyModifiers::CONTROL) => {
+                self.completion_popup = None;
+                return true;
+            },
+            _ => return false,
+        }
+        self.completion_resolve();
+        true
+    }
+
+    // Replace the typed part of the word with the selected item
+    fn completion_accept(&mut self) {
+        let Some(popup) = self.completion_popup.take() else { return };
+        let Some(item) = popup.selected_item() else { return };
+        let row = popup.line;
+        let x = (self.cursor_pos.0 as usize).min(self.buffer[row].len());
+
+        let tail = self.buffer[row].split_off(x);
+        self.buffer[row].truncate(popup.start);
+        let mut inserted = item.insert.split('\n');
+        self.buffer[row].extend(inserted.next().unwrap_or("").chars());
+        let mut last = row;
+        for text in inserted {
+            last += 1;
+            self.buffer.insert(last, text.chars().collect());
+        }
+        self.cursor_pos = (self.buffer[last].len() as u16, last as u16);
+        self.buffer[last].extend(tail);
+
+        // Imports land above the cursor, which moves down with them
+        if !item.additional_edits.is_empty() {
+            let before = self.buffer.len();
+            apply_text_edits(&mut self.buffer, &item.additional_edits);
+            let added = self.buffer.len() as isize - before as isize;
+            self.cursor_pos.1 = (self.cursor_pos.1 as isize + added).max(0) as u16;
+            self.lsp_buffer_edited();
+        }
+    }
+
+    // Ask the server for the documentation of the selected item
+    pub(crate) fn completion_resolve(&mut self) {
+        let Some(popup) = &mut self.completion_popup else { return };
+        let Some(&(index, _)) = popup.matches.get(popup.selected) else { return };
+        let Some(item) = popup.items[index].unresolved.take() else { return };
+        let result = self.lsp_current()
+            .filter(|(client, _)| *client.capabilities.get("completionProvider").get("resolveProvider") == Json::Bool(true))
+            .map(|(client, _)| client.request("completionItem/resolve", item, LspRequest::ResolveCompletion));
+        if let Some(Err(e)) = result {
+            self.messages.push(format!("Language server: {}", e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(line: &str, skip: Option<usize>) -> Vec<String> {
+        let mut words = Vec::new();
+        collect_words(&line.chars().collect::<Vec<char>>(), skip, &mut HashSet::new(), &mut words);
+        words
+    }
+
+    #[test]
+    fn collect_words_skips_short_words_and_numbers() {
+        assert_eq!(words("let x = foo_bar(42, 3d, baz);", None), vec!["let", "foo_bar", "baz"]);
+        assert_eq!(words("émile épée é", None), vec!["émile", "épée"]);
+    }
+
+    #[test]
+    fn collect_words_skips_the_word_being_typed() {
+        assert_eq!(words("compl compute", Some(5)), vec!["compute"]);
+        assert_eq!(words("compl compute", Some(0)), vec!["compute"]);
+        assert_eq!(words("compl compute", Some(6)), vec!["compl"]);
+    }
+
+    #[test]
+    fn collect_words_keeps_the_first_occurrence() {
+        let mut seen = HashSet::new();
+        let mut words = Vec::new();
+        for line in ["alpha beta", "beta gamma alpha"] {
+            collect_words(&line.chars().collect::<Vec<char>>(), None, &mut seen, &mut words);
+        }
+        assert_eq!(words, vec!["alpha", "beta", "gamma"]);
+    }
+}
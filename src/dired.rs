@@ -0,0 +1,2565 @@
+use super::*;
+
+// The `ls -l` columns of an entry, read once per refresh
+#[derive(Clone)]
+pub(crate) struct DiredEntry {
+    pub(crate) name: String,
+    path: PathBuf,
+    metadata: Option<fs::Metadata>, // Of the link itself, None when it can't be read
+    link_target: Option<PathBuf>,
+    broken_link: bool,
+    is_dir: bool, // Following links
+    pub(crate) depth: usize, // Levels below the directory of the listing, in the tree view
+}
+
+impl DiredEntry {
+    fn read(path: PathBuf, name: String) -> Self {
+        let metadata = fs::symlink_metadata(&path).ok();
+        let is_link = metadata.as_ref().is_some_and(|m| m.file_type().is_symlink());
+        let link_target = if is_link { fs::read_link(&path).ok() } else { None };
+        let target = fs::metadata(&path);
+        DiredEntry {
+            name,
+            broken_link: is_link && target.is_err(),
+            is_dir: target.is_ok_and(|m| m.is_dir()),
+            depth: 0,
+            path,
+            metadata,
+            link_target,
+        }
+    }
+
+    // Type and permission bits the way `ls -l` writes them
+    fn mode_string(&self) -> String {
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+        let Some(metadata) = &self.metadata else { return "??????????".to_string() };
+        let file_type = metadata.file_type();
+        let kind = if file_type.is_symlink() {
+            'l'
+        } else if file_type.is_dir() {
+            'd'
+        } else if file_type.is_char_device() {
+            'c'
+        } else if file_type.is_block_device() {
+            'b'
+        } else if file_type.is_fifo() {
+            'p'
+        } else if file_type.is_socket() {
+            's'
+        } else {
+            '-'
+        };
+
+        let mode = metadata.permissions().mode();
+        let bit = |mask: u32, ch: char| if mode & mask != 0 { ch } else { '-' };
+        // Setuid, setgid and sticky take the place of an x
+        let special = |exec: u32, flag: u32, set: char| match (mode & exec != 0, mode & flag != 0) {
+            (true, true) => set,
+            (false, true) => set.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        };
+        [
+            kind,
+            bit(0o400, 'r'), bit(0o200, 'w'), special(0o100, 0o4000, 's'),
+            bit(0o040, 'r'), bit(0o020, 'w'), special(0o010, 0o2000, 's'),
+            bit(0o004, 'r'), bit(0o002, 'w'), special(0o001, 0o1000, 't'),
+        ].iter().collect()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum DiredSort {
+    Name,
+    Size, // Largest first
+    Modified, // Newest first
+    Extension,
+}
+
+impl DiredSort {
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "name" => Some(DiredSort::Name),
+            "size" => Some(DiredSort::Size),
+            "time" => Some(DiredSort::Modified),
+            "extension" => Some(DiredSort::Extension),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            DiredSort::Name => "name",
+            DiredSort::Size => "size",
+            DiredSort::Modified => "time",
+            DiredSort::Extension => "extension",
+        }
+    }
+
+    fn next(&self) -> Self {
+        match self {
+            DiredSort::Name => DiredSort::Size,
+            DiredSort::Size => DiredSort::Modified,
+            DiredSort::Modified => DiredSort::Extension,
+            DiredSort::Extension => DiredSort::Name,
+        }
+    }
+}
+
+// How a listing is shown, kept from one directory to the next
+#[derive(Clone, Copy)]
+pub(crate) struct DiredView {
+    sort: DiredSort,
+    directories_first: bool,
+    show_hidden: bool,
+    preview: bool,
+}
+
+impl DiredView {
+    // Stable, so equal keys stay in name order
+    fn sort(&self, entries: &mut [DiredEntry]) {
+        use std::cmp::Ordering as Order;
+
+        let size = |entry: &DiredEntry| entry.metadata.as_ref().map_or(0, |m| m.len());
+        let modified = |entry: &DiredEntry| entry.metadata.as_ref().and_then(|m| m.modified().ok());
+        let extension = |entry: &DiredEntry| Path::new(&entry.name).extension().map(|ext| ext.to_string_lossy().to_lowercase());
+        entries.sort_by(|a, b| a.name.to_lowercase().cmp(&b.name.to_lowercase()).then_with(|| a.name.cmp(&b.name)));
+        entries.sort_by(|a, b| {
+            let kind = if self.directories_first { b.is_dir.cmp(&a.is_dir) } else { Order::Equal };
+            kind.then_with(|| match self.sort {
+                DiredSort::Name => Order::Equal,
+                DiredSort::Size => size(b).cmp(&size(a)),
+                DiredSort::Modified => modified(b).cmp(&modified(a)),
+                DiredSort::Extension => extension(a).cmp(&extension(b)),
+            })
+        });
+    }
+}
+
+// What dired does to the marked files, or the one under the cursor
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum DiredOp {
+    Copy,
+    Move,
+    Symlink,
+    Chmod,
+    Trash,
+    Delete, // For good, not to the trash
+}
+
+impl DiredOp {
+    pub(crate) fn verb(&self) -> &'static str {
+        match self {
+            DiredOp::Copy => "Copy",
+            DiredOp::Move => "Move",
+            DiredOp::Symlink => "Symlink",
+            DiredOp::Chmod => "Change mode of",
+            DiredOp::Trash => "Trash",
+            DiredOp::Delete => "Permanently delete",
+        }
+    }
+
+    pub(crate) fn done(&self) -> &'static str {
+        match self {
+            DiredOp::Copy => "Copied",
+            DiredOp::Move => "Moved",
+            DiredOp::Symlink => "Linked",
+            DiredOp::Chmod => "Changed mode of",
+            DiredOp::Trash => "Trashed",
+            DiredOp::Delete => "Deleted",
+        }
+    }
+}
+
+// What a dired key does
+#[derive(Clone, Copy, PartialEq)]
+enum DiredCommand {
+    Next,
+    Previous,
+    Parent,
+    Visit,
+    Expand,
+    Refresh,
+    Sort,
+    Hidden,
+    Filter,
+    Preview,
+    Mark(Option<char>), // '*', 'D' or none to unmark
+    ToggleMarks,
+    UnmarkAll,
+    Prefix(char), // % and *, a second key says what
+    DeleteFlagged,
+    Do(DiredOp),
+    Rename,
+    Wdired,
+    Touch { open: bool },
+    Mkdir,
+    Shell { background: bool },
+    OpenExternal,
+    Help,
+    Quit,
+}
+
+// The keys of dired, handle_dired_mode goes by it and ? lists it
+const DIRED_KEYS: &[(&[KeyCode], DiredCommand, &str)] = &[
+    (&[KeyCode::Char('j'), KeyCode::Char('n'), KeyCode::Down], DiredCommand::Next, "next"),
+    (&[KeyCode::Char('k'), KeyCode::Char('p'), KeyCode::Up], DiredCommand::Previous, "previous"),
+    (&[KeyCode::Char('h'), KeyCode::Char('b'), KeyCode::Left], DiredCommand::Parent, "parent"),
+    (&[KeyCode::Char('l'), KeyCode::Char('f'), KeyCode::Right, KeyCode::Enter], DiredCommand::Visit, "open"),
+    (&[KeyCode::Tab], DiredCommand::Expand, "expand"),
+    (&[KeyCode::Char('g')], DiredCommand::Refresh, "refresh"),
+    (&[KeyCode::Char('s')], DiredCommand::Sort, "sort"),
+    (&[KeyCode::Char('.')], DiredCommand::Hidden, "hidden files"),
+    (&[KeyCode::Char('/')], DiredCommand::Filter, "filter"),
+    (&[KeyCode::Char('P')], DiredCommand::Preview, "preview"),
+    (&[KeyCode::Char('m')], DiredCommand::Mark(Some('*')), "mark"),
+    (&[KeyCode::Char('u')], DiredCommand::Mark(None), "unmark"),
+    (&[KeyCode::Char('t')], DiredCommand::ToggleMarks, "toggle marks"),
+    (&[KeyCode::Char('U')], DiredCommand::UnmarkAll, "unmark all"),
+    (&[KeyCode::Char('%')], DiredCommand::Prefix('%'), "m mark by regexp"),
+    (&[KeyCode::Char('*')], DiredCommand::Prefix('*'), "/ mark directories"),
+    (&[KeyCode::Char('d')], DiredCommand::Mark(Some('D')), "flag"),
+    (&[KeyCode::Char('x')], DiredCommand::DeleteFlagged, "trash flagged"),
+    (&[KeyCode::Char('D')], DiredCommand::Do(DiredOp::Trash), "trash"),
+    (&[KeyCode::Char('C')], DiredCommand::Do(DiredOp::Copy), "copy"),
+    (&[KeyCode::Char('R')], DiredCommand::Do(DiredOp::Move), "move"),
+    (&[KeyCode::Char('S')], DiredCommand::Do(DiredOp::Symlink), "symlink"),
+    (&[KeyCode::Char('M')], DiredCommand::Do(DiredOp::Chmod), "chmod"),
+    (&[KeyCode::Char('r')], DiredCommand::Rename, "rename"),
+    (&[KeyCode::Char('i')], DiredCommand::Wdired, "edit names"),
+    (&[KeyCode::Char('T')], DiredCommand::Touch { open: false }, "touch"),
+    (&[KeyCode::Char('c')], DiredCommand::Touch { open: true }, "new file"),
+    (&[KeyCode::Char('+')], DiredCommand::Mkdir, "mkdir"),
+    (&[KeyCode::Char('!')], DiredCommand::Shell { background: false }, "shell command"),
+    (&[KeyCode::Char('&')], DiredCommand::Shell { background: true }, "in the background"),
+    (&[KeyCode::Char('W')], DiredCommand::OpenExternal, "open outside"),
+    (&[KeyCode::Char('?')], DiredCommand::Help, "help"),
+    (&[KeyCode::Char('q')], DiredCommand::Quit, "quit"),
+];
+
+// "j/n next, k/p previous, ...", arrows left out
+fn dired_help() -> String {
+    let keys = |keys: &[KeyCode]| keys.iter().filter_map(|key| match key {
+        KeyCode::Char(c) => Some(c.to_string()),
+        KeyCode::Enter => Some("RET".to_string()),
+        KeyCode::Tab => Some("TAB".to_string()),
+        _ => None,
+    }).collect::<Vec<_>>().join("/");
+    let help: Vec<String> = DIRED_KEYS.iter().map(|(codes, _, what)| format!("{} {}", keys(codes), what)).collect();
+    format!("{}; M-x dired-undo takes back a trash, rename or move", help.join(", "))
+}
+
+// "a", or "3 files: a, b, c", cut short when there are many
+pub(crate) fn dired_summary(paths: &[PathBuf]) -> String {
+    let names: Vec<String> = paths.iter().map(|path| path.file_name().map_or(String::new(), |name| name.to_string_lossy().into_owned())).collect();
+    match names.len() {
+        1 => names[0].clone(),
+        n if n <= 4 => format!("{} files: {}", n, names.join(", ")),
+        n => format!("{} files: {} and {} more", n, names[..3].join(", "), n - 3),
+    }
+}
+
+fn path_exists(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok() // Broken links too
+}
+
+// Directories with everything in them, links as links. `copied` hears of
+// every piece of file content written
+fn copy_path(from: &Path, to: &Path, copied: &mut dyn FnMut(u64)) -> io::Result<()> {
+    if path_exists(to) {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", to.display())));
+    }
+    let metadata = fs::symlink_metadata(from)?;
+    if metadata.file_type().is_symlink() {
+        std::os::unix::fs::symlink(fs::read_link(from)?, to)
+    } else if metadata.is_dir() {
+        if to.starts_with(from) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "can't copy a directory into itself"));
+        }
+        fs::create_dir(to)?;
+        fs::set_permissions(to, metadata.permissions())?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_path(&entry.path(), &to.join(entry.file_name()), copied)?;
+        }
+        Ok(())
+    } else {
+        let mut reader = fs::File::open(from)?;
+        let mut writer = fs::OpenOptions::new().write(true).create_new(true).open(to)?;
+        let mut chunk = vec![0; 1024 * 1024];
+        loop {
+            let read = reader.read(&mut chunk)?;
+            if read == 0 {
+                break;
+            }
+            writer.write_all(&chunk[..read])?;
+            copied(read as u64);
+        }
+        fs::set_permissions(to, metadata.permissions())
+    }
+}
+
+fn move_path(from: &Path, to: &Path, copied: &mut dyn FnMut(u64)) -> io::Result<()> {
+    if path_exists(to) {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", to.display())));
+    }
+    match rename_no_replace(from, to) {
+        // Another filesystem, rename can't do that
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+            copy_path(from, to, copied)?;
+            remove_path(from)
+        },
+        result => result,
+    }
+}
+
+// fs::rename, but an error instead of replacing what is already at `to`
+#[cfg(target_os = "linux")]
+fn rename_no_replace(from: &Path, to: &Path) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    let c_path = |path: &Path| std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path with a NUL byte"));
+    let (c_from, c_to) = (c_path(from)?, c_path(to)?);
+    // SAFETY: both are NUL terminated strings that outlive the call
+    let result = unsafe { libc::renameat2(libc::AT_FDCWD, c_from.as_ptr(), libc::AT_FDCWD, c_to.as_ptr(), libc::RENAME_NOREPLACE) };
+    if result == 0 {
+        return Ok(());
+    }
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        // A filesystem or kernel without it, check first instead
+        Some(libc::EINVAL) | Some(libc::ENOSYS) => rename_if_free(from, to),
+        _ => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn rename_no_replace(from: &Path, to: &Path) -> io::Result<()> {
+    rename_if_free(from, to)
+}
+
+fn rename_if_free(from: &Path, to: &Path) -> io::Result<()> {
+    if path_exists(to) {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", to.display())));
+    }
+    fs::rename(from, to)
+}
+
+// A link to a directory goes as a file, its target stays
+fn remove_path(path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+// The home trash of the freedesktop.org spec: files/ holds what was
+// deleted, info/ a .trashinfo for each saying where it came from
+fn trash_directory() -> Option<PathBuf> {
+    env::var_os("XDG_DATA_HOME").filter(|data| !data.is_empty()).map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))
+        .map(|data| data.join("Trash"))
+}
+
+// Percent-encoded the way Path= wants it, slashes kept
+fn trash_info_path(path: &Path) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().iter().map(|&byte| match byte {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => (byte as char).to_string(),
+        _ => format!("%{:02X}", byte),
+    }).collect()
+}
+
+// The trash on the file's own filesystem, so trashing it is a rename:
+// the home trash if it is there, else $top/.Trash/$uid when the admin made
+// a sticky .Trash, else $top/.Trash-$uid. Also what Path= should say,
+// relative to $top for those two. Only if none of them can be had does
+// the file get copied home
+fn trash_for(path: &Path, home: Option<PathBuf>) -> io::Result<(PathBuf, PathBuf)> {
+    use std::os::unix::fs::MetadataExt;
+    let device = fs::symlink_metadata(path)?.dev();
+    let device_of = |path: &Path| path.ancestors().find_map(|path| fs::metadata(path).ok()).map(|metadata| metadata.dev());
+    if let Some(home) = home.as_ref().filter(|home| device_of(home) == Some(device)) {
+        return Ok((home.clone(), path.to_path_buf()));
+    }
+
+    let top = path.ancestors().skip(1)
+        .take_while(|path| fs::metadata(path).is_ok_and(|metadata| metadata.dev() == device))
+        .last()
+        .unwrap_or(Path::new("/"));
+    if let Some(trash) = top_trash(top) {
+        return Ok((trash, path.strip_prefix(top).unwrap_or(path).to_path_buf()));
+    }
+    home.map(|home| (home, path.to_path_buf()))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no home directory for the trash"))
+}
+
+// $top/.Trash/$uid or $top/.Trash-$uid, made if it isn't there yet
+fn top_trash(top: &Path) -> Option<PathBuf> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+    // SAFETY: getuid can't fail and touches no memory
+    let uid = unsafe { libc::getuid() };
+    let shared = top.join(".Trash");
+    let shared = fs::symlink_metadata(&shared).ok()
+        .filter(|metadata| metadata.is_dir() && metadata.mode() & 0o1000 != 0)
+        .map(|_| shared.join(uid.to_string()));
+    shared.into_iter().chain([top.join(format!(".Trash-{}", uid))]).find(|trash| {
+        fs::DirBuilder::new().mode(0o700).create(trash).is_ok()
+            || fs::symlink_metadata(trash).is_ok_and(|metadata| metadata.is_dir() && metadata.uid() == uid)
+    })
+}
+
+// Where the file went in the trash, and its .trashinfo
+pub(crate) fn trash_path(path: &Path) -> io::Result<(PathBuf, PathBuf)> {
+    trash_path_in(path, trash_directory())
+}
+
+// The same, with the home trash given
+fn trash_path_in(path: &Path, home: Option<PathBuf>) -> io::Result<(PathBuf, PathBuf)> {
+    let path = std::path::absolute(path)?;
+    let name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "can't trash the root"))?;
+    let (trash, info_path_value) = trash_for(&path, home)?;
+    let (files, info) = (trash.join("files"), trash.join("info"));
+    fs::create_dir_all(&files)?;
+    fs::create_dir_all(&info)?;
+
+    // The .trashinfo is made first and only if it is new, that claims the name
+    let mut number = 1;
+    let (trashed, info_path, mut info_file) = loop {
+        let mut candidate = name.to_os_string();
+        if number > 1 {
+            candidate.push(format!(".{}", number));
+        }
+        let mut info_name = candidate.clone();
+        info_name.push(".trashinfo");
+        let info_path = info.join(info_name);
+        match fs::OpenOptions::new().write(true).create_new(true).open(&info_path) {
+            Ok(file) if !path_exists(&files.join(&candidate)) => break (files.join(candidate), info_path, file),
+            Ok(_) => fs::remove_file(&info_path)?, // A file without its info, left by something else
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {},
+            Err(e) => return Err(e),
+        }
+        number += 1;
+    };
+
+    let date = Local::now().format("%Y-%m-%dT%H:%M:%S");
+    let moved = write!(info_file, "[Trash Info]\nPath={}\nDeletionDate={}\n", trash_info_path(&info_path_value), date)
+        .and_then(|_| move_path(&path, &trashed, &mut |_| {}));
+    if let Err(e) = moved {
+        let _ = fs::remove_file(&info_path);
+        return Err(e);
+    }
+    Ok((trashed, info_path))
+}
+
+// Octal like 755, or symbolic like `chmod` takes: u+x,go-w or a=r
+fn parse_mode(spec: &str, mode: u32, is_dir: bool) -> Option<u32> {
+    if !spec.is_empty() && spec.chars().all(|c| ('0'..='7').contains(&c)) {
+        return u32::from_str_radix(spec, 8).ok().filter(|mode| *mode <= 0o7777);
+    }
+    let mut mode = mode & 0o7777;
+    for clause in spec.split(',') {
+        let who_end = clause.find(|c| !"ugoa".contains(c)).unwrap_or(clause.len());
+        let who = &clause[..who_end];
+        let mask = if who.is_empty() || who.contains('a') {
+            0o7777
+        } else {
+            who.chars().fold(0, |mask, c| mask | match c { 'u' => 0o4700, 'g' => 0o2070, _ => 0o1007 })
+        };
+        let mut rest = &clause[who_end..];
+        if rest.is_empty() {
+            return None;
+        }
+        while let Some(op) = rest.chars().next() {
+            if !"+-=".contains(op) {
+                return None;
+            }
+            let end = rest[1..].find(|c| "+-=".contains(c)).map_or(rest.len(), |i| i + 1);
+            let mut bits = 0;
+            for c in rest[1..end].chars() {
+                bits |= match c {
+                    'r' => 0o444,
+                    'w' => 0o222,
+                    'x' => 0o111,
+                    'X' if is_dir || mode & 0o111 != 0 => 0o111,
+                    'X' => 0,
+                    's' => 0o6000,
+                    't' => 0o1000,
+                    _ => return None,
+                };
+            }
+            let bits = bits & mask;
+            mode = match op {
+                '+' => mode | bits,
+                '-' => mode & !bits,
+                _ => (mode & !mask) | bits,
+            };
+            rest = &rest[end..];
+        }
+    }
+    Some(mode)
+}
+
+fn chmod_path(path: &Path, spec: &str) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let metadata = fs::metadata(path)?;
+    let mode = parse_mode(spec, metadata.permissions().mode(), metadata.is_dir())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid mode: {}", spec)))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+// Everything under a path, links as themselves
+fn path_size(path: &Path) -> u64 {
+    let Ok(metadata) = fs::symlink_metadata(path) else { return 0 };
+    if metadata.is_dir() {
+        fs::read_dir(path).map_or(0, |entries| entries.filter_map(|entry| entry.ok()).map(|entry| path_size(&entry.path())).sum())
+    } else {
+        metadata.len()
+    }
+}
+
+fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', r"'\''"))
+}
+
+// A `*` on its own stands for all the files, a `?` runs the command once
+// for each of them, without either the files go at the end
+fn dired_shell_command(command: &str, files: &[String]) -> String {
+    let quoted: Vec<String> = files.iter().map(|file| shell_quote(file)).collect();
+    let word = |word: &str| Regex::new(&format!(r"(^|\s){}(\s|$)", regex::escape(word))).unwrap();
+    let substitute = |word: &Regex, with: &str| {
+        word.replace_all(command, |caps: &regex::Captures| format!("{}{}{}", &caps[1], with, &caps[2])).into_owned()
+    };
+    let (all, each) = (word("*"), word("?"));
+    if all.is_match(command) {
+        substitute(&all, &quoted.join(" "))
+    } else if each.is_match(command) {
+        quoted.iter().map(|file| substitute(&each, file)).collect::<Vec<_>>().join("; ")
+    } else {
+        format!("{} {}", command, quoted.join(" "))
+    }
+}
+
+// A dired operation as the files it moved: from, to, and the .trashinfo
+// when to is in the trash. dired-undo moves them back, last first
+pub(crate) struct DiredUndo {
+    what: String, // "Trashed 2 files: a, b"
+    moves: Vec<UndoMove>,
+}
+
+// From, to, and the .trashinfo if it went to the trash
+type UndoMove = (PathBuf, PathBuf, Option<PathBuf>);
+
+// Moves back what dired moved, last first, and drops the .trashinfo of
+// what comes out of the trash. Gives back the moves that failed, in order
+fn undo_moves(moves: &[UndoMove]) -> (Vec<UndoMove>, Vec<String>) {
+    let mut left = Vec::new();
+    let mut failures = Vec::new();
+    for (from, to, info) in moves.iter().rev() {
+        match move_path(to, from, &mut |_| {}) {
+            Ok(()) => {
+                if let Some(info) = info {
+                    let _ = fs::remove_file(info);
+                }
+            },
+            Err(e) => {
+                failures.push(format!("{}: {}", from.file_name().unwrap_or_default().to_string_lossy(), e));
+                left.push((from.clone(), to.clone(), info.clone()));
+            },
+        }
+    }
+    left.reverse();
+    (left, failures)
+}
+
+enum DiredJobEvent {
+    Total(u64), // Bytes there are to copy, once they are counted
+    Copied(u64), // Bytes, as they are written
+    Finished(PathBuf, PathBuf, std::result::Result<(), String>),
+}
+
+// A copy or move in another thread, the modeline shows how far it got
+pub(crate) struct DiredJob {
+    pub(crate) op: DiredOp,
+    pub(crate) count: usize,
+    total: Option<u64>,
+    copied: u64,
+    pub(crate) done: Vec<(PathBuf, PathBuf)>,
+    pub(crate) failures: Vec<String>,
+    pub(crate) finished: bool,
+    receiver: Receiver<DiredJobEvent>,
+}
+
+impl DiredJob {
+    fn start(op: DiredOp, moves: Vec<(PathBuf, PathBuf)>) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let count = moves.len();
+        std::thread::spawn(move || {
+            // Sizing a big tree takes a while too, so it is done here. A move
+            // within a filesystem is a rename and copies nothing
+            use std::os::unix::fs::MetadataExt;
+            let device = |path: &Path| fs::symlink_metadata(path).map_or(0, |metadata| metadata.dev());
+            let total = moves.iter()
+                .filter(|(from, to)| op == DiredOp::Copy || device(from) != device(to.parent().unwrap_or(Path::new("/"))))
+                .map(|(from, _)| path_size(from))
+                .sum();
+            if sender.send(DiredJobEvent::Total(total)).is_err() {
+                return;
+            }
+            for (from, to) in moves {
+                let mut copied = |bytes| {
+                    let _ = sender.send(DiredJobEvent::Copied(bytes));
+                };
+                let result = match op {
+                    DiredOp::Move => move_path(&from, &to, &mut copied),
+                    _ => copy_path(&from, &to, &mut copied),
+                };
+                let name = from.file_name().unwrap_or_default().to_string_lossy().into_owned();
+                if sender.send(DiredJobEvent::Finished(from, to, result.map_err(|e| format!("{}: {}", name, e)))).is_err() {
+                    break;
+                }
+            }
+        });
+        DiredJob {
+            op,
+            count,
+            total: None,
+            copied: 0,
+            done: Vec::new(),
+            failures: Vec::new(),
+            finished: false,
+            receiver,
+        }
+    }
+
+    // True if anything came in
+    pub(crate) fn poll(&mut self) -> bool {
+        let mut changed = false;
+        loop {
+            match self.receiver.try_recv() {
+                Ok(DiredJobEvent::Total(bytes)) => self.total = Some(bytes),
+                Ok(DiredJobEvent::Copied(bytes)) => self.copied += bytes,
+                Ok(DiredJobEvent::Finished(from, to, Ok(()))) => self.done.push((from, to)),
+                Ok(DiredJobEvent::Finished(_, _, Err(e))) => self.failures.push(e),
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    self.finished = true;
+                    break;
+                },
+            }
+            changed = true;
+        }
+        changed
+    }
+
+    // "Copying 2/5 40%", only the count until the size is known
+    pub(crate) fn progress(&self) -> String {
+        let verb = if self.op == DiredOp::Move { "Moving" } else { "Copying" };
+        let current = (self.done.len() + self.failures.len() + 1).min(self.count);
+        match self.total {
+            Some(total) if total > 0 => format!("{} {}/{} {}%", verb, current, self.count, (self.copied * 100 / total).min(100)),
+            _ => format!("{} {}/{}", verb, current, self.count),
+        }
+    }
+}
+
+const DIRED_PREVIEW_BYTES: usize = 64 * 1024;
+const DIRED_PREVIEW_HEX_BYTES: usize = 4096;
+
+// A line of the preview pane, in colored pieces
+type PreviewLine = Vec<(Color, String)>;
+
+// The pane on the right of dired, for the entry under the cursor
+struct DiredPreview {
+    path: PathBuf,
+    lines: Vec<PreviewLine>,
+}
+
+impl DiredPreview {
+    fn new(entry: &DiredEntry, width: usize, users: &HashMap<u32, String>, groups: &HashMap<u32, String>, theme: &Theme) -> Self {
+        let is_file = fs::metadata(&entry.path).is_ok_and(|m| m.is_file());
+        let lines = if entry.is_dir {
+            DiredPreview::directory(&entry.path, theme)
+        } else if is_file {
+            let mut bytes = Vec::new();
+            let read = fs::File::open(&entry.path).and_then(|file| file.take(DIRED_PREVIEW_BYTES as u64).read_to_end(&mut bytes));
+            match read {
+                Ok(0) | Err(_) => DiredPreview::metadata(entry, users, groups, theme),
+                Ok(_) if DiredPreview::is_text(&bytes) => DiredPreview::text(&entry.path, &String::from_utf8_lossy(&bytes), theme),
+                Ok(_) => DiredPreview::hex(&bytes[..bytes.len().min(DIRED_PREVIEW_HEX_BYTES)], width, theme),
+            }
+        } else {
+            DiredPreview::metadata(entry, users, groups, theme)
+        };
+        DiredPreview { path: entry.path.clone(), lines }
+    }
+
+    // No NUL bytes and valid UTF-8, short of a character cut at the end
+    fn is_text(bytes: &[u8]) -> bool {
+        !bytes[..bytes.len().min(8192)].contains(&0) && std::str::from_utf8(bytes).map_or_else(|e| e.error_len().is_none(), |_| true)
+    }
+
+    fn directory(path: &Path, theme: &Theme) -> Vec<PreviewLine> {
+        let read_dir = match fs::read_dir(path) {
+            Ok(read_dir) => read_dir,
+            Err(e) => return vec![vec![(theme.error_color, e.to_string())]],
+        };
+        let mut children: Vec<(bool, String)> = read_dir.flatten()
+            .map(|entry| (entry.path().is_dir(), entry.file_name().to_string_lossy().into_owned()))
+            .collect();
+        if children.is_empty() {
+            return vec![vec![(theme.comment_color, "Empty directory".to_string())]];
+        }
+        children.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.to_lowercase().cmp(&b.1.to_lowercase())));
+        children.into_iter().map(|(is_dir, name)| {
+            if is_dir { vec![(theme.dired_dir_color, format!("{}/", name))] } else { vec![(theme.text_color, name)] }
+        }).collect()
+    }
+
+    // Rust through tree-sitter, the languages PreviewSyntax knows through it
+    fn text(path: &Path, source: &str, theme: &Theme) -> Vec<PreviewLine> {
+        let source = source.replace('\t', "    ");
+        if language_id(path) == Some("rust") {
+            return highlight_rust(&source, theme);
+        }
+        match PreviewSyntax::of(path) {
+            Some(syntax) => syntax.highlight(&source, theme),
+            None => source.lines().map(|line| vec![(theme.text_color, line.to_string())]).collect(),
+        }
+    }
+
+    // Offset, bytes and their printable characters, as many as fit a row
+    fn hex(bytes: &[u8], width: usize, theme: &Theme) -> Vec<PreviewLine> {
+        let per_row = (width.saturating_sub(12) / 4).clamp(1, 16);
+        bytes.chunks(per_row).enumerate().map(|(row, chunk)| {
+            let hex: String = chunk.iter().map(|byte| format!("{:02x} ", byte)).collect();
+            let ascii: String = chunk.iter().map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }).collect();
+            vec![
+                (theme.comment_color, format!("{:08x}  ", row * per_row)),
+                (theme.text_color, format!("{:1$}", hex, per_row * 3)),
+                (theme.string_color, ascii),
+            ]
+        }).collect()
+    }
+
+    fn metadata(entry: &DiredEntry, users: &HashMap<u32, String>, groups: &HashMap<u32, String>, theme: &Theme) -> Vec<PreviewLine> {
+        use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+        let Some(metadata) = &entry.metadata else {
+            return vec![vec![(theme.error_color, "Can't read this file".to_string())]];
+        };
+        let file_type = metadata.file_type();
+        let kind = if file_type.is_symlink() {
+            "symbolic link"
+        } else if file_type.is_char_device() {
+            "character device"
+        } else if file_type.is_block_device() {
+            "block device"
+        } else if file_type.is_fifo() {
+            "named pipe"
+        } else if file_type.is_socket() {
+            "socket"
+        } else if metadata.len() == 0 {
+            "empty file"
+        } else {
+            "file"
+        };
+        let name_of = |names: &HashMap<u32, String>, id: u32| names.get(&id).cloned().unwrap_or_else(|| id.to_string());
+        let modified = metadata.modified().ok().map_or(String::new(), |time| DateTime::<Local>::from(time).format("%Y-%m-%d %H:%M:%S").to_string());
+
+        let mut fields = vec![
+            ("Type", kind.to_string()),
+            ("Size", format!("{} bytes", metadata.len())),
+            ("Mode", entry.mode_string()),
+            ("Owner", format!("{}:{}", name_of(users, metadata.uid()), name_of(groups, metadata.gid()))),
+            ("Modified", modified),
+        ];
+        if let Some(target) = &entry.link_target {
+            let broken = if entry.broken_link { " (broken)" } else { "" };
+            fields.push(("Target", format!("{}{}", target.display(), broken)));
+        }
+        fields.into_iter().map(|(label, value)| {
+            let color = if label == "Target" && entry.broken_link { theme.error_color } else { theme.text_color };
+            vec![(theme.comment_color, format!("{:<10}", label)), (color, value)]
+        }).collect()
+    }
+}
+
+// Enough of a language to color its comments, strings, numbers and
+// keywords without a parser. Capitalized words go as types
+struct PreviewSyntax {
+    line_comments: &'static [&'static str],
+    block_comment: Option<(&'static str, &'static str)>,
+    long_strings: &'static [&'static str], // Opened and closed by the same delimiter, over lines
+    quotes: &'static str,
+    keywords: &'static [&'static str],
+}
+
+const C_LIKE_KEYWORDS: &[&str] = &[
+    "if", "else", "for", "while", "do", "switch", "case", "default", "break", "continue", "return", "goto",
+    "struct", "union", "enum", "typedef", "static", "const", "extern", "inline", "sizeof", "void", "int", "char",
+    "float", "double", "long", "short", "unsigned", "signed", "bool", "true", "false", "null", "nullptr", "NULL",
+    "class", "public", "private", "protected", "new", "delete", "this", "try", "catch", "throw", "throws",
+    "template", "typename", "namespace", "using", "virtual", "override", "final", "import", "export", "from",
+    "package", "func", "var", "let", "fn", "pub", "defer", "go", "chan", "map", "interface", "type", "async",
+    "await", "function", "yield", "in", "of", "instanceof", "typeof", "extends", "implements", "super", "val",
+    "fun", "when", "object", "undefined", "comptime", "errdefer", "orelse", "self", "guard", "#include", "#define",
+];
+const PYTHON_KEYWORDS: &[&str] = &[
+    "def", "class", "if", "elif", "else", "for", "while", "in", "not", "and", "or", "is", "return", "yield",
+    "import", "from", "as", "with", "try", "except", "finally", "raise", "pass", "break", "continue", "lambda",
+    "global", "nonlocal", "del", "assert", "async", "await", "None", "True", "False", "self",
+];
+const SHELL_KEYWORDS: &[&str] = &[
+    "if", "then", "else", "elif", "fi", "for", "while", "until", "do", "done", "case", "esac", "in", "function",
+    "return", "local", "export", "set", "unset", "source", "exit",
+];
+const LUA_KEYWORDS: &[&str] = &[
+    "function", "local", "if", "then", "elseif", "else", "end", "for", "while", "do", "repeat", "until", "return",
+    "break", "in", "and", "or", "not", "nil", "true", "false", "goto",
+];
+const RUBY_KEYWORDS: &[&str] = &[
+    "def", "end", "if", "elsif", "else", "unless", "while", "until", "for", "in", "do", "class", "module", "return",
+    "yield", "begin", "rescue", "ensure", "raise", "require", "nil", "true", "false", "self", "and", "or", "not",
+];
+const CONFIG_KEYWORDS: &[&str] = &["true", "false", "null", "yes", "no", "on", "off"];
+
+impl PreviewSyntax {
+    fn of(path: &Path) -> Option<PreviewSyntax> {
+        let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+        let syntax = |line_comments, block_comment, long_strings, quotes, keywords| {
+            Some(PreviewSyntax { line_comments, block_comment, long_strings, quotes, keywords })
+        };
+        match extension {
+            "c" | "h" | "cpp" | "cc" | "cxx" | "hpp" | "java" | "kt" | "swift" | "cs" | "zig" =>
+                syntax(&["//"], Some(("/*", "*/")), &[], "\"'", C_LIKE_KEYWORDS),
+            "js" | "ts" | "jsx" | "tsx" | "mjs" | "go" => syntax(&["//"], Some(("/*", "*/")), &["`"], "\"'", C_LIKE_KEYWORDS),
+            "py" => syntax(&["#"], None, &["\"\"\"", "'''"], "\"'", PYTHON_KEYWORDS),
+            "sh" | "bash" | "zsh" | "fish" => syntax(&["#"], None, &[], "\"'", SHELL_KEYWORDS),
+            "lua" => syntax(&["--"], Some(("--[[", "]]")), &[], "\"'", LUA_KEYWORDS),
+            "rb" => syntax(&["#"], None, &[], "\"'", RUBY_KEYWORDS),
+            "toml" | "yaml" | "yml" | "conf" | "ini" | "nix" => syntax(&["#", ";"], None, &[], "\"'", CONFIG_KEYWORDS),
+            "json" => syntax(&[], None, &[], "\"", CONFIG_KEYWORDS),
+            "sql" => syntax(&["--"], Some(("/*", "*/")), &[], "'", &[]),
+            "hs" => syntax(&["--"], Some(("{-", "-}")), &[], "\"", &[]),
+            "el" | "lisp" | "scm" | "clj" => syntax(&[";"], None, &[], "\"", &[]),
+            _ => None,
+        }
+    }
+
+    fn highlight(&self, source: &str, theme: &Theme) -> Vec<PreviewLine> {
+        let mut colors = vec![theme.text_color; source.len()];
+        let is_word = |c: char| c.is_alphanumeric() || c == '_' || c == '#';
+        let mut i = 0;
+        while let Some(c) = source[i..].chars().next() {
+            let rest = &source[i..];
+            // Where the comment, string, number or word ends, and its color
+            let (end, color) = if let Some((open, close)) = self.block_comment.filter(|(open, _)| rest.starts_with(open)) {
+                (rest[open.len()..].find(close).map_or(source.len(), |at| i + open.len() + at + close.len()), theme.comment_color)
+            } else if self.line_comments.iter().any(|prefix| rest.starts_with(prefix)) {
+                (rest.find('\n').map_or(source.len(), |at| i + at), theme.comment_color)
+            } else if let Some(delimiter) = self.long_strings.iter().find(|delimiter| rest.starts_with(**delimiter)) {
+                (rest[delimiter.len()..].find(delimiter).map_or(source.len(), |at| i + 2 * delimiter.len() + at), theme.string_color)
+            } else if self.quotes.contains(c) {
+                // To the closing quote, not one after a backslash, or the end of the line
+                let mut escaped = false;
+                let close = rest.char_indices().skip(1).find(|&(_, d)| {
+                    let found = d == '\n' || (d == c && !escaped);
+                    escaped = !escaped && d == '\\';
+                    found
+                });
+                let end = match close {
+                    Some((at, '\n')) => i + at,
+                    Some((at, _)) => i + at + 1,
+                    None => source.len(),
+                };
+                (end, theme.string_color)
+            } else if is_word(c) {
+                let end = rest.find(|d: char| !is_word(d)).map_or(source.len(), |at| i + at);
+                let word = &source[i..end];
+                let color = if c.is_ascii_digit() {
+                    theme.warning_color
+                } else if self.keywords.contains(&word) {
+                    theme.use_color
+                } else if c.is_uppercase() && word.chars().any(|d| d.is_lowercase()) {
+                    theme.dired_dir_color
+                } else {
+                    theme.text_color
+                };
+                (end, color)
+            } else {
+                (i + c.len_utf8(), theme.text_color)
+            };
+            colors[i..end].fill(color);
+            i = end;
+        }
+        colored_lines(source, &colors)
+    }
+}
+
+// Comments, strings, literals, types and keywords of Rust source
+fn highlight_rust(source: &str, theme: &Theme) -> Vec<PreviewLine> {
+    fn paint(node: tree_sitter::Node, colors: &mut [Color], theme: &Theme) {
+        let kind = node.kind();
+        let color = match kind {
+            "line_comment" | "block_comment" => Some(theme.comment_color),
+            "string_literal" | "raw_string_literal" | "char_literal" => Some(theme.string_color),
+            "integer_literal" | "float_literal" | "boolean_literal" => Some(theme.warning_color),
+            "primitive_type" | "type_identifier" => Some(theme.dired_dir_color),
+            _ if !node.is_named() && kind.len() > 1 && kind.chars().all(|c| c.is_ascii_lowercase()) => Some(theme.use_color),
+            _ => None,
+        };
+        match color {
+            Some(color) => colors[node.byte_range()].fill(color),
+            None => {
+                for i in 0..node.child_count() {
+                    if let Some(child) = node.child(i) {
+                        paint(child, colors, theme);
+                    }
+                }
+            },
+        }
+    }
+
+    let mut colors = vec![theme.text_color; source.len()];
+    let mut parser = tree_sitter::Parser::new();
+    if parser.set_language(tree_sitter_rust::language()).is_ok() {
+        if let Some(tree) = parser.parse(source, None) {
+            paint(tree.root_node(), &mut colors, theme);
+        }
+    }
+    colored_lines(source, &colors)
+}
+
+// The lines of the source in pieces of one color, `colors` has one per byte
+fn colored_lines(source: &str, colors: &[Color]) -> Vec<PreviewLine> {
+    let mut lines = Vec::new();
+    let mut line: PreviewLine = Vec::new();
+    for (i, c) in source.char_indices() {
+        if c == '\n' {
+            lines.push(std::mem::take(&mut line));
+            continue;
+        }
+        match line.last_mut() {
+            Some((color, text)) if *color == colors[i] => text.push(c),
+            _ => line.push((colors[i], c.to_string())),
+        }
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+// uid or gid to name, from /etc/passwd or /etc/group
+fn id_names(path: &str) -> HashMap<u32, String> {
+    fs::read_to_string(path).unwrap_or_default().lines().filter_map(|line| {
+        let mut fields = line.split(':');
+        let name = fields.next()?;
+        let id = fields.nth(1)?.parse().ok()?;
+        Some((id, name.to_string()))
+    }).collect()
+}
+
+pub(crate) struct Dired {
+    pub(crate) current_path: PathBuf,
+    dots: Vec<DiredEntry>, // '.' and '..'
+    listing: Vec<DiredEntry>, // Everything in the directory
+    expanded: HashSet<PathBuf>, // Directories open in the tree, Tab opens and closes them
+    children: HashMap<PathBuf, Vec<DiredEntry>>, // Listings of the expanded directories
+    entries: Vec<DiredEntry>, // The tree flattened, what the view and the filter let through, in order
+    view: DiredView,
+    filter: String,
+    pub(crate) offset: usize, // First row on screen
+    preview: Option<DiredPreview>, // Of the entry it was last drawn for
+    users: HashMap<u32, String>,
+    groups: HashMap<u32, String>,
+    pub(crate) marks: HashMap<PathBuf, char>, // '*' marked, 'D' flagged for deletion
+    prefix: Option<char>, // '%' or '*', waiting for the second key
+    pub(crate) cursor_pos: u16,
+    pub(crate) entry_first_char_column: u16,
+    pub(crate) listing_width: u16, // Left of the preview pane
+    color_dired: bool,
+}
+
+impl Dired {
+    pub(crate) fn new(current_path: PathBuf, focus: Option<&str>, view: DiredView) -> io::Result<Self> {
+        let listing = Dired::list_directory_contents(&current_path)?;
+        let mut dired = Dired {
+            dots: Dired::list_dots(&current_path),
+            current_path,
+            listing,
+            expanded: HashSet::new(),
+            children: HashMap::new(),
+            entries: Vec::new(),
+            view,
+            filter: String::new(),
+            offset: 0,
+            preview: None,
+            users: id_names("/etc/passwd"),
+            groups: id_names("/etc/group"),
+            marks: HashMap::new(),
+            prefix: None,
+            cursor_pos: 0,
+            entry_first_char_column: 0,
+            listing_width: u16::MAX,
+            color_dired: true,
+        };
+        dired.apply_view();
+        dired.cursor_pos = if dired.entries.is_empty() { 0 } else { 2 }; // Skip '.' and '..'
+
+        // If a focus is specified, attempt to find it in the list and set the cursor position
+        if let Some(dir_name) = focus {
+            for (i, entry) in dired.entries.iter().enumerate() {
+                if entry.name == dir_name {
+                    dired.cursor_pos = i as u16 + 2; // Adjust for '.' and '..' being at positions 0 and 1
+                    break;
+                }
+            }
+        }
+        Ok(dired)
+    }
+
+    // Sort and filter the listing again, the cursor stays on its entry
+    fn apply_view(&mut self) {
+        let current = self.current_entry().map(|entry| entry.path.clone());
+        // Smart case, like search
+        let case_sensitive = self.filter.chars().any(char::is_uppercase);
+        let filter = if case_sensitive { self.filter.clone() } else { self.filter.to_lowercase() };
+        let matches = |name: &str| if case_sensitive { name.contains(&filter) } else { name.to_lowercase().contains(&filter) };
+        self.entries = self.flatten(&self.listing, 0, &matches);
+
+        let position = current.and_then(|path| self.entries.iter().position(|entry| entry.path == path));
+        self.cursor_pos = match position {
+            Some(i) => i as u16 + 2,
+            None => self.cursor_pos.min(self.entries.len() as u16 + 1),
+        };
+    }
+
+    // Each level sorted on its own, with the children of expanded directories
+    // under them. A directory that doesn't match stays for children that do
+    fn flatten(&self, level: &[DiredEntry], depth: usize, matches: &dyn Fn(&str) -> bool) -> Vec<DiredEntry> {
+        let mut sorted: Vec<DiredEntry> = level.iter().filter(|entry| self.view.show_hidden || !entry.name.starts_with('.')).cloned().collect();
+        self.view.sort(&mut sorted);
+
+        let mut entries = Vec::new();
+        for mut entry in sorted {
+            let children = match self.children.get(&entry.path) {
+                Some(children) if self.expanded.contains(&entry.path) => self.flatten(children, depth + 1, matches),
+                _ => Vec::new(),
+            };
+            if matches(&entry.name) || !children.is_empty() {
+                entry.depth = depth;
+                entries.push(entry);
+                entries.extend(children);
+            }
+        }
+        entries
+    }
+
+    // Tab: open or close the directory under the cursor, on a file close the
+    // directory it is in
+    fn toggle_expanded(&mut self) -> io::Result<()> {
+        let Some(entry) = self.current_entry() else { return Ok(()) };
+        let path = entry.path.clone();
+        if entry.is_dir {
+            if !self.expanded.remove(&path) {
+                self.children.insert(path.clone(), Dired::list_directory_contents(&path)?);
+                self.expanded.insert(path);
+            }
+        } else if entry.depth > 0 {
+            let parent = path.parent().map(Path::to_path_buf).unwrap_or_default();
+            self.expanded.remove(&parent);
+            if let Some(i) = self.entries.iter().position(|entry| entry.path == parent) {
+                self.cursor_pos = i as u16 + 2;
+            }
+        }
+        self.apply_view();
+        Ok(())
+    }
+
+    pub(crate) fn set_filter(&mut self, filter: &str) {
+        if self.filter != filter {
+            self.filter = filter.to_string();
+            self.apply_view();
+        }
+    }
+
+    fn list_directory_contents(path: &PathBuf) -> io::Result<Vec<DiredEntry>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            entries.push(DiredEntry::read(entry.path(), name));
+        }
+        Ok(entries)
+    }
+
+    fn list_dots(path: &Path) -> Vec<DiredEntry> {
+        [".", ".."].into_iter().map(|name| DiredEntry::read(path.join(name), name.to_string())).collect()
+    }
+
+    
+
+    pub(crate) fn refresh_directory_contents(&mut self) -> io::Result<()> {
+        self.listing = Dired::list_directory_contents(&self.current_path)?;
+        self.dots = Dired::list_dots(&self.current_path);
+        self.expanded.retain(|path| path.is_dir());
+        self.children = self.expanded.iter()
+            .filter_map(|path| Some((path.clone(), Dired::list_directory_contents(path).ok()?)))
+            .collect();
+        self.marks.retain(|path, _| path_exists(path));
+        self.apply_view();
+        self.preview = None;
+        Ok(())
+    }
+
+    pub(crate) fn current_entry(&self) -> Option<&DiredEntry> {
+        (self.cursor_pos as usize).checked_sub(2).and_then(|i| self.entries.get(i)) // Not '.' or '..'
+    }
+
+    // m, u and d: mark the entry, then go to the next one
+    fn set_mark(&mut self, mark: Option<char>) {
+        let Some(path) = self.current_entry().map(|entry| entry.path.clone()) else { return };
+        match mark {
+            Some(mark) => { self.marks.insert(path, mark); },
+            None => { self.marks.remove(&path); },
+        }
+        if (self.cursor_pos as usize) < self.entries.len() + 1 {
+            self.cursor_pos += 1;
+        }
+    }
+
+    // t: marked ones get unmarked and the others marked, flags stay
+    fn toggle_marks(&mut self) {
+        for entry in &self.entries {
+            match self.marks.get(&entry.path) {
+                Some('*') => { self.marks.remove(&entry.path); },
+                Some(_) => {},
+                None => { self.marks.insert(entry.path.clone(), '*'); },
+            }
+        }
+    }
+
+    pub(crate) fn mark_where(&mut self, matches: impl Fn(&DiredEntry) -> bool) -> usize {
+        let paths: Vec<PathBuf> = self.entries.iter().filter(|entry| matches(entry)).map(|entry| entry.path.clone()).collect();
+        let count = paths.len();
+        for path in paths {
+            self.marks.insert(path, '*');
+        }
+        count
+    }
+
+    // In the order of the listing
+    fn marked(&self, mark: char) -> Vec<PathBuf> {
+        self.entries.iter().filter(|entry| self.marks.get(&entry.path) == Some(&mark)).map(|entry| entry.path.clone()).collect()
+    }
+
+    // What an operation works on: the marked files, or the one under the cursor
+    fn targets(&self) -> Vec<PathBuf> {
+        let marked = self.marked('*');
+        if marked.is_empty() { self.current_entry().map(|entry| entry.path.clone()).into_iter().collect() } else { marked }
+    }
+
+    pub fn create_directory(&mut self, dir_name: &str) -> io::Result<()> {
+        let new_dir_path = self.current_path.join(dir_name);
+        fs::create_dir(&new_dir_path)?;
+        Ok(())
+    }
+
+    // The old and the new path, for dired-undo
+    pub fn rename_entry(&mut self, new_name: &str) -> io::Result<(PathBuf, PathBuf)> {
+        if self.cursor_pos > 1 && (self.cursor_pos as usize - 2) < self.entries.len() { // Skipping '.' and '..'
+            let entry_to_rename = &self.entries[self.cursor_pos as usize - 2];
+            let original_path = entry_to_rename.path.clone();
+            let new_path = original_path.parent().unwrap().join(new_name);
+            rename_no_replace(&original_path, &new_path)?;
+            self.refresh_directory_contents()?;
+            Ok((original_path, new_path))
+        } else {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid selection for rename."))
+        }
+    }
+
+    // TODO color file extentions if color_dired is true
+    pub fn draw_dired(&mut self, stdout: &mut Stdout, height: u16, theme: &Theme, preview_width: u16, icons: Option<&Icons>) -> io::Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        // The preview pane takes the right of the screen, the listing keeps to its left
+        let (width, _) = terminal::size()?;
+        self.listing_width = if self.view.preview { width.saturating_sub(preview_pane_width(width, preview_width)) } else { width };
+
+        let display_path = self.current_path.display().to_string();
+        let trimmed_path = display_path.trim_end_matches('/');
+        let mut header = vec![(theme.dired_path_color, format!("{}:", trimmed_path))];
+
+        // How the listing is narrowed and ordered, when that isn't the default
+        let mut view = Vec::new();
+        if !self.filter.is_empty() {
+            view.push(format!("filter \"{}\"", self.filter));
+        }
+        if self.view.sort != DiredSort::Name {
+            view.push(format!("by {}", self.view.sort.name()));
+        }
+        if !self.view.show_hidden {
+            view.push("no dotfiles".to_string());
+        }
+        if !view.is_empty() {
+            header.push((theme.comment_color, format!(" [{}]", view.join(", "))));
+        }
+        execute!(stdout, MoveTo(3, 0), SetBackgroundColor(theme.background_color))?;
+        print_clipped(stdout, &header, (self.listing_width as usize).saturating_sub(3))?;
+        execute!(stdout, ResetColor)?;
+
+        // Rows from line 2 to above the modeline, the cursor always among them
+        let rows = height.saturating_sub(3) as usize;
+        let cursor = self.cursor_pos as usize;
+        if cursor < self.offset {
+            self.offset = cursor;
+        } else if rows > 0 && cursor >= self.offset + rows {
+            self.offset = cursor + 1 - rows;
+        }
+
+        let mut line_number = 2u16;
+
+        let entries: Vec<&DiredEntry> = self.dots.iter().chain(self.entries.iter()).collect();
+        let name_of = |names: &HashMap<u32, String>, id: u32| names.get(&id).cloned().unwrap_or_else(|| id.to_string());
+        let owner_of = |entry: &DiredEntry| entry.metadata.as_ref().map_or("?".to_string(), |m| name_of(&self.users, m.uid()));
+        let group_of = |entry: &DiredEntry| entry.metadata.as_ref().map_or("?".to_string(), |m| name_of(&self.groups, m.gid()));
+
+        // Every column as wide as its widest value
+        let width_of = |column: &dyn Fn(&DiredEntry) -> String| entries.iter().map(|entry| column(entry).chars().count()).max().unwrap_or(0);
+        let links_length = width_of(&|entry| entry.metadata.as_ref().map_or(0, |m| m.nlink()).to_string());
+        let owner_length = width_of(&owner_of);
+        let group_length = width_of(&group_of);
+        let max_size_length = width_of(&|entry| entry.metadata.as_ref().map_or(0, |m| m.len()).to_string());
+
+        self.entry_first_char_column = 34 + (links_length + owner_length + group_length + max_size_length) as u16;
+        if icons.is_some() {
+            self.entry_first_char_column += 2;
+        }
+
+        for entry in entries.iter().skip(self.offset).take(rows) {
+            let mode = entry.mode_string();
+            let (file_type_char, permissions) = mode.split_at(1);
+            let links = entry.metadata.as_ref().map_or(0, |m| m.nlink());
+            let size = entry.metadata.as_ref().map_or(0, |m| m.len());
+            let modified = entry.metadata.as_ref().and_then(|m| m.modified().ok()).map_or(String::new(), |time| {
+                DateTime::<Local>::from(time).format("%b %d %H:%M").to_string()
+            });
+            let size_str = format!("{:1$}", size, max_size_length);
+
+            let entry_color = if entry.broken_link {
+                theme.error_color
+            } else if entry.is_dir {
+                theme.dired_dir_color
+            } else {
+                theme.text_color
+            };
+
+            let mark = self.marks.get(&entry.path).copied();
+            if let Some(mark) = mark {
+                let color = if mark == 'D' { theme.error_color } else { theme.warning_color };
+                execute!(stdout, MoveTo(3, line_number), SetForegroundColor(color), SetBackgroundColor(theme.background_color), Print(mark), ResetColor)?;
+            }
+            let entry_color = match mark {
+                Some('D') => theme.error_color,
+                Some(_) => theme.warning_color,
+                None => entry_color,
+            };
+
+            // Piece by piece, cut where the listing ends
+            let mut pieces: PreviewLine = Vec::new();
+            if self.color_dired {
+                pieces.push((entry_color, file_type_char.to_string()));
+                for ch in permissions.chars() {
+                    let color = match ch {
+                        'r' => theme.warning_color,
+                        'w' => theme.error_color,
+                        'x' | 's' | 't' => theme.ok_color,
+                        '-' => theme.comment_color,
+                        _ => theme.text_color, // Default color
+                    };
+                    pieces.push((color, ch.to_string()));
+                }
+            } else {
+                pieces.push((theme.text_color, mode.clone()));
+            }
+            pieces.extend([
+                (theme.text_color, format!(" {:>1$} ", links, links_length)),
+                (theme.text_color, format!("{:<1$} ", owner_of(entry), owner_length)),
+                (theme.text_color, format!("{:<1$} ", group_of(entry), group_length)),
+                (if self.color_dired { theme.dired_size_color } else { theme.text_color }, format!("{} ", size_str)),
+                (if self.color_dired { theme.dired_timestamp_color } else { theme.text_color }, format!("{:14}", modified)),
+                (theme.comment_color, "│ ".repeat(entry.depth)),
+            ]);
+            if let Some(icons) = icons {
+                let (icon, icon_color) = icons.lookup(&entry.name, entry.is_dir, entry_color);
+                pieces.push((icon_color, format!("{} ", icon)));
+            }
+            pieces.push((entry_color, entry.name.clone()));
+            if let Some(target) = &entry.link_target {
+                pieces.push((theme.text_color, " -> ".to_string()));
+                pieces.push((entry_color, target.display().to_string()));
+            }
+            execute!(stdout, MoveTo(5, line_number), SetBackgroundColor(theme.background_color))?;
+            print_clipped(stdout, &pieces, (self.listing_width as usize).saturating_sub(5))?;
+            execute!(stdout, ResetColor)?;
+
+            line_number += 1;
+        }
+
+        if self.view.preview {
+            self.draw_preview(stdout, height, theme, preview_width)?;
+        }
+
+        Ok(())
+    }
+
+    // On the right of the listing, `percent` of the screen wide
+    fn draw_preview(&mut self, stdout: &mut Stdout, height: u16, theme: &Theme, percent: u16) -> io::Result<()> {
+        let (width, _) = terminal::size()?;
+        let pane_width = preview_pane_width(width, percent) as usize;
+        let x = width.saturating_sub(pane_width as u16);
+        let room = pane_width.saturating_sub(2); // The border and a space
+
+        let cursor = self.cursor_pos as usize;
+        let entry = if cursor < 2 { self.dots.get(cursor) } else { self.current_entry() };
+        if let Some(entry) = entry {
+            if self.preview.as_ref().is_none_or(|preview| preview.path != entry.path) {
+                self.preview = Some(DiredPreview::new(entry, room, &self.users, &self.groups, theme));
+            }
+        } else {
+            self.preview = None;
+        }
+
+        for y in 0..height.saturating_sub(1) {
+            execute!(
+                stdout,
+                MoveTo(x, y),
+                SetBackgroundColor(theme.background_color),
+                Print(" ".repeat(pane_width)),
+                MoveTo(x, y),
+                SetForegroundColor(theme.comment_color),
+                Print("│"),
+            )?;
+        }
+        let Some(preview) = &self.preview else { return execute!(stdout, ResetColor) };
+
+        let title = preview.path.file_name().map_or(preview.path.display().to_string(), |name| name.to_string_lossy().into_owned());
+        execute!(stdout, MoveTo(x + 2, 0), SetForegroundColor(theme.dired_path_color), Print(title.chars().take(room).collect::<String>()))?;
+        for (row, line) in preview.lines.iter().take(height.saturating_sub(3) as usize).enumerate() {
+            execute!(stdout, MoveTo(x + 2, row as u16 + 2))?;
+            print_clipped(stdout, line, room)?;
+        }
+        execute!(stdout, ResetColor)
+    }
+}
+
+fn preview_pane_width(width: u16, percent: u16) -> u16 {
+    (width as u32 * percent.clamp(10, 90) as u32 / 100).max(12).min(width as u32) as u16
+}
+
+// Colored pieces of a line, as many characters of them as fit in `room`
+fn print_clipped(stdout: &mut Stdout, pieces: &[(Color, String)], room: usize) -> io::Result<()> {
+    let mut left = room;
+    for (color, text) in pieces {
+        if left == 0 {
+            break;
+        }
+        let shown: String = text.chars().take(left).collect();
+        left -= shown.chars().count();
+        execute!(stdout, SetForegroundColor(*color), Print(shown))?;
+    }
+    Ok(())
+}
+
+// The dired listing as a buffer of file names, renamed on C-c C-c
+pub(crate) struct Wdired {
+    directory: PathBuf,
+    names: Vec<String>, // Line by line, the paths from the directory as they are now
+    // The buffer it took the place of
+    buffer: Vec<Vec<char>>,
+    file_path: PathBuf,
+    cursor_pos: (u16, u16),
+    offset: (u16, u16),
+    history: UndoHistory,
+}
+
+impl Wdired {
+    fn buffer_path(&self) -> PathBuf {
+        self.directory.join("*wdired*")
+    }
+
+    // What can't be renamed, checked before touching anything
+    fn problems(&self, lines: &[String]) -> Vec<String> {
+        if lines.len() != self.names.len() {
+            return vec![format!("{} lines for {} files, lines can't be added or removed", lines.len(), self.names.len())];
+        }
+        let mut problems = Vec::new();
+        let mut targets: HashMap<&str, &str> = HashMap::new();
+        for (old, new) in self.names.iter().zip(lines) {
+            if new.is_empty() {
+                problems.push(format!("{}: empty name", old));
+            } else if let Some(other) = targets.insert(new, old) {
+                problems.push(format!("{} and {} would both be {}", other, old, new));
+            } else if new != old && path_exists(&self.directory.join(new)) && !self.names.contains(new) {
+                problems.push(format!("{}: {} already exists", old, new));
+            }
+        }
+        problems
+    }
+}
+
+// From and to
+type Renames = Vec<(PathBuf, PathBuf)>;
+
+// Renames in an order that never overwrites a file still waiting for its
+// own rename, a cycle goes around through a temporary name
+fn order_renames(mut pending: Renames) -> Renames {
+    let mut ordered = Vec::new();
+    let mut temporary = 0;
+    while !pending.is_empty() {
+        let free = (0..pending.len()).find(|&i| !pending.iter().any(|(from, _)| *from == pending[i].1));
+        match free {
+            Some(i) => ordered.push(pending.remove(i)),
+            None => {
+                let from = pending[0].0.clone();
+                let name = from.file_name().unwrap_or_default().to_string_lossy().into_owned();
+                let moved = loop {
+                    temporary += 1;
+                    let candidate = from.with_file_name(format!(".wdired-{}-{}", temporary, name));
+                    if !path_exists(&candidate) {
+                        break candidate;
+                    }
+                };
+                ordered.push((from, moved.clone()));
+                pending[0].0 = moved;
+            },
+        }
+    }
+    ordered
+}
+
+// Do renames in the order of order_renames. One that fails keeps its file
+// where it was, so those that would go there are skipped too, and a file
+// moved out of the way to a temporary name comes back
+fn rename_all(ordered: Renames, rename: &mut dyn FnMut(&Path, &Path) -> io::Result<()>) -> (Renames, Vec<(PathBuf, io::Error)>) {
+    let mut done: Renames = Vec::new();
+    let mut failures = Vec::new();
+    let mut occupied: HashSet<PathBuf> = HashSet::new(); // By the files that failed to move
+    for (from, to) in ordered {
+        let result = if occupied.contains(&to) {
+            Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} was not renamed", to.file_name().unwrap_or_default().to_string_lossy())))
+        } else {
+            rename(&from, &to)
+        };
+        let Err(e) = result else {
+            done.push((from, to));
+            continue;
+        };
+        let mut stuck = from;
+        if let Some(i) = done.iter().position(|(_, moved)| *moved == stuck) {
+            if rename(&stuck, &done[i].0).is_ok() {
+                stuck = done.remove(i).0;
+            }
+        }
+        occupied.insert(stuck.clone());
+        failures.push((stuck, e));
+    }
+    (done, failures)
+}
+
+impl Editor {
+    pub(crate) fn dired_jump(&mut self) {
+        // Clone the path to avoid borrowing issues
+        let current_file_path_clone = self.current_file_path.clone();
+        let (path_to_open, focus) = if current_file_path_clone.is_file() {
+            (current_file_path_clone.parent().unwrap_or_else(|| Path::new("/")).to_path_buf(),
+             current_file_path_clone.file_name().and_then(|n| n.to_str()))
+        } else {
+            (current_file_path_clone, None)
+        };
+
+        // Now it's safe to call `self.open` since `self.current_file_path` is not borrowed anymore
+        if let Err(e) = self.open(&path_to_open, focus) {
+            // Handle the error, maybe show a message to the user
+            eprintln!("Error opening directory: {}", e);
+        }
+    }
+
+    // i in dired: edit the file names like any text, C-c C-c renames
+    pub fn wdired(&mut self) {
+        let Some(dired) = self.dired.as_ref().filter(|_| self.mode == Mode::Dired) else {
+            self.message("Not in dired");
+            return;
+        };
+        if dired.entries.is_empty() {
+            self.message("No files to rename");
+            return;
+        }
+        let names: Vec<String> = dired.entries.iter().map(|entry| {
+            entry.path.strip_prefix(&dired.current_path).map_or(entry.name.clone(), |relative| relative.to_string_lossy().into_owned())
+        }).collect();
+        let line = dired.cursor_pos.saturating_sub(2);
+        let wdired = Wdired {
+            directory: dired.current_path.clone(),
+            buffer: std::mem::replace(&mut self.buffer, names.iter().map(|name| name.chars().collect()).collect()),
+            names,
+            file_path: self.current_file_path.clone(),
+            cursor_pos: self.cursor_pos,
+            offset: self.offset,
+            history: std::mem::replace(&mut self.history, UndoHistory::new()),
+        };
+        self.set_current_file(wdired.buffer_path());
+        self.wdired = Some(wdired);
+        self.git_gutter = None;
+        self.cursor_pos = (0, line);
+        self.offset = (0, 0);
+        self.history.reset(&self.buffer, self.cursor_pos);
+        self.syntax_highlighter.parse(&self.buffer);
+        self.mode = Mode::Normal;
+        self.set_cursor_shape();
+        self.message("Edit the file names, C-c C-c to rename, C-c C-k to give up");
+    }
+
+    pub(crate) fn editing_wdired(&self) -> bool {
+        self.wdired.as_ref().is_some_and(|wdired| self.current_file_path == wdired.buffer_path())
+    }
+
+    pub(crate) fn wdired_finish(&mut self) {
+        let Some(wdired) = &self.wdired else { return };
+        let lines: Vec<String> = self.buffer.iter().map(|line| line.iter().collect()).collect();
+        let problems = wdired.problems(&lines);
+        if !problems.is_empty() {
+            self.error(&format!("Nothing renamed: {}", problems.join("; ")));
+            return;
+        }
+
+        let directory = wdired.directory.clone();
+        let mut renames: Vec<(PathBuf, PathBuf)> = wdired.names.iter().zip(&lines)
+            .filter(|(old, new)| old != new)
+            .map(|(old, new)| (directory.join(old), directory.join(new)))
+            .collect();
+        // Files in the tree before the directories they are in
+        renames.sort_by_key(|(from, _)| std::cmp::Reverse(from.components().count()));
+        let count = renames.len();
+        let (done, failures) = rename_all(order_renames(renames), &mut |from, to| rename_no_replace(from, to));
+        let failures: Vec<String> = failures.iter()
+            .map(|(path, e)| format!("{}: {}", path.file_name().unwrap_or_default().to_string_lossy(), e))
+            .collect();
+        self.dired_undo_push(format!("Renamed {} file{}", count, if count == 1 { "" } else { "s" }), done.into_iter().map(|(from, to)| (from, to, None)).collect());
+
+        self.wdired_close();
+        if failures.is_empty() {
+            self.message(&format!("Renamed {} file{}", count, if count == 1 { "" } else { "s" }));
+        } else {
+            self.error(&format!("Failed to rename {} of {}: {}", failures.len(), count, failures.join("; ")));
+        }
+    }
+
+    pub(crate) fn wdired_abort(&mut self) {
+        self.wdired_close();
+        self.message("Wdired aborted, nothing renamed");
+    }
+
+    // Back to the listing, with the buffer that was there before
+    fn wdired_close(&mut self) {
+        let Some(wdired) = self.wdired.take() else { return };
+        let line = self.cursor_pos.1;
+        *self.buffer = wdired.buffer;
+        self.set_current_file(wdired.file_path);
+        self.cursor_pos = wdired.cursor_pos;
+        self.offset = wdired.offset;
+        self.history = wdired.history;
+        self.syntax_highlighter.parse(&self.buffer);
+        self.git_gutter_reload();
+
+        if let Some(dired) = &mut self.dired {
+            let _ = dired.refresh_directory_contents();
+            dired.cursor_pos = (line + 2).min(dired.entries.len() as u16 + 1);
+        }
+        self.mode = Mode::Dired;
+        self.set_cursor_shape();
+    }
+
+    // The open listing keeps its sorting, a new one starts from the config
+    pub(crate) fn dired_view(&self) -> DiredView {
+        self.dired.as_ref().map_or(DiredView {
+            sort: self.config.dired_sort,
+            directories_first: self.config.dired_directories_first,
+            show_hidden: self.config.dired_show_hidden,
+            preview: self.config.dired_preview,
+        }, |dired| dired.view)
+    }
+
+    // C, R, S and M ask where to (or the mode) first, D asks only to confirm
+    fn dired_do(&mut self, op: DiredOp) {
+        let Some(dired) = &self.dired else { return };
+        let paths = dired.targets();
+        if paths.is_empty() {
+            self.message("No file here");
+            return;
+        }
+        match op {
+            DiredOp::Trash | DiredOp::Delete => self.minibuffer_open(Prompt::DiredConfirm { op, paths, target: String::new() }, ""),
+            DiredOp::Chmod => self.minibuffer_open(Prompt::DiredTarget { op, paths }, ""),
+            _ => {
+                let directory = format!("{}/", dired.current_path.display().to_string().trim_end_matches('/'));
+                self.minibuffer_open(Prompt::DiredTarget { op, paths }, &directory);
+            },
+        }
+    }
+
+    // x: delete what d flagged
+    fn dired_do_flagged_delete(&mut self) {
+        let Some(dired) = &self.dired else { return };
+        let paths = dired.marked('D');
+        if paths.is_empty() {
+            self.message("No files flagged for deletion");
+            return;
+        }
+        self.minibuffer_open(Prompt::DiredConfirm { op: DiredOp::Trash, paths, target: String::new() }, "");
+    }
+
+    // Every file gets its go, the failures are reported together
+    pub(crate) fn dired_execute(&mut self, op: DiredOp, paths: &[PathBuf], target: &str) {
+        let Some(dired) = &mut self.dired else { return };
+        let target_path = resolve_typed_path(&dired.current_path, target);
+        let into_directory = target_path.is_dir();
+        if matches!(op, DiredOp::Copy | DiredOp::Move | DiredOp::Symlink) && paths.len() > 1 && !into_directory {
+            self.error(&format!("{} is not a directory", target_path.display()));
+            return;
+        }
+
+        let destination = |path: &Path| if into_directory { target_path.join(path.file_name().unwrap_or_default()) } else { target_path.clone() };
+
+        // Even sizing them could keep the editor waiting
+        if matches!(op, DiredOp::Copy | DiredOp::Move) {
+            if self.dired_job.is_some() {
+                self.message("Another copy or move is still going, try again when it is done");
+                return;
+            }
+            let moves = paths.iter().map(|path| (path.clone(), destination(path))).collect();
+            self.dired_job = Some(DiredJob::start(op, moves));
+            return;
+        }
+
+        let mut done = Vec::new();
+        let mut moves = Vec::new(); // For dired-undo
+        let mut failures = Vec::new();
+        for path in paths {
+            let name = path.file_name().unwrap_or_default();
+            let destination = destination(path);
+            let result = match op {
+                DiredOp::Copy | DiredOp::Move => unreachable!("copies and moves go to a DiredJob"),
+                DiredOp::Symlink => std::os::unix::fs::symlink(path, &destination).map(|_| None),
+                DiredOp::Chmod => chmod_path(path, target).map(|_| None),
+                DiredOp::Trash => trash_path(path).map(|(trashed, info)| Some((path.clone(), trashed, Some(info)))),
+                DiredOp::Delete => remove_path(path).map(|_| None),
+            };
+            match result {
+                Ok(moved) => {
+                    dired.marks.remove(path);
+                    done.push(path.clone());
+                    moves.extend(moved);
+                },
+                Err(e) => failures.push(format!("{}: {}", name.to_string_lossy(), e)),
+            }
+        }
+        if let Err(e) = dired.refresh_directory_contents() {
+            failures.push(format!("{}: {}", dired.current_path.display(), e));
+        }
+        self.dired_report(op, done.len(), paths.len(), &failures);
+        if !done.is_empty() {
+            self.dired_undo_push(format!("{} {}", op.done(), dired_summary(&done)), moves);
+        }
+    }
+
+    // What dired moved, renamed or trashed, for dired-undo
+    pub(crate) fn dired_undo_push(&mut self, what: String, moves: Vec<UndoMove>) {
+        if !moves.is_empty() {
+            self.dired_undo.push(DiredUndo { what, moves });
+        }
+    }
+
+    // Put back what the last dired delete, rename or move did. Files
+    // come back out of the trash with their .trashinfo gone
+    pub fn dired_undo(&mut self) {
+        let Some(undo) = self.dired_undo.pop() else {
+            self.message("No dired operation to undo");
+            return;
+        };
+        let (left, mut failures) = undo_moves(&undo.moves);
+        if let Some(dired) = &mut self.dired {
+            if let Err(e) = dired.refresh_directory_contents() {
+                failures.push(format!("{}: {}", dired.current_path.display(), e));
+            }
+        }
+        if failures.is_empty() {
+            self.message(&format!("Undone: {}", undo.what));
+        } else {
+            self.error(&format!("Couldn't undo all of \"{}\": {}", undo.what, failures.join("; ")));
+        }
+        // What couldn't be put back can be tried again
+        self.dired_undo_push(undo.what, left);
+    }
+
+    // M-x dired-delete-permanently, D and x only go as far as the trash
+    pub fn dired_delete_permanently(&mut self) {
+        if self.mode != Mode::Dired {
+            self.message("Not in dired");
+            return;
+        }
+        self.dired_do(DiredOp::Delete);
+    }
+
+    pub(crate) fn dired_report(&mut self, op: DiredOp, done: usize, count: usize, failures: &[String]) {
+        let files = |count: usize| format!("{} file{}", count, if count == 1 { "" } else { "s" });
+        if failures.is_empty() {
+            self.message(&format!("{} {}", op.done(), files(done)));
+        } else {
+            self.error(&format!("{} {} of {}, failed: {}", op.done(), done, files(count), failures.join("; ")));
+        }
+    }
+
+    // ! and &: a shell command on the marked files, or the one under the cursor
+    fn dired_shell_prompt(&mut self, background: bool) {
+        let Some(dired) = &self.dired else { return };
+        let paths = dired.targets();
+        if paths.is_empty() {
+            self.message("No file here");
+            return;
+        }
+        self.minibuffer_open(Prompt::DiredShell { paths, background }, "");
+    }
+
+    // In the listed directory, & with its output in the compilation buffer
+    pub(crate) fn dired_shell(&mut self, command: &str, paths: &[PathBuf], background: bool) {
+        let Some(dired) = &self.dired else { return };
+        let directory = dired.current_path.clone();
+        let files: Vec<String> = paths.iter().map(|path| path.strip_prefix(&directory).unwrap_or(path).to_string_lossy().into_owned()).collect();
+        let command = dired_shell_command(command, &files);
+
+        if background {
+            self.compilation = None;
+            self.sync_compile_diagnostics();
+            match Compilation::start(&command, &self.config.shell, directory) {
+                Ok(mut compilation) => {
+                    compilation.title = "Shell command";
+                    compilation.back = Mode::Dired;
+                    self.compilation = Some(compilation);
+                    self.mode = Mode::Compilation;
+                },
+                Err(e) => self.error(&format!("Couldn't run {}: {}", command, e)),
+            }
+            return;
+        }
+
+        let output = Command::new(&self.config.shell)
+            .arg("-c")
+            .arg(&command)
+            .current_dir(&directory)
+            .stdin(Stdio::null())
+            .output();
+        if let Some(dired) = &mut self.dired {
+            let _ = dired.refresh_directory_contents();
+        }
+        match output {
+            Ok(output) => {
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                let text = if stdout.trim().is_empty() { String::from_utf8_lossy(&output.stderr) } else { stdout };
+                match (output.status.success(), text.trim_end()) {
+                    (true, "") => self.message("(Shell command succeeded with no output)"),
+                    (true, text) => self.message(text),
+                    (false, "") => self.error(&format!("Shell command failed: {}", output.status)),
+                    (false, text) => self.error(text),
+                }
+            },
+            Err(e) => self.error(&format!("Couldn't run {}: {}", command, e)),
+        }
+    }
+
+    // W: the marked files, or the one under the cursor, in the programs
+    // the desktop opens them with
+    fn dired_open_external(&mut self) {
+        let Some(dired) = &self.dired else { return };
+        let paths = dired.targets();
+        if paths.is_empty() {
+            self.message("No file here");
+            return;
+        }
+        for path in &paths {
+            // Its own process group, so it outlives the editor's terminal
+            let child = Command::new("xdg-open")
+                .arg(path)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .process_group(0)
+                .spawn();
+            match child {
+                Ok(mut child) => {
+                    std::thread::spawn(move || child.wait());
+                },
+                Err(e) => {
+                    self.error(&format!("Couldn't run xdg-open: {}", e));
+                    return;
+                },
+            }
+        }
+        self.message(&format!("Opened {}", dired_summary(&paths)));
+    }
+
+    pub(crate) fn handle_dired_mode(&mut self, key: KeyEvent) -> Result<()> {
+        // The second key of % m and * /
+        if let Some(prefix) = self.dired.as_mut().and_then(|dired| dired.prefix.take()) {
+            match (prefix, key.code) {
+                ('%', KeyCode::Char('m')) => self.minibuffer_open(Prompt::DiredMarkRegexp, ""),
+                ('*', KeyCode::Char('/')) => {
+                    let count = self.dired.as_mut().map_or(0, |dired| dired.mark_where(|entry| entry.is_dir));
+                    self.message(&format!("Marked {} director{}", count, if count == 1 { "y" } else { "ies" }));
+                },
+                (_, KeyCode::Char(c)) => self.message(&format!("{} {} is undefined", prefix, c)),
+                _ => {},
+            }
+            return Ok(());
+        }
+        let Some(&(_, command, _)) = DIRED_KEYS.iter().find(|(keys, _, _)| keys.contains(&key.code)) else {
+            return Ok(());
+        };
+        match command {
+            DiredCommand::Next => {
+                if let Some(dired) = &mut self.dired {
+                    let max_index = dired.entries.len() as u16 + 1;
+                    if dired.cursor_pos < max_index {
+                        dired.cursor_pos += 1;
+                    }
+                }
+            },
+            DiredCommand::Previous => {
+                if let Some(dired) = &mut self.dired {
+                    if dired.cursor_pos > 0 {
+                        dired.cursor_pos -= 1;
+                    }
+                }
+            },
+            DiredCommand::Parent => {
+                if let Some(dired) = &mut self.dired {
+                    let current_dir_name = dired.current_path.file_name()
+                        .and_then(|name| name.to_str())
+                        .unwrap_or(""); // Get the current directory name as a &str
+
+                    let parent_path = dired.current_path.parent()
+                        .unwrap_or_else(|| Path::new("/"))
+                        .to_path_buf();
+
+                    // Update Dired with the parent path, highlighting the directory we came from
+                    *dired = Dired::new(parent_path, Some(current_dir_name), dired.view)?;
+                }
+            },
+
+            DiredCommand::Visit => {
+                if let Some(dired) = &mut self.dired {
+                    if dired.cursor_pos == 0 {
+                        // Do nothing for '.'
+                    } else if dired.cursor_pos == 1 {
+                        // Handle '..' the same as 'h', navigate to the parent directory
+                        let parent_path = dired.current_path.parent().unwrap_or_else(|| Path::new("/")).to_path_buf();
+                        *dired = Dired::new(parent_path, None, dired.view)?;
+                    } else {
+                        let selected_entry = &dired.entries[dired.cursor_pos as usize - 2]; // Adjusting for '.' and '..'
+                        let path = selected_entry.path.clone();
+                        if path.is_dir() {
+                            *dired = Dired::new(path.to_path_buf(), None, dired.view)?;
+                        } else if path.is_file() {
+                            self.open(&path, None)?;
+                        }
+                    }
+                }
+            },
+
+            DiredCommand::Touch { open } => {
+                self.minibuffer_open(Prompt::DiredTouch { open }, "");
+            },
+
+            DiredCommand::Wdired => {
+                self.wdired();
+            },
+
+            DiredCommand::Help => {
+                self.message(&dired_help());
+            },
+
+            DiredCommand::Sort => {
+                if let Some(dired) = &mut self.dired {
+                    dired.view.sort = dired.view.sort.next();
+                    dired.apply_view();
+                    let sort = dired.view.sort.name();
+                    self.message(&format!("Sorted by {}", sort));
+                }
+            },
+
+            DiredCommand::Expand => {
+                if let Some(dired) = &mut self.dired {
+                    if let Err(e) = dired.toggle_expanded() {
+                        self.error(&format!("Failed to read the directory: {}", e));
+                    }
+                }
+            },
+
+            DiredCommand::Preview => {
+                if let Some(dired) = &mut self.dired {
+                    dired.view.preview = !dired.view.preview;
+                }
+            },
+
+            DiredCommand::Shell { background } => self.dired_shell_prompt(background),
+            DiredCommand::OpenExternal => self.dired_open_external(),
+
+            DiredCommand::Hidden => {
+                if let Some(dired) = &mut self.dired {
+                    dired.view.show_hidden = !dired.view.show_hidden;
+                    dired.apply_view();
+                }
+            },
+
+            DiredCommand::Filter => {
+                let filter = self.dired.as_ref().map_or(String::new(), |dired| dired.filter.clone());
+                self.minibuffer_open(Prompt::DiredFilter(filter.clone()), &filter);
+            },
+
+            DiredCommand::Refresh => {
+                if let Some(dired) = &mut self.dired {
+                    dired.refresh_directory_contents()?;
+                }
+            },
+
+            DiredCommand::Mkdir => {
+                self.minibuffer_open(Prompt::DiredCreateDirectory, "");
+            },
+
+            DiredCommand::Mark(mark) => {
+                if let Some(dired) = &mut self.dired {
+                    dired.set_mark(mark);
+                }
+            },
+
+            DiredCommand::UnmarkAll => {
+                if let Some(dired) = &mut self.dired {
+                    dired.marks.clear();
+                }
+            },
+
+            DiredCommand::ToggleMarks => {
+                if let Some(dired) = &mut self.dired {
+                    dired.toggle_marks();
+                }
+            },
+
+            DiredCommand::Prefix(prefix) => {
+                if let Some(dired) = &mut self.dired {
+                    dired.prefix = Some(prefix);
+                }
+            },
+
+            DiredCommand::DeleteFlagged => {
+                self.dired_do_flagged_delete();
+            },
+
+            DiredCommand::Do(op) => {
+                self.dired_do(op);
+            },
+
+            DiredCommand::Rename => {
+                if let Some(dired) = &self.dired {
+                    // Ensure the cursor is on a valid entry (not '.' or '..')
+                    if dired.cursor_pos > 1 && (dired.cursor_pos as usize - 2) < dired.entries.len() {
+                        let entry_to_rename = &dired.entries[dired.cursor_pos as usize - 2];
+                        let entry_name = entry_to_rename.name.clone();
+
+                        // Activate the minibuffer for renaming, pre-filling it with the entry's name
+                        self.minibuffer_open(Prompt::DiredRename, &entry_name);
+                    }
+                }
+            },
+
+            DiredCommand::Quit => {
+                self.mode = Mode::Normal;
+                // self.message_buffers();
+            },
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    #[test]
+    fn parse_mode_octal() {
+        assert_eq!(parse_mode("755", 0o644, false), Some(0o755));
+        assert_eq!(parse_mode("4755", 0o644, false), Some(0o4755));
+        assert_eq!(parse_mode("0", 0o644, false), Some(0));
+        assert_eq!(parse_mode("17777", 0o644, false), None);
+    }
+
+    #[test]
+    fn parse_mode_symbolic() {
+        assert_eq!(parse_mode("u+x,go-w", 0o666, false), Some(0o744));
+        assert_eq!(parse_mode("a=r", 0o755, false), Some(0o444));
+        assert_eq!(parse_mode("=r", 0o755, false), Some(0o444));
+        assert_eq!(parse_mode("go=", 0o755, false), Some(0o700));
+        assert_eq!(parse_mode("u=rw+x", 0o644, false), Some(0o744));
+        assert_eq!(parse_mode("g+w", 0o100644, false), Some(0o664));
+    }
+
+    #[test]
+    fn parse_mode_x_only_for_directories_and_executables() {
+        assert_eq!(parse_mode("a+X", 0o644, false), Some(0o644));
+        assert_eq!(parse_mode("a+X", 0o644, true), Some(0o755));
+        assert_eq!(parse_mode("a+X", 0o744, false), Some(0o755));
+    }
+
+    #[test]
+    fn parse_mode_setuid_setgid_sticky() {
+        assert_eq!(parse_mode("u+s", 0o755, false), Some(0o4755));
+        assert_eq!(parse_mode("g+s", 0o755, true), Some(0o2755));
+        assert_eq!(parse_mode("+t", 0o777, true), Some(0o1777));
+        assert_eq!(parse_mode("u+t", 0o755, true), Some(0o755));
+        assert_eq!(parse_mode("a-s", 0o6755, false), Some(0o755));
+    }
+
+    #[test]
+    fn parse_mode_rejects_invalid_specs() {
+        for spec in ["", "u", "u+z", "q+x", "u+x,", "8", "rwx", "u+x;g+w"] {
+            assert_eq!(parse_mode(spec, 0o644, false), None, "{:?}", spec);
+        }
+    }
+
+    #[test]
+    fn summary_names_a_few_files() {
+        let paths = |names: &[&str]| names.iter().map(|name| PathBuf::from("/dir").join(name)).collect::<Vec<_>>();
+        assert_eq!(dired_summary(&paths(&["a"])), "a");
+        assert_eq!(dired_summary(&paths(&["a", "b", "c"])), "3 files: a, b, c");
+        assert_eq!(dired_summary(&paths(&["a", "b", "c", "d"])), "4 files: a, b, c, d");
+        assert_eq!(dired_summary(&paths(&["a", "b", "c", "d", "e", "f"])), "6 files: a, b, c and 3 more");
+    }
+
+    // A tree with a file, an executable, a link and a subdirectory
+    fn tree(root: &Path) {
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("file"), "content").unwrap();
+        fs::write(root.join("sub/script"), "#!/bin/sh").unwrap();
+        fs::set_permissions(root.join("sub/script"), fs::Permissions::from_mode(0o750)).unwrap();
+        std::os::unix::fs::symlink("../file", root.join("sub/link")).unwrap();
+    }
+
+    fn same_tree(copy: &Path) {
+        assert_eq!(fs::read_to_string(copy.join("file")).unwrap(), "content");
+        assert_eq!(fs::metadata(copy.join("sub/script")).unwrap().permissions().mode() & 0o7777, 0o750);
+        assert_eq!(fs::read_link(copy.join("sub/link")).unwrap(), PathBuf::from("../file"));
+    }
+
+    #[test]
+    fn copy_path_copies_trees() {
+        let dir = crate::tests::temp_dir("dired-copy");
+        tree(&dir.join("from"));
+        let mut copied = 0;
+        copy_path(&dir.join("from"), &dir.join("to"), &mut |bytes| copied += bytes).unwrap();
+        same_tree(&dir.join("to"));
+        same_tree(&dir.join("from"));
+        assert_eq!(copied, "content".len() as u64 + "#!/bin/sh".len() as u64);
+
+        let exists = copy_path(&dir.join("from/file"), &dir.join("to/file"), &mut |_| {}).unwrap_err();
+        assert_eq!(exists.kind(), io::ErrorKind::AlreadyExists);
+        let into_itself = copy_path(&dir.join("from"), &dir.join("from/sub/again"), &mut |_| {}).unwrap_err();
+        assert_eq!(into_itself.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn move_path_renames_and_refuses_to_overwrite() {
+        let dir = crate::tests::temp_dir("dired-move");
+        tree(&dir.join("from"));
+        fs::write(dir.join("taken"), "").unwrap();
+        assert_eq!(move_path(&dir.join("from"), &dir.join("taken"), &mut |_| {}).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        move_path(&dir.join("from"), &dir.join("to"), &mut |_| panic!("a rename copies nothing")).unwrap();
+        same_tree(&dir.join("to"));
+        assert!(!path_exists(&dir.join("from")));
+    }
+
+    #[test]
+    fn help_lists_every_key_once() {
+        let codes: Vec<KeyCode> = DIRED_KEYS.iter().flat_map(|(codes, _, _)| codes.iter().copied()).collect();
+        for (i, code) in codes.iter().enumerate() {
+            assert!(!codes[i + 1..].contains(code), "{:?} is bound twice", code);
+        }
+        let help = dired_help();
+        for c in ['s', '.', '/', 'P', '!', '&', 'W', 'U', 'D', 'x'] {
+            assert!(help.contains(&format!("{} ", c)), "{} missing from {}", c, help);
+        }
+        assert!(help.contains("TAB expand") && help.contains("l/f/RET open") && help.contains("% m mark by regexp"), "{}", help);
+    }
+
+    fn view(sort: DiredSort, directories_first: bool) -> DiredView {
+        DiredView { sort, directories_first, show_hidden: false, preview: false }
+    }
+
+    #[test]
+    fn rename_entry_refuses_to_overwrite() {
+        let dir = files("dired-rename-entry", &["a", "b"]);
+        let mut dired = Dired::new(dir.clone(), Some("a"), view(DiredSort::Name, true)).unwrap();
+        assert_eq!(dired.rename_entry("b").unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        expect(&dir, &[("a", "a"), ("b", "b")]);
+        assert_eq!(dired.rename_entry("c").unwrap(), (dir.join("a"), dir.join("c")));
+        expect(&dir, &[("b", "b"), ("c", "a")]);
+    }
+
+    // Files named like their content, in a new directory
+    fn files(name: &str, names: &[&str]) -> PathBuf {
+        let dir = crate::tests::temp_dir(name);
+        for name in names {
+            fs::write(dir.join(name), name).unwrap();
+        }
+        dir
+    }
+
+    // Every file of the directory and its content
+    fn contents(dir: &Path) -> Vec<(String, String)> {
+        let mut contents: Vec<(String, String)> = fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .map(|path| (path.file_name().unwrap().to_string_lossy().into_owned(), fs::read_to_string(&path).unwrap()))
+            .collect();
+        contents.sort();
+        contents
+    }
+
+    fn pairs(dir: &Path, renames: &[(&str, &str)]) -> Vec<(PathBuf, PathBuf)> {
+        renames.iter().map(|(from, to)| (dir.join(from), dir.join(to))).collect()
+    }
+
+    fn expect(dir: &Path, files: &[(&str, &str)]) {
+        assert_eq!(contents(dir), files.iter().map(|(name, content)| (name.to_string(), content.to_string())).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn rename_no_replace_keeps_the_target() {
+        let dir = files("dired-no-replace", &["a", "b"]);
+        let e = rename_no_replace(&dir.join("a"), &dir.join("b")).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        expect(&dir, &[("a", "a"), ("b", "b")]);
+        rename_no_replace(&dir.join("a"), &dir.join("c")).unwrap();
+        expect(&dir, &[("b", "b"), ("c", "a")]);
+    }
+
+    #[test]
+    fn order_renames_swaps_through_a_temporary_name() {
+        let dir = files("dired-swap", &["a", "b"]);
+        let ordered = order_renames(pairs(&dir, &[("a", "b"), ("b", "a")]));
+        assert_eq!(ordered.len(), 3);
+        assert_eq!(ordered[0].0, dir.join("a"));
+        assert!(ordered[0].1.file_name().unwrap().to_string_lossy().starts_with(".wdired-"));
+        let (done, failures) = rename_all(ordered, &mut |from, to| rename_no_replace(from, to));
+        assert!(failures.is_empty());
+        assert_eq!(done.len(), 3);
+        expect(&dir, &[("a", "b"), ("b", "a")]);
+    }
+
+    #[test]
+    fn order_renames_goes_around_a_cycle() {
+        let dir = files("dired-cycle", &["a", "b", "c"]);
+        let ordered = order_renames(pairs(&dir, &[("a", "b"), ("b", "c"), ("c", "a")]));
+        assert_eq!(ordered.len(), 4);
+        let (_, failures) = rename_all(ordered, &mut |from, to| rename_no_replace(from, to));
+        assert!(failures.is_empty());
+        expect(&dir, &[("a", "c"), ("b", "a"), ("c", "b")]);
+    }
+
+    #[test]
+    fn order_renames_empties_a_target_before_filling_it() {
+        let dir = files("dired-chain", &["a", "b", "c"]);
+        let ordered = order_renames(pairs(&dir, &[("a", "b"), ("b", "c"), ("c", "d")]));
+        assert_eq!(ordered, pairs(&dir, &[("c", "d"), ("b", "c"), ("a", "b")]));
+        rename_all(ordered, &mut |from, to| rename_no_replace(from, to));
+        expect(&dir, &[("b", "a"), ("c", "b"), ("d", "c")]);
+    }
+
+    #[test]
+    fn a_failed_rename_stops_those_behind_it() {
+        let dir = files("dired-chain-failure", &["a", "b", "c", "x"]);
+        let renames = pairs(&dir, &[("a", "b"), ("b", "c"), ("c", "d"), ("x", "y")]);
+        let (done, failures) = rename_all(order_renames(renames), &mut |from, to| {
+            if from.ends_with("c") {
+                return Err(io::Error::other("refused"));
+            }
+            rename_no_replace(from, to)
+        });
+        assert_eq!(done, pairs(&dir, &[("x", "y")]));
+        let failed: Vec<&PathBuf> = failures.iter().map(|(path, _)| path).collect();
+        assert_eq!(failed, vec![&dir.join("c"), &dir.join("b"), &dir.join("a")]);
+        expect(&dir, &[("a", "a"), ("b", "b"), ("c", "c"), ("y", "x")]);
+    }
+
+    #[test]
+    fn a_failed_swap_puts_the_file_back() {
+        let dir = files("dired-swap-failure", &["a", "b"]);
+        let (done, failures) = rename_all(order_renames(pairs(&dir, &[("a", "b"), ("b", "a")])), &mut |from, to| {
+            if from.ends_with("b") {
+                return Err(io::Error::other("refused"));
+            }
+            rename_no_replace(from, to)
+        });
+        assert!(done.is_empty());
+        assert_eq!(failures.len(), 2);
+        expect(&dir, &[("a", "a"), ("b", "b")]);
+    }
+
+    // Every color a syntax can give, told apart
+    fn theme() -> Theme {
+        let mut theme = Theme::fallback();
+        let rgb = |n: u8| Color::Rgb { r: n, g: n, b: n };
+        (theme.text_color, theme.comment_color, theme.string_color, theme.warning_color, theme.use_color, theme.dired_dir_color) = (rgb(1), rgb(2), rgb(3), rgb(4), rgb(5), rgb(6));
+        theme
+    }
+
+    // The pieces of each line, named by what they were colored as
+    fn highlighted(path: &str, source: &str) -> Vec<Vec<(&'static str, String)>> {
+        let theme = theme();
+        let kind = |color: Color| match color {
+            c if c == theme.comment_color => "comment",
+            c if c == theme.string_color => "string",
+            c if c == theme.warning_color => "number",
+            c if c == theme.use_color => "keyword",
+            c if c == theme.dired_dir_color => "type",
+            _ => "text",
+        };
+        DiredPreview::text(Path::new(path), source, &theme).into_iter()
+            .map(|line| line.into_iter().map(|(color, text)| (kind(color), text)).collect())
+            .collect()
+    }
+
+    fn pieces(pieces: &[(&'static str, &str)]) -> Vec<(&'static str, String)> {
+        pieces.iter().map(|(kind, text)| (*kind, text.to_string())).collect()
+    }
+
+    #[test]
+    fn preview_highlights_c_like_languages() {
+        let lines = highlighted("main.c", "int n = 42; // \"not a string\"\nchar *s = \"a \\\" // b\"; /* one\ntwo */ Point p;");
+        assert_eq!(lines, vec![
+            pieces(&[("keyword", "int"), ("text", " n = "), ("number", "42"), ("text", "; "), ("comment", "// \"not a string\"")]),
+            pieces(&[("keyword", "char"), ("text", " *s = "), ("string", "\"a \\\" // b\""), ("text", "; "), ("comment", "/* one")]),
+            pieces(&[("comment", "two */"), ("text", " "), ("type", "Point"), ("text", " p;")]),
+        ]);
+    }
+
+    #[test]
+    fn preview_highlights_python_and_lua() {
+        assert_eq!(highlighted("a.py", "def f():\n    '''doc\n    # not a comment'''\n    return None # done"), vec![
+            pieces(&[("keyword", "def"), ("text", " f():")]),
+            pieces(&[("text", "    "), ("string", "'''doc")]),
+            pieces(&[("string", "    # not a comment'''")]),
+            pieces(&[("text", "    "), ("keyword", "return"), ("text", " "), ("keyword", "None"), ("text", " "), ("comment", "# done")]),
+        ]);
+        assert_eq!(highlighted("init.lua", "--[[ a\nb ]] local x = 'it''s' -- end"), vec![
+            pieces(&[("comment", "--[[ a")]),
+            pieces(&[("comment", "b ]]"), ("text", " "), ("keyword", "local"), ("text", " x = "), ("string", "'it''s'"), ("text", " "), ("comment", "-- end")]),
+        ]);
+    }
+
+    #[test]
+    fn preview_keeps_unknown_text_plain() {
+        assert_eq!(highlighted("notes.txt", "if \"x\" // 1"), vec![pieces(&[("text", "if \"x\" // 1")])]);
+        // An unterminated string stops at the end of its line
+        assert_eq!(highlighted("a.sh", "echo \"open\nls"), vec![
+            pieces(&[("text", "echo "), ("string", "\"open")]),
+            pieces(&[("text", "ls")]),
+        ]);
+    }
+
+    #[test]
+    fn preview_pane_leaves_the_listing_room() {
+        assert_eq!(preview_pane_width(100, 50), 50);
+        assert_eq!(preview_pane_width(100, 95), 90);
+        assert_eq!(preview_pane_width(100, 1), 12);
+        assert_eq!(preview_pane_width(8, 50), 8);
+    }
+
+    #[test]
+    fn dired_job_sizes_the_copy_itself() {
+        let dir = crate::tests::temp_dir("dired-job");
+        tree(&dir.join("from"));
+        let mut job = DiredJob::start(DiredOp::Copy, vec![(dir.join("from"), dir.join("to"))]);
+        assert_eq!(job.progress(), "Copying 1/1");
+        while !job.finished {
+            job.poll();
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        same_tree(&dir.join("to"));
+        assert_eq!(job.done, vec![(dir.join("from"), dir.join("to"))]);
+        assert!(job.total.is_some_and(|total| total >= job.copied) && job.copied > 0);
+        assert!(job.progress().ends_with('%'), "{}", job.progress());
+
+        // A rename copies nothing, there is only the count to show
+        let mut job = DiredJob::start(DiredOp::Move, vec![(dir.join("to"), dir.join("moved"))]);
+        while !job.finished {
+            job.poll();
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        assert_eq!(job.total, Some(0));
+        assert_eq!(job.progress(), "Moving 1/1");
+        same_tree(&dir.join("moved"));
+    }
+
+    // Needs /dev/shm on another filesystem than the temporary directory
+    #[test]
+    fn move_path_copies_across_filesystems() {
+        let dir = crate::tests::temp_dir("dired-move-exdev");
+        let other = PathBuf::from("/dev/shm").join(format!("redit-test-{}-exdev", std::process::id()));
+        let device = |path: &Path| fs::metadata(path).map(|metadata| metadata.dev());
+        let _ = fs::remove_dir_all(&other);
+        if fs::create_dir_all(&other).is_err() || device(&other).ok() == device(&dir).ok() {
+            return;
+        }
+        tree(&dir.join("from"));
+        let mut copied = 0;
+        move_path(&dir.join("from"), &other.join("to"), &mut |bytes| copied += bytes).unwrap();
+        same_tree(&other.join("to"));
+        assert!(!path_exists(&dir.join("from")));
+        assert!(copied > 0);
+        fs::remove_dir_all(&other).unwrap();
+    }
+
+    // With a home trash of its own, on the same filesystem as the files
+    #[test]
+    fn trash_path_follows_the_spec_and_undo_puts_it_back() {
+        let dir = crate::tests::temp_dir("dired-trash");
+        let trash = dir.join("home/Trash");
+        let path = dir.join("a file é.txt");
+        let info_text = |info: &Path| fs::read_to_string(info).unwrap();
+
+        fs::write(&path, "first").unwrap();
+        let (trashed, info) = trash_path_in(&path, Some(trash.clone())).unwrap();
+        assert_eq!(trashed, trash.join("files/a file é.txt"));
+        assert_eq!(info, trash.join("info/a file é.txt.trashinfo"));
+        assert!(!path_exists(&path));
+        let text = info_text(&info);
+        let encoded = format!("Path={}/a%20file%20%C3%A9.txt\n", dir.display());
+        assert!(text.starts_with("[Trash Info]\n"), "{}", text);
+        assert!(text.contains(&encoded), "{}", text);
+        assert!(text.lines().any(|line| line.strip_prefix("DeletionDate=").is_some_and(|date| date.len() == 19)), "{}", text);
+
+        // The same name again takes the next number
+        fs::write(&path, "second").unwrap();
+        let (trashed_again, info_again) = trash_path_in(&path, Some(trash.clone())).unwrap();
+        assert_eq!(trashed_again, trash.join("files/a file é.txt.2"));
+        assert_eq!(info_again, trash.join("info/a file é.txt.2.trashinfo"));
+        assert_eq!(fs::read_to_string(&trashed_again).unwrap(), "second");
+
+        // The second comes back, the first finds its place taken and is kept
+        let moves = vec![(path.clone(), trashed.clone(), Some(info.clone())), (path.clone(), trashed_again, Some(info_again.clone()))];
+        let (left, failures) = undo_moves(&moves);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+        assert!(!path_exists(&info_again));
+        assert_eq!(left, moves[..1]);
+        assert_eq!(failures.len(), 1);
+
+        fs::remove_file(&path).unwrap();
+        let (left, failures) = undo_moves(&left);
+        assert!(left.is_empty() && failures.is_empty());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first");
+        assert!(!path_exists(&info) && !path_exists(&trashed));
+    }
+
+    #[test]
+    fn top_trash_takes_a_sticky_trash_over_its_own() {
+        let uid = unsafe { libc::getuid() };
+        let dir = crate::tests::temp_dir("dired-top-trash");
+        let own = top_trash(&dir).unwrap();
+        assert_eq!(own, dir.join(format!(".Trash-{}", uid)));
+        assert_eq!(fs::metadata(&own).unwrap().permissions().mode() & 0o7777, 0o700);
+        assert_eq!(top_trash(&dir), Some(own.clone())); // Already there
+
+        // Not sticky, anyone could swap it, so it isn't used
+        fs::create_dir(dir.join(".Trash")).unwrap();
+        fs::set_permissions(dir.join(".Trash"), fs::Permissions::from_mode(0o777)).unwrap();
+        assert_eq!(top_trash(&dir), Some(own));
+        fs::set_permissions(dir.join(".Trash"), fs::Permissions::from_mode(0o1777)).unwrap();
+        assert_eq!(top_trash(&dir), Some(dir.join(".Trash").join(uid.to_string())));
+    }
+
+    // Files of different sizes, times and extensions, a directory and a
+    // hidden file
+    fn listing(name: &str) -> PathBuf {
+        let dir = crate::tests::temp_dir(name);
+        let now = std::time::SystemTime::now();
+        for (name, size, age) in [("b.txt", 3, 300), ("a.rs", 10, 100), ("C.md", 1, 200), (".hidden", 5, 0)] {
+            fs::write(dir.join(name), "x".repeat(size)).unwrap();
+            fs::File::options().write(true).open(dir.join(name)).unwrap().set_modified(now - Duration::from_secs(age)).unwrap();
+        }
+        fs::create_dir(dir.join("zdir")).unwrap();
+        dir
+    }
+
+    fn names(dired: &Dired) -> Vec<&str> {
+        dired.entries.iter().map(|entry| entry.name.as_str()).collect()
+    }
+
+    #[test]
+    fn view_sorts_each_way() {
+        let dir = listing("dired-sort");
+        let mut dired = Dired::new(dir, None, view(DiredSort::Name, false)).unwrap();
+        assert_eq!(names(&dired), vec!["a.rs", "b.txt", "C.md", "zdir"]); // Case doesn't count
+        let mut sorted = |sort, directories_first| {
+            dired.view = view(sort, directories_first);
+            dired.apply_view();
+            names(&dired).iter().map(|name| name.to_string()).collect::<Vec<_>>()
+        };
+        assert_eq!(sorted(DiredSort::Name, true), vec!["zdir", "a.rs", "b.txt", "C.md"]);
+        assert_eq!(sorted(DiredSort::Size, true), vec!["zdir", "a.rs", "b.txt", "C.md"]);
+        assert_eq!(sorted(DiredSort::Modified, true), vec!["zdir", "a.rs", "C.md", "b.txt"]);
+        assert_eq!(sorted(DiredSort::Extension, true), vec!["zdir", "C.md", "a.rs", "b.txt"]);
+        // Without an extension first, directories or not
+        assert_eq!(sorted(DiredSort::Extension, false), vec!["zdir", "C.md", "a.rs", "b.txt"]);
+    }
+
+    #[test]
+    fn view_puts_directories_first_and_hides_dot_files() {
+        let dir = listing("dired-directories-first");
+        fs::create_dir(dir.join("adir")).unwrap();
+        let mut dired = Dired::new(dir.clone(), None, view(DiredSort::Size, true)).unwrap();
+        assert_eq!(names(&dired), vec!["adir", "zdir", "a.rs", "b.txt", "C.md"]); // Equal sizes by name
+        dired.view = view(DiredSort::Modified, false);
+        fs::File::open(dir.join("adir")).unwrap().set_modified(std::time::SystemTime::now() - Duration::from_secs(250)).unwrap();
+        dired.refresh_directory_contents().unwrap();
+        assert_eq!(names(&dired), vec!["zdir", "a.rs", "C.md", "adir", "b.txt"]);
+        dired.view.show_hidden = true;
+        dired.apply_view();
+        assert_eq!(names(&dired)[..2], [".hidden", "zdir"]);
+    }
+
+    #[test]
+    fn filter_keeps_the_marks_and_the_cursor() {
+        let dir = listing("dired-filter");
+        let mut dired = Dired::new(dir.clone(), Some("b.txt"), view(DiredSort::Name, false)).unwrap();
+        dired.marks.insert(dir.join("a.rs"), '*');
+        dired.marks.insert(dir.join("C.md"), 'D');
+
+        dired.set_filter("b");
+        assert_eq!(names(&dired), vec!["b.txt"]);
+        assert_eq!(dired.current_entry().unwrap().name, "b.txt");
+        assert_eq!(dired.marks.len(), 2);
+        assert!(dired.marked('*').is_empty()); // Only what is shown
+
+        dired.set_filter("c"); // Smart case, lower case matches upper case too
+        assert_eq!(names(&dired), vec!["C.md"]);
+        assert_eq!(dired.marked('D'), vec![dir.join("C.md")]);
+        dired.set_filter("Md"); // And upper case only itself
+        assert!(names(&dired).is_empty());
+
+        dired.set_filter("");
+        assert_eq!(names(&dired), vec!["a.rs", "b.txt", "C.md", "zdir"]);
+        assert_eq!(dired.marked('*'), vec![dir.join("a.rs")]);
+        assert_eq!(dired.marked('D'), vec![dir.join("C.md")]);
+    }
+
+    fn strings(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn shell_command_puts_the_files_in() {
+        let files = strings(&["a", "b c"]);
+        assert_eq!(dired_shell_command("tar cf x.tar * -v", &files), "tar cf x.tar 'a' 'b c' -v");
+        assert_eq!(dired_shell_command("gzip ?", &files), "gzip 'a'; gzip 'b c'");
+        assert_eq!(dired_shell_command("mv ? ?.bak", &files), "mv 'a' ?.bak; mv 'b c' ?.bak");
+        assert_eq!(dired_shell_command("wc -l", &files), "wc -l 'a' 'b c'");
+        // Only on their own, in a word they are the shell's
+        assert_eq!(dired_shell_command("ls *.txt", &files), "ls *.txt 'a' 'b c'");
+        assert_eq!(dired_shell_command("echo * ?", &files), "echo 'a' 'b c' ?");
+    }
+
+    #[test]
+    fn shell_command_quotes_what_the_shell_would_read() {
+        let files = strings(&["it's", "two  spaces", "$HOME `x` \"q\""]);
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        let run = |command: &str| {
+            let output = std::process::Command::new("sh").arg("-c").arg(command).output().unwrap();
+            String::from_utf8(output.stdout).unwrap()
+        };
+        assert_eq!(run(&dired_shell_command("printf '%s|' *", &files)), "it's|two  spaces|$HOME `x` \"q\"|");
+        assert_eq!(run(&dired_shell_command("printf '<%s>' ?", &files)), "<it's><two  spaces><$HOME `x` \"q\">");
+    }
+
+    // Names with their depth in the tree
+    fn depths(dired: &Dired) -> Vec<(&str, usize)> {
+        dired.entries.iter().map(|entry| (entry.name.as_str(), entry.depth)).collect()
+    }
+
+    fn select(dired: &mut Dired, name: &str) {
+        dired.cursor_pos = dired.entries.iter().position(|entry| entry.name == name).unwrap() as u16 + 2;
+    }
+
+    #[test]
+    fn tab_expands_nested_directories() {
+        let dir = crate::tests::temp_dir("dired-expand");
+        fs::create_dir_all(dir.join("a/b")).unwrap();
+        fs::write(dir.join("a/b/deep"), "").unwrap();
+        fs::write(dir.join("a/x"), "").unwrap();
+        fs::write(dir.join("top"), "").unwrap();
+        let mut dired = Dired::new(dir, Some("a"), view(DiredSort::Name, true)).unwrap();
+        assert_eq!(depths(&dired), vec![("a", 0), ("top", 0)]);
+
+        dired.toggle_expanded().unwrap();
+        assert_eq!(depths(&dired), vec![("a", 0), ("b", 1), ("x", 1), ("top", 0)]);
+        assert_eq!(dired.current_entry().unwrap().name, "a");
+        select(&mut dired, "b");
+        dired.toggle_expanded().unwrap();
+        assert_eq!(depths(&dired), vec![("a", 0), ("b", 1), ("deep", 2), ("x", 1), ("top", 0)]);
+
+        // The filter keeps the directories a match is in
+        dired.set_filter("dee");
+        assert_eq!(depths(&dired), vec![("a", 0), ("b", 1), ("deep", 2)]);
+    }
+
+    #[test]
+    fn tab_collapses_from_a_directory_or_a_file_in_it() {
+        let dir = crate::tests::temp_dir("dired-collapse");
+        fs::create_dir_all(dir.join("a/b")).unwrap();
+        fs::write(dir.join("a/b/deep"), "").unwrap();
+        fs::write(dir.join("a/x"), "").unwrap();
+        fs::write(dir.join("top"), "").unwrap();
+        let mut dired = Dired::new(dir, Some("a"), view(DiredSort::Name, true)).unwrap();
+        dired.toggle_expanded().unwrap();
+        select(&mut dired, "b");
+        dired.toggle_expanded().unwrap();
+
+        // On a file, its directory closes and gets the cursor
+        select(&mut dired, "deep");
+        dired.toggle_expanded().unwrap();
+        assert_eq!(depths(&dired), vec![("a", 0), ("b", 1), ("x", 1), ("top", 0)]);
+        assert_eq!(dired.current_entry().unwrap().name, "b");
+
+        dired.toggle_expanded().unwrap();
+        select(&mut dired, "a");
+        dired.toggle_expanded().unwrap();
+        assert_eq!(depths(&dired), vec![("a", 0), ("top", 0)]);
+        // Opened again, b is still open
+        dired.toggle_expanded().unwrap();
+        assert_eq!(depths(&dired), vec![("a", 0), ("b", 1), ("deep", 2), ("x", 1), ("top", 0)]);
+
+        select(&mut dired, "x");
+        dired.toggle_expanded().unwrap();
+        assert_eq!(depths(&dired), vec![("a", 0), ("top", 0)]);
+        assert_eq!(dired.current_entry().unwrap().name, "a");
+        // A file at the top has no directory to close
+        select(&mut dired, "top");
+        dired.toggle_expanded().unwrap();
+        assert_eq!(depths(&dired), vec![("a", 0), ("top", 0)]);
+        assert_eq!(dired.current_entry().unwrap().name, "top");
+    }
+}
@@ -0,0 +1,878 @@
+use super::*;
+
+macro_rules! register_command {
+    ($commands:expr, $name:expr, $func:expr) => {
+        $commands.insert(
+            $name.to_string(),
+            Box::new(move |editor: &mut Editor| {
+                // Call the function, and wrap non-Result returning functions with Ok(())
+                $func(editor);
+                Ok(())
+            }) as Box<dyn FnMut(&mut Editor) -> io::Result<()>>
+        );
+    };
+}
+
+const FZY_SCORE_GAP_LEADING: f64 = -0.005;
+const FZY_SCORE_GAP_TRAILING: f64 = -0.005;
+const FZY_SCORE_GAP_INNER: f64 = -0.01;
+const FZY_SCORE_MATCH_CONSECUTIVE: f64 = 1.0;
+const FZY_SCORE_MATCH_SLASH: f64 = 0.9;
+const FZY_SCORE_MATCH_WORD: f64 = 0.8;
+const FZY_SCORE_MATCH_CAPITAL: f64 = 0.7;
+const FZY_SCORE_MATCH_DOT: f64 = 0.6;
+const FZY_MAX_LEN: usize = 1024;
+
+// The buffers of `fzy_score`, kept between candidates so scoring a list
+// doesn't allocate two n×m matrices per item
+#[derive(Default)]
+pub(crate) struct FzyScratch {
+    haystack: Vec<char>,
+    lower: Vec<char>,
+    bonus: Vec<f64>,
+    d: Vec<f64>, // Row major, n rows of m
+    best: Vec<f64>,
+}
+
+// The scoring of fzy (https://github.com/jhawthorn/fzy): consecutive
+// matches and matches right after a separator or on a camelCase hump are
+// worth more, gaps cost a little. `needle` must be lowercase. None when it
+// isn't a subsequence of `haystack`, otherwise the score and the positions
+// of the matched chars.
+pub(crate) fn fzy_score(needle: &[char], haystack: &str, scratch: &mut FzyScratch) -> Option<(f64, Vec<usize>)> {
+    let FzyScratch { haystack: chars, lower, bonus, d, best } = scratch;
+    chars.clear();
+    chars.extend(haystack.chars());
+    lower.clear();
+    lower.extend(chars.iter().map(|&c| c.to_lowercase().next().unwrap_or(c)));
+    let (n, m) = (needle.len(), chars.len());
+
+    // Most candidates stop at this cheap check
+    let mut rest = lower.iter();
+    if !needle.iter().all(|c| rest.any(|h| h == c)) {
+        return None;
+    }
+    if n == 0 {
+        return Some((0.0, Vec::new()));
+    }
+    if n == m {
+        return Some((f64::INFINITY, (0..m).collect()));
+    }
+    if m > FZY_MAX_LEN {
+        return Some((f64::NEG_INFINITY, Vec::new()));
+    }
+
+    let mut last = '/';
+    bonus.clear();
+    bonus.extend(chars.iter().map(|&c| {
+        let bonus = match last {
+            '/' if c.is_alphanumeric() => FZY_SCORE_MATCH_SLASH,
+            '-' | '_' | ' ' if c.is_alphanumeric() => FZY_SCORE_MATCH_WORD,
+            '.' if c.is_alphanumeric() => FZY_SCORE_MATCH_DOT,
+            l if l.is_lowercase() && c.is_uppercase() => FZY_SCORE_MATCH_CAPITAL,
+            _ => 0.0,
+        };
+        last = c;
+        bonus
+    }));
+
+    // d: best score with needle[i] matched exactly at j
+    // best: best score for needle[..=i] within haystack[..=j]
+    d.clear();
+    d.resize(n * m, f64::NEG_INFINITY);
+    best.clear();
+    best.resize(n * m, f64::NEG_INFINITY);
+    let at = |i: usize, j: usize| i * m + j;
+    for i in 0..n {
+        let gap = if i == n - 1 { FZY_SCORE_GAP_TRAILING } else { FZY_SCORE_GAP_INNER };
+        let mut previous = f64::NEG_INFINITY;
+        for j in 0..m {
+            if needle[i] == lower[j] {
+                let score = if i == 0 {
+                    j as f64 * FZY_SCORE_GAP_LEADING + bonus[j]
+                } else if j > 0 {
+                    (best[at(i - 1, j - 1)] + bonus[j]).max(d[at(i - 1, j - 1)] + FZY_SCORE_MATCH_CONSECUTIVE)
+                } else {
+                    f64::NEG_INFINITY
+                };
+                d[at(i, j)] = score;
+                previous = score.max(previous + gap);
+            } else {
+                previous += gap;
+            }
+            best[at(i, j)] = previous;
+        }
+    }
+
+    // Walk back through the matrices to find which chars made the score
+    let mut positions = vec![0; n];
+    let mut match_required = false;
+    let mut j = m;
+    for i in (0..n).rev() {
+        while j > 0 {
+            j -= 1;
+            if d[at(i, j)] != f64::NEG_INFINITY && (match_required || d[at(i, j)] == best[at(i, j)]) {
+                match_required = i > 0 && j > 0 && best[at(i, j)] == d[at(i - 1, j - 1)] + FZY_SCORE_MATCH_CONSECUTIVE;
+                positions[i] = j;
+                break;
+            }
+        }
+    }
+
+    Some((best[at(n - 1, m - 1)], positions))
+}
+
+// The candidates matching `input`, best first (shortest, then first seen
+// on ties), each with its index and matched positions
+fn fzy_rank<'a>(input: &str, candidates: impl Iterator<Item = (usize, &'a str)>) -> Vec<(usize, Vec<usize>)> {
+    let needle: Vec<char> = input.to_lowercase().chars().collect();
+    let mut scratch = FzyScratch::default();
+    let mut ranked: Vec<(usize, usize, f64, Vec<usize>)> = candidates
+        .filter_map(|(idx, candidate)| {
+            fzy_score(&needle, candidate, &mut scratch).map(|(score, positions)| (idx, candidate.len(), score, positions))
+        })
+        .collect();
+    if !needle.is_empty() {
+        ranked.sort_by(|a, b| b.2.total_cmp(&a.2).then(a.1.cmp(&b.1)).then(a.0.cmp(&b.0)));
+    }
+    ranked.into_iter().map(|(idx, _, _, positions)| (idx, positions)).collect()
+}
+
+type FzyAction = Box<dyn FnMut(&mut Editor, &str) -> io::Result<()>>;
+type FzyPreview = Box<dyn FnMut(&mut Editor, Option<&str>)>;
+
+// Where the candidates of a completion come from
+pub(crate) enum FzySource {
+    Items(Vec<String>),
+    // Streamed by a worker thread, they show up as they come
+    Async(Receiver<String>),
+}
+
+// One completing-read: a prompt, the candidates, and what to do with
+// the one picked
+pub(crate) struct Completion {
+    prompt: String,
+    candidates: Vec<String>,
+    receiver: Option<Receiver<String>>,
+    base: Option<PathBuf>, // Candidates are file names relative to it
+    preview: Option<FzyPreview>, // Called as the selection moves, with None on cancel
+    action: FzyAction,
+    browse: bool, // Find file: walk the directories under `base`, the action gets full paths
+}
+
+impl Completion {
+    pub(crate) fn new(prompt: &str, source: FzySource, action: FzyAction) -> Self {
+        let (candidates, receiver) = match source {
+            FzySource::Items(items) => (items, None),
+            FzySource::Async(receiver) => (Vec::new(), Some(receiver)),
+        };
+        Completion {
+            prompt: prompt.to_string(),
+            candidates,
+            receiver,
+            base: None,
+            preview: None,
+            action,
+            browse: false,
+        }
+    }
+
+    fn browsing(mut self) -> Self {
+        self.browse = true;
+        self
+    }
+
+    fn with_base(mut self, base: PathBuf) -> Self {
+        self.base = Some(base);
+        self
+    }
+
+    fn with_preview(mut self, preview: FzyPreview) -> Self {
+        self.preview = Some(preview);
+        self
+    }
+}
+
+// TODO Change colors on selction
+pub(crate) struct Fzy {
+    pub(crate) active: bool,
+    items: Vec<String>,
+    input: String,
+    selection_index: usize,
+    pub(crate) max_visible_lines: usize,
+    current_path: PathBuf,
+    initial_input_line_y: Option<u16>,
+    initial_items_start_y: Option<u16>,
+    initial_positioning_done: bool,
+    commands: HashMap<String, Box<dyn FnMut(&mut Editor) -> io::Result<()>>>,
+    completion: Option<Completion>,
+    pub(crate) chosen: Option<(FzyAction, String)>, // Run by the editor once Fzy is back in place
+    item_positions: Vec<Vec<usize>>, // Matched chars of each item, for the highlight
+    narrowed: Option<(String, Vec<usize>)>, // Last input and the candidates it matched
+}
+
+// TODO IMPORTANT automatcally add all the functions from rust at compile time
+// and all the functions from lua at runtime
+impl Fzy {
+    pub(crate) fn new(current_path: PathBuf) -> Self {
+        let mut commands: HashMap<String, Box<dyn FnMut(&mut Editor) -> io::Result<()>>> = HashMap::new();
+        register_command!(commands, "dired-jump",  Editor::dired_jump);
+        register_command!(commands, "eval-buffer", Editor::eval_buffer);
+        register_command!(commands, "debug-ast",   Editor::debug_print_ast);
+        register_command!(commands, "compile",     Editor::compile);
+        register_command!(commands, "recompile",            Editor::recompile);
+        register_command!(commands, "kill-compilation",     Editor::kill_compilation);
+        register_command!(commands, "next-error",           Editor::next_error);
+        register_command!(commands, "previous-error",       Editor::previous_error);
+        register_command!(commands, "lsp-hover",            Editor::lsp_hover);
+        register_command!(commands, "lsp-goto-definition",  Editor::lsp_goto_definition);
+        register_command!(commands, "lsp-find-references",  Editor::lsp_find_references);
+        register_command!(commands, "lsp-rename",           Editor::lsp_rename);
+        register_command!(commands, "lsp-code-actions",     Editor::lsp_code_actions);
+        register_command!(commands, "lsp-format-buffer",    Editor::lsp_format_buffer);
+        register_command!(commands, "lsp-restart",          Editor::lsp_restart);
+        register_command!(commands, "completion-at-point",  Editor::completion_at_point);
+        register_command!(commands, "format-buffer",        Editor::format_buffer);
+        register_command!(commands, "git-status",           Editor::git_status);
+        register_command!(commands, "wdired",               Editor::wdired);
+        register_command!(commands, "dired-undo",           Editor::dired_undo);
+        register_command!(commands, "dired-delete-permanently", Editor::dired_delete_permanently);
+        register_command!(commands, "git-blame",            Editor::git_blame);
+        register_command!(commands, "git-log-file",         Editor::git_log_file);
+        register_command!(commands, "next-hunk",            Editor::next_hunk);
+        register_command!(commands, "previous-hunk",        Editor::previous_hunk);
+        register_command!(commands, "revert-hunk",          Editor::revert_hunk);
+        register_command!(commands, "eval_buffer", Editor::eval_buffer);
+        register_command!(commands, "eval_region", Editor::eval_region);
+        register_command!(commands, "eval_line",   Editor::eval_line);
+        register_command!(commands, "replace-string",       Editor::replace_string);
+        register_command!(commands, "replace-regexp",       Editor::replace_regexp);
+        register_command!(commands, "query-replace",        Editor::query_replace);
+        register_command!(commands, "query-replace-regexp", Editor::query_replace_regexp);
+        register_command!(commands, "project-grep",         Editor::project_grep);
+        register_command!(commands, "find-file",            Editor::find_file);
+        register_command!(commands, "find-file-in-project", Editor::find_file_in_project);
+        register_command!(commands, "switch-theme",         Editor::select_theme);
+        register_command!(commands, "switch-to-buffer",     Editor::switch_to_buffer);
+        register_command!(commands, "goto-symbol",          Editor::goto_symbol);
+        Fzy {
+            active: false,
+            items: Vec::new(),
+            input: String::new(),
+            selection_index: 0,
+            max_visible_lines: 11,
+            current_path,
+            initial_input_line_y: None,
+            initial_items_start_y: None,
+            initial_positioning_done: false,
+            commands,
+            completion: None,
+            chosen: None,
+            item_positions: Vec::new(),
+            narrowed: None,
+        }
+    }
+
+    fn open(&mut self, completion: Completion) {
+        if let (true, Some(base)) = (completion.browse, &completion.base) {
+            self.current_path = base.clone();
+        }
+        self.completion = Some(completion);
+        self.narrowed = None;
+        self.active = true;
+        self.input.clear();
+        self.update_items();
+        self.recalculate_positions();
+    }
+
+    fn list_directory(path: &Path) -> Vec<String> {
+        let mut entries = vec![".".to_string(), "..".to_string()];
+        if let Ok(read_dir) = std::fs::read_dir(path) {
+            entries.extend(read_dir.filter_map(|entry| entry.ok()?.file_name().to_str().map(String::from)));
+        }
+        entries
+    }
+
+    // Take the candidates streamed since last time, true if the items changed
+    pub(crate) fn poll_source(&mut self) -> bool {
+        let completion = match &mut self.completion {
+            Some(completion) => completion,
+            None => return false,
+        };
+        let before = completion.candidates.len();
+        let mut finished = false;
+        if let Some(receiver) = &completion.receiver {
+            loop {
+                match receiver.try_recv() {
+                    Ok(candidate) => completion.candidates.push(candidate),
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        finished = true;
+                        break;
+                    },
+                }
+            }
+        }
+        if finished {
+            completion.receiver = None;
+        }
+        if completion.candidates.len() == before {
+            return false;
+        }
+
+        // New candidates invalidate the narrowing, keep the selection in place
+        let selection_index = self.selection_index;
+        self.narrowed = None;
+        self.update_items();
+        self.selection_index = selection_index.min(self.items.len().saturating_sub(1));
+        true
+    }
+
+    fn deactivate(&mut self) {
+        self.active = false;
+        self.input.clear();
+        self.items.clear();
+        self.item_positions.clear();
+        self.completion = None;
+        self.narrowed = None;
+    }
+
+    fn selected_item(&self) -> Option<String> {
+        self.items.get(self.selection_index).cloned()
+    }
+
+    fn preview(&mut self, editor: &mut Editor, item: Option<&str>) {
+        if let Some(preview) = self.completion.as_mut().and_then(|completion| completion.preview.as_mut()) {
+            preview(editor, item);
+        }
+    }
+
+    fn prompt(&self) -> String {
+        match &self.completion {
+            Some(completion) if completion.browse => {
+                let dir = completion.base.as_ref().map_or(String::new(), |base| abbreviate_home(base));
+                let separator = if dir.ends_with('/') { "" } else { "/" };
+                format!("{}{}{}", completion.prompt, dir, separator)
+            },
+            Some(completion) => completion.prompt.clone(),
+            None => String::new(),
+        }
+    }
+
+    fn browsing(&self) -> bool {
+        self.completion.as_ref().is_some_and(|completion| completion.browse)
+    }
+
+    // Show the entries of `dir` instead, selecting `focus` if given
+    fn browse_to(&mut self, dir: PathBuf, focus: Option<&str>) {
+        let dir = fs::canonicalize(&dir).unwrap_or(dir);
+        if let Some(completion) = &mut self.completion {
+            completion.candidates = Fzy::list_directory(&dir);
+            completion.base = Some(dir.clone());
+        }
+        self.current_path = dir;
+        self.input.clear();
+        self.narrowed = None;
+        self.update_items();
+        if let Some(index) = focus.and_then(|focus| self.items.iter().position(|item| item == focus)) {
+            self.selection_index = index;
+        }
+    }
+
+    fn browse_up(&mut self) {
+        let dir = self.current_path.clone();
+        if let Some(parent) = dir.parent() {
+            let focus = dir.file_name().and_then(|name| name.to_str());
+            self.browse_to(parent.to_path_buf(), focus);
+        }
+    }
+
+    // Typing / after a directory (~, .., /abs, relative) goes into it
+    fn browse_typed(&mut self) -> bool {
+        let dir = if self.input.is_empty() {
+            PathBuf::from("/")
+        } else {
+            resolve_typed_path(&self.current_path, &self.input)
+        };
+        if dir.is_dir() {
+            self.browse_to(dir, None);
+            true
+        } else {
+            false
+        }
+    }
+
+    // Tab: extend the input to what all the items have in common, or go
+    // into the directory when only one is left
+    fn complete(&mut self) {
+        let names: Vec<&String> = self.items.iter().filter(|item| *item != "." && *item != "..").collect();
+        if names.len() == 1 {
+            let path = self.current_path.join(names[0]);
+            if path.is_dir() {
+                self.browse_to(path, None);
+            } else {
+                self.input = names[0].clone();
+                self.update_items();
+            }
+            return;
+        }
+
+        let names: Vec<String> = names.into_iter().cloned().collect();
+        let common = common_prefix(&names);
+        if common.chars().count() > self.input.chars().count() && common.to_lowercase().starts_with(&self.input.to_lowercase()) {
+            self.input = common;
+            self.update_items();
+        }
+    }
+
+    // Enter while browsing: go into directories, pick files, or create
+    // the typed file when nothing matches it
+    fn browse_enter(&mut self, literal: bool) -> bool {
+        let path = match self.selected_item() {
+            Some(item) if !literal => {
+                if item == "." {
+                    self.current_path.clone()
+                } else {
+                    let path = self.current_path.join(&item);
+                    if path.is_dir() {
+                        self.browse_to(path, None);
+                        return false;
+                    }
+                    path
+                }
+            },
+            _ if !self.input.is_empty() => resolve_typed_path(&self.current_path, &self.input),
+            _ => return false,
+        };
+
+        if let Some(completion) = self.completion.take() {
+            self.chosen = Some((completion.action, path.display().to_string()));
+        }
+        self.deactivate();
+        true
+    }
+
+    fn counter(&self) -> String {
+        format!(" {:}/{:<2} ", self.selection_index + 1, self.items.len())
+    }
+
+    pub fn cursor_column(&self) -> u16 {
+        (1 + self.counter().chars().count() + self.prompt().chars().count() + self.input.chars().count()) as u16
+    }
+
+    fn insert_char(&mut self, c: char) {
+        if c == '/' && self.browsing() && self.browse_typed() {
+            return;
+        }
+        self.input.push(c);
+        self.update_items();
+    }
+
+    fn item_path(&self, item: &str) -> Option<PathBuf> {
+        self.completion.as_ref()?.base.as_ref().map(|base| base.join(item))
+    }
+
+    fn update_items(&mut self) {
+        let completion = match &self.completion {
+            Some(completion) => completion,
+            None => {
+                self.items.clear();
+                self.item_positions.clear();
+                return;
+            },
+        };
+
+        // A longer input can only match fewer candidates, so only
+        // rescore the ones the previous input matched
+        let pool: Vec<usize> = match &self.narrowed {
+            Some((input, matched)) if self.input.starts_with(input.as_str()) => matched.clone(),
+            _ => (0..completion.candidates.len()).collect(),
+        };
+        let ranked = fzy_rank(&self.input, pool.iter().map(|&idx| (idx, completion.candidates[idx].as_str())));
+        self.narrowed = Some((self.input.clone(), ranked.iter().map(|(idx, _)| *idx).collect()));
+
+        (self.items, self.item_positions) = ranked.into_iter()
+            .map(|(idx, positions)| (completion.candidates[idx].clone(), positions))
+            .unzip();
+        self.selection_index = 0; // Reset selection index on each update
+    }
+
+    pub fn calculate_minibuffer_height(&self, max_height: usize) -> usize {
+        let total_items = self.items.len();
+        let visible_items = total_items.min(self.max_visible_lines) + 1; // +1 for the input line
+        visible_items.min(max_height + 1) // Ensure it does not exceed max_height
+    }
+
+
+    // TODO prefix, path, scroll, end scroll
+    pub(crate) fn draw(&mut self, stdout: &mut Stdout, theme: &Theme, icons: Option<&Icons>) -> io::Result<()> {
+        let (width, height) = terminal::size()?;
+
+        if !self.initial_positioning_done {
+            let items_to_display = self.items.len().min(self.max_visible_lines);
+            self.initial_input_line_y = Some(height.saturating_sub(items_to_display as u16 + 1));
+            self.initial_items_start_y = Some(self.initial_input_line_y.unwrap().saturating_add(1));
+            self.initial_positioning_done = true;
+        }
+
+        let input_line_y = self.initial_input_line_y.unwrap_or(height.saturating_sub(self.max_visible_lines as u16 + 1));
+        let items_start_y = self.initial_items_start_y.unwrap_or(input_line_y.saturating_add(1));
+
+        execute!(
+            stdout,
+            MoveTo(1, input_line_y),
+            SetForegroundColor(theme.normal_cursor_color),
+            Print(self.counter()),
+            Print(self.prompt()),
+            SetForegroundColor(theme.text_color),
+            Print(&self.input)
+        )?;
+
+        // Scroll the list so the selection stays visible
+        let first_visible = (self.selection_index + 1).saturating_sub(self.max_visible_lines);
+        for (index, item) in self.items.iter().enumerate().skip(first_visible).take(self.max_visible_lines) {
+            let y_pos = items_start_y + (index - first_visible) as u16;
+            if y_pos >= height { break; }
+
+            let path = self.item_path(item);
+            let is_dir = path.as_ref().is_some_and(|path| item == "." || item == ".." || path.is_dir());
+            let formatted_item = if is_dir { format!("{}/", item) } else { item.clone() };
+
+            // Only files and directories get icons, and only with the icons on
+            let (icon, icon_color) = match icons {
+                Some(icons) if path.is_some() => icons.lookup(item, is_dir, theme.text_color),
+                _ => ("", theme.text_color),
+            };
+
+            if index == self.selection_index {
+                execute!(
+                    stdout,
+                    MoveTo(0, y_pos),
+                    SetBackgroundColor(theme.normal_cursor_color),
+                    Print(" ".repeat(width as usize)),
+                )?;
+            }
+
+            execute!(
+                stdout,
+                MoveTo(1, y_pos),
+                SetForegroundColor(icon_color),
+                Print(format!("{} ", icon)),
+            )?;
+
+            let item_color = if is_dir { theme.dired_dir_color } else { theme.text_color };
+            let match_color = if index == self.selection_index { Color::Black } else { theme.normal_cursor_color };
+            let positions = &self.item_positions[index];
+            execute!(stdout, Print(" "))?;
+            for (char_index, c) in formatted_item.chars().enumerate() {
+                if positions.contains(&char_index) {
+                    execute!(stdout, SetForegroundColor(match_color), SetAttribute(Attribute::Bold), Print(c), SetAttribute(Attribute::NormalIntensity))?;
+                } else {
+                    execute!(stdout, SetForegroundColor(item_color), Print(c))?;
+                }
+            }
+            execute!(stdout, SetBackgroundColor(theme.minibuffer_color))?;
+        }
+
+        execute!(stdout, MoveTo(0, height - 1))?;
+        Ok(())
+    }
+
+    pub(crate) fn recalculate_positions(&mut self) {
+        let items_to_display = self.items.len().min(self.max_visible_lines);
+        self.initial_input_line_y = Some(terminal::size().unwrap().1.saturating_sub(items_to_display as u16 + 1));
+        self.initial_items_start_y = Some(self.initial_input_line_y.unwrap().saturating_add(1));
+    }
+
+    fn select_next(&mut self) {
+        if self.selection_index + 1 < self.items.len() {
+            self.selection_index += 1;
+        }
+    }
+
+    fn select_previous(&mut self) {
+        self.selection_index = self.selection_index.saturating_sub(1);
+    }
+
+    fn cancel(&mut self, editor: &mut Editor) {
+        self.preview(editor, None);
+        self.deactivate();
+    }
+
+    pub fn handle_input(&mut self, key: KeyEvent, editor: &mut Editor) -> bool {
+        let mut state_changed = false;
+        let selected_before = self.selected_item();
+
+        match key {
+            KeyEvent {
+                code: KeyCode::Char('n') | KeyCode::Char('j'),
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } => self.select_next(),
+            KeyEvent {
+                code: KeyCode::Char('p') | KeyCode::Char('k'),
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } => self.select_previous(),
+            KeyEvent {
+                code: KeyCode::Char('g'),
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } => {
+                self.cancel(editor);
+                state_changed = true; // Indicate that the fuzzy finder was deactivated
+            },
+            KeyEvent {
+                code: KeyCode::Char(c),
+                modifiers: KeyModifiers::SHIFT,
+                ..
+            } => self.insert_char(c),
+            // M-RET takes the input as typed, to create a file whose name
+            // fuzzy matches existing ones
+            KeyEvent {
+                code: KeyCode::Enter,
+                modifiers: KeyModifiers::ALT,
+                ..
+            } => {
+                if self.browsing() {
+                    state_changed = self.browse_enter(true);
+                }
+            },
+            KeyEvent {
+                code,
+                modifiers: KeyModifiers::NONE,
+                ..
+            } => match code {
+                KeyCode::Char(c) => self.insert_char(c),
+                KeyCode::Backspace => {
+                    if self.input.is_empty() && self.browsing() {
+                        self.browse_up();
+                    } else {
+                        self.input.pop();
+                        self.update_items();
+                    }
+                },
+                KeyCode::Tab => {
+                    if self.browsing() {
+                        self.complete();
+                    }
+                },
+                KeyCode::Up => self.select_previous(),
+                KeyCode::Down => self.select_next(),
+                KeyCode::Esc => {
+                    self.cancel(editor);
+                    state_changed = true; // Indicate that the fuzzy finder was deactivated
+                },
+                KeyCode::Enter if self.browsing() => {
+                    state_changed = self.browse_enter(false);
+                },
+                KeyCode::Enter => {
+                    if let Some(item) = self.selected_item() {
+                        if let Some(completion) = self.completion.take() {
+                            self.chosen = Some((completion.action, item));
+                        }
+                        self.deactivate();
+                        state_changed = true;
+                    }
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+
+        if self.active {
+            let selected = self.selected_item();
+            if selected != selected_before {
+                if let Some(item) = selected {
+                    self.preview(editor, Some(&item));
+                }
+            }
+        }
+
+        state_changed // Return whether the fuzzy finder's state has changed
+    }
+}
+
+impl Editor {
+    pub(crate) fn completing_read(&mut self, completion: Completion) {
+        if let Some(fzy) = &mut self.fzy {
+            fzy.open(completion);
+            self.minibuffer_height = fzy.calculate_minibuffer_height(fzy.max_visible_lines) as u16;
+        }
+    }
+
+    // Browse from the directory of the current buffer, Fzy descends into
+    // directories itself and hands the full path to the action
+    pub fn find_file(&mut self) {
+        let start = match &self.dired {
+            Some(dired) if self.mode == Mode::Dired => dired.current_path.clone(),
+            _ if self.current_file_path.is_dir() => self.current_file_path.clone(),
+            _ => self.current_file_path.parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_else(|| env::current_dir().unwrap_or_default()),
+        };
+        let items = Fzy::list_directory(&start);
+        self.completing_read(
+            Completion::new("Find file: ", FzySource::Items(items), Box::new(|editor: &mut Editor, path: &str| {
+                let path = PathBuf::from(path);
+                let is_new = !path.exists();
+                editor.open(&path, None)?;
+                if is_new {
+                    editor.message("(New file)");
+                }
+                Ok(())
+            }))
+            .with_base(start)
+            .browsing()
+        );
+    }
+
+    pub fn find_file_in_project(&mut self) {
+        let root = project_root(&self.current_file_path);
+
+        // The index streams in, big projects are usable right away
+        let (sender, receiver) = mpsc::channel();
+        let walk_root = root.clone();
+        std::thread::spawn(move || {
+            walk_project(&walk_root, &mut |path| {
+                let relative = path.strip_prefix(&walk_root).unwrap_or(path);
+                sender.send(relative.to_string_lossy().to_string()).is_ok()
+            });
+        });
+
+        let open_root = root.clone();
+        self.completing_read(
+            Completion::new("Find file in project: ", FzySource::Async(receiver), Box::new(move |editor: &mut Editor, item: &str| {
+                editor.open(&open_root.join(item), None)
+            }))
+            .with_base(root)
+        );
+    }
+
+    pub fn execute_extended_command(&mut self) {
+        let mut names: Vec<String> = match &self.fzy {
+            Some(fzy) => fzy.commands.keys().cloned().collect(),
+            None => return,
+        };
+        names.sort();
+        self.completing_read(Completion::new("M-x ", FzySource::Items(names), Box::new(|editor: &mut Editor, name: &str| {
+            editor.run_command(name);
+            Ok(())
+        })));
+    }
+
+    // Themes are previewed while moving through them, C-g puts the old one back
+    pub fn select_theme(&mut self) {
+        let mut names: Vec<String> = self.config.themes.keys().cloned().collect();
+        names.sort();
+        let original = self.config.current_theme_name.clone();
+        self.completing_read(
+            Completion::new("Switch theme: ", FzySource::Items(names), Box::new(|editor: &mut Editor, name: &str| {
+                editor.switch_theme(name);
+                Ok(())
+            }))
+            .with_preview(Box::new(move |editor: &mut Editor, name: Option<&str>| {
+                editor.switch_theme(name.unwrap_or(&original));
+            }))
+        );
+    }
+
+    pub fn switch_to_buffer(&mut self) {
+        let items: Vec<String> = self.buffer_stack.iter().map(|path| path.display().to_string()).collect();
+        self.completing_read(Completion::new("Switch to buffer: ", FzySource::Items(items), Box::new(|editor: &mut Editor, item: &str| {
+            editor.open(&PathBuf::from(item), None)
+        })));
+    }
+
+    pub fn goto_symbol(&mut self) {
+        self.syntax_highlighter.parse(&self.buffer);
+        let symbols = self.syntax_highlighter.symbols(&self.buffer);
+        let items: Vec<String> = symbols.iter().map(|(label, _)| label.clone()).collect();
+        let positions: HashMap<String, (u16, u16)> = symbols.into_iter().collect();
+        let preview_positions = positions.clone();
+        let origin = self.cursor_pos;
+
+        self.completing_read(
+            Completion::new("Goto symbol: ", FzySource::Items(items), Box::new(move |editor: &mut Editor, item: &str| {
+                if let Some(&position) = positions.get(item) {
+                    editor.cursor_pos = position;
+                    editor.request_recenter();
+                }
+                Ok(())
+            }))
+            .with_preview(Box::new(move |editor: &mut Editor, item: Option<&str>| {
+                editor.cursor_pos = item.and_then(|item| preview_positions.get(item).copied()).unwrap_or(origin);
+                editor.request_recenter();
+            }))
+        );
+    }
+
+    pub(crate) fn lua_completing_read(&mut self, request: LuaCompletingRead) {
+        let on_select = request.on_select;
+        self.completing_read(Completion::new(&request.prompt, FzySource::Items(request.items), Box::new(move |editor: &mut Editor, item: &str| {
+            let callback: mlua::Function = editor.lua.registry_value(&on_select)
+                .map_err(|e| io::Error::other(e.to_string()))?;
+            callback.call::<_, ()>(item.to_string())
+                .map_err(|e| io::Error::other(e.to_string()))
+        })));
+    }
+
+    // Run an M-x command, the command is lent out of Fzy for the call
+    fn run_command(&mut self, name: &str) {
+        let command = self.fzy.as_mut().and_then(|fzy| fzy.commands.remove(name));
+        if let Some(mut command) = command {
+            if let Err(e) = command(self) {
+                self.error(&format!("{}: {}", name, e));
+            }
+            if let Some(fzy) = &mut self.fzy {
+                fzy.commands.insert(name.to_string(), command);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn score(needle: &str, haystack: &str) -> Option<(f64, Vec<usize>)> {
+        let needle: Vec<char> = needle.chars().collect();
+        fzy_score(&needle, haystack, &mut FzyScratch::default())
+    }
+
+    #[test]
+    fn score_needs_a_subsequence() {
+        assert!(score("abc", "a_b_c").is_some());
+        assert!(score("acb", "a_b_c").is_none());
+        assert_eq!(score("", "anything"), Some((0.0, Vec::new())));
+        assert_eq!(score("abc", "ABC"), Some((f64::INFINITY, vec![0, 1, 2])));
+    }
+
+    #[test]
+    fn score_prefers_word_starts_and_runs() {
+        assert_eq!(score("fb", "src/foo_bar.rs").unwrap().1, vec![4, 8]);
+        assert_eq!(score("main", "src/domain/main.rs").unwrap().1, vec![11, 12, 13, 14]);
+        assert!(score("fb", "foo_bar").unwrap().0 > score("fb", "afoobar").unwrap().0);
+        assert!(score("gs", "GitStatus").unwrap().0 > score("gs", "grass").unwrap().0);
+    }
+
+    #[test]
+    fn scratch_is_reused_across_candidates() {
+        let needle: Vec<char> = "ab".chars().collect();
+        let mut scratch = FzyScratch::default();
+        let long = fzy_score(&needle, "a-very-long-candidate/b", &mut scratch);
+        let short = fzy_score(&needle, "xab", &mut scratch);
+        assert_eq!(short, score("ab", "xab"));
+        assert_eq!(long, score("ab", "a-very-long-candidate/b"));
+    }
+
+    #[test]
+    fn rank_orders_by_score_then_length() {
+        let candidates = ["zzfoo", "foo", "src/foo.rs", "bar"];
+        let ranked = fzy_rank("foo", candidates.iter().copied().enumerate());
+        let order: Vec<usize> = ranked.iter().map(|(idx, _)| *idx).collect();
+        assert_eq!(order, vec![1, 2, 0]);
+        let unranked: Vec<usize> = fzy_rank("", candidates.iter().copied().enumerate()).iter().map(|(idx, _)| *idx).collect();
+        assert_eq!(unranked, vec![0, 1, 2, 3]);
+    }
+}
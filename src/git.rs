@@ -0,0 +1,1627 @@
+use super::*;
+
+const DIFF_MAX_EDITS: isize = 1000;
+
+// Pairs of equal lines that old and new keep in the same order, from a
+// Myers diff of what is between their common ends. Files too different
+// for that only get the common ends
+pub(crate) fn matching_lines(old: &[Vec<char>], new: &[Vec<char>]) -> Vec<(usize, usize)> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+    let (a, b) = (&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix]);
+
+    let mut pairs: Vec<(usize, usize)> = (0..prefix).map(|i| (i, i)).collect();
+    pairs.extend(myers_diff(a, b).into_iter().map(|(i, j)| (prefix + i, prefix + j)));
+    pairs.extend((0..suffix).map(|k| (old.len() - suffix + k, new.len() - suffix + k)));
+    pairs
+}
+
+fn myers_diff(a: &[Vec<char>], b: &[Vec<char>]) -> Vec<(usize, usize)> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    if n == 0 || m == 0 {
+        return Vec::new();
+    }
+    let max = n + m;
+    let mut v = vec![0isize; 2 * max as usize + 2];
+    let at = |k: isize| (k + max) as usize;
+
+    // The furthest x of each diagonal k before every round, for the way back
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+    let mut edits = None;
+    'rounds: for d in 0..=max.min(DIFF_MAX_EDITS) {
+        trace.push(v[at(-d)..=at(d)].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && v[at(k - 1)] < v[at(k + 1)]) { v[at(k + 1)] } else { v[at(k - 1)] + 1 };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[at(k)] = x;
+            if x >= n && y >= m {
+                edits = Some(d);
+                break 'rounds;
+            }
+        }
+    }
+    let Some(edits) = edits else { return Vec::new() };
+
+    let mut pairs = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for d in (1..=edits).rev() {
+        let before = &trace[d as usize];
+        let get = |k: isize| before[(k + d) as usize];
+        let k = x - y;
+        let previous_k = if k == -d || (k != d && get(k - 1) < get(k + 1)) { k + 1 } else { k - 1 };
+        let previous_x = get(previous_k);
+        let previous_y = previous_x - previous_k;
+        while x > previous_x && y > previous_y {
+            x -= 1;
+            y -= 1;
+            pairs.push((x as usize, y as usize));
+        }
+        x = previous_x;
+        y = previous_y;
+    }
+    while x > 0 && y > 0 {
+        x -= 1;
+        y -= 1;
+        pairs.push((x as usize, y as usize));
+    }
+    pairs.reverse();
+    pairs
+}
+
+// The column of `new` with as much text before it as `column` has in `old`,
+// for a line that only had its spacing changed
+pub(crate) fn same_text_column(old: &[char], column: usize, new: &[char]) -> usize {
+    let wanted = old[..column.min(old.len())].iter().filter(|c| !c.is_whitespace()).count();
+    // On a char, not on the blanks before it
+    let on_text = old.get(column).is_some_and(|c| !c.is_whitespace());
+    let mut seen = 0;
+    for (i, c) in new.iter().enumerate() {
+        if seen == wanted && !(on_text && c.is_whitespace()) {
+            return i;
+        }
+        if !c.is_whitespace() {
+            seen += 1;
+        }
+    }
+    new.len()
+}
+
+// Run git in `root`: its output, or the first thing it complained about
+fn git_output(root: &Path, args: &[&str], input: Option<&str>) -> std::result::Result<String, String> {
+    let mut child = Command::new("git")
+        .arg("-C")
+        .arg(root)
+        .args(args)
+        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Couldn't run git: {}", e))?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        stdin.write_all(input.as_bytes()).map_err(|e| e.to_string())?;
+    }
+    let output = child.wait_with_output().map_err(|e| e.to_string())?;
+    if output.status.success() {
+        return Ok(String::from_utf8_lossy(&output.stdout).into_owned());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    Err(stderr.lines().find(|line| !line.trim().is_empty()).map_or_else(
+        || format!("git {} failed", args.first().unwrap_or(&"")),
+        |line| line.trim().trim_start_matches("fatal: ").trim_start_matches("error: ").to_string(),
+    ))
+}
+
+// Where git keeps the repository of `start`, None outside of one
+fn git_root(start: &Path) -> Option<PathBuf> {
+    let dir = if start.is_dir() { start } else { start.parent().filter(|dir| dir.is_dir())? };
+    git_output(dir, &["rev-parse", "--show-toplevel"], None).ok().map(|root| PathBuf::from(root.trim()))
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum GutterMark {
+    Added,
+    Modified,
+    DeletedAbove,
+    DeletedBelow, // Lines removed at the end of the file
+}
+
+impl GutterMark {
+    pub(crate) fn symbol(&self) -> &'static str {
+        match self {
+            GutterMark::Added | GutterMark::Modified => "▎",
+            GutterMark::DeletedAbove => "▔",
+            GutterMark::DeletedBelow => "▁",
+        }
+    }
+
+    pub(crate) fn color(&self, theme: &Theme) -> Color {
+        match self {
+            GutterMark::Added => theme.ok_color,
+            GutterMark::Modified => theme.warning_color,
+            GutterMark::DeletedAbove | GutterMark::DeletedBelow => theme.error_color,
+        }
+    }
+}
+
+// Lines `old` of HEAD became lines `new` of the buffer, end exclusive
+struct GutterHunk {
+    old: (usize, usize),
+    new: (usize, usize),
+}
+
+// The open file against its HEAD version, for the fringe and the modeline
+pub(crate) struct GitGutter {
+    pub(crate) branch: String,
+    head: Vec<Vec<char>>,
+    diffed: Vec<Vec<char>>, // The buffer the hunks are about
+    hunks: Vec<GutterHunk>,
+}
+
+impl GitGutter {
+    // None when the file isn't in HEAD, nothing to compare with
+    fn load(path: &Path) -> Option<Self> {
+        let path = fs::canonicalize(path).ok()?;
+        let root = git_root(&path)?;
+        let root = fs::canonicalize(&root).unwrap_or(root);
+        let relative = path.strip_prefix(&root).ok()?.to_string_lossy().into_owned();
+        let head = git_output(&root, &["show", &format!("HEAD:{}", relative)], None).ok()?;
+        let branch = git_output(&root, &["rev-parse", "--abbrev-ref", "HEAD"], None).map_or(String::new(), |branch| branch.trim().to_string());
+        Some(GitGutter {
+            branch,
+            head: head.lines().map(|line| line.chars().collect()).collect(),
+            diffed: Vec::new(),
+            hunks: Vec::new(),
+        })
+    }
+
+    fn update(&mut self, buffer: &[Vec<char>]) {
+        self.hunks.clear();
+        let (mut old, mut new) = (0, 0);
+        let pairs = matching_lines(&self.head, buffer);
+        for &(i, j) in pairs.iter().chain(std::iter::once(&(self.head.len(), buffer.len()))) {
+            if i > old || j > new {
+                self.hunks.push(GutterHunk { old: (old, i), new: (new, j) });
+            }
+            old = i + 1;
+            new = j + 1;
+        }
+        self.diffed = buffer.to_vec();
+    }
+
+    pub(crate) fn mark(&self, line: usize) -> Option<GutterMark> {
+        let last = self.diffed.len().saturating_sub(1);
+        self.hunks.iter().find_map(|hunk| {
+            let (start, end) = hunk.new;
+            if start == end {
+                return if start < self.diffed.len() {
+                    (line == start).then_some(GutterMark::DeletedAbove)
+                } else {
+                    (line == last).then_some(GutterMark::DeletedBelow)
+                };
+            }
+            if !(start..end).contains(&line) {
+                return None;
+            }
+            let replaced = hunk.old.1 - hunk.old.0;
+            Some(if line - start < replaced { GutterMark::Modified } else { GutterMark::Added })
+        })
+    }
+
+    // Hunk under the line, a deletion counts on the line it is marked on
+    fn hunk_at(&self, line: usize) -> Option<&GutterHunk> {
+        let last = self.diffed.len().saturating_sub(1);
+        self.hunks.iter().find(|hunk| {
+            let (start, end) = hunk.new;
+            (start..end).contains(&line) || (start == end && start.min(last) == line)
+        })
+    }
+
+    pub(crate) fn counts(&self) -> (usize, usize) {
+        self.hunks.iter().fold((0, 0), |(added, removed), hunk| {
+            (added + hunk.new.1 - hunk.new.0, removed + hunk.old.1 - hunk.old.0)
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum GitSection {
+    Untracked,
+    Unstaged,
+    Staged,
+}
+
+impl GitSection {
+    fn title(&self) -> &'static str {
+        match self {
+            GitSection::Untracked => "Untracked files",
+            GitSection::Unstaged => "Unstaged changes",
+            GitSection::Staged => "Staged changes",
+        }
+    }
+}
+
+struct GitHunk {
+    header: String, // The @@ line
+    lines: Vec<String>,
+}
+
+impl GitHunk {
+    // Line of the new file where the hunk starts, 1 based
+    fn new_start(&self) -> usize {
+        self.header.split(' ').find_map(|part| part.strip_prefix('+'))
+            .and_then(|range| range.split(',').next()?.parse().ok())
+            .unwrap_or(1)
+    }
+}
+
+struct GitFile {
+    section: GitSection,
+    path: String, // Relative to the root, like git prints it
+    status: char, // The porcelain letter: M, A, D, R, ...
+    diff_header: Vec<String>, // Up to the first hunk, what a patch needs
+    hunks: Vec<GitHunk>,
+    expanded: bool,
+}
+
+impl GitFile {
+    fn kind(&self) -> &'static str {
+        match self.status {
+            '?' => "untracked",
+            'A' => "new file",
+            'D' => "deleted",
+            'R' => "renamed",
+            'C' => "copied",
+            'T' => "typechange",
+            'U' => "unmerged",
+            _ => "modified",
+        }
+    }
+
+    // A patch of just one of the hunks, for git apply
+    fn patch(&self, hunk: usize) -> String {
+        let hunk = &self.hunks[hunk];
+        let mut patch = self.diff_header.join("\n");
+        patch.push('\n');
+        patch.push_str(&hunk.header);
+        patch.push('\n');
+        for line in &hunk.lines {
+            patch.push_str(line);
+            patch.push('\n');
+        }
+        patch
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum GitRow {
+    Section(GitSection),
+    File(usize),
+    Hunk(usize, usize),
+    Line(usize, usize, usize),
+    Blank,
+}
+
+// Split `git diff` output by file: the header lines and the hunks, by path
+fn parse_git_diff(diff: &str) -> HashMap<String, (Vec<String>, Vec<GitHunk>)> {
+    let mut files: HashMap<String, (Vec<String>, Vec<GitHunk>)> = HashMap::new();
+    let mut current: Option<(String, Vec<String>, Vec<GitHunk>)> = None;
+    let mut finish = |current: Option<(String, Vec<String>, Vec<GitHunk>)>| {
+        if let Some((path, header, hunks)) = current {
+            files.insert(path, (header, hunks));
+        }
+    };
+    for line in diff.lines() {
+        if line.starts_with("diff --git ") {
+            finish(current.take());
+            // The b/ side, unless the +++ line says better below
+            let path = line.rsplit(" b/").next().unwrap_or("").to_string();
+            current = Some((path, vec![line.to_string()], Vec::new()));
+            continue;
+        }
+        let Some((path, header, hunks)) = &mut current else { continue };
+        if line.starts_with("@@") {
+            hunks.push(GitHunk { header: line.to_string(), lines: Vec::new() });
+        } else if let Some(hunk) = hunks.last_mut() {
+            hunk.lines.push(line.to_string());
+        } else {
+            if let Some(new_path) = line.strip_prefix("+++ b/") {
+                *path = new_path.to_string();
+            }
+            header.push(line.to_string());
+        }
+    }
+    finish(current);
+    files
+}
+
+// The magit-like status buffer of a repository
+pub(crate) struct GitStatus {
+    root: PathBuf,
+    branch: String,
+    files: Vec<GitFile>,
+    rows: Vec<GitRow>,
+    pub(crate) selected: usize,
+    pub(crate) offset: usize,
+}
+
+impl GitStatus {
+    fn load(root: PathBuf) -> std::result::Result<Self, String> {
+        let status = git_output(&root, &["status", "--porcelain=v1", "-b", "-z"], None)?;
+        let diff_args = ["--no-color", "--no-ext-diff", "--src-prefix=a/", "--dst-prefix=b/"];
+        let mut unstaged = parse_git_diff(&git_output(&root, &[&["diff"][..], &diff_args].concat(), None)?);
+        let mut staged = parse_git_diff(&git_output(&root, &[&["diff", "--cached"][..], &diff_args].concat(), None)?);
+
+        let mut branch = String::new();
+        let mut files = Vec::new();
+        let mut records = status.split('\0').filter(|record| !record.is_empty());
+        while let Some(record) = records.next() {
+            if let Some(head) = record.strip_prefix("## ") {
+                branch = head.to_string();
+                continue;
+            }
+            let mut letters = record.chars();
+            let (x, y) = (letters.next().unwrap_or(' '), letters.next().unwrap_or(' '));
+            let path = record.get(3..).unwrap_or("").to_string();
+            if x == 'R' || x == 'C' {
+                records.next(); // Where it came from
+            }
+            let mut add = |section: GitSection, status: char, diffs: &mut HashMap<String, (Vec<String>, Vec<GitHunk>)>| {
+                let (diff_header, hunks) = diffs.remove(&path).unwrap_or_default();
+                files.push(GitFile { section, path: path.clone(), status, diff_header, hunks, expanded: false });
+            };
+            match (x, y) {
+                ('?', '?') => add(GitSection::Untracked, '?', &mut HashMap::new()),
+                ('!', '!') => {},
+                _ => {
+                    if x != ' ' {
+                        add(GitSection::Staged, x, &mut staged);
+                    }
+                    if y != ' ' {
+                        add(GitSection::Unstaged, y, &mut unstaged);
+                    }
+                },
+            }
+        }
+
+        let mut status = GitStatus { root, branch, files, rows: Vec::new(), selected: 0, offset: 0 };
+        status.build_rows();
+        Ok(status)
+    }
+
+    // Load again, keeping what was expanded and the selection where it was
+    fn reload(&mut self) -> std::result::Result<(), String> {
+        let mut fresh = GitStatus::load(self.root.clone())?;
+        let expanded: HashSet<(GitSection, &str)> = self.files.iter()
+            .filter(|file| file.expanded)
+            .map(|file| (file.section, file.path.as_str()))
+            .collect();
+        for file in &mut fresh.files {
+            file.expanded = expanded.contains(&(file.section, file.path.as_str()));
+        }
+        fresh.build_rows();
+        fresh.selected = self.selected.min(fresh.rows.len().saturating_sub(1));
+        fresh.offset = self.offset;
+        *self = fresh;
+        if self.rows.get(self.selected) == Some(&GitRow::Blank) {
+            self.step(-1);
+        }
+        Ok(())
+    }
+
+    fn build_rows(&mut self) {
+        self.rows.clear();
+        for section in [GitSection::Untracked, GitSection::Unstaged, GitSection::Staged] {
+            let files: Vec<usize> = (0..self.files.len()).filter(|&i| self.files[i].section == section).collect();
+            if files.is_empty() {
+                continue;
+            }
+            if !self.rows.is_empty() {
+                self.rows.push(GitRow::Blank);
+            }
+            self.rows.push(GitRow::Section(section));
+            for file in files {
+                self.rows.push(GitRow::File(file));
+                if !self.files[file].expanded {
+                    continue;
+                }
+                for (h, hunk) in self.files[file].hunks.iter().enumerate() {
+                    self.rows.push(GitRow::Hunk(file, h));
+                    self.rows.extend((0..hunk.lines.len()).map(|l| GitRow::Line(file, h, l)));
+                }
+            }
+        }
+    }
+
+    fn current(&self) -> GitRow {
+        self.rows.get(self.selected).copied().unwrap_or(GitRow::Blank)
+    }
+
+    fn current_file(&self) -> Option<usize> {
+        match self.current() {
+            GitRow::File(file) | GitRow::Hunk(file, _) | GitRow::Line(file, _, _) => Some(file),
+            _ => None,
+        }
+    }
+
+    fn current_hunk(&self) -> Option<(usize, usize)> {
+        match self.current() {
+            GitRow::Hunk(file, hunk) | GitRow::Line(file, hunk, _) => Some((file, hunk)),
+            _ => None,
+        }
+    }
+
+    // Move over the rows, blank ones don't count
+    fn step(&mut self, delta: isize) {
+        let mut row = self.selected as isize;
+        loop {
+            row += delta;
+            if row < 0 || row >= self.rows.len() as isize {
+                return;
+            }
+            if self.rows[row as usize] != GitRow::Blank {
+                self.selected = row as usize;
+                return;
+            }
+        }
+    }
+
+    // To the next (or previous) section or file, over the diffs
+    fn jump(&mut self, forward: bool) {
+        let is_stop = |row: &GitRow| matches!(row, GitRow::Section(_) | GitRow::File(_));
+        let found = if forward {
+            self.rows.iter().skip(self.selected + 1).position(is_stop).map(|n| self.selected + 1 + n)
+        } else {
+            self.rows[..self.selected].iter().rposition(is_stop)
+        };
+        if let Some(row) = found {
+            self.selected = row;
+        }
+    }
+
+    fn toggle(&mut self) {
+        let Some(file) = self.current_file() else { return };
+        if self.files[file].hunks.is_empty() {
+            return;
+        }
+        self.files[file].expanded = !self.files[file].expanded;
+        self.build_rows();
+        self.selected = self.rows.iter().position(|row| *row == GitRow::File(file)).unwrap_or(0);
+    }
+
+    pub(crate) fn draw(&mut self, stdout: &mut Stdout, height: u16, theme: &Theme) -> io::Result<()> {
+        let (width, _) = terminal::size()?;
+        let header = format!("Head: {} in {}", self.branch, self.root.display());
+        execute!(
+            stdout,
+            MoveTo(3, 0),
+            SetForegroundColor(theme.dired_path_color),
+            SetBackgroundColor(theme.background_color),
+            Print(header),
+            ResetColor
+        )?;
+        if self.rows.is_empty() {
+            execute!(stdout, MoveTo(3, 2), SetForegroundColor(theme.comment_color), Print("Nothing to commit, working tree clean"), ResetColor)?;
+            return Ok(());
+        }
+
+        let visible = height.saturating_sub(2) as usize;
+        if self.selected < self.offset {
+            self.offset = self.selected;
+        } else if visible > 0 && self.selected >= self.offset + visible {
+            self.offset = self.selected + 1 - visible;
+        }
+
+        let room = (width as usize).saturating_sub(3);
+        for (row, kind) in self.rows.iter().enumerate().skip(self.offset).take(visible) {
+            let y = (row - self.offset) as u16 + 2;
+            let background = if row == self.selected { theme.hl_line_color } else { theme.background_color };
+            execute!(stdout, MoveTo(0, y), SetBackgroundColor(background), Print(" ".repeat(width as usize)), MoveTo(3, y))?;
+            let clip = |text: &str| text.chars().take(room).collect::<String>();
+            match *kind {
+                GitRow::Section(section) => {
+                    let count = self.files.iter().filter(|file| file.section == section).count();
+                    execute!(
+                        stdout,
+                        SetForegroundColor(theme.normal_cursor_color),
+                        SetAttribute(Attribute::Bold),
+                        Print(clip(&format!("{} ({})", section.title(), count))),
+                        SetAttribute(Attribute::NormalIntensity)
+                    )?;
+                },
+                GitRow::File(file) => {
+                    let file = &self.files[file];
+                    let fold = if file.hunks.is_empty() { " " } else if file.expanded { "▾" } else { "▸" };
+                    execute!(
+                        stdout,
+                        SetForegroundColor(theme.comment_color),
+                        Print(format!("{} {:<11}", fold, file.kind())),
+                        SetForegroundColor(theme.dired_dir_color),
+                        Print(clip(&file.path)),
+                    )?;
+                },
+                GitRow::Hunk(file, hunk) => {
+                    execute!(stdout, SetForegroundColor(theme.dired_size_color), Print(clip(&format!("  {}", self.files[file].hunks[hunk].header))))?;
+                },
+                GitRow::Line(file, hunk, line) => {
+                    let text = &self.files[file].hunks[hunk].lines[line];
+                    let color = match text.chars().next() {
+                        Some('+') => theme.ok_color,
+                        Some('-') => theme.error_color,
+                        _ => theme.text_color,
+                    };
+                    execute!(stdout, SetForegroundColor(color), Print(clip(&format!("  {}", text))))?;
+                },
+                GitRow::Blank => {},
+            }
+        }
+        execute!(stdout, ResetColor)?;
+
+        Ok(())
+    }
+}
+
+// Between `old` at 0 and `new` at 1, for colors that tell an age
+fn blend_colors(old: Color, new: Color, t: f32) -> Color {
+    match (old, new) {
+        (Color::Rgb { r: r1, g: g1, b: b1 }, Color::Rgb { r: r2, g: g2, b: b2 }) => {
+            let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t.clamp(0.0, 1.0)).round() as u8;
+            Color::Rgb { r: mix(r1, r2), g: mix(g1, g2), b: mix(b1, b2) }
+        },
+        _ => if t < 0.5 { old } else { new },
+    }
+}
+
+fn format_date(timestamp: i64) -> String {
+    DateTime::from_timestamp(timestamp, 0).map_or(String::new(), |time| time.with_timezone(&Local).format("%Y-%m-%d").to_string())
+}
+
+// Read-only git output: a commit, or a file as it was
+struct GitPage {
+    title: String,
+    lines: Vec<String>,
+    diff: bool, // Color it like a patch, otherwise number the lines
+    selected: usize,
+    offset: usize,
+}
+
+impl GitPage {
+    fn new(title: String, text: &str, diff: bool) -> Self {
+        GitPage { title, lines: text.lines().map(String::from).collect(), diff, selected: 0, offset: 0 }
+    }
+
+    fn scroll(&mut self, delta: isize) {
+        let last = self.lines.len().saturating_sub(1) as isize;
+        self.selected = (self.selected as isize + delta).clamp(0, last) as usize;
+    }
+
+    // False when the key closes the page
+    fn handle_key(&mut self, code: KeyCode, height: u16) -> bool {
+        let page = height.saturating_sub(2).max(1) as isize;
+        match code {
+            KeyCode::Char('q') => return false,
+            KeyCode::Char('j') | KeyCode::Down => self.scroll(1),
+            KeyCode::Char('k') | KeyCode::Up => self.scroll(-1),
+            KeyCode::Char(' ') | KeyCode::PageDown => self.scroll(page),
+            KeyCode::Backspace | KeyCode::PageUp => self.scroll(-page),
+            KeyCode::Char('<') => self.selected = 0,
+            KeyCode::Char('G') | KeyCode::Char('>') => self.selected = self.lines.len().saturating_sub(1),
+            _ => {},
+        }
+        true
+    }
+
+    fn draw(&mut self, stdout: &mut Stdout, height: u16, theme: &Theme) -> io::Result<()> {
+        let (width, _) = terminal::size()?;
+        execute!(
+            stdout,
+            MoveTo(3, 0),
+            SetForegroundColor(theme.dired_path_color),
+            SetBackgroundColor(theme.background_color),
+            Print(&self.title),
+            ResetColor
+        )?;
+
+        let visible = height.saturating_sub(2) as usize;
+        if self.selected < self.offset {
+            self.offset = self.selected;
+        } else if visible > 0 && self.selected >= self.offset + visible {
+            self.offset = self.selected + 1 - visible;
+        }
+
+        let number_width = self.lines.len().to_string().len();
+        for (row, line) in self.lines.iter().enumerate().skip(self.offset).take(visible) {
+            let y = (row - self.offset) as u16 + 2;
+            let background = if row == self.selected { theme.hl_line_color } else { theme.background_color };
+            execute!(stdout, MoveTo(0, y), SetBackgroundColor(background), Print(" ".repeat(width as usize)), MoveTo(3, y))?;
+            let mut room = (width as usize).saturating_sub(3);
+            let color = if self.diff {
+                match line.chars().next() {
+                    _ if line.starts_with("@@") => theme.dired_size_color,
+                    _ if line.starts_with("commit ") || line.starts_with("diff --git") => theme.dired_path_color,
+                    _ if line.starts_with("+++") || line.starts_with("---") => theme.text_color,
+                    Some('+') => theme.ok_color,
+                    Some('-') => theme.error_color,
+                    _ => theme.text_color,
+                }
+            } else {
+                execute!(stdout, SetForegroundColor(theme.line_numbers_color), Print(format!("{:>width$} ", row + 1, width = number_width)))?;
+                room = room.saturating_sub(number_width + 1);
+                theme.text_color
+            };
+            execute!(stdout, SetForegroundColor(color), Print(line.chars().take(room).collect::<String>()))?;
+        }
+        execute!(stdout, ResetColor)?;
+
+        Ok(())
+    }
+}
+
+struct BlameCommit {
+    short: String,
+    author: String,
+    time: i64,
+    summary: String,
+}
+
+// `git blame --porcelain` output: the commits by hash, and the commit hash
+// and text of every line
+fn parse_git_blame(output: &str) -> (HashMap<String, BlameCommit>, Vec<(String, String)>) {
+    let mut commits: HashMap<String, BlameCommit> = HashMap::new();
+    let mut lines = Vec::new();
+    let mut current = String::new();
+    for line in output.lines() {
+        if let Some(text) = line.strip_prefix('\t') {
+            lines.push((current.clone(), text.to_string()));
+            continue;
+        }
+        let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+        if key.len() == 40 && key.chars().all(|c| c.is_ascii_hexdigit()) {
+            current = key.to_string();
+            commits.entry(current.clone()).or_insert_with(|| BlameCommit {
+                short: key[..8].to_string(),
+                author: String::new(),
+                time: 0,
+                summary: String::new(),
+            });
+            continue;
+        }
+        let Some(commit) = commits.get_mut(&current) else { continue };
+        match key {
+            "author" => commit.author = value.to_string(),
+            "author-time" => commit.time = value.parse().unwrap_or(0),
+            "summary" => commit.summary = value.to_string(),
+            _ => {},
+        }
+    }
+    (commits, lines)
+}
+
+// `git blame` of the buffer, every line with the commit that last touched it
+pub(crate) struct GitBlame {
+    root: PathBuf,
+    path: String, // Relative to the root
+    commits: HashMap<String, BlameCommit>,
+    lines: Vec<(String, String)>, // Commit hash and text
+    oldest: i64,
+    newest: i64,
+    selected: usize,
+    offset: usize,
+    page: Option<GitPage>, // The commit of a line, over the blame
+}
+
+impl GitBlame {
+    // The buffer goes in on stdin, so unsaved lines line up too
+    fn load(root: PathBuf, path: String, contents: &str) -> std::result::Result<Self, String> {
+        let output = git_output(&root, &["blame", "--porcelain", "--contents", "-", "--", &path], Some(contents))?;
+        let (commits, lines) = parse_git_blame(&output);
+
+        let committed = commits.iter().filter(|(hash, _)| !GitBlame::uncommitted(hash)).map(|(_, commit)| commit.time);
+        let oldest = committed.clone().min().unwrap_or(0);
+        let newest = committed.max().unwrap_or(0);
+        Ok(GitBlame { root, path, commits, lines, oldest, newest, selected: 0, offset: 0, page: None })
+    }
+
+    // Row of the cursor on screen, in the page when one is open
+    pub(crate) fn cursor_row(&self) -> usize {
+        match &self.page {
+            Some(page) => page.selected.saturating_sub(page.offset),
+            None => self.selected.saturating_sub(self.offset),
+        }
+    }
+
+    fn uncommitted(hash: &str) -> bool {
+        hash.chars().all(|c| c == '0')
+    }
+
+    // The first line of the next (or previous) run of lines from another commit
+    fn chunk_step(&mut self, forward: bool) {
+        let Some((hash, _)) = self.lines.get(self.selected) else { return };
+        let found = if forward {
+            self.lines.iter().skip(self.selected).position(|(other, _)| other != hash).map(|n| self.selected + n)
+        } else {
+            let start = self.lines[..self.selected].iter().rposition(|(other, _)| other != hash);
+            start.map(|end| {
+                let previous = &self.lines[end].0;
+                self.lines[..end].iter().rposition(|(other, _)| other != previous).map_or(0, |n| n + 1)
+            })
+        };
+        if let Some(row) = found {
+            self.selected = row;
+        }
+    }
+
+    pub(crate) fn draw(&mut self, stdout: &mut Stdout, height: u16, theme: &Theme) -> io::Result<()> {
+        if let Some(page) = &mut self.page {
+            return page.draw(stdout, height, theme);
+        }
+        let (width, _) = terminal::size()?;
+        let header = match self.lines.get(self.selected).and_then(|(hash, _)| self.commits.get(hash)) {
+            Some(commit) => format!("Blame {}: {} {}", self.path, commit.short, commit.summary),
+            None => format!("Blame {}", self.path),
+        };
+        execute!(
+            stdout,
+            MoveTo(3, 0),
+            SetForegroundColor(theme.dired_path_color),
+            SetBackgroundColor(theme.background_color),
+            Print(header.chars().take((width as usize).saturating_sub(3)).collect::<String>()),
+            ResetColor
+        )?;
+
+        let visible = height.saturating_sub(2) as usize;
+        if self.selected < self.offset {
+            self.offset = self.selected;
+        } else if visible > 0 && self.selected >= self.offset + visible {
+            self.offset = self.selected + 1 - visible;
+        }
+
+        let span = (self.newest - self.oldest).max(1) as f32;
+        let room = (width as usize).saturating_sub(3 + 36);
+        for (row, (hash, text)) in self.lines.iter().enumerate().skip(self.offset).take(visible) {
+            let y = (row - self.offset) as u16 + 2;
+            let background = if row == self.selected { theme.hl_line_color } else { theme.background_color };
+            execute!(stdout, MoveTo(0, y), SetBackgroundColor(background), Print(" ".repeat(width as usize)), MoveTo(3, y))?;
+
+            // Annotated once per run of lines from the same commit, older is dimmer
+            let commit = &self.commits[hash];
+            let starts_run = row == self.offset || self.lines[row - 1].0 != *hash;
+            let (annotation, color) = if GitBlame::uncommitted(hash) {
+                (format!("{:<8} {:<14} {:<10}", "", "Not committed", ""), theme.warning_color)
+            } else {
+                let author: String = commit.author.chars().take(14).collect();
+                let age = (commit.time - self.oldest) as f32 / span;
+                (format!("{:<8} {:<14} {:<10}", commit.short, author, format_date(commit.time)), blend_colors(theme.comment_color, theme.normal_cursor_color, age))
+            };
+            let annotation = if starts_run { annotation } else { " ".repeat(34) };
+            execute!(
+                stdout,
+                SetForegroundColor(color),
+                Print(annotation),
+                SetForegroundColor(theme.comment_color),
+                Print(" │"),
+                SetForegroundColor(theme.text_color),
+                Print(text.chars().take(room).collect::<String>())
+            )?;
+        }
+        execute!(stdout, ResetColor)?;
+
+        Ok(())
+    }
+}
+
+struct GitLogEntry {
+    hash: String,
+    author: String,
+    date: String,
+    subject: String,
+    path: String, // What the file was called in that commit
+}
+
+// `git log --name-only` output with a NUL before every tab separated
+// header, the file of `path` keeps its name when no name follows
+fn parse_git_log(output: &str, path: &str) -> Vec<GitLogEntry> {
+    output.split('\0').filter(|record| !record.trim().is_empty()).filter_map(|record| {
+        let mut lines = record.lines();
+        let mut fields = lines.next()?.splitn(4, '\t');
+        let (hash, author, date, subject) = (fields.next()?, fields.next()?, fields.next()?, fields.next().unwrap_or(""));
+        let file = lines.find(|line| !line.trim().is_empty()).unwrap_or(path);
+        Some(GitLogEntry { hash: hash.to_string(), author: author.to_string(), date: date.to_string(), subject: subject.to_string(), path: file.to_string() })
+    }).collect()
+}
+
+// The commits that touched one file, following renames
+pub(crate) struct GitLog {
+    root: PathBuf,
+    path: String,
+    entries: Vec<GitLogEntry>,
+    selected: usize,
+    offset: usize,
+    page: Option<GitPage>, // The file at a revision, or a commit
+}
+
+impl GitLog {
+    fn load(root: PathBuf, path: String) -> std::result::Result<Self, String> {
+        let output = git_output(&root, &["log", "--follow", "--date=short", "--name-only", "--format=%x00%h%x09%an%x09%ad%x09%s", "--", &path], None)?;
+        let entries = parse_git_log(&output, &path);
+        Ok(GitLog { root, path, entries, selected: 0, offset: 0, page: None })
+    }
+
+    pub(crate) fn cursor_row(&self) -> usize {
+        match &self.page {
+            Some(page) => page.selected.saturating_sub(page.offset),
+            None => self.selected.saturating_sub(self.offset),
+        }
+    }
+
+    pub(crate) fn draw(&mut self, stdout: &mut Stdout, height: u16, theme: &Theme) -> io::Result<()> {
+        if let Some(page) = &mut self.page {
+            return page.draw(stdout, height, theme);
+        }
+        let (width, _) = terminal::size()?;
+        let count = match self.entries.len() {
+            1 => "1 commit".to_string(),
+            n => format!("{} commits", n),
+        };
+        execute!(
+            stdout,
+            MoveTo(3, 0),
+            SetForegroundColor(theme.dired_path_color),
+            SetBackgroundColor(theme.background_color),
+            Print(format!("Log of {} in {}: {}", self.path, self.root.display(), count)),
+            ResetColor
+        )?;
+
+        let visible = height.saturating_sub(2) as usize;
+        if self.selected < self.offset {
+            self.offset = self.selected;
+        } else if visible > 0 && self.selected >= self.offset + visible {
+            self.offset = self.selected + 1 - visible;
+        }
+
+        for (row, entry) in self.entries.iter().enumerate().skip(self.offset).take(visible) {
+            let y = (row - self.offset) as u16 + 2;
+            let background = if row == self.selected { theme.hl_line_color } else { theme.background_color };
+            let author: String = entry.author.chars().take(16).collect();
+            let room = (width as usize).saturating_sub(3 + entry.hash.len() + 30);
+            execute!(
+                stdout,
+                MoveTo(0, y),
+                SetBackgroundColor(background),
+                Print(" ".repeat(width as usize)),
+                MoveTo(3, y),
+                SetForegroundColor(theme.dired_size_color),
+                Print(format!("{} ", entry.hash)),
+                SetForegroundColor(theme.comment_color),
+                Print(format!("{:<10} {:<16} ", entry.date, author)),
+                SetForegroundColor(theme.text_color),
+                Print(entry.subject.chars().take(room).collect::<String>())
+            )?;
+        }
+        execute!(stdout, ResetColor)?;
+
+        Ok(())
+    }
+}
+
+impl Editor {
+    // HEAD changed, or another file is open
+    pub(crate) fn git_gutter_reload(&mut self) {
+        self.git_gutter = if self.current_file_path.is_file() { GitGutter::load(&self.current_file_path) } else { None };
+        if let Some(gutter) = &mut self.git_gutter {
+            gutter.update(&self.buffer);
+        }
+    }
+
+    // After every key, only diffing again when the buffer changed
+    pub(crate) fn git_gutter_update(&mut self) {
+        if let Some(gutter) = &mut self.git_gutter {
+            if gutter.diffed != self.buffer {
+                gutter.update(&self.buffer);
+            }
+        }
+    }
+
+    fn hunk_step(&mut self, forward: bool) {
+        let Some(gutter) = &self.git_gutter else {
+            self.message("No changes against HEAD here");
+            return;
+        };
+        let row = self.cursor_pos.1 as usize;
+        let last = self.buffer.len().saturating_sub(1);
+        let starts = gutter.hunks.iter().map(|hunk| hunk.new.0.min(last));
+        let target = if forward { starts.clone().find(|&start| start > row) } else { starts.rev().find(|&start| start < row) };
+        match target {
+            Some(line) => {
+                self.cursor_pos = (0, line as u16);
+                self.request_recenter();
+            },
+            None => self.message(if forward { "No next hunk" } else { "No previous hunk" }),
+        }
+    }
+
+    pub fn next_hunk(&mut self) {
+        self.hunk_step(true);
+    }
+
+    pub fn previous_hunk(&mut self) {
+        self.hunk_step(false);
+    }
+
+    // Put back what HEAD has for the hunk under the cursor
+    pub fn revert_hunk(&mut self) {
+        self.git_gutter_update();
+        let Some(gutter) = &self.git_gutter else {
+            self.message("No changes against HEAD here");
+            return;
+        };
+        let Some(hunk) = gutter.hunk_at(self.cursor_pos.1 as usize) else {
+            self.message("No hunk here");
+            return;
+        };
+        let (start, end) = hunk.new;
+        let lines = gutter.head[hunk.old.0..hunk.old.1].to_vec();
+        let count = lines.len();
+
+        self.snapshot();
+        self.buffer.splice(start..end, lines);
+        if self.buffer.is_empty() {
+            self.buffer.push(Vec::new());
+        }
+        let line = start.min(self.buffer.len() - 1);
+        self.cursor_pos = (0, line as u16);
+        self.snapshot();
+        self.git_gutter_update();
+        self.message(&format!("Reverted hunk, {} line{} from HEAD", count, if count == 1 { "" } else { "s" }));
+    }
+
+    // The repository of the open file and the file's path in it
+    fn git_file(&mut self) -> Option<(PathBuf, String)> {
+        let path = fs::canonicalize(&self.current_file_path).ok().filter(|path| path.is_file());
+        let Some(path) = path else {
+            self.message("Not visiting a file");
+            return None;
+        };
+        let Some(root) = git_root(&path) else {
+            self.message("Not in a git repository");
+            return None;
+        };
+        let root = fs::canonicalize(&root).unwrap_or(root);
+        let relative = path.strip_prefix(&root).ok()?.to_string_lossy().into_owned();
+        Some((root, relative))
+    }
+
+    // Who last touched each line of the buffer, starting on the cursor line
+    pub fn git_blame(&mut self) {
+        let Some((root, relative)) = self.git_file() else { return };
+        let contents: String = self.buffer.iter().map(|line| line.iter().collect::<String>() + "\n").collect();
+        match GitBlame::load(root, relative, &contents) {
+            Ok(mut blame) => {
+                blame.selected = (self.cursor_pos.1 as usize).min(blame.lines.len().saturating_sub(1));
+                self.blame = Some(blame);
+                self.mode = Mode::Blame;
+            },
+            Err(e) => self.error(&format!("git: {}", e)),
+        }
+    }
+
+    // The commits that touched the open file
+    pub fn git_log_file(&mut self) {
+        let Some((root, relative)) = self.git_file() else { return };
+        match GitLog::load(root, relative) {
+            Ok(log) if log.entries.is_empty() => self.message(&format!("No commits touch {}", log.path)),
+            Ok(log) => {
+                self.git_log = Some(log);
+                self.mode = Mode::GitLog;
+            },
+            Err(e) => self.error(&format!("git: {}", e)),
+        }
+    }
+
+    // `git show` of a commit, or of a file at a commit, as a read-only page
+    fn git_page(&mut self, root: &Path, object: &str, title: String, diff: bool) -> Option<GitPage> {
+        match git_output(root, &["show", "--no-color", object], None) {
+            Ok(text) => Some(GitPage::new(title, &text, diff)),
+            Err(e) => {
+                self.error(&format!("git: {}", e));
+                None
+            },
+        }
+    }
+
+    // Enter in the blame: the diff of the commit of the line
+    fn blame_show_commit(&mut self) {
+        let Some(blame) = &self.blame else { return };
+        let Some((hash, _)) = blame.lines.get(blame.selected) else { return };
+        if GitBlame::uncommitted(hash) {
+            self.message("Not committed yet");
+            return;
+        }
+        let (root, hash) = (blame.root.clone(), hash.clone());
+        let title = format!("Commit {}", blame.commits[&hash].short);
+        let page = self.git_page(&root, &hash, title, true);
+        if let Some(blame) = &mut self.blame {
+            blame.page = page;
+        }
+    }
+
+    // Enter in the log: the file as it was, d: what the commit changed
+    fn git_log_show(&mut self, revision: bool) {
+        let Some(log) = &self.git_log else { return };
+        let Some(entry) = log.entries.get(log.selected) else { return };
+        let root = log.root.clone();
+        let (object, title, diff) = if revision {
+            (format!("{}:{}", entry.hash, entry.path), format!("{} at {} (read-only)", entry.path, entry.hash), false)
+        } else {
+            (entry.hash.clone(), format!("Commit {}", entry.hash), true)
+        };
+        let page = self.git_page(&root, &object, title, diff);
+        if let Some(log) = &mut self.git_log {
+            log.page = page;
+        }
+    }
+
+    // C-h C-c: the status of the repository of the open file
+    pub fn git_status(&mut self) {
+        let start = if self.current_file_path.as_os_str().is_empty() { env::current_dir().unwrap_or_default() } else { self.current_file_path.clone() };
+        let Some(root) = git_root(&start).or_else(|| git_root(&env::current_dir().unwrap_or_default())) else {
+            self.message("Not in a git repository");
+            return;
+        };
+        match GitStatus::load(root) {
+            Ok(status) => {
+                self.git = Some(status);
+                self.mode = Mode::Git;
+            },
+            Err(e) => self.error(&format!("git: {}", e)),
+        }
+    }
+
+    fn git_refresh(&mut self) {
+        let result = self.git.as_mut().map(|git| git.reload());
+        if let Some(Err(e)) = result {
+            self.error(&format!("git: {}", e));
+        }
+        self.git_gutter_reload();
+    }
+
+    // Run git in the repository of the status buffer, then show the new state
+    fn git_run(&mut self, args: &[&str], input: Option<&str>, done: &str) {
+        let Some(root) = self.git.as_ref().map(|git| git.root.clone()) else { return };
+        match git_output(&root, args, input) {
+            Ok(_) => {
+                self.git_refresh();
+                self.message(done);
+            },
+            Err(e) => self.error(&format!("git: {}", e)),
+        }
+    }
+
+    // s: the section, the file or the hunk at point goes to the index
+    fn git_stage(&mut self) {
+        let Some(git) = &self.git else { return };
+        match git.current() {
+            GitRow::Section(GitSection::Untracked) => {
+                let paths: Vec<String> = git.files.iter().filter(|file| file.section == GitSection::Untracked).map(|file| file.path.clone()).collect();
+                let args: Vec<&str> = ["add", "--"].into_iter().chain(paths.iter().map(String::as_str)).collect();
+                self.git_run(&args, None, "Staged untracked files");
+            },
+            GitRow::Section(GitSection::Unstaged) => self.git_run(&["add", "-u"], None, "Staged all changes"),
+            GitRow::File(file) if git.files[file].section != GitSection::Staged => {
+                let path = git.files[file].path.clone();
+                self.git_run(&["add", "--", &path], None, &format!("Staged {}", path));
+            },
+            GitRow::Hunk(file, hunk) | GitRow::Line(file, hunk, _) if git.files[file].section == GitSection::Unstaged => {
+                let patch = git.files[file].patch(hunk);
+                self.git_run(&["apply", "--cached", "-"], Some(&patch), "Staged hunk");
+            },
+            GitRow::Blank => {},
+            _ => self.message("Already staged"),
+        }
+    }
+
+    // u: back out of the index
+    fn git_unstage(&mut self) {
+        let Some(git) = &self.git else { return };
+        match git.current() {
+            GitRow::Section(GitSection::Staged) => self.git_run(&["reset", "-q", "--", "."], None, "Unstaged all changes"),
+            GitRow::File(file) if git.files[file].section == GitSection::Staged => {
+                let path = git.files[file].path.clone();
+                self.git_run(&["reset", "-q", "--", &path], None, &format!("Unstaged {}", path));
+            },
+            GitRow::Hunk(file, hunk) | GitRow::Line(file, hunk, _) if git.files[file].section == GitSection::Staged => {
+                let patch = git.files[file].patch(hunk);
+                self.git_run(&["apply", "--cached", "--reverse", "-"], Some(&patch), "Unstaged hunk");
+            },
+            GitRow::Blank => {},
+            _ => self.message("Not staged"),
+        }
+    }
+
+    // x: asks first. Files git never had go to the trash, where dired-undo
+    // finds them, and a staged file only loses what is staged
+    fn git_discard(&mut self) {
+        let Some(git) = &self.git else { return };
+        let what = match (git.current_hunk(), git.current_file()) {
+            (Some(_), _) => "this hunk".to_string(),
+            (None, Some(file)) if matches!(git.current(), GitRow::File(_)) => git.files[file].path.clone(),
+            _ => return,
+        };
+        self.minibuffer_open(Prompt::GitDiscard(what), "");
+    }
+
+    pub(crate) fn git_discard_confirmed(&mut self) {
+        let Some(git) = &self.git else { return };
+        let root = git.root.clone();
+        if let Some((file, hunk)) = git.current_hunk() {
+            let patch = git.files[file].patch(hunk);
+            // A staged hunk leaves both the index and the file
+            let args: &[&str] = if git.files[file].section == GitSection::Staged { &["apply", "--reverse", "--index", "-"] } else { &["apply", "--reverse", "-"] };
+            self.git_run(args, Some(&patch), "Discarded hunk");
+            return;
+        }
+        let Some(file) = git.current_file() else { return };
+        let (section, status, path) = (git.files[file].section, git.files[file].status, git.files[file].path.clone());
+        match (section, status) {
+            (GitSection::Untracked, _) => self.git_trash(&root, &path),
+            // Never in HEAD either, out of the index and into the trash
+            (GitSection::Staged, 'A') => match git_output(&root, &["rm", "--cached", "-q", "--", &path], None) {
+                Ok(_) => self.git_trash(&root, &path),
+                Err(e) => self.error(&format!("git: {}", e)),
+            },
+            // Nothing in the file to lose, HEAD's copy comes back
+            (GitSection::Staged, 'D') if !root.join(&path).exists() => {
+                self.git_run(&["checkout", "HEAD", "--", &path], None, &format!("Discarded {}", path));
+            },
+            // Only the index goes back to HEAD, edits in the file are kept
+            (GitSection::Staged, _) => self.git_run(&["reset", "-q", "--", &path], None, &format!("Unstaged {}, the file keeps its changes", path)),
+            (GitSection::Unstaged, _) => self.git_run(&["checkout", "--", &path], None, &format!("Discarded {}", path)),
+        }
+    }
+
+    // Git can't bring back what it never had, the trash can
+    fn git_trash(&mut self, root: &Path, path: &str) {
+        let full = root.join(path);
+        match trash_path(&full) {
+            Ok((trashed, info)) => {
+                self.dired_undo_push(format!("Trashed {}", path), vec![(full, trashed, Some(info))]);
+                self.git_refresh();
+                self.message(&format!("Trashed {}, dired-undo puts it back", path));
+            },
+            Err(e) => {
+                self.git_refresh();
+                self.error(&format!("Failed to trash {}: {}", path, e));
+            },
+        }
+    }
+
+    // Enter: the file, on the line of the diff when there is one
+    fn git_visit(&mut self) {
+        let Some(git) = &self.git else { return };
+        let Some(file) = git.current_file() else { return };
+        let path = git.root.join(&git.files[file].path);
+        let line = match git.current() {
+            GitRow::Hunk(file, hunk) => Some(git.files[file].hunks[hunk].new_start()),
+            GitRow::Line(file, hunk, line) => {
+                let hunk = &git.files[file].hunks[hunk];
+                let before = hunk.lines[..line].iter().filter(|text| !text.starts_with('-') && !text.starts_with('\\')).count();
+                Some(hunk.new_start() + before)
+            },
+            _ => None,
+        };
+        if !path.is_file() {
+            self.message("Nothing to visit, the file is gone");
+            return;
+        }
+        if let Err(e) = self.open(&path, None) {
+            self.error(&format!("Failed to open {}: {}", path.display(), e));
+            return;
+        }
+        if let Some(line) = line {
+            self.goto_line(line);
+        }
+    }
+
+    // c: write the message in a buffer, C-c C-c commits and C-c C-k gives up
+    fn git_commit(&mut self) {
+        let Some(git) = &self.git else { return };
+        if !git.files.iter().any(|file| file.section == GitSection::Staged) {
+            self.message("Nothing staged to commit");
+            return;
+        }
+        let root = git.root.clone();
+        let git_dir = match git_output(&root, &["rev-parse", "--absolute-git-dir"], None) {
+            Ok(dir) => PathBuf::from(dir.trim()),
+            Err(e) => {
+                self.error(&format!("git: {}", e));
+                return;
+            },
+        };
+        let mut template = String::from("\n# Write the commit message, lines starting with '#' are ignored.\n# C-c C-c to commit, C-c C-k to give up.\n#\n# Changes to be committed:\n");
+        for file in git.files.iter().filter(|file| file.section == GitSection::Staged) {
+            template.push_str(&format!("#\t{:<11}{}\n", format!("{}:", file.kind()), file.path));
+        }
+        let path = git_dir.join("COMMIT_EDITMSG");
+        if let Err(e) = fs::write(&path, template) {
+            self.error(&format!("Failed to write {}: {}", path.display(), e));
+            return;
+        }
+        let previous = self.current_file_path.clone();
+        if self.open(&path, None).is_ok() {
+            self.git_commit_message = Some((root, previous));
+            self.mode = Mode::Insert;
+            self.set_cursor_shape();
+            self.message("C-c C-c to commit, C-c C-k to give up");
+        }
+    }
+
+    pub(crate) fn editing_commit_message(&self) -> bool {
+        self.git_commit_message.is_some() && self.current_file_path.file_name().is_some_and(|name| name == "COMMIT_EDITMSG")
+    }
+
+    pub(crate) fn git_commit_finish(&mut self) {
+        let Some((root, _)) = self.git_commit_message.clone() else { return };
+        if self.buffer_save().is_err() {
+            return;
+        }
+        let path = self.current_file_path.to_string_lossy().into_owned();
+        match git_output(&root, &["commit", "--cleanup=strip", "-F", &path], None) {
+            Ok(output) => {
+                self.git_commit_close();
+                self.git_refresh();
+                self.message(output.lines().next().unwrap_or("Committed"));
+            },
+            Err(e) => self.error(&format!("git: {}", e)),
+        }
+    }
+
+    pub(crate) fn git_commit_abort(&mut self) {
+        self.git_commit_close();
+        self.message("Commit aborted");
+    }
+
+    // Back to where the commit started from, the status buffer
+    fn git_commit_close(&mut self) {
+        let Some((_, previous)) = self.git_commit_message.take() else { return };
+        let message_path = self.current_file_path.clone();
+        if !previous.as_os_str().is_empty() && previous.exists() {
+            let _ = self.open(&previous, None);
+        }
+        if let Some(index) = self.buffer_stack.iter().position(|path| *path == message_path) {
+            self.buffer_stack.remove(index);
+            self.current_buffer = self.buffer_stack.iter().position(|path| *path == self.current_file_path).unwrap_or(0);
+        }
+        if self.git.is_some() {
+            self.mode = Mode::Git;
+        }
+    }
+
+    pub(crate) fn handle_git_mode(&mut self, key: KeyEvent) -> Result<()> {
+        if key.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) {
+            return Ok(());
+        }
+        match key.code {
+            KeyCode::Char('q') => {
+                self.mode = Mode::Normal; // TODO to the preferred base mode instead
+            },
+            KeyCode::Char('j') | KeyCode::Down => {
+                if let Some(git) = &mut self.git {
+                    git.step(1);
+                }
+            },
+            KeyCode::Char('k') | KeyCode::Up => {
+                if let Some(git) = &mut self.git {
+                    git.step(-1);
+                }
+            },
+            KeyCode::Char('n') | KeyCode::Char('p') => {
+                if let Some(git) = &mut self.git {
+                    git.jump(key.code == KeyCode::Char('n'));
+                }
+            },
+            KeyCode::Tab => {
+                if let Some(git) = &mut self.git {
+                    git.toggle();
+                }
+            },
+            KeyCode::Char('s') => {
+                self.git_stage();
+            },
+            KeyCode::Char('u') => {
+                self.git_unstage();
+            },
+            KeyCode::Char('S') => {
+                self.git_run(&["add", "-A"], None, "Staged everything");
+            },
+            KeyCode::Char('U') => {
+                self.git_run(&["reset", "-q", "--", "."], None, "Unstaged everything");
+            },
+            KeyCode::Char('x') => {
+                self.git_discard();
+            },
+            KeyCode::Char('c') => {
+                self.git_commit();
+            },
+            KeyCode::Char('g') => {
+                self.git_refresh();
+            },
+            KeyCode::Enter => {
+                self.git_visit();
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub(crate) fn handle_blame_mode(&mut self, key: KeyEvent) -> Result<()> {
+        if key.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) {
+            return Ok(());
+        }
+        let height = self.text_area_height();
+        let Some(blame) = &mut self.blame else { return Ok(()) };
+        if let Some(page) = &mut blame.page {
+            if !page.handle_key(key.code, height) {
+                blame.page = None;
+            }
+            return Ok(());
+        }
+        match key.code {
+            KeyCode::Char('q') => {
+                // Back to the buffer on the line the blame was looking at
+                let line = blame.selected;
+                self.mode = Mode::Normal;
+                self.goto_line(line + 1);
+            },
+            KeyCode::Char('j') | KeyCode::Down => {
+                blame.selected = (blame.selected + 1).min(blame.lines.len().saturating_sub(1));
+            },
+            KeyCode::Char('k') | KeyCode::Up => {
+                blame.selected = blame.selected.saturating_sub(1);
+            },
+            KeyCode::Char('n') | KeyCode::Char('p') => {
+                blame.chunk_step(key.code == KeyCode::Char('n'));
+            },
+            KeyCode::Enter => {
+                self.blame_show_commit();
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub(crate) fn handle_git_log_mode(&mut self, key: KeyEvent) -> Result<()> {
+        if key.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) {
+            return Ok(());
+        }
+        let height = self.text_area_height();
+        let Some(log) = &mut self.git_log else { return Ok(()) };
+        if let Some(page) = &mut log.page {
+            if !page.handle_key(key.code, height) {
+                log.page = None;
+            }
+            return Ok(());
+        }
+        match key.code {
+            KeyCode::Char('q') => {
+                self.mode = Mode::Normal; // TODO to the preferred base mode instead
+            },
+            KeyCode::Char('j') | KeyCode::Char('n') | KeyCode::Down => {
+                log.selected = (log.selected + 1).min(log.entries.len().saturating_sub(1));
+            },
+            KeyCode::Char('k') | KeyCode::Char('p') | KeyCode::Up => {
+                log.selected = log.selected.saturating_sub(1);
+            },
+            KeyCode::Enter => {
+                self.git_log_show(true);
+            },
+            KeyCode::Char('d') => {
+                self.git_log_show(false);
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A repository with one commit of `files`, by a made up author
+    fn repository(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let root = crate::tests::temp_dir(name);
+        git(&root, &["init", "-q", "-b", "main"]);
+        for (path, text) in files {
+            write(&root, path, text);
+        }
+        git(&root, &["add", "-A"]);
+        git(&root, &["-c", "user.name=Test", "-c", "user.email=test@example.com", "commit", "-q", "-m", "Start"]);
+        root
+    }
+
+    fn git(root: &Path, args: &[&str]) -> String {
+        git_output(root, args, None).unwrap()
+    }
+
+    fn write(root: &Path, path: &str, text: &str) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, text).unwrap();
+    }
+
+    fn entries(status: &GitStatus) -> Vec<(GitSection, char, &str)> {
+        let mut entries: Vec<_> = status.files.iter().map(|file| (file.section, file.status, file.path.as_str())).collect();
+        entries.sort_by_key(|&(section, _, path)| (section as u8, path));
+        entries
+    }
+
+    #[test]
+    fn status_reads_every_section() {
+        let root = repository("git-status", &[("a.txt", "a\n"), ("old name.txt", "old\n"), ("both.txt", "1\n"), ("gone.txt", "x\n")]);
+        write(&root, "a.txt", "changed\n");
+        write(&root, "new file.txt", "new\n");
+        git(&root, &["add", "new file.txt"]);
+        git(&root, &["mv", "old name.txt", "renamed ✓.txt"]);
+        write(&root, "both.txt", "2\n");
+        git(&root, &["add", "both.txt"]);
+        write(&root, "both.txt", "3\n");
+        fs::remove_file(root.join("gone.txt")).unwrap();
+        write(&root, "dir/untracked.txt", "?\n");
+
+        let status = GitStatus::load(root).unwrap();
+        assert!(status.branch.starts_with("main"), "{}", status.branch);
+        assert_eq!(entries(&status), vec![
+            (GitSection::Untracked, '?', "dir/"),
+            (GitSection::Unstaged, 'M', "a.txt"),
+            (GitSection::Unstaged, 'M', "both.txt"),
+            (GitSection::Unstaged, 'D', "gone.txt"),
+            (GitSection::Staged, 'M', "both.txt"),
+            (GitSection::Staged, 'A', "new file.txt"),
+            (GitSection::Staged, 'R', "renamed ✓.txt"),
+        ]);
+        let both: Vec<&GitFile> = status.files.iter().filter(|file| file.path == "both.txt").collect();
+        assert!(both.iter().all(|file| file.hunks.len() == 1));
+        let staged = both.iter().find(|file| file.section == GitSection::Staged).unwrap();
+        assert_eq!(staged.hunks[0].lines, vec!["-1", "+2"]);
+    }
+
+    #[test]
+    fn hunks_stage_and_unstage_one_at_a_time() {
+        let lines: Vec<String> = (1..=30).map(|n| n.to_string()).collect();
+        let root = repository("git-hunks", &[("numbers.txt", &(lines.join("\n") + "\n"))]);
+        let mut changed = lines.clone();
+        changed[1] = "two".to_string();
+        changed[27] = "twenty-eight".to_string();
+        write(&root, "numbers.txt", &(changed.join("\n") + "\n"));
+
+        let hunks = |status: &GitStatus, section: GitSection| {
+            status.files.iter().find(|file| file.section == section).map_or(0, |file| file.hunks.len())
+        };
+        let status = GitStatus::load(root.clone()).unwrap();
+        assert_eq!((hunks(&status, GitSection::Unstaged), hunks(&status, GitSection::Staged)), (2, 0));
+
+        let patch = status.files[0].patch(1);
+        git_output(&root, &["apply", "--cached", "-"], Some(&patch)).unwrap();
+        let status = GitStatus::load(root.clone()).unwrap();
+        assert_eq!((hunks(&status, GitSection::Unstaged), hunks(&status, GitSection::Staged)), (1, 1));
+        assert_eq!(git(&root, &["diff", "--cached", "--numstat"]), "1\t1\tnumbers.txt\n");
+        assert!(git(&root, &["diff", "--cached"]).contains("+twenty-eight"));
+
+        let staged = status.files.iter().find(|file| file.section == GitSection::Staged).unwrap();
+        git_output(&root, &["apply", "--cached", "--reverse", "-"], Some(&staged.patch(0))).unwrap();
+        let status = GitStatus::load(root.clone()).unwrap();
+        assert_eq!((hunks(&status, GitSection::Unstaged), hunks(&status, GitSection::Staged)), (2, 0));
+        // The file itself never changed
+        assert_eq!(fs::read_to_string(root.join("numbers.txt")).unwrap(), changed.join("\n") + "\n");
+    }
+
+    #[test]
+    fn blame_porcelain_gives_every_line_its_commit() {
+        let a = "a".repeat(40);
+        let b = "0123456789abcdef0123456789abcdef01234567";
+        let none = "0".repeat(40);
+        let output = [
+            format!("{} 1 1 2", a),
+            "author Ann Example".to_string(),
+            "author-mail <ann@example.com>".to_string(),
+            "author-time 1700000000".to_string(),
+            "author-tz +0100".to_string(),
+            "committer Bob".to_string(),
+            "committer-time 1800000000".to_string(),
+            "summary First commit".to_string(),
+            "boundary".to_string(),
+            "filename src/main.rs".to_string(),
+            "\tfn main() {".to_string(),
+            format!("{} 2 2", a),
+            "\t    run();".to_string(),
+            format!("{} 5 3 1", b),
+            "author Bob".to_string(),
+            "author-time 1710000000".to_string(),
+            "summary Tab\tin the summary".to_string(),
+            "previous aaaaaaaa src/old.rs".to_string(),
+            "filename src/main.rs".to_string(),
+            "\t\tindented with a tab".to_string(),
+            format!("{} 4 4 1", none),
+            "author Not Committed Yet".to_string(),
+            "author-time 1720000000".to_string(),
+            "summary Version of src/main.rs from src/main.rs".to_string(),
+            "filename src/main.rs".to_string(),
+            "\t}".to_string(),
+        ].join("\n") + "\n";
+
+        let (commits, lines) = parse_git_blame(&output);
+        assert_eq!(lines, vec![
+            (a.clone(), "fn main() {".to_string()),
+            (a.clone(), "    run();".to_string()),
+            (b.to_string(), "\tindented with a tab".to_string()),
+            (none.clone(), "}".to_string()),
+        ]);
+        assert_eq!(commits.len(), 3);
+        let first = &commits[&a];
+        assert_eq!((first.short.as_str(), first.author.as_str(), first.time, first.summary.as_str()), ("aaaaaaaa", "Ann Example", 1700000000, "First commit"));
+        let second = &commits[b];
+        assert_eq!((second.short.as_str(), second.author.as_str(), second.time, second.summary.as_str()), ("01234567", "Bob", 1710000000, "Tab\tin the summary"));
+        assert!(GitBlame::uncommitted(&none) && !GitBlame::uncommitted(b));
+    }
+
+    #[test]
+    fn log_lines_give_each_commit_its_file() {
+        let output = "\0e5f6a7b\tAnn Example\t2024-03-04\tMove it\tfor real\n\nsrc/new name.rs\n\0c3d4e5f\tBob\t2024-02-03\tEdit\n\nsrc/old.rs\n\0a1b2c3d\tBob\t2024-01-02\n";
+        let entries: Vec<_> = parse_git_log(output, "src/new name.rs").into_iter()
+            .map(|entry| (entry.hash, entry.author, entry.date, entry.subject, entry.path))
+            .collect();
+        let entry = |hash: &str, author: &str, date: &str, subject: &str, path: &str| {
+            (hash.to_string(), author.to_string(), date.to_string(), subject.to_string(), path.to_string())
+        };
+        assert_eq!(entries, vec![
+            entry("e5f6a7b", "Ann Example", "2024-03-04", "Move it\tfor real", "src/new name.rs"),
+            entry("c3d4e5f", "Bob", "2024-02-03", "Edit", "src/old.rs"),
+            // No subject and no name, like a commit with an empty message
+            entry("a1b2c3d", "Bob", "2024-01-02", "", "src/new name.rs"),
+        ]);
+        assert!(parse_git_log("", "a").is_empty());
+    }
+
+    // The mark of every line of `buffer` against `head`, and the counts
+    fn gutter(head: &str, buffer: &str) -> (Vec<Option<GutterMark>>, (usize, usize)) {
+        let mut gutter = GitGutter { branch: String::new(), head: crate::tests::lines(head), diffed: Vec::new(), hunks: Vec::new() };
+        let buffer = crate::tests::lines(buffer);
+        gutter.update(&buffer);
+        ((0..buffer.len()).map(|line| gutter.mark(line)).collect(), gutter.counts())
+    }
+
+    #[test]
+    fn matching_lines_pairs_what_stayed() {
+        let pairs = matching_lines(&crate::tests::lines("a\nb\nc\nd"), &crate::tests::lines("a\nx\nc\nd\ne"));
+        assert_eq!(pairs, vec![(0, 0), (2, 2), (3, 3)]);
+        assert_eq!(myers_diff(&crate::tests::lines("a\nb\nc"), &crate::tests::lines("b\nc\na")), vec![(1, 0), (2, 1)]);
+        assert_eq!(myers_diff(&[], &crate::tests::lines("a")), vec![]);
+    }
+
+    #[test]
+    fn gutter_marks_insertions() {
+        use GutterMark::*;
+        assert_eq!(gutter("a\nb", "a\nnew\nnewer\nb"), (vec![None, Some(Added), Some(Added), None], (2, 0)));
+        assert_eq!(gutter("a\nb", "a\nb\nnew"), (vec![None, None, Some(Added)], (1, 0)));
+    }
+
+    #[test]
+    fn gutter_marks_deletions() {
+        use GutterMark::*;
+        // On the line after the gap, or the last line when the end went
+        assert_eq!(gutter("a\nb\nc\nd", "a\nd"), (vec![None, Some(DeletedAbove)], (0, 2)));
+        assert_eq!(gutter("a\nb\nc", "a\nb"), (vec![None, Some(DeletedBelow)], (0, 1)));
+        assert_eq!(gutter("a\nb\nc", "b\nc"), (vec![Some(DeletedAbove), None], (0, 1)));
+    }
+
+    #[test]
+    fn gutter_marks_modifications() {
+        use GutterMark::*;
+        assert_eq!(gutter("a\nb\nc", "a\nB\nc"), (vec![None, Some(Modified), None], (1, 1)));
+        // Past the lines it replaced a change is an addition
+        assert_eq!(gutter("a\nb\nc", "a\nB\nB2\nc"), (vec![None, Some(Modified), Some(Added), None], (2, 1)));
+    }
+}
@@ -0,0 +1,324 @@
+// Just enough JSON for the language server protocol
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>), // In insertion order
+}
+
+static JSON_NULL: Json = Json::Null;
+
+impl Json {
+    pub(crate) fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(fields.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+    }
+
+    // Missing keys and indexes give Null, so lookups can be chained
+    pub(crate) fn get(&self, key: &str) -> &Json {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map_or(&JSON_NULL, |(_, value)| value),
+            _ => &JSON_NULL,
+        }
+    }
+
+    pub(crate) fn is_null(&self) -> bool {
+        *self == Json::Null
+    }
+
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_i64(&self) -> Option<i64> {
+        match self {
+            Json::Number(n) => Some(*n as i64),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_usize(&self) -> usize {
+        self.as_i64().map_or(0, |n| n.max(0) as usize)
+    }
+
+    pub(crate) fn as_array(&self) -> &[Json] {
+        match self {
+            Json::Array(items) => items,
+            _ => &[],
+        }
+    }
+
+    pub(crate) fn parse(text: &str) -> std::result::Result<Json, String> {
+        let chars: Vec<char> = text.chars().collect();
+        let mut pos = 0;
+        let value = Json::parse_value(&chars, &mut pos)?;
+        Json::skip_whitespace(&chars, &mut pos);
+        if pos < chars.len() {
+            return Err(format!("Trailing characters at {}", pos));
+        }
+        Ok(value)
+    }
+
+    fn skip_whitespace(chars: &[char], pos: &mut usize) {
+        while *pos < chars.len() && chars[*pos].is_whitespace() {
+            *pos += 1;
+        }
+    }
+
+    fn parse_value(chars: &[char], pos: &mut usize) -> std::result::Result<Json, String> {
+        Json::skip_whitespace(chars, pos);
+        let literal = |pos: &mut usize, word: &str, value: Json| {
+            let end = *pos + word.chars().count();
+            if end <= chars.len() && chars[*pos..end].iter().copied().eq(word.chars()) {
+                *pos = end;
+                Ok(value)
+            } else {
+                Err(format!("Unexpected character at {}", *pos))
+            }
+        };
+
+        match chars.get(*pos) {
+            Some('n') => literal(pos, "null", Json::Null),
+            Some('t') => literal(pos, "true", Json::Bool(true)),
+            Some('f') => literal(pos, "false", Json::Bool(false)),
+            Some('"') => Json::parse_string(chars, pos).map(Json::String),
+            Some('[') => {
+                *pos += 1;
+                let mut items = Vec::new();
+                loop {
+                    Json::skip_whitespace(chars, pos);
+                    if chars.get(*pos) == Some(&']') {
+                        *pos += 1;
+                        return Ok(Json::Array(items));
+                    }
+                    if !items.is_empty() {
+                        if chars.get(*pos) != Some(&',') {
+                            return Err(format!("Expected , at {}", *pos));
+                        }
+                        *pos += 1;
+                    }
+                    items.push(Json::parse_value(chars, pos)?);
+                }
+            },
+            Some('{') => {
+                *pos += 1;
+                let mut fields = Vec::new();
+                loop {
+                    Json::skip_whitespace(chars, pos);
+                    if chars.get(*pos) == Some(&'}') {
+                        *pos += 1;
+                        return Ok(Json::Object(fields));
+                    }
+                    if !fields.is_empty() {
+                        if chars.get(*pos) != Some(&',') {
+                            return Err(format!("Expected , at {}", *pos));
+                        }
+                        *pos += 1;
+                        Json::skip_whitespace(chars, pos);
+                    }
+                    let key = Json::parse_string(chars, pos)?;
+                    Json::skip_whitespace(chars, pos);
+                    if chars.get(*pos) != Some(&':') {
+                        return Err(format!("Expected : at {}", *pos));
+                    }
+                    *pos += 1;
+                    fields.push((key, Json::parse_value(chars, pos)?));
+                }
+            },
+            Some(c) if *c == '-' || c.is_ascii_digit() => {
+                let start = *pos;
+                while *pos < chars.len() && (chars[*pos].is_ascii_digit() || "+-.eE".contains(chars[*pos])) {
+                    *pos += 1;
+                }
+                let number: String = chars[start..*pos].iter().collect();
+                number.parse().map(Json::Number).map_err(|_| format!("Invalid number {}", number))
+            },
+            _ => Err(format!("Unexpected character at {}", *pos)),
+        }
+    }
+
+    fn parse_string(chars: &[char], pos: &mut usize) -> std::result::Result<String, String> {
+        if chars.get(*pos) != Some(&'"') {
+            return Err(format!("Expected string at {}", *pos));
+        }
+        *pos += 1;
+        let mut s = String::new();
+        let hex = |pos: usize| -> Option<u32> {
+            let digits: String = chars.get(pos..pos + 4)?.iter().collect();
+            u32::from_str_radix(&digits, 16).ok()
+        };
+        while let Some(&c) = chars.get(*pos) {
+            *pos += 1;
+            match c {
+                '"' => return Ok(s),
+                '\\' => {
+                    let escaped = chars.get(*pos).copied().ok_or("Unterminated string")?;
+                    *pos += 1;
+                    match escaped {
+                        'n' => s.push('\n'),
+                        't' => s.push('\t'),
+                        'r' => s.push('\r'),
+                        'b' => s.push('\u{8}'),
+                        'f' => s.push('\u{c}'),
+                        'u' => {
+                            let mut code = hex(*pos).ok_or("Invalid \\u escape")?;
+                            *pos += 4;
+                            // Characters outside the BMP come as a surrogate pair
+                            if (0xD800..0xDC00).contains(&code) && chars.get(*pos) == Some(&'\\') && chars.get(*pos + 1) == Some(&'u') {
+                                if let Some(low) = hex(*pos + 2) {
+                                    code = 0x10000 + ((code - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF);
+                                    *pos += 6;
+                                }
+                            }
+                            s.push(char::from_u32(code).unwrap_or('\u{FFFD}'));
+                        },
+                        other => s.push(other),
+                    }
+                },
+                _ => s.push(c),
+            }
+        }
+        Err("Unterminated string".to_string())
+    }
+}
+
+impl std::fmt::Display for Json {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => {
+                write!(f, "\"")?;
+                for c in s.chars() {
+                    match c {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '\n' => write!(f, "\\n")?,
+                        '\r' => write!(f, "\\r")?,
+                        '\t' => write!(f, "\\t")?,
+                        c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                write!(f, "\"")
+            },
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            },
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}:{}", Json::String(key.clone()), value)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Json {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Json {
+        Json::String(s)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Json {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(n: i64) -> Json {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Json {
+        Json::Bool(b)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(items: Vec<Json>) -> Json {
+        Json::Array(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_values() {
+        let json = Json::parse(r#" {"id": 3, "ok": true, "none": null, "list": [1, -2.5, 1e3, "x"], "nested": {"a": {}}} "#).unwrap();
+        assert_eq!(json.get("id").as_i64(), Some(3));
+        assert_eq!(json.get("ok"), &Json::Bool(true));
+        assert!(json.get("none").is_null());
+        assert!(json.get("missing").get("deeper").is_null());
+        assert_eq!(json.get("list").as_array(), &[Json::Number(1.0), Json::Number(-2.5), Json::Number(1000.0), "x".into()]);
+        assert_eq!(json.get("nested").get("a"), &Json::Object(Vec::new()));
+        assert_eq!(Json::parse("[]"), Ok(Json::Array(Vec::new())));
+    }
+
+    #[test]
+    fn parse_string_escapes() {
+        assert_eq!(Json::parse(r#""a\"b\\c\/d\n\t\r""#), Ok("a\"b\\c/d\n\t\r".into()));
+        assert_eq!(Json::parse(r#""caf\u00e9 \u00E9""#), Ok("café é".into()));
+        assert_eq!(Json::parse(r#""\ud83d\ude00!""#), Ok("😀!".into()));
+        assert_eq!(Json::parse(r#""\ud83d""#), Ok("\u{FFFD}".into()));
+        assert_eq!(Json::parse("\"héllo wörld\""), Ok("héllo wörld".into()));
+    }
+
+    #[test]
+    fn parse_rejects_malformed_text() {
+        for text in ["", "[1,]", "{\"a\":1,}", "{\"a\" 1}", "[1 2]", "\"open", "\"\\u12\"", "nul", "1 2", "{1: 2}", "-"] {
+            assert!(Json::parse(text).is_err(), "{:?} should not parse", text);
+        }
+    }
+
+    #[test]
+    fn display_escapes_and_round_trips() {
+        let json = Json::object(vec![
+            ("text", "quote \" slash \\ line\nbell\u{7}é".into()),
+            ("n", 42i64.into()),
+            ("half", Json::Number(0.5)),
+            ("list", vec![Json::Null, false.into(), Json::Array(Vec::new())].into()),
+        ]);
+        let text = json.to_string();
+        assert_eq!(text, r#"{"text":"quote \" slash \\ line\nbell\u0007é","n":42,"half":0.5,"list":[null,false,[]]}"#);
+        assert_eq!(Json::parse(&text), Ok(json));
+    }
+
+    #[test]
+    fn numbers_read_as_integers() {
+        assert_eq!(Json::parse("7").unwrap().as_i64(), Some(7));
+        assert_eq!(Json::parse("-7").unwrap().as_usize(), 0);
+        assert_eq!(Json::parse("\"7\"").unwrap().as_i64(), None);
+        assert_eq!(Json::from(12usize).to_string(), "12");
+    }
+}
@@ -0,0 +1,1057 @@
+use super::*;
+
+// The language id the protocol wants, also the key of `Lsp_servers` in config.lua
+pub(crate) fn language_id(path: &Path) -> Option<&'static str> {
+    let language = match path.extension()?.to_str()? {
+        "rs" => "rust",
+        "lua" => "lua",
+        "c" | "h" => "c",
+        "cpp" | "cc" | "cxx" | "hpp" => "cpp",
+        "py" => "python",
+        "go" => "go",
+        "js" => "javascript",
+        "ts" => "typescript",
+        "zig" => "zig",
+        _ => return None,
+    };
+    Some(language)
+}
+
+fn path_to_uri(path: &Path) -> String {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut uri = "file://".to_string();
+    for byte in path.to_string_lossy().bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-_.~".contains(&byte) {
+            uri.push(byte as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    uri
+}
+
+fn uri_to_path(uri: &str) -> PathBuf {
+    let encoded = uri.strip_prefix("file://").unwrap_or(uri).as_bytes();
+    let mut bytes = Vec::new();
+    let mut i = 0;
+    while i < encoded.len() {
+        let decoded = (encoded[i] == b'%')
+            .then(|| std::str::from_utf8(encoded.get(i + 1..i + 3)?).ok())
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match decoded {
+            Some(byte) => {
+                bytes.push(byte);
+                i += 3;
+            },
+            None => {
+                bytes.push(encoded[i]);
+                i += 1;
+            },
+        }
+    }
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+// Positions count UTF-16 code units, the buffer counts chars
+fn utf16_column(line: &[char], column: usize) -> usize {
+    line.iter().take(column).map(|c| c.len_utf16()).sum()
+}
+
+fn char_column(line: &[char], utf16: usize) -> usize {
+    let mut units = 0;
+    for (column, c) in line.iter().enumerate() {
+        if units >= utf16 {
+            return column;
+        }
+        units += c.len_utf16();
+    }
+    line.len()
+}
+
+fn lsp_position(lines: &[Vec<char>], line: usize, column: usize) -> Json {
+    let character = lines.get(line).map_or(column, |chars| utf16_column(chars, column));
+    Json::object(vec![("line", line.into()), ("character", character.into())])
+}
+
+// Hover and documentation text: a string, a MarkupContent or a list of
+// MarkedStrings. The markdown code fences go, the rest reads fine as it is
+pub(crate) fn lsp_markup_text(contents: &Json) -> String {
+    let text = match contents {
+        Json::String(text) => text.clone(),
+        Json::Array(items) => items.iter()
+            .map(|item| item.as_str().or_else(|| item.get("value").as_str()).unwrap_or(""))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => contents.get("value").as_str().unwrap_or("").to_string(),
+    };
+    text.lines().filter(|line| !line.starts_with("```")).collect::<Vec<_>>().join("\n").trim().to_string()
+}
+
+// A protocol position as (column, line) in chars, clamped to the text
+fn buffer_position(lines: &[Vec<char>], position: &Json) -> (usize, usize) {
+    let line = position.get("line").as_usize();
+    if line >= lines.len() {
+        // Past the last line is the end of the text
+        let last = lines.len().saturating_sub(1);
+        return (lines.get(last).map_or(0, |chars| chars.len()), last);
+    }
+    (char_column(&lines[line], position.get("character").as_usize()), line)
+}
+
+// Apply TextEdits to the lines, last one first so the earlier ranges stay valid
+pub(crate) fn apply_text_edits(lines: &mut Vec<Vec<char>>, edits: &[Json]) {
+    let mut edits = edits.iter().map(|edit| {
+        let (start_column, start_line) = buffer_position(lines, edit.get("range").get("start"));
+        let (end_column, end_line) = buffer_position(lines, edit.get("range").get("end"));
+        ((start_line, start_column), (end_line, end_column), edit.get("newText").as_str().unwrap_or("").to_string())
+    }).collect::<Vec<_>>();
+    edits.sort_by_key(|edit| std::cmp::Reverse(edit.0));
+
+    for ((start_line, start_column), (end_line, end_column), text) in edits {
+        if lines.is_empty() {
+            lines.push(Vec::new());
+        }
+        let tail: Vec<char> = lines[end_line][end_column.min(lines[end_line].len())..].to_vec();
+        let mut head: Vec<char> = lines[start_line][..start_column.min(lines[start_line].len())].to_vec();
+        let mut inserted: Vec<Vec<char>> = text.split('\n').map(|line| line.trim_end_matches('\r').chars().collect()).collect();
+        head.extend(inserted.remove(0));
+        inserted.insert(0, head);
+        if let Some(last) = inserted.last_mut() {
+            last.extend(tail);
+        }
+        lines.splice(start_line..=end_line.max(start_line), inserted);
+    }
+}
+
+const LSP_SHUTDOWN_WAIT: Duration = Duration::from_secs(1);
+
+// Requests we are waiting an answer for, and what to do with it
+pub(crate) enum LspRequest {
+    Initialize,
+    Hover,
+    Definition,
+    References(String),
+    Rename,
+    CodeActions,
+    ResolveCodeAction,
+    Formatting,
+    Completion,
+    ResolveCompletion,
+}
+
+enum LspMessage {
+    Response { request: LspRequest, result: std::result::Result<Json, String> },
+    Notification { method: String, params: Json },
+    Request { id: Json, method: String, params: Json }, // From the server, it wants an answer
+}
+
+// A file as the server last saw it
+struct LspDocument {
+    version: i64,
+    lines: Vec<Vec<char>>,
+}
+
+// A language server speaking JSON-RPC over its stdin and stdout
+pub(crate) struct LspClient {
+    language: String,
+    root: PathBuf,
+    child: Child,
+    writer: Sender<String>, // Message bodies, for the thread writing to the server's stdin
+    receiver: Receiver<Json>,
+    next_id: i64,
+    pending: HashMap<i64, LspRequest>,
+    initialized: bool,
+    pub(crate) capabilities: Json,
+    documents: HashMap<PathBuf, LspDocument>,
+    queued: Vec<Json>, // Held back until the server answered initialize
+}
+
+impl LspClient {
+    fn start(language: &str, command: &str, root: PathBuf) -> io::Result<Self> {
+        let mut words = command.split_whitespace();
+        let program = words.next().ok_or_else(|| io::Error::other("Empty server command"))?;
+        let mut child = Command::new(program)
+            .args(words)
+            .current_dir(&root)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let mut stdin = child.stdin.take().ok_or_else(|| io::Error::other("No stdin"))?;
+        let stdout = child.stdout.take().ok_or_else(|| io::Error::other("No stdout"))?;
+
+        // A server busy with something else may stop reading for a while,
+        // the editor shouldn't wait for its pipe to have room
+        let (writer, bodies) = mpsc::channel::<String>();
+        std::thread::spawn(move || {
+            for body in bodies {
+                if write!(stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).and_then(|_| stdin.flush()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut reader = BufReader::new(stdout);
+            while let Some(message) = LspClient::read_message(&mut reader) {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut client = LspClient {
+            language: language.to_string(),
+            root,
+            child,
+            writer,
+            receiver,
+            next_id: 0,
+            pending: HashMap::new(),
+            initialized: false,
+            capabilities: Json::Null,
+            documents: HashMap::new(),
+            queued: Vec::new(),
+        };
+        client.request("initialize", client.initialize_params(), LspRequest::Initialize)?;
+        Ok(client)
+    }
+
+    // Content-Length framed messages, None once the server is gone
+    fn read_message(reader: &mut impl BufRead) -> Option<Json> {
+        loop {
+            let mut length = None;
+            loop {
+                let mut header = String::new();
+                if reader.read_line(&mut header).ok()? == 0 {
+                    return None;
+                }
+                let header = header.trim_end();
+                if header.is_empty() {
+                    break;
+                }
+                if let Some(value) = header.strip_prefix("Content-Length:") {
+                    length = value.trim().parse::<usize>().ok();
+                }
+            }
+            let mut body = vec![0; length?];
+            reader.read_exact(&mut body).ok()?;
+            // A message we can't read isn't worth stopping for
+            if let Ok(message) = Json::parse(&String::from_utf8_lossy(&body)) {
+                return Some(message);
+            }
+        }
+    }
+
+    fn initialize_params(&self) -> Json {
+        let root_uri = path_to_uri(&self.root);
+        let name = self.root.file_name().map_or(String::new(), |name| name.to_string_lossy().into_owned());
+        let capabilities = Json::parse(r#"{
+            "general": { "positionEncodings": ["utf-16"] },
+            "workspace": { "applyEdit": true, "workspaceEdit": { "documentChanges": true }, "configuration": true },
+            "textDocument": {
+                "synchronization": { "didSave": true, "dynamicRegistration": false },
+                "publishDiagnostics": { "relatedInformation": false },
+                "hover": { "contentFormat": ["plaintext", "markdown"] },
+                "definition": { "linkSupport": true },
+                "references": {},
+                "rename": { "prepareSupport": false },
+                "formatting": {},
+                "completion": { "completionItem": {
+                    "snippetSupport": false,
+                    "documentationFormat": ["plaintext", "markdown"],
+                    "resolveSupport": { "properties": ["documentation", "detail", "additionalTextEdits"] }
+                } },
+                "codeAction": {
+                    "codeActionLiteralSupport": { "codeActionKind": { "valueSet": [
+                        "", "quickfix", "refactor", "refactor.extract", "refactor.inline", "refactor.rewrite", "source", "source.organizeImports"
+                    ] } },
+                    "resolveSupport": { "properties": ["edit"] }
+                }
+            }
+        }"#).unwrap_or(Json::Null);
+
+        Json::object(vec![
+            ("processId", (std::process::id() as i64).into()),
+            ("rootUri", root_uri.clone().into()),
+            ("rootPath", self.root.to_string_lossy().into_owned().into()),
+            ("capabilities", capabilities),
+            ("workspaceFolders", vec![Json::object(vec![("uri", root_uri.into()), ("name", name.into())])].into()),
+        ])
+    }
+
+    // Only queued here, the writer thread does the writing
+    fn write(&mut self, message: &Json) -> io::Result<()> {
+        self.writer.send(message.to_string()).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the server stopped reading"))
+    }
+
+    // Everything but initialize waits for the handshake
+    fn send(&mut self, message: Json) -> io::Result<()> {
+        if self.initialized {
+            self.write(&message)
+        } else {
+            self.queued.push(message);
+            Ok(())
+        }
+    }
+
+    pub(crate) fn request(&mut self, method: &str, params: Json, request: LspRequest) -> io::Result<()> {
+        self.next_id += 1;
+        let message = Json::object(vec![
+            ("jsonrpc", "2.0".into()),
+            ("id", self.next_id.into()),
+            ("method", method.into()),
+            ("params", params),
+        ]);
+        let initialize = matches!(request, LspRequest::Initialize);
+        self.pending.insert(self.next_id, request);
+        if initialize { self.write(&message) } else { self.send(message) }
+    }
+
+    fn notify(&mut self, method: &str, params: Json) -> io::Result<()> {
+        self.send(Json::object(vec![("jsonrpc", "2.0".into()), ("method", method.into()), ("params", params)]))
+    }
+
+    fn respond(&mut self, id: Json, result: Json) -> io::Result<()> {
+        self.send(Json::object(vec![("jsonrpc", "2.0".into()), ("id", id), ("result", result)]))
+    }
+
+    fn alive(&mut self) -> bool {
+        matches!(self.child.try_wait(), Ok(None))
+    }
+
+    // What the server said since last time, the handshake is handled here
+    fn poll(&mut self) -> Vec<LspMessage> {
+        let mut messages = Vec::new();
+        while let Ok(message) = self.receiver.try_recv() {
+            let method = message.get("method").as_str().map(|method| method.to_string());
+            let id = message.get("id").clone();
+
+            match (method, id.is_null()) {
+                (Some(method), true) => messages.push(LspMessage::Notification { method, params: message.get("params").clone() }),
+                (Some(method), false) => messages.push(LspMessage::Request { id, method, params: message.get("params").clone() }),
+                (None, false) => {
+                    let Some(request) = id.as_i64().and_then(|id| self.pending.remove(&id)) else { continue };
+                    let result = match message.get("error") {
+                        Json::Null => Ok(message.get("result").clone()),
+                        error => Err(error.get("message").as_str().unwrap_or("Unknown error").to_string()),
+                    };
+                    if let LspRequest::Initialize = request {
+                        self.capabilities = result.as_ref().map_or(Json::Null, |result| result.get("capabilities").clone());
+                        self.initialized = true;
+                        let _ = self.write(&Json::object(vec![
+                            ("jsonrpc", "2.0".into()),
+                            ("method", "initialized".into()),
+                            ("params", Json::object(vec![])),
+                        ]));
+                        for queued in std::mem::take(&mut self.queued) {
+                            let _ = self.write(&queued);
+                        }
+                        continue;
+                    }
+                    messages.push(LspMessage::Response { request, result });
+                },
+                (None, true) => {},
+            }
+        }
+        messages
+    }
+
+    fn did_open(&mut self, path: &Path, lines: &[Vec<char>]) -> io::Result<()> {
+        if self.documents.contains_key(path) {
+            self.did_close(path)?;
+        }
+        let text = lines.iter().map(|line| line.iter().collect::<String>()).collect::<Vec<_>>().join("\n");
+        self.documents.insert(path.to_path_buf(), LspDocument { version: 1, lines: lines.to_vec() });
+        self.notify("textDocument/didOpen", Json::object(vec![("textDocument", Json::object(vec![
+            ("uri", path_to_uri(path).into()),
+            ("languageId", self.language.clone().into()),
+            ("version", 1i64.into()),
+            ("text", text.into()),
+        ]))]))
+    }
+
+    // Send what changed since the server last saw the document: one range
+    // covering the lines that differ, or the whole text if the server asked
+    // for full sync
+    fn did_change(&mut self, path: &Path, lines: &[Vec<char>]) -> io::Result<()> {
+        let sync = self.capabilities.get("textDocumentSync");
+        let full = sync.as_i64().or_else(|| sync.get("change").as_i64()) == Some(1);
+        let Some(document) = self.documents.get_mut(path) else { return Ok(()) };
+        if document.lines == lines {
+            return Ok(());
+        }
+
+        let old = &document.lines;
+        let text_of = |lines: &[Vec<char>]| lines.iter().map(|line| line.iter().collect::<String>()).collect::<Vec<_>>();
+        let prefix = old.iter().zip(lines).take_while(|(a, b)| a == b).count();
+        let room = old.len().min(lines.len()) - prefix;
+        let suffix = old.iter().rev().zip(lines.iter().rev()).take(room).take_while(|(a, b)| a == b).count();
+
+        // A buffer with no lines at all has no range to replace either
+        let change = if full || old.is_empty() {
+            Json::object(vec![("text", text_of(lines).join("\n").into())])
+        } else {
+            let (start, end, text) = if suffix > 0 {
+                let text: String = text_of(&lines[prefix..lines.len() - suffix]).iter().map(|line| format!("{}\n", line)).collect();
+                (lsp_position(old, prefix, 0), lsp_position(old, old.len() - suffix, 0), text)
+            } else if prefix > 0 {
+                // Nothing after the change, start at the end of the line before it
+                let text: String = text_of(&lines[prefix..]).iter().map(|line| format!("\n{}", line)).collect();
+                let last = old.len() - 1;
+                (lsp_position(old, prefix - 1, old[prefix - 1].len()), lsp_position(old, last, old[last].len()), text)
+            } else {
+                let last = old.len() - 1;
+                (lsp_position(old, 0, 0), lsp_position(old, last, old[last].len()), text_of(lines).join("\n"))
+            };
+            Json::object(vec![("range", Json::object(vec![("start", start), ("end", end)])), ("text", text.into())])
+        };
+
+        document.version += 1;
+        document.lines = lines.to_vec();
+        let version = document.version;
+        self.notify("textDocument/didChange", Json::object(vec![
+            ("textDocument", Json::object(vec![("uri", path_to_uri(path).into()), ("version", version.into())])),
+            ("contentChanges", vec![change].into()),
+        ]))
+    }
+
+    fn did_save(&mut self, path: &Path) -> io::Result<()> {
+        if !self.documents.contains_key(path) {
+            return Ok(());
+        }
+        self.notify("textDocument/didSave", Json::object(vec![("textDocument", Json::object(vec![("uri", path_to_uri(path).into())]))]))
+    }
+
+    fn did_close(&mut self, path: &Path) -> io::Result<()> {
+        self.documents.remove(path);
+        self.notify("textDocument/didClose", Json::object(vec![("textDocument", Json::object(vec![("uri", path_to_uri(path).into())]))]))
+    }
+}
+
+impl Drop for LspClient {
+    // Ask the server to shut down, and to exit once it agreed. One that takes
+    // longer than LSP_SHUTDOWN_WAIT is killed
+    fn drop(&mut self) {
+        if self.initialized && self.alive() {
+            let deadline = std::time::Instant::now() + LSP_SHUTDOWN_WAIT;
+            self.next_id += 1;
+            let id = self.next_id;
+            let shutdown = Json::object(vec![("jsonrpc", "2.0".into()), ("id", id.into()), ("method", "shutdown".into())]);
+            if self.write(&shutdown).is_ok() {
+                while let Some(left) = deadline.checked_duration_since(std::time::Instant::now()) {
+                    match self.receiver.recv_timeout(left) {
+                        Ok(message) if message.get("id").as_i64() == Some(id) && message.get("method").is_null() => {
+                            let _ = self.write(&Json::object(vec![("jsonrpc", "2.0".into()), ("method", "exit".into())]));
+                            break;
+                        },
+                        Ok(_) => {},
+                        Err(_) => break,
+                    }
+                }
+                while self.alive() && std::time::Instant::now() < deadline {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            }
+        }
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl Editor {
+    // Start the server for the file's language if needed and tell it about the file
+    pub(crate) fn lsp_open_document(&mut self) {
+        let Some(language) = language_id(&self.current_file_path) else { return };
+        let Some(command) = self.config.lsp_servers.get(language).cloned() else { return };
+        let path = self.current_file_canonical.clone();
+
+        if self.lsp.get_mut(language).is_some_and(|client| !client.alive()) {
+            self.lsp.remove(language);
+        }
+        if !self.lsp.contains_key(language) {
+            if self.lsp_failed.contains(language) {
+                return;
+            }
+            match LspClient::start(language, &command, project_root(&path)) {
+                Ok(client) => {
+                    self.lsp.insert(language.to_string(), client);
+                },
+                Err(e) => {
+                    self.lsp_failed.insert(language.to_string());
+                    self.error(&format!("Couldn't start {}: {}", command, e));
+                    return;
+                },
+            }
+        }
+
+        let result = self.lsp.get_mut(language).map(|client| client.did_open(&path, &self.buffer));
+        if let Some(Err(e)) = result {
+            self.error(&format!("Language server: {}", e));
+        }
+    }
+
+    // The server of the open file, with the file as the server knows it
+    pub(crate) fn lsp_current(&mut self) -> Option<(&mut LspClient, PathBuf)> {
+        let language = language_id(&self.current_file_path)?;
+        let path = self.current_file_canonical.clone();
+        let client = self.lsp.get_mut(language)?;
+        client.documents.contains_key(&path).then_some((client, path))
+    }
+
+    // Bring the server up to date with the buffer, then send a request about
+    // the cursor position
+    pub(crate) fn lsp_request(&mut self, method: &str, extra: Vec<(&str, Json)>, request: LspRequest) {
+        self.lsp_sync();
+        let position = lsp_position(&self.buffer, self.cursor_pos.1 as usize, self.cursor_pos.0 as usize);
+        let result = match self.lsp_current() {
+            Some((client, path)) => {
+                let mut params = vec![
+                    ("textDocument", Json::object(vec![("uri", path_to_uri(&path).into())])),
+                    ("position", position),
+                ];
+                params.extend(extra);
+                Some(client.request(method, Json::object(params), request))
+            },
+            None => None,
+        };
+
+        match result {
+            Some(Ok(())) => {},
+            Some(Err(e)) => self.error(&format!("Language server: {}", e)),
+            None => self.message("No language server for this buffer"),
+        }
+    }
+
+    pub(crate) fn lsp_sync(&mut self) {
+        if self.lsp.is_empty() || !matches!(self.mode, Mode::Normal | Mode::Insert | Mode::Visual) {
+            return;
+        }
+        let buffer = std::mem::take(&mut self.buffer);
+        let result = self.lsp_current().map(|(client, path)| client.did_change(&path, &buffer));
+        self.buffer = buffer;
+        if let Some(Err(e)) = result {
+            self.error(&format!("Language server: {}", e));
+        }
+    }
+
+    pub(crate) fn lsp_did_save(&mut self) {
+        self.lsp_sync();
+        let result = self.lsp_current().map(|(client, path)| client.did_save(&path));
+        if let Some(Err(e)) = result {
+            self.error(&format!("Language server: {}", e));
+        }
+    }
+
+    // Handle what the servers sent, true if anything happened
+    pub(crate) fn lsp_poll(&mut self) -> bool {
+        let mut messages = Vec::new();
+        for (language, client) in self.lsp.iter_mut() {
+            messages.extend(client.poll().into_iter().map(|message| (language.clone(), message)));
+        }
+        let changed = !messages.is_empty();
+
+        for (language, message) in messages {
+            match message {
+                LspMessage::Response { result: Err(e), .. } => self.error(&format!("Language server: {}", e)),
+                LspMessage::Response { request, result: Ok(result) } => self.lsp_response(&language, request, result),
+                LspMessage::Notification { method, params } => match method.as_str() {
+                    "textDocument/publishDiagnostics" => self.lsp_diagnostics(&params),
+                    "window/showMessage" => self.message(params.get("message").as_str().unwrap_or("")),
+                    "window/logMessage" => self.messages.push(params.get("message").as_str().unwrap_or("").to_string()),
+                    _ => {},
+                },
+                LspMessage::Request { id, method, params } => {
+                    let result = match method.as_str() {
+                        "workspace/applyEdit" => {
+                            self.apply_workspace_edit(params.get("edit"));
+                            Json::object(vec![("applied", true.into())])
+                        },
+                        "workspace/configuration" => vec![Json::Null; params.get("items").as_array().len()].into(),
+                        _ => Json::Null,
+                    };
+                    if let Some(client) = self.lsp.get_mut(&language) {
+                        let _ = client.respond(id, result);
+                    }
+                },
+            }
+        }
+        changed
+    }
+
+    fn lsp_diagnostics(&mut self, params: &Json) {
+        let path = uri_to_path(params.get("uri").as_str().unwrap_or(""));
+        let path = fs::canonicalize(&path).unwrap_or(path);
+        let current = self.current_file_canonical.clone();
+        let lines: &[Vec<char>] = if path == current { &self.buffer } else { &[] };
+
+        let mut diagnostics = Vec::new();
+        for diagnostic in params.get("diagnostics").as_array() {
+            let (column, line) = buffer_position(lines, diagnostic.get("range").get("start"));
+            let (end_column, end_line) = buffer_position(lines, diagnostic.get("range").get("end"));
+            let line = if lines.is_empty() { diagnostic.get("range").get("start").get("line").as_usize() } else { line };
+            diagnostics.push(Diagnostic {
+                source: DiagnosticSource::Lsp,
+                path: path.clone(),
+                line,
+                column: Some(column),
+                end: if end_line == line { Some(end_column) } else { None },
+                severity: match diagnostic.get("severity").as_i64() {
+                    Some(1) | None => Severity::Error,
+                    Some(2) => Severity::Warning,
+                    _ => Severity::Note,
+                },
+                message: diagnostic.get("message").as_str().unwrap_or("").lines().next().unwrap_or("").to_string(),
+            });
+        }
+
+        self.diagnostics.retain(|diagnostic| diagnostic.source != DiagnosticSource::Lsp || diagnostic.path != path);
+        self.diagnostics.extend(diagnostics);
+        self.diagnostic_echoed = None;
+    }
+
+    fn lsp_response(&mut self, language: &str, request: LspRequest, result: Json) {
+        match request {
+            LspRequest::Initialize => {},
+            LspRequest::Hover => {
+                let text = lsp_markup_text(result.get("contents"));
+                if text.is_empty() {
+                    self.message("No hover information");
+                } else {
+                    self.message(&text);
+                }
+            },
+            LspRequest::Definition => {
+                let locations: Vec<Json> = match &result {
+                    Json::Array(items) => items.clone(),
+                    Json::Null => Vec::new(),
+                    location => vec![location.clone()],
+                };
+                let Some(location) = locations.first() else {
+                    self.message("No definition found");
+                    return;
+                };
+                // Location or LocationLink
+                let uri = location.get("uri").as_str().or_else(|| location.get("targetUri").as_str()).unwrap_or("");
+                let range = if location.get("range").is_null() { location.get("targetSelectionRange") } else { location.get("range") };
+                self.lsp_jump(&uri_to_path(uri), range.get("start"));
+            },
+            LspRequest::References(symbol) => {
+                let root = self.lsp.get(language).map_or_else(|| project_root(&self.current_file_path), |client| client.root.clone());
+                let mut file_lines: HashMap<PathBuf, Vec<Vec<char>>> = HashMap::new();
+                let mut matches = Vec::new();
+                for location in result.as_array() {
+                    let path = uri_to_path(location.get("uri").as_str().unwrap_or(""));
+                    let lines = file_lines.entry(path.clone()).or_insert_with(|| {
+                        fs::read_to_string(&path).unwrap_or_default().lines().map(|line| line.chars().collect()).collect()
+                    });
+                    let (start, line) = buffer_position(lines, location.get("range").get("start"));
+                    let (end, end_line) = buffer_position(lines, location.get("range").get("end"));
+                    let text: String = lines.get(line).map_or(String::new(), |chars| chars.iter().collect());
+                    let end = if end_line == line { end } else { text.chars().count() };
+                    matches.push(GrepMatch { path, line: line + 1, columns: (start, end), text });
+                }
+                matches.sort_by(|a, b| (&a.path, a.line).cmp(&(&b.path, b.line)));
+                if matches.is_empty() {
+                    self.message("No references found");
+                } else {
+                    self.message(&format!("{} references to {}", matches.len(), symbol));
+                    self.grep = Some(Grep::finished(&format!("references to {}", symbol), root, matches));
+                    self.mode = Mode::Grep;
+                }
+            },
+            LspRequest::Rename => {
+                let files = self.apply_workspace_edit(&result);
+                self.message(&format!("Renamed in {} file{}", files, if files == 1 { "" } else { "s" }));
+            },
+            LspRequest::CodeActions => {
+                let actions: Vec<Json> = result.as_array().to_vec();
+                if actions.is_empty() {
+                    self.message("No code actions here");
+                    return;
+                }
+                let titles: Vec<String> = actions.iter().enumerate()
+                    .map(|(i, action)| format!("{}. {}", i + 1, action.get("title").as_str().unwrap_or("")))
+                    .collect();
+                let language = language.to_string();
+                self.completing_read(Completion::new("Code action: ", FzySource::Items(titles), Box::new(move |editor, choice| {
+                    let index = choice.split('.').next().and_then(|n| n.parse::<usize>().ok()).unwrap_or(0);
+                    if let Some(action) = actions.get(index.wrapping_sub(1)) {
+                        editor.lsp_run_code_action(&language, action);
+                    }
+                    Ok(())
+                })));
+            },
+            LspRequest::ResolveCodeAction => {
+                self.lsp_run_code_action(language, &result);
+            },
+            LspRequest::Formatting => {
+                let edits = result.as_array();
+                if edits.is_empty() {
+                    self.message("Already formatted");
+                    return;
+                }
+                let mut formatted = self.buffer.to_vec();
+                apply_text_edits(&mut formatted, edits);
+                if self.apply_formatted(formatted) {
+                    self.message("Formatted");
+                } else {
+                    self.message("Already formatted");
+                }
+            },
+            LspRequest::Completion => {
+                let Some(popup) = self.completion_popup.as_mut().filter(|popup| popup.waiting) else { return };
+                popup.waiting = false;
+                if self.cursor_pos.1 as usize != popup.line {
+                    return;
+                }
+                // A CompletionList or just the items. The server's come first,
+                // and the words it already has go
+                let items = if result.get("items").is_null() { result.as_array() } else { result.get("items").as_array() };
+                let mut items: Vec<CompletionItem> = items.iter().map(CompletionItem::from_lsp).collect();
+                let labels: HashSet<String> = items.iter().map(|item| item.label.clone()).collect();
+                items.extend(popup.items.drain(..).filter(|item| !labels.contains(&item.label)));
+                popup.items = items;
+
+                let line = &self.buffer[popup.line];
+                let x = (self.cursor_pos.0 as usize).clamp(popup.start.min(line.len()), line.len());
+                let typed: String = line[popup.start.min(x)..x].iter().collect();
+                popup.filter(&typed);
+                if popup.matches.is_empty() {
+                    self.completion_popup = None;
+                } else {
+                    self.completion_resolve();
+                }
+            },
+            LspRequest::ResolveCompletion => {
+                let Some(popup) = &mut self.completion_popup else { return };
+                let resolved = CompletionItem::from_lsp(&result);
+                let item = popup.items.iter_mut().find(|item| item.source == CompletionSource::Lsp && item.label == resolved.label);
+                if let Some(item) = item {
+                    item.detail = resolved.detail;
+                    item.documentation = resolved.documentation;
+                    if !resolved.additional_edits.is_empty() {
+                        item.additional_edits = resolved.additional_edits;
+                    }
+                }
+            },
+        }
+    }
+
+    fn lsp_jump(&mut self, path: &Path, position: &Json) {
+        let current = self.current_file_canonical.clone();
+        if fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()) != current {
+            if let Err(e) = self.open(&path.to_path_buf(), None) {
+                self.error(&format!("Failed to open {}: {}", path.display(), e));
+                return;
+            }
+        }
+        let (column, line) = buffer_position(&self.buffer, position);
+        self.cursor_pos = (column as u16, line as u16);
+        self.request_recenter();
+    }
+
+    // A CodeAction carries an edit, a command, or neither until resolved;
+    // a bare Command runs on the server
+    fn lsp_run_code_action(&mut self, language: &str, action: &Json) {
+        let command = if action.get("command").as_str().is_some() { action } else { action.get("command") };
+        let needs_resolve = action.get("edit").is_null() && command.is_null() && !action.get("data").is_null();
+
+        let Some(client) = self.lsp.get_mut(language) else { return };
+        let result = if needs_resolve {
+            client.request("codeAction/resolve", action.clone(), LspRequest::ResolveCodeAction)
+        } else if !command.is_null() {
+            client.notify("workspace/executeCommand", Json::object(vec![
+                ("command", command.get("command").clone()),
+                ("arguments", command.get("arguments").clone()),
+            ]))
+        } else {
+            Ok(())
+        };
+        if let Err(e) = result {
+            self.error(&format!("Language server: {}", e));
+        }
+        if !action.get("edit").is_null() {
+            self.apply_workspace_edit(action.get("edit"));
+        }
+    }
+
+    // Apply a WorkspaceEdit to the buffer and to the other files on disk,
+    // returns how many files changed
+    fn apply_workspace_edit(&mut self, edit: &Json) -> usize {
+        let mut file_edits: Vec<(PathBuf, Vec<Json>)> = Vec::new();
+        if let Json::Object(changes) = edit.get("changes") {
+            for (uri, edits) in changes {
+                file_edits.push((uri_to_path(uri), edits.as_array().to_vec()));
+            }
+        }
+        for change in edit.get("documentChanges").as_array() {
+            // Resource operations (create, rename, delete) are left alone
+            if let Some(uri) = change.get("textDocument").get("uri").as_str() {
+                file_edits.push((uri_to_path(uri), change.get("edits").as_array().to_vec()));
+            }
+        }
+
+        let current = self.current_file_canonical.clone();
+        let mut files = 0;
+        for (path, edits) in file_edits {
+            if fs::canonicalize(&path).unwrap_or_else(|_| path.clone()) == current {
+                apply_text_edits(&mut self.buffer, &edits);
+                self.lsp_buffer_edited();
+                files += 1;
+                continue;
+            }
+            let mut lines: Vec<Vec<char>> = match fs::read_to_string(&path) {
+                Ok(content) => content.lines().map(|line| line.chars().collect()).collect(),
+                Err(e) => {
+                    self.error(&format!("Couldn't edit {}: {}", path.display(), e));
+                    continue;
+                },
+            };
+            apply_text_edits(&mut lines, &edits);
+            let content = lines.iter().map(|line| line.iter().collect::<String>()).collect::<Vec<_>>().join("\n");
+            match fs::write(&path, content) {
+                Ok(()) => files += 1,
+                Err(e) => self.error(&format!("Couldn't edit {}: {}", path.display(), e)),
+            }
+        }
+        files
+    }
+
+    // After the server changed the buffer: keep the cursor inside it and make it undoable
+    pub(crate) fn lsp_buffer_edited(&mut self) {
+        if self.buffer.is_empty() {
+            self.buffer.push(Vec::new());
+        }
+        let line = (self.cursor_pos.1 as usize).min(self.buffer.len() - 1);
+        let column = (self.cursor_pos.0 as usize).min(self.buffer[line].len());
+        self.cursor_pos = (column as u16, line as u16);
+        self.snapshot();
+    }
+
+    pub fn lsp_hover(&mut self) {
+        self.lsp_request("textDocument/hover", Vec::new(), LspRequest::Hover);
+    }
+
+    pub fn lsp_goto_definition(&mut self) {
+        self.lsp_request("textDocument/definition", Vec::new(), LspRequest::Definition);
+    }
+
+    pub fn lsp_find_references(&mut self) {
+        let symbol = self.word_at_cursor();
+        self.lsp_request(
+            "textDocument/references",
+            vec![("context", Json::object(vec![("includeDeclaration", true.into())]))],
+            LspRequest::References(symbol),
+        );
+    }
+
+    pub fn lsp_rename(&mut self) {
+        let symbol = self.word_at_cursor();
+        self.minibuffer_open(Prompt::LspRename, &symbol);
+    }
+
+    pub fn lsp_code_actions(&mut self) {
+        let line = self.cursor_pos.1 as usize;
+        let range = Json::object(vec![
+            ("start", lsp_position(&self.buffer, line, 0)),
+            ("end", lsp_position(&self.buffer, line, self.buffer.get(line).map_or(0, |chars| chars.len()))),
+        ]);
+        // Hand back what the server reported on this line so it can offer fixes
+        let diagnostics: Vec<Json> = self.buffer_diagnostics().into_iter()
+            .filter(|diagnostic| diagnostic.source == DiagnosticSource::Lsp && diagnostic.line == line)
+            .map(|diagnostic| {
+                let start = diagnostic.column.unwrap_or(0);
+                Json::object(vec![
+                    ("range", Json::object(vec![
+                        ("start", lsp_position(&self.buffer, line, start)),
+                        ("end", lsp_position(&self.buffer, line, diagnostic.end.unwrap_or(start))),
+                    ])),
+                    ("message", diagnostic.message.clone().into()),
+                ])
+            })
+            .collect();
+        self.lsp_request(
+            "textDocument/codeAction",
+            vec![("range", range), ("context", Json::object(vec![("diagnostics", diagnostics.into())]))],
+            LspRequest::CodeActions,
+        );
+    }
+
+    pub fn lsp_format_buffer(&mut self) {
+        let options = Json::object(vec![
+            ("tabSize", self.config.indentation.into()),
+            ("insertSpaces", true.into()),
+        ]);
+        self.lsp_request("textDocument/formatting", vec![("options", options)], LspRequest::Formatting);
+    }
+
+    pub fn lsp_restart(&mut self) {
+        if let Some(language) = language_id(&self.current_file_path) {
+            self.lsp.remove(language);
+            self.lsp_failed.remove(language);
+            self.diagnostics.retain(|diagnostic| diagnostic.source != DiagnosticSource::Lsp);
+        }
+        self.lsp_open_document();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // examples/mock_lsp.rs, built once for all the tests since only a full
+    // cargo test builds the examples by itself
+    fn mock_server() -> PathBuf {
+        static SERVER: std::sync::OnceLock<PathBuf> = std::sync::OnceLock::new();
+        SERVER.get_or_init(|| {
+            let manifest = Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml");
+            let output = Command::new(env!("CARGO"))
+                .args(["build", "--example", "mock_lsp", "--message-format=json", "--manifest-path"])
+                .arg(&manifest)
+                .args(if cfg!(debug_assertions) { &[][..] } else { &["--release"][..] })
+                .stderr(Stdio::inherit())
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "building mock_lsp failed");
+            // The artifact message says where the binary went
+            String::from_utf8_lossy(&output.stdout).lines()
+                .filter_map(|line| Json::parse(line).ok())
+                .filter(|message| message.get("target").get("name").as_str() == Some("mock_lsp"))
+                .find_map(|message| message.get("executable").as_str().map(PathBuf::from))
+                .expect("cargo didn't say where mock_lsp went")
+        }).clone()
+    }
+
+    fn lines(text: &str) -> Vec<Vec<char>> {
+        text.split('\n').map(|line| line.chars().collect()).collect()
+    }
+
+    // A server done with the handshake, and a file it has open
+    fn start(name: &str, text: &str) -> (LspClient, PathBuf) {
+        let dir = crate::tests::temp_dir(name);
+        let path = dir.join("main.rs");
+        fs::write(&path, text).unwrap();
+        let command = format!("{} {}", mock_server().display(), dir.join("log").display());
+        let mut client = LspClient::start("rust", &command, dir).unwrap();
+        // Queued until the server answered initialize
+        client.did_open(&path, &lines(text)).unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !client.initialized {
+            assert!(std::time::Instant::now() < deadline, "no answer to initialize");
+            assert!(client.poll().is_empty());
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        (client, path)
+    }
+
+    fn ask(client: &mut LspClient, method: &str, path: &Path, position: (usize, usize), extra: Vec<(&str, Json)>) -> Json {
+        let mut params = vec![
+            ("textDocument", Json::object(vec![("uri", path_to_uri(path).into())])),
+            ("position", Json::object(vec![("line", position.0.into()), ("character", position.1.into())])),
+        ];
+        params.extend(extra);
+        client.request(method, Json::object(params), LspRequest::Hover).unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        loop {
+            for message in client.poll() {
+                if let LspMessage::Response { request: LspRequest::Hover, result } = message {
+                    return result.unwrap();
+                }
+            }
+            assert!(std::time::Instant::now() < deadline, "no answer to {}", method);
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    // The document as the server has it
+    fn server_text(client: &mut LspClient, path: &Path) -> String {
+        lsp_markup_text(ask(client, "textDocument/hover", path, (0, 0), Vec::new()).get("contents"))
+    }
+
+    #[test]
+    fn initialize_then_send_what_was_queued() {
+        let (mut client, path) = start("lsp-initialize", "fn main() {}");
+        assert_eq!(client.capabilities.get("hoverProvider"), &Json::Bool(true));
+        assert_eq!(client.capabilities.get("textDocumentSync").get("change").as_i64(), Some(2));
+        assert_eq!(server_text(&mut client, &path), "fn main() {}");
+    }
+
+    #[test]
+    fn did_change_sends_ranges_the_server_can_apply() {
+        let (mut client, path) = start("lsp-did-change", "fn main() {\n    a();\n}");
+        for text in [
+            "fn main() {\n    a();\n    b();\n}", // A line inserted
+            "fn main() {\n    b();\n}", // One removed
+            "fn main(x: u8) {\n    b();\n}", // The first one changed
+            "fn main(x: u8) {\n    b();\n}\n\nfn é() {}", // Lines added at the end
+            "fn main(x: u8) {\n    b();", // Removed from the end
+            "", // Everything removed
+            "😀 wide\nchars", // And back
+        ] {
+            client.did_change(&path, &lines(text)).unwrap();
+            assert_eq!(server_text(&mut client, &path), text);
+        }
+        // Not even an empty line, like a new file
+        client.did_change(&path, &[]).unwrap();
+        assert_eq!(server_text(&mut client, &path), "");
+        client.did_change(&path, &lines("typed")).unwrap();
+        assert_eq!(server_text(&mut client, &path), "typed");
+        assert_eq!(client.documents[&path].version, 10);
+    }
+
+    // More than a pipe holds, to a server that never reads
+    #[test]
+    fn a_server_not_reading_does_not_block_sending() {
+        let dir = crate::tests::temp_dir("lsp-not-reading");
+        let mut client = LspClient::start("rust", "sleep 10", dir.clone()).unwrap();
+        client.initialized = true;
+        let started = std::time::Instant::now();
+        let big = lines(&"x".repeat(1024 * 1024));
+        for i in 0..4 {
+            client.did_open(&dir.join(format!("{}.rs", i)), &big).unwrap();
+        }
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn hover_text_loses_the_code_fences() {
+        let (mut client, path) = start("lsp-hover", "let x = 1;");
+        let hover = ask(&mut client, "textDocument/hover", &path, (0, 4), Vec::new());
+        assert!(hover.get("contents").get("value").as_str().unwrap().starts_with("```"));
+        assert_eq!(lsp_markup_text(hover.get("contents")), "let x = 1;");
+    }
+
+    #[test]
+    fn definition_points_into_the_file() {
+        let (mut client, path) = start("lsp-definition", "fn helper() {}\nfn main() { helper(); }");
+        let location = ask(&mut client, "textDocument/definition", &path, (1, 14), Vec::new());
+        assert_eq!(uri_to_path(location.get("uri").as_str().unwrap()), fs::canonicalize(&path).unwrap());
+        let (column, line) = buffer_position(&lines("fn helper() {}"), location.get("range").get("start"));
+        assert_eq!((line, column), (0, 3));
+    }
+
+    #[test]
+    fn rename_edits_apply_to_the_buffer() {
+        let text = "let count = 1;\nlet total = count + count;";
+        let (mut client, path) = start("lsp-rename", text);
+        let edit = ask(&mut client, "textDocument/rename", &path, (1, 13), vec![("newName", "n".into())]);
+        let Json::Object(changes) = edit.get("changes") else { panic!("no changes") };
+        assert_eq!(changes.len(), 1);
+        assert_eq!(uri_to_path(&changes[0].0), fs::canonicalize(&path).unwrap());
+        let mut buffer = lines(text);
+        apply_text_edits(&mut buffer, changes[0].1.as_array());
+        assert_eq!(buffer, lines("let n = 1;\nlet total = n + n;"));
+    }
+
+    #[test]
+    fn drop_shuts_the_server_down() {
+        let (client, path) = start("lsp-drop", "");
+        let log = path.with_file_name("log");
+        drop(client);
+        let methods = fs::read_to_string(log).unwrap();
+        assert_eq!(methods.lines().collect::<Vec<_>>(), vec!["initialize", "initialized", "textDocument/didOpen", "shutdown", "exit"]);
+    }
+}
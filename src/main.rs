@@ -2,24 +2,43 @@ use crossterm::{
     cursor::{self, MoveTo}, event::{self, poll, Event, KeyCode, KeyEvent, KeyModifiers, ModifierKeyCode}, execute, style::{Attribute, Color, Print, ResetColor, SetAttribute, SetBackgroundColor, SetForegroundColor}, terminal::{self, disable_raw_mode, enable_raw_mode, size, ClearType}
 };
 
-use std::{io::{self, stdout, Stdout, Write}, vec};
+use std::{io::{self, stdout, BufRead, BufReader, Read, Stdout, Write}, vec};
 use std::io::Result;
 use std::env;
 use std::fs;
 use std::path::Path;
 
-use std::fs::DirEntry;
 use std::path::PathBuf;
 use chrono::{DateTime, Local};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use std::time::Duration;
-
-use std::process::Command;
-use regex::Regex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::os::unix::process::CommandExt;
+use regex::{Regex, RegexBuilder};
+
+mod compilation;
+use compilation::*;
+mod json;
+use json::*;
+mod lsp;
+use lsp::*;
+mod completion;
+use completion::*;
+mod git;
+use git::*;
+mod dired;
+use dired::*;
+mod fzy;
+use fzy::*;
 
 // TODO fzy find in M-x 
-// TODO wdired
 // TODO per project rust local documentation explorer
 // TODO revert_buffer_mode
 // TODO the modeline will eb a single line buffer fully configurble in lua
@@ -36,13 +55,17 @@ use regex::Regex;
 // extern crate tree_sitter_rust;
 
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, PartialEq)]
 enum Mode {
     Normal,
     Insert,
     Dired,
     Visual,
     Git,
+    Grep,
+    Compilation,
+    Blame,
+    GitLog,
 }
 
 // (for rainbow mode) TODO MOVEME
@@ -55,221 +78,6 @@ fn calculate_luminance(color: &Color) -> u8 {
     }
 }
 
-struct Dired {
-    current_path: PathBuf,
-    entries: Vec<DirEntry>,
-    cursor_pos: u16,
-    entry_first_char_column: u16,
-    color_dired: bool,
-}
-
-impl Dired {
-    fn new(current_path: PathBuf, focus: Option<&str>) -> io::Result<Self> {
-        let entries = Dired::list_directory_contents(&current_path)?;
-        let mut cursor_pos = if entries.len() == 0 { 0 } else { 2 }; // Skip '.' and '..'
-
-        // If a focus is specified, attempt to find it in the list and set the cursor position
-        if let Some(dir_name) = focus {
-            for (i, entry) in entries.iter().enumerate() {
-                if entry.file_name().to_str() == Some(dir_name) {
-                    cursor_pos = i as u16 + 2; // Adjust for '.' and '..' being at positions 0 and 1
-                    break;
-                }
-            }
-        }
-
-        Ok(Dired {
-            current_path,
-            entries,
-            cursor_pos,
-            entry_first_char_column: 0,
-            color_dired: true,
-        })
-    }
-
-    fn list_directory_contents(path: &PathBuf) -> io::Result<Vec<DirEntry>> {
-        let mut entries = Vec::new();
-        for entry in fs::read_dir(path)? {
-            let entry = entry?;
-            entries.push(entry);
-        }
-        Ok(entries)
-    }
-
-    
-
-    fn refresh_directory_contents(&mut self) -> io::Result<()> {
-        self.entries = Dired::list_directory_contents(&self.current_path)?;
-        Ok(())
-    }
-
-    pub fn create_directory(&mut self, dir_name: &str) -> io::Result<()> {
-        let new_dir_path = self.current_path.join(dir_name);
-        fs::create_dir(&new_dir_path)?;
-        Ok(())
-    }
-
-    pub fn delete_entry(&mut self) -> io::Result<()> {
-        // Ensure its not '.' or '..'
-        if self.cursor_pos > 1 && (self.cursor_pos as usize - 2) < self.entries.len() {
-            let entry_to_delete = &self.entries[self.cursor_pos as usize - 2];
-            let path_to_delete = entry_to_delete.path();
-
-            if path_to_delete.is_dir() {
-                // Recursive
-                fs::remove_dir_all(&path_to_delete)?;
-            } else {
-                fs::remove_file(&path_to_delete)?;
-            }
-
-            self.refresh_directory_contents()?;
-
-            // Check if the deleted entry was the last one
-            if self.cursor_pos as usize - 2 >= self.entries.len() {
-                self.cursor_pos -= 1;
-            }
-        }
-
-        Ok(())
-    }
-
-    pub fn rename_entry(&mut self, new_name: &str) -> io::Result<()> {
-        if self.cursor_pos > 1 && (self.cursor_pos as usize - 2) < self.entries.len() { // Skipping '.' and '..'
-            let entry_to_rename = &self.entries[self.cursor_pos as usize - 2];
-            let original_path = entry_to_rename.path();
-            let new_path = original_path.parent().unwrap().join(new_name);
-            fs::rename(&original_path, &new_path)?;
-            self.refresh_directory_contents()?;
-        } else {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid selection for rename."));
-        }
-
-        Ok(())
-    }
-
-    // TODO color file extentions if color_dired is true
-    // TODO scrolling, it overlap the modeline..
-    pub fn draw_dired(&mut self, stdout: &mut Stdout, height: u16, theme: &Theme) -> io::Result<()> {
-        let display_path = self.current_path.display().to_string();
-        let trimmed_path = display_path.trim_end_matches('/');
-        execute!(
-            stdout,
-            MoveTo(3, 0),
-            SetForegroundColor(theme.dired_path_color),
-            SetBackgroundColor(theme.background_color),
-            Print(format!("{}:", trimmed_path)),
-            ResetColor
-        )?;
-
-        let mut line_number = 2u16;
-
-        let entries = vec![".".into(), "..".into()]
-            .into_iter()
-            .chain(self.entries.iter().map(|e| e.file_name().to_str().unwrap_or("").to_string()))
-            .collect::<Vec<String>>();
-
-        // Determine the maximum length of file size
-        let max_size_length = entries.iter()
-            .map(|entry_name| {
-                let path = self.current_path.join(entry_name);
-                fs::metadata(&path).map(|m| m.len().to_string().len()).unwrap_or(0)
-            })
-            .max()
-            .unwrap_or(0);
-
-        self.entry_first_char_column = 35 + max_size_length as u16;
-
-        for entry_name in &entries {
-            if line_number >= height - 1 { break; }
-
-            let path = self.current_path.join(entry_name);
-            let metadata = fs::metadata(&path)?;
-            let is_dir = metadata.is_dir();
-            let file_type_char = if is_dir { "d" } else { "-" };
-            let permissions = "rwxr-xr-x";
-            let size = metadata.len();
-            let modified: DateTime<Local> = DateTime::from(metadata.modified()?);
-            let owner = "l l";
-            let size_str = format!("{:1$}", size, max_size_length);
-
-            let entry_color = if entry_name == "." || entry_name == ".." || is_dir {
-                if self.color_dired {
-                    // theme.normal_cursor_color
-                    theme.dired_dir_color
-                } else {
-                    theme.dired_dir_color
-                }
-            } else {
-                theme.text_color
-            };
-
-            execute!(stdout, MoveTo(5, line_number))?;
-
-            if self.color_dired {
-                execute!(
-                    stdout,
-                    SetForegroundColor(entry_color),
-                    SetBackgroundColor(theme.background_color),
-                    Print(file_type_char),
-                    ResetColor
-                )?;
-            } else {
-                execute!(
-                    stdout,
-                    SetForegroundColor(theme.text_color),
-                    Print(file_type_char),
-                    ResetColor
-                )?;
-            }
-
-            if self.color_dired {
-                for ch in permissions.chars() {
-                    let color = match ch {
-                        'r' => theme.warning_color,
-                        'w' => theme.error_color,
-                        'x' => theme.ok_color,
-                        '-' => theme.comment_color,
-                        _ => theme.text_color, // Default color
-                    };
-                    execute!(
-                        stdout,
-                        SetForegroundColor(color),
-                        SetBackgroundColor(theme.background_color),
-                        Print(ch)
-                    )?;
-                }
-            } else {
-                execute!(
-                    stdout,
-                    SetForegroundColor(theme.text_color),
-                    SetBackgroundColor(theme.background_color),
-                    Print(permissions),
-                    ResetColor
-                )?;
-            }
-
-            execute!(
-                stdout,
-                ResetColor,
-                SetBackgroundColor(theme.background_color),
-                Print(" "),
-                SetForegroundColor(theme.text_color), Print(format!("{:<3} ", owner)),
-                SetForegroundColor(if self.color_dired { theme.dired_size_color } else { theme.text_color }), Print(format!("{} ", size_str)),
-                SetForegroundColor(if self.color_dired { theme.dired_timestamp_color } else { theme.text_color }), Print(format!("{:14}", modified.format("%b %d %H:%M"))),
-                SetForegroundColor(entry_color), Print(format!("{}", entry_name)),
-                ResetColor
-            )?;
-
-            line_number += 1;
-        }
-
-        Ok(())
-    }
-}
-
-
-
-
 
 use mlua::{Lua, Result as LuaResult};
 
@@ -299,6 +107,21 @@ struct Config {
     scroll_bar_mode: bool,
     max_minibuffer_height: u16,
     emacs_scrolling: bool,
+    search_smart_case: bool,
+    lsp_servers: HashMap<String, String>, // Language id to server command
+    completion_auto: bool,
+    completion_delay: u64, // Milliseconds after the last key before the popup opens
+    completion_min_prefix: usize,
+    formatters: HashMap<String, String>, // Language id to a command that formats stdin to stdout
+    format_on_save: bool,
+    dired_sort: DiredSort,
+    dired_directories_first: bool,
+    dired_show_hidden: bool,
+    dired_preview: bool,
+    dired_preview_width: u16, // Percent of the screen
+    icons: Icons,
+    dired_icons: bool,
+    fzy_icons: bool,
 }
 
 impl Config {
@@ -328,6 +151,24 @@ impl Config {
             max_minibuffer_height: 30,
             compile_command: "make -k".to_string(),
             emacs_scrolling: true,
+            search_smart_case: true,
+            lsp_servers: HashMap::from([("rust".to_string(), "rust-analyzer".to_string())]),
+            completion_auto: true,
+            completion_delay: 150,
+            completion_min_prefix: 2,
+            formatters: HashMap::from([
+                ("rust".to_string(), "rustfmt --edition 2021".to_string()),
+                ("lua".to_string(), "stylua -".to_string()),
+            ]),
+            format_on_save: false,
+            dired_sort: DiredSort::Name,
+            dired_directories_first: true,
+            dired_show_hidden: true,
+            dired_preview: false,
+            dired_preview_width: 50,
+            icons: Icons::new(),
+            dired_icons: true,
+            fzy_icons: true,
         };
         
         if let Some(path) = lua_script_path {
@@ -345,7 +186,6 @@ impl Config {
             let initial_theme_name: String = globals.get("Theme").unwrap_or("wal".to_string());
 
 
-
             for pair in lua_themes.pairs::<String, mlua::Table>() {
                 let (name, theme_table) = pair?;
                 let theme = Theme {
@@ -415,6 +255,21 @@ impl Config {
                 scroll_bar_mode: globals.get("Scroll_bar_mode").unwrap_or(defaults.scroll_bar_mode),
                 max_minibuffer_height: globals.get("Max_minibuffer_height").unwrap_or(defaults.max_minibuffer_height),
                 emacs_scrolling: globals.get("Emacs_scrolling").unwrap_or(defaults.emacs_scrolling),
+                search_smart_case: globals.get("Search_smart_case").unwrap_or(defaults.search_smart_case),
+                lsp_servers: lua_string_table(&globals, "Lsp_servers").unwrap_or_else(|| defaults.lsp_servers.clone()),
+                completion_auto: globals.get("Completion_auto").unwrap_or(defaults.completion_auto),
+                completion_delay: globals.get("Completion_delay").unwrap_or(defaults.completion_delay),
+                completion_min_prefix: globals.get("Completion_min_prefix").unwrap_or(defaults.completion_min_prefix),
+                formatters: lua_string_table(&globals, "Formatters").unwrap_or_else(|| defaults.formatters.clone()),
+                format_on_save: globals.get("Format_on_save").unwrap_or(defaults.format_on_save),
+                dired_sort: globals.get::<_, String>("Dired_sort").ok().and_then(|name| DiredSort::from_name(&name)).unwrap_or(defaults.dired_sort),
+                dired_directories_first: globals.get("Dired_directories_first").unwrap_or(defaults.dired_directories_first),
+                dired_show_hidden: globals.get("Dired_show_hidden").unwrap_or(defaults.dired_show_hidden),
+                dired_preview: globals.get("Dired_preview").unwrap_or(defaults.dired_preview),
+                dired_preview_width: globals.get("Dired_preview_width").unwrap_or(defaults.dired_preview_width),
+                icons: defaults.icons.with_lua(&globals),
+                dired_icons: globals.get("Dired_icons").unwrap_or(defaults.dired_icons),
+                fzy_icons: globals.get("Fzy_icons").unwrap_or(defaults.fzy_icons),
 
             })
         } else {
@@ -424,16 +279,212 @@ impl Config {
 }
 
 
+// A Lua table of strings by name, like Lsp_servers = { rust = "rust-analyzer" }
+fn lua_string_table(globals: &mlua::Table, name: &str) -> Option<HashMap<String, String>> {
+    let table: mlua::Table = globals.get(name).ok()?;
+    Some(table.pairs::<String, String>().filter_map(|pair| pair.ok()).collect())
+}
+
+// An icon and its color, no color draws it like the name
+type Icon = (String, Option<Color>);
+
+// File-type icons for dired and fzy. A file goes by its name, then its
+// extension, a directory by its name. Without a Nerd Font every file is
+// drawn as "-" and every directory as "+", in the same colors
+#[derive(Clone)]
+struct Icons {
+    nerd_font: bool,
+    filenames: HashMap<String, Icon>,
+    extensions: HashMap<String, Icon>,
+    directories: HashMap<String, Icon>,
+}
+
+impl Icons {
+    const FILE: &'static str = "󰈚";
+    const DIRECTORY: &'static str = "󰉋";
+
+    fn new() -> Self {
+        let table = |icons: &[(&str, &str, Option<&str>)]| -> HashMap<String, Icon> {
+            icons.iter().map(|(name, icon, color)| (name.to_string(), (icon.to_string(), color.and_then(|color| hex_to_rgb(color).ok())))).collect()
+        };
+        Icons {
+            nerd_font: true,
+            filenames: table(&[
+                ("Cargo.lock", "󰌾", None),
+                ("Makefile", "", Some("#6D8086")),
+                ("LICENSE", "", Some("#CBCB41")),
+                (".gitignore", "", Some("#F54D27")),
+                ("Dockerfile", "󰡨", Some("#458EE6")),
+            ]),
+            extensions: table(&[
+                ("rs", "", Some("#DEA584")),
+                ("lua", "", Some("#51A0CF")),
+                ("org", "", Some("#77AA99")),
+                ("lock", "󰌾", None),
+                ("toml", "", None),
+                ("json", "", Some("#CBCB41")),
+                ("md", "", None),
+                ("txt", "󰈙", None),
+                ("c", "", Some("#599EFF")),
+                ("h", "", Some("#A074C4")),
+                ("py", "", Some("#FFBC03")),
+                ("js", "", Some("#CBCB41")),
+                ("ts", "", Some("#519ABA")),
+                ("sh", "", Some("#4D5A5E")),
+                ("html", "", Some("#E44D26")),
+                ("css", "", Some("#42A5F5")),
+                ("yml", "", Some("#6D8086")),
+                ("yaml", "", Some("#6D8086")),
+                ("png", "", Some("#A074C4")),
+                ("jpg", "", Some("#A074C4")),
+                ("pdf", "", Some("#B30B00")),
+                ("zip", "", Some("#ECA517")),
+                ("gz", "", Some("#ECA517")),
+            ]),
+            directories: table(&[
+                ("..", "󱚁", None),
+                (".git", "", None),
+                ("node_modules", "", Some("#E8274B")),
+                ("target", "󱧽", None),
+            ]),
+        }
+    }
+
+    // Icons = { extensions = { rs = { "", "#DEA584" } }, filenames = ..., directories = ... }
+    // on top of the built-in ones, a color is optional
+    fn with_lua(mut self, globals: &mlua::Table) -> Self {
+        let Ok(table) = globals.get::<_, mlua::Table>("Icons") else { return self };
+        for name in ["filenames", "extensions", "directories"] {
+            let Ok(entries) = table.get::<_, mlua::Table>(name) else { continue };
+            for (key, entry) in entries.pairs::<String, mlua::Table>().filter_map(|pair| pair.ok()) {
+                let Ok(icon) = entry.get::<_, String>(1) else { continue };
+                let color = entry.get::<_, String>(2).ok().and_then(|color| hex_to_rgb(&color).ok());
+                self.set(name, key, (icon, color));
+            }
+        }
+        self.nerd_font = globals.get("Icons_nerd_font").unwrap_or(self.nerd_font);
+        self
+    }
+
+    // One icon of the "filenames", "extensions" or "directories" table,
+    // over the built-in one
+    fn set(&mut self, table: &str, key: String, icon: Icon) {
+        let icons = match table {
+            "filenames" => &mut self.filenames,
+            "extensions" => &mut self.extensions,
+            "directories" => &mut self.directories,
+            _ => return,
+        };
+        icons.insert(key, icon);
+    }
+
+    // The icon to draw before a name, and its color
+    fn lookup(&self, name: &str, is_dir: bool, default_color: Color) -> (&str, Color) {
+        let found = if is_dir {
+            self.directories.get(name)
+        } else {
+            let extension = Path::new(name).extension().map(|extension| extension.to_string_lossy().to_lowercase());
+            self.filenames.get(name).or_else(|| extension.and_then(|extension| self.extensions.get(&extension)))
+        };
+        let color = found.and_then(|(_, color)| *color).unwrap_or(default_color);
+        let icon = match found {
+            _ if !self.nerd_font => if is_dir { "+" } else { "-" },
+            Some((icon, _)) => icon.as_str(),
+            None => if is_dir { Icons::DIRECTORY } else { Icons::FILE },
+        };
+        (icon, color)
+    }
+}
+
+// The lines of the file. Each mutable borrow counts as an edit, so what is
+// worked out from the text knows when to work it out again
+#[derive(Clone, Default)]
+struct Buffer {
+    lines: Vec<Vec<char>>,
+    edits: u64,
+}
+
+impl std::ops::Deref for Buffer {
+    type Target = Vec<Vec<char>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.lines
+    }
+}
+
+impl std::ops::DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.edits += 1;
+        &mut self.lines
+    }
+}
+
+impl PartialEq<Vec<Vec<char>>> for Buffer {
+    fn eq(&self, other: &Vec<Vec<char>>) -> bool {
+        self.lines == *other
+    }
+}
+
+impl PartialEq<Buffer> for Vec<Vec<char>> {
+    fn eq(&self, other: &Buffer) -> bool {
+        *self == other.lines
+    }
+}
+
 struct UndoState {
     buffer: Vec<Vec<char>>,
     cursor_pos: (u16, u16),
 }
 
+// The states undo and redo move through
+struct UndoHistory {
+    states: Vec<UndoState>,
+    current: usize,
+}
+
+impl UndoHistory {
+    fn new() -> Self {
+        UndoHistory { states: vec![UndoState { buffer: vec![vec![]], cursor_pos: (0, 0) }], current: 0 }
+    }
+
+    // A new state unless nothing changed since the last one. What was undone
+    // before it is dropped
+    // TODO LATER Don't discard the branches build a tree like emacs does
+    fn snapshot(&mut self, buffer: &[Vec<char>], cursor_pos: (u16, u16)) {
+        if self.states.last().is_some_and(|last| last.buffer == buffer && last.cursor_pos == cursor_pos) {
+            return;
+        }
+        self.states.truncate(self.current + 1);
+        self.states.push(UndoState { buffer: buffer.to_vec(), cursor_pos });
+        self.current = self.states.len() - 1;
+    }
+
+    fn undo(&mut self) -> Option<&UndoState> {
+        self.current = self.current.checked_sub(1)?;
+        Some(&self.states[self.current])
+    }
+
+    fn redo(&mut self) -> Option<&UndoState> {
+        if self.current + 1 >= self.states.len() {
+            return None;
+        }
+        self.current += 1;
+        Some(&self.states[self.current])
+    }
+
+    // Only the state the buffer is in now, for a newly opened file
+    fn reset(&mut self, buffer: &[Vec<char>], cursor_pos: (u16, u16)) {
+        self.states = vec![UndoState { buffer: buffer.to_vec(), cursor_pos }];
+        self.current = 0;
+    }
+}
+
 
 struct Keychords {
     ctrl_x_pressed: bool,
     ctrl_c_pressed: bool,
     ctrl_h_pressed: bool,
+    meta_g_pressed: bool,
     leader_key_active: bool,
     toggle_category_active: bool,
 }
@@ -444,6 +495,7 @@ impl Keychords {
             ctrl_x_pressed: false,
             ctrl_c_pressed: false,
             ctrl_h_pressed: false,
+            meta_g_pressed: false,
             leader_key_active: false,
             toggle_category_active: false,
         }
@@ -453,6 +505,7 @@ impl Keychords {
         self.ctrl_x_pressed = false;
         self.ctrl_c_pressed = false;
         self.ctrl_h_pressed = false;
+        self.meta_g_pressed = false;
         self.leader_key_active = false;
         self.toggle_category_active = false;
     }
@@ -461,112 +514,1164 @@ impl Keychords {
         !self.ctrl_x_pressed
             && !self.ctrl_c_pressed
             && !self.ctrl_h_pressed
+            && !self.meta_g_pressed
             && !self.leader_key_active
             && !self.toggle_category_active 
     }
 }
 
 
-#[derive(Debug)]
-struct Highlight {
-    start: usize,
-    end: usize,
-    color: Color,
+// Smart case: the search ignores case unless the query has an uppercase letter
+fn build_search_regex(query: &str, regex_mode: bool, smart_case: bool) -> std::result::Result<Regex, regex::Error> {
+    let pattern = if regex_mode { query.to_string() } else { regex::escape(query) };
+    let case_insensitive = smart_case && !query.chars().any(|c| c.is_uppercase());
+    RegexBuilder::new(&pattern).case_insensitive(case_insensitive).build()
 }
 
-struct SyntaxHighlighter {
-    parser: tree_sitter::Parser,
-    tree: Option<tree_sitter::Tree>,
-    highlights: Vec<Highlight>,
+// The compiled query and its matches, only rebuilt when the query or the
+// buffer changed
+struct SearchCache {
+    key: (String, bool, bool), // Query, regex mode, smart case
+    edits: u64, // Of the buffer the matches are for
+    regex: Option<Regex>, // None when the query doesn't compile
+    matches: Vec<(usize, usize, usize)>, // Line and char range, in buffer order
 }
 
-impl SyntaxHighlighter {
+// Incremental search state, the query lives here between searches so n/N can reuse it
+struct Search {
+    query: String,
+    regex_mode: bool,
+    origin: (u16, u16), // Cursor position when the search started
+    wrapped: bool,
+    failing: bool,
+    cache: Option<SearchCache>,
+}
+
+impl Search {
     fn new() -> Self {
-        let mut parser = tree_sitter::Parser::new();
-        parser.set_language(tree_sitter_rust::language()).expect("Error loading Rust grammar");
-        SyntaxHighlighter {
-            parser,
-            tree: None,
-            highlights:  Vec::new()
+        Search {
+            query: String::new(),
+            regex_mode: false,
+            origin: (0, 0),
+            wrapped: false,
+            failing: false,
+            cache: None,
         }
     }
-    
-    pub fn parse(&mut self, buffer: &[Vec<char>]) {
-        let source_code: String = buffer.iter()
-            .map(|line| line.iter().collect::<String>() + "\n")
-            .collect();
 
-        self.tree = self.parser.parse(&source_code, None);
+    // Called before the matches are used. Nothing is redone while the query
+    // and the buffer's edit count stay the same
+    fn refresh(&mut self, buffer: &[Vec<char>], edits: u64, smart_case: bool) {
+        let key = (self.query.clone(), self.regex_mode, smart_case);
+        let regex = match self.cache.take() {
+            Some(cache) if cache.key == key && cache.edits == edits => {
+                self.cache = Some(cache);
+                return;
+            },
+            Some(cache) if cache.key == key => cache.regex,
+            _ if self.query.is_empty() => None,
+            _ => build_search_regex(&self.query, self.regex_mode, smart_case).ok(),
+        };
+        let matches = regex.as_ref().map_or(Vec::new(), |re| {
+            buffer.iter().enumerate()
+                .flat_map(|(line, text)| Search::visible_matches(re, text).into_iter().map(move |(start, end)| (line, start, end)))
+                .collect()
+        });
+        self.cache = Some(SearchCache { key, edits, regex, matches });
     }
 
-    fn get_color_for_node_kind(&self, kind: &str, theme: &Theme) -> Option<Color> {
-        match kind {
-            "let_condition" => Some(theme.dired_path_color),
-            "string_literal" => Some(theme.string_color),
-            "use_declaration" => Some(theme.use_color),
-            _ => None,
+    fn regex(&self) -> Option<&Regex> {
+        self.cache.as_ref()?.regex.as_ref()
+    }
+
+    fn matches(&self) -> &[(usize, usize, usize)] {
+        self.cache.as_ref().map_or(&[], |cache| &cache.matches)
+    }
+
+    // Char ranges of the matches in a line. Empty ones are kept so ^, $
+    // and \b can be replaced, searching skips them (see `visible_matches`)
+    fn line_matches(re: &Regex, line: &[char]) -> Vec<(usize, usize)> {
+        let line_content: String = line.iter().collect();
+        re.find_iter(&line_content)
+            .map(|m| (line_content[..m.start()].chars().count(), line_content[..m.end()].chars().count()))
+            .collect()
+    }
+
+    // The matches the cursor can stop on and the highlight can show
+    fn visible_matches(re: &Regex, line: &[char]) -> Vec<(usize, usize)> {
+        let mut matches = Search::line_matches(re, line);
+        matches.retain(|(start, end)| start < end);
+        matches
+    }
+}
+
+// State of an ongoing replace-string, replace-regexp or query-replace
+struct Replace {
+    regex_mode: bool,
+    query: bool,
+    from: Option<String>, // None while we are still reading the pattern
+    region: Option<((u16, u16), (u16, u16))>, // Inclusive, (col, line) like the selection
+    re: Option<Regex>,
+    to: String,
+    current: Option<(u16, u16, u16)>, // line, start, end of the match we are asking about
+    answers: Vec<(usize, Vec<char>, (u16, u16), bool)>, // line, line before, cursor, replaced? (for ^)
+    count: usize,
+}
+
+impl Replace {
+    fn new(regex_mode: bool, query: bool, region: Option<((u16, u16), (u16, u16))>) -> Self {
+        Replace {
+            regex_mode,
+            query,
+            from: None,
+            region,
+            re: None,
+            to: String::new(),
+            current: None,
+            answers: Vec::new(),
+            count: 0,
         }
     }
 
+    fn prompt(&self) -> String {
+        let mut prompt = match (self.query, self.regex_mode) {
+            (true, true) => "Query replace regexp",
+            (true, false) => "Query replace",
+            (false, true) => "Replace regexp",
+            (false, false) => "Replace string",
+        }.to_string();
+
+        if self.region.is_some() {
+            prompt.push_str(" in region");
+        }
+        match &self.from {
+            Some(from) => format!("{} {} with: ", prompt, from),
+            None => format!("{}: ", prompt),
+        }
+    }
 
+    // Emacs style replacement (\1, \&) to the regex crate syntax (${1}, ${0})
+    fn template(&self) -> String {
+        if !self.regex_mode {
+            return self.to.replace('$', "$$");
+        }
 
-    pub fn update_syntax_highlights(&mut self, theme: &Theme) {
-        let mut highlights = std::mem::take(&mut self.highlights); // Temporarily take highlights out
+        let mut template = String::new();
+        let mut chars = self.to.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.peek().copied() {
+                    Some(d) if d.is_ascii_digit() => {
+                        chars.next();
+                        template.push_str(&format!("${{{}}}", d));
+                    },
+                    Some('&') => {
+                        chars.next();
+                        template.push_str("${0}");
+                    },
+                    Some('\\') => {
+                        chars.next();
+                        template.push('\\');
+                    },
+                    _ => template.push('\\'),
+                },
+                '$' => template.push_str("$$"),
+                _ => template.push(c),
+            }
+        }
+        template
+    }
 
-        if let Some(tree) = &self.tree {
-            let root_node = tree.root_node();
-            self.collect_highlights(&root_node, &mut highlights, theme); // Work with the taken highlights
+    fn in_region(&self, line: usize, start: usize, end: usize) -> bool {
+        match self.region {
+            Some((region_start, region_end)) => {
+                let after_start = (line, start) >= (region_start.1 as usize, region_start.0 as usize);
+                let before_end = (line, end) <= (region_end.1 as usize, region_end.0 as usize + 1);
+                after_start && before_end
+            },
+            None => true,
         }
+    }
 
-        self.highlights = highlights; // Put it back
+    // Next match at or after `from` that fits in the region. Right after a
+    // replacement `empty_at_from` is false, so an empty match there isn't
+    // replaced again and again
+    fn find_match(&self, buffer: &[Vec<char>], from: (u16, u16), inclusive: bool, empty_at_from: bool) -> Option<(u16, u16, u16)> {
+        let re = self.re.as_ref()?;
+        let last_line = self.region.map_or(usize::MAX, |(_, end)| end.1 as usize);
+
+        for (line_idx, line) in buffer.iter().enumerate().take(last_line.saturating_add(1)).skip(from.1 as usize) {
+            for (start, end) in Search::line_matches(re, line) {
+                if line_idx == from.1 as usize && (start < from.0 as usize || (start == from.0 as usize && (!inclusive || (start == end && !empty_at_from)))) {
+                    continue;
+                }
+                if self.in_region(line_idx, start, end) {
+                    return Some((line_idx as u16, start as u16, end as u16));
+                }
+            }
+        }
+        None
     }
 
+    // Replace one match, returns where the replacement ends. `cursor` is
+    // where ^ goes back to
+    fn replace_match(&mut self, buffer: &mut [Vec<char>], cursor: (u16, u16), line_idx: u16, start: u16, end: u16) -> u16 {
+        let Some(re) = self.re.as_ref() else { return end };
 
-    fn collect_highlights(&self, node: &tree_sitter::Node, highlights: &mut Vec<Highlight>, theme: &Theme) {
-        // Check if the node kind should be highlighted
-        if let Some(color) = self.get_color_for_node_kind(node.kind(), theme) {
-            highlights.push(Highlight {
-                start: node.start_byte(),
-                end: node.end_byte(),
-                color,
-            });
+        let line = &buffer[line_idx as usize];
+        let line_content: String = line.iter().collect();
+        let byte_start = line_content.char_indices().nth(start as usize).map_or(line_content.len(), |(i, _)| i);
+
+        let mut replacement = String::new();
+        if let Some(caps) = re.captures_at(&line_content, byte_start) {
+            caps.expand(&self.template(), &mut replacement);
         }
 
-        // Recursively visit children
-        let child_count = node.child_count();
-        for i in 0..child_count {
-            if let Some(child) = node.child(i) {
-                self.collect_highlights(&child, highlights, theme);
+        let replacement: Vec<char> = replacement.chars().collect();
+        let new_end = start + replacement.len() as u16;
+        self.answers.push((line_idx as usize, line.clone(), cursor, true));
+        buffer[line_idx as usize].splice(start as usize..end as usize, replacement);
+        self.count += 1;
+
+        // Keep the region end in place when the last line changes length
+        if let Some((_, region_end)) = self.region.as_mut() {
+            if region_end.1 == line_idx {
+                region_end.0 = (region_end.0 as i32 + new_end as i32 - end as i32).max(0) as u16;
             }
         }
+        new_end
     }
 
-    pub fn highlight_line(&self, line_num: usize, buffer: &[Vec<char>], theme: &Theme) -> Vec<Highlight> {
-        let mut highlights = Vec::new();
-        let line_start_byte = self.line_to_byte_index(line_num, buffer);
-        let line_end_byte = if line_num + 1 < buffer.len() {
-            self.line_to_byte_index(line_num + 1, buffer)
-        } else {
-            buffer.iter().map(|line| line.len() + 1).sum::<usize>() - 1 // Adjusting for the last line
-        };
+    // Every match from `from` on, returns where the last one ended
+    fn replace_all(&mut self, buffer: &mut [Vec<char>], from: (u16, u16)) -> Option<(u16, u16)> {
+        // An empty match right after a replacement is skipped, so this always terminates
+        let mut from = from;
+        let mut cursor = None;
+        let mut empty_at_from = true;
+        while let Some((line, start, end)) = self.find_match(buffer, from, true, empty_at_from) {
+            let new_end = self.replace_match(buffer, cursor.unwrap_or(from), line, start, end);
+            from = (new_end, line);
+            cursor = Some(from);
+            empty_at_from = false;
+        }
+        cursor
+    }
 
-        if let Some(tree) = &self.tree {
-            let root_node = tree.root_node();
-            self.traverse_node_for_highlights(root_node, line_start_byte, line_end_byte, &mut highlights, theme);
+    // The whole operation is a single undo step, returns how many were replaced
+    fn finish(&self, history: &mut UndoHistory, buffer: &[Vec<char>], cursor: (u16, u16)) -> usize {
+        if self.count > 0 {
+            history.snapshot(buffer, cursor);
         }
-        highlights
+        self.count
     }
+}
 
-    fn line_to_byte_index(&self, line_num: usize, buffer: &[Vec<char>]) -> usize {
-        buffer.iter().take(line_num).map(|line| line.len() + 1).sum()
+// What the minibuffer is reading, decides what Enter, Tab and M-p/M-n do
+#[derive(Clone, PartialEq)]
+enum Prompt {
+    Eval,
+    ShellCommand,
+    ProjectGrep,
+    Ex,
+    Search,
+    RegexpSearch,
+    Replace(String), // The label changes as the replace goes on
+    DiredTouch { open: bool },
+    DiredCreateDirectory,
+    DiredRename,
+    DiredMarkRegexp,
+    DiredFilter(String), // The filter before, back on C-g
+    DiredTarget { op: DiredOp, paths: Vec<PathBuf> }, // Where to, or the new mode
+    DiredConfirm { op: DiredOp, paths: Vec<PathBuf>, target: String },
+    DiredShell { paths: Vec<PathBuf>, background: bool },
+    LspRename,
+    GitDiscard(String),
+}
+
+impl Prompt {
+    fn label(&self) -> String {
+        match self {
+            Prompt::Eval => "Eval: ".to_string(),
+            Prompt::ShellCommand => "Shell command: ".to_string(),
+            Prompt::ProjectGrep => "Project grep: ".to_string(),
+            Prompt::Ex => ":".to_string(),
+            Prompt::Search => "Search: ".to_string(),
+            Prompt::RegexpSearch => "Regexp search: ".to_string(),
+            Prompt::Replace(label) => label.clone(),
+            Prompt::DiredTouch { open: true } => "Touch and open: ".to_string(),
+            Prompt::DiredTouch { open: false } => "Touch: ".to_string(),
+            Prompt::DiredCreateDirectory => "Create directory: ".to_string(),
+            Prompt::DiredRename => "Rename: ".to_string(),
+            Prompt::DiredMarkRegexp => "Mark files (regexp): ".to_string(),
+            Prompt::DiredFilter(_) => "Filter: ".to_string(),
+            Prompt::DiredTarget { op, paths } => format!("{} {} to: ", op.verb(), dired_summary(paths)),
+            Prompt::DiredConfirm { op: op @ (DiredOp::Trash | DiredOp::Delete), paths, .. } => format!("{} {} [y/n]: ", op.verb(), dired_summary(paths)),
+            Prompt::DiredConfirm { op, paths, target } => format!("{} {} to {} [y/n]: ", op.verb(), dired_summary(paths), target),
+            Prompt::DiredShell { paths, background } => format!("{} on {}: ", if *background { "&" } else { "!" }, dired_summary(paths)),
+            Prompt::LspRename => "Rename symbol to: ".to_string(),
+            Prompt::GitDiscard(what) => format!("Discard {} [y/n]: ", what),
+        }
     }
 
-    fn traverse_node_for_highlights(
-        &self, node: tree_sitter::Node,
-        line_start_byte: usize,
-        line_end_byte: usize,
-        highlights: &mut Vec<Highlight>,
-        theme: &Theme,
+    // Prompts that read the same kind of thing share a history,
+    // y/n questions have none
+    fn history(&self) -> Option<&'static str> {
+        match self {
+            Prompt::Eval => Some("eval"),
+            Prompt::ShellCommand | Prompt::DiredShell { .. } => Some("shell-command"),
+            Prompt::ProjectGrep => Some("project-grep"),
+            Prompt::Ex => Some("ex"),
+            Prompt::Replace(_) => Some("replace"),
+            Prompt::LspRename => Some("symbol"),
+            Prompt::DiredTarget { op: DiredOp::Chmod, .. } => Some("chmod"),
+            Prompt::DiredTouch { .. } | Prompt::DiredCreateDirectory | Prompt::DiredRename | Prompt::DiredTarget { .. } => Some("file-name"),
+            Prompt::DiredMarkRegexp => Some("dired-mark"),
+            Prompt::DiredFilter(_) => Some("dired-filter"),
+            Prompt::Search => Some("search"),
+            Prompt::RegexpSearch => Some("regexp-search"),
+            Prompt::DiredConfirm { .. } | Prompt::GitDiscard(_) => None,
+        }
+    }
+}
+
+const MINIBUFFER_HISTORY_LENGTH: usize = 100;
+
+// What was entered at each prompt, kept across sessions
+struct MinibufferHistory {
+    path: Option<PathBuf>,
+    entries: HashMap<String, Vec<String>>, // Oldest first
+    index: Option<usize>, // Entry shown while walking with M-p/M-n
+    draft: String, // What was typed before the walk started
+}
+
+impl MinibufferHistory {
+    // One `history<TAB>entry` per line, see `escape`
+    fn load(path: Option<PathBuf>) -> Self {
+        let mut entries: HashMap<String, Vec<String>> = HashMap::new();
+        if let Some(content) = path.as_ref().and_then(|path| fs::read_to_string(path).ok()) {
+            for line in content.lines() {
+                if let Some((name, entry)) = line.split_once('\t') {
+                    entries.entry(name.to_string()).or_default().push(MinibufferHistory::unescape(entry));
+                }
+            }
+        }
+        MinibufferHistory { path, entries, index: None, draft: String::new() }
+    }
+
+    fn escape(entry: &str) -> String {
+        entry.replace('\\', "\\\\").replace('\n', "\\n").replace('\t', "\\t")
+    }
+
+    fn unescape(entry: &str) -> String {
+        let mut unescaped = String::new();
+        let mut chars = entry.chars();
+        while let Some(c) = chars.next() {
+            match (c, chars.clone().next()) {
+                ('\\', Some('n')) => { chars.next(); unescaped.push('\n'); },
+                ('\\', Some('t')) => { chars.next(); unescaped.push('\t'); },
+                ('\\', Some('\\')) => { chars.next(); unescaped.push('\\'); },
+                _ => unescaped.push(c),
+            }
+        }
+        unescaped
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else { return Ok(()) };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut names: Vec<&String> = self.entries.keys().collect();
+        names.sort();
+        let mut content = String::new();
+        for name in names {
+            for entry in &self.entries[name] {
+                content.push_str(&format!("{}\t{}\n", name, MinibufferHistory::escape(entry)));
+            }
+        }
+        fs::write(path, content)
+    }
+
+    fn push(&mut self, name: &str, entry: &str) -> io::Result<()> {
+        self.index = None;
+        if entry.trim().is_empty() {
+            return Ok(());
+        }
+
+        let entries = self.entries.entry(name.to_string()).or_default();
+        entries.retain(|e| e != entry);
+        entries.push(entry.to_string());
+        if entries.len() > MINIBUFFER_HISTORY_LENGTH {
+            let excess = entries.len() - MINIBUFFER_HISTORY_LENGTH;
+            entries.drain(..excess);
+        }
+        self.save()
+    }
+
+    // M-p goes to older entries, M-n back towards what was being typed
+    fn step(&mut self, name: &str, older: bool, current: &str) -> Option<String> {
+        let entries = self.entries.get(name)?;
+        match (self.index, older) {
+            (None, true) if !entries.is_empty() => {
+                self.draft = current.to_string();
+                self.index = Some(entries.len() - 1);
+            },
+            (Some(i), true) if i > 0 => self.index = Some(i - 1),
+            (Some(i), false) if i + 1 < entries.len() => self.index = Some(i + 1),
+            (Some(_), false) => {
+                self.index = None;
+                return Some(std::mem::take(&mut self.draft));
+            },
+            _ => return None,
+        }
+        self.index.map(|i| entries[i].clone())
+    }
+
+    fn last(&self, name: &str) -> Option<&String> {
+        self.entries.get(name)?.last()
+    }
+}
+
+// The longest prefix all the strings share
+fn common_prefix(strings: &[String]) -> String {
+    let mut common: Vec<char> = match strings.first() {
+        Some(first) => first.chars().collect(),
+        None => return String::new(),
+    };
+    for string in &strings[1..] {
+        let shared = common.iter().zip(string.chars()).take_while(|(a, b)| **a == *b).count();
+        common.truncate(shared);
+    }
+    common.into_iter().collect()
+}
+
+// Names on $PATH that can be run, for completing shell commands
+fn path_executables() -> Vec<String> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut names = Vec::new();
+    for dir in env::split_paths(&env::var_os("PATH").unwrap_or_default()) {
+        let Ok(read_dir) = fs::read_dir(dir) else { continue };
+        for entry in read_dir.flatten() {
+            let executable = entry.metadata().is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0);
+            if let (true, Some(name)) = (executable, entry.file_name().to_str()) {
+                names.push(name.to_string());
+            }
+        }
+    }
+    names
+}
+
+// Completions for a file name being typed relative to `base`, directories end with /
+fn file_name_candidates(base: &Path, typed: &str) -> Vec<String> {
+    let dir_part = match typed.rfind('/') {
+        Some(i) => &typed[..=i],
+        None => "",
+    };
+    let dir = if dir_part.is_empty() { base.to_path_buf() } else { resolve_typed_path(base, dir_part) };
+    let Ok(read_dir) = fs::read_dir(dir) else { return Vec::new() };
+    read_dir.flatten().filter_map(|entry| {
+        let name = entry.file_name().to_str()?.to_string();
+        let slash = if entry.path().is_dir() { "/" } else { "" };
+        Some(format!("{}{}{}", dir_part, name, slash))
+    }).collect()
+}
+
+// Walk up from `start` to the project root: the closest directory with a
+// .git, or failing that the closest one with a Cargo.toml
+fn project_root(start: &Path) -> PathBuf {
+    let start = if start.is_file() { start.parent().unwrap_or(start) } else { start };
+    start.ancestors()
+        .find(|dir| dir.join(".git").exists())
+        .or_else(|| start.ancestors().find(|dir| dir.join("Cargo.toml").is_file()))
+        .unwrap_or(start)
+        .to_path_buf()
+}
+
+fn home_dir() -> Option<PathBuf> {
+    directories::BaseDirs::new().map(|dirs| dirs.home_dir().to_path_buf())
+}
+
+// `path` with the home directory shown as ~
+fn abbreviate_home(path: &Path) -> String {
+    match home_dir().and_then(|home| path.strip_prefix(home).ok().map(Path::to_path_buf)) {
+        Some(rest) if rest.as_os_str().is_empty() => "~".to_string(),
+        Some(rest) => format!("~/{}", rest.display()),
+        None => path.display().to_string(),
+    }
+}
+
+// A path typed by the user: absolute, ~/ or relative to `base`
+fn resolve_typed_path(base: &Path, typed: &str) -> PathBuf {
+    if typed == "~" {
+        return home_dir().unwrap_or_else(|| base.to_path_buf());
+    }
+    if let (Some(rest), Some(home)) = (typed.strip_prefix("~/"), home_dir()) {
+        return home.join(rest);
+    }
+    base.join(typed) // `join` keeps absolute paths as they are
+}
+
+struct IgnoreRule {
+    regex: Regex,
+    negated: bool,
+    dir_only: bool,
+}
+
+// The rules of the .gitignore and .ignore files of one directory,
+// matched against paths relative to that directory
+struct GitIgnore {
+    base: PathBuf,
+    rules: Vec<IgnoreRule>,
+}
+
+impl GitIgnore {
+    fn load(dir: &Path) -> Option<Self> {
+        let mut rules = Vec::new();
+        for name in [".gitignore", ".ignore"] {
+            if let Ok(content) = fs::read_to_string(dir.join(name)) {
+                rules.extend(content.lines().filter_map(GitIgnore::parse_rule));
+            }
+        }
+        if rules.is_empty() {
+            None
+        } else {
+            Some(GitIgnore { base: dir.to_path_buf(), rules })
+        }
+    }
+
+    // Translate one gitignore glob into an anchored regex
+    fn parse_rule(line: &str) -> Option<IgnoreRule> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, pattern) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (dir_only, pattern) = match pattern.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+        // A slash before the end anchors the pattern to the .gitignore directory,
+        // otherwise it matches at any depth
+        let anchored = pattern.contains('/');
+        let pattern = pattern.trim_start_matches('/');
+        if pattern.is_empty() {
+            return None;
+        }
+
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut regex = String::from(if anchored { "^" } else { "^(?:.*/)?" });
+        let mut i = 0;
+        while i < chars.len() {
+            match chars[i] {
+                '*' if chars.get(i + 1) == Some(&'*') => {
+                    if chars.get(i + 2) == Some(&'/') {
+                        regex.push_str("(?:.*/)?");
+                        i += 3;
+                    } else {
+                        regex.push_str(".*");
+                        i += 2;
+                    }
+                    continue;
+                },
+                '*' => regex.push_str("[^/]*"),
+                '?' => regex.push_str("[^/]"),
+                '[' => {
+                    if let Some(len) = chars[i + 1..].iter().position(|&c| c == ']') {
+                        let class: String = chars[i + 1..i + 1 + len].iter().collect();
+                        let class = class.strip_prefix('!').map_or(class.clone(), |rest| format!("^{}", rest));
+                        regex.push_str(&format!("[{}]", class));
+                        i += len + 2;
+                        continue;
+                    }
+                    regex.push_str("\\[");
+                },
+                c => regex.push_str(&regex::escape(&c.to_string())),
+            }
+            i += 1;
+        }
+        regex.push('$');
+
+        Regex::new(&regex).ok().map(|regex| IgnoreRule { regex, negated, dir_only })
+    }
+
+    // None when no rule talks about `path`, the last matching rule wins
+    fn is_ignored(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        let relative = path.strip_prefix(&self.base).ok()?;
+        let relative = relative.to_str()?.replace(std::path::MAIN_SEPARATOR, "/");
+        self.rules.iter().rev()
+            .find(|rule| (is_dir || !rule.dir_only) && rule.regex.is_match(&relative))
+            .map(|rule| !rule.negated)
+    }
+}
+
+// Visit every file under `root` that git wouldn't ignore, in a stable
+// order. `visit` returns false to stop the walk.
+fn walk_project(root: &Path, visit: &mut dyn FnMut(&Path) -> bool) {
+    let mut ignores = Vec::new();
+    walk_project_dir(root, &mut ignores, visit);
+}
+
+fn walk_project_dir(dir: &Path, ignores: &mut Vec<GitIgnore>, visit: &mut dyn FnMut(&Path) -> bool) -> bool {
+    let has_ignore = match GitIgnore::load(dir) {
+        Some(ignore) => {
+            ignores.push(ignore);
+            true
+        },
+        None => false,
+    };
+
+    // Symlinks count as files, so linked directories can't loop the walk
+    let mut entries: Vec<(PathBuf, bool)> = fs::read_dir(dir)
+        .map(|read_dir| read_dir
+             .filter_map(|entry| entry.ok())
+             .map(|entry| (entry.path(), entry.file_type().is_ok_and(|t| t.is_dir())))
+             .collect())
+        .unwrap_or_default();
+    entries.sort();
+
+    let mut keep_going = true;
+    for (path, is_dir) in entries {
+        if path.file_name().is_some_and(|name| name == ".git") {
+            continue;
+        }
+        // The closest .gitignore has the last word
+        let ignored = ignores.iter().rev()
+            .find_map(|ignore| ignore.is_ignored(&path, is_dir))
+            .unwrap_or(false);
+        if ignored {
+            continue;
+        }
+        keep_going = if is_dir { walk_project_dir(&path, ignores, visit) } else { visit(&path) };
+        if !keep_going {
+            break;
+        }
+    }
+
+    if has_ignore {
+        ignores.pop();
+    }
+    keep_going
+}
+
+// Bigger files are most likely generated, not worth grepping
+const GREP_MAX_FILE_SIZE: u64 = 4 * 1024 * 1024;
+
+struct GrepMatch {
+    path: PathBuf,
+    line: usize, // 1 based, like the results buffer shows it
+    columns: (usize, usize), // char range of the match in `text`
+    text: String,
+}
+
+// A project-grep run, the matches stream in from a worker thread
+struct Grep {
+    pattern: String,
+    references: bool, // Found by the language server, not by searching
+    root: PathBuf,
+    matches: Vec<GrepMatch>,
+    selected: usize,
+    offset: usize, // first row of the results on screen
+    done: bool,
+    receiver: Receiver<GrepMatch>,
+    cancel: Arc<AtomicBool>,
+}
+
+impl Grep {
+    fn start(pattern: &str, re: Regex, root: PathBuf) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let cancel = Arc::new(AtomicBool::new(false));
+
+        let worker_cancel = Arc::clone(&cancel);
+        let worker_root = root.clone();
+        std::thread::spawn(move || {
+            walk_project(&worker_root, &mut |path| {
+                !worker_cancel.load(Ordering::Relaxed) && Grep::search_file(&re, path, &sender)
+            });
+        });
+
+        Grep {
+            pattern: pattern.to_string(),
+            references: false,
+            root,
+            matches: Vec::new(),
+            selected: 0,
+            offset: 0,
+            done: false,
+            receiver,
+            cancel,
+        }
+    }
+
+    // Results that are already known, like the references to a symbol
+    fn finished(symbol: &str, root: PathBuf, matches: Vec<GrepMatch>) -> Self {
+        let (_, receiver) = mpsc::channel();
+        Grep {
+            pattern: symbol.to_string(),
+            references: true,
+            root,
+            matches,
+            selected: 0,
+            offset: 0,
+            done: true,
+            receiver,
+            cancel: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    // Send every matching line of `path`, false once nobody is listening
+    fn search_file(re: &Regex, path: &Path, sender: &Sender<GrepMatch>) -> bool {
+        if fs::metadata(path).map_or(true, |metadata| metadata.len() > GREP_MAX_FILE_SIZE) {
+            return true;
+        }
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(_) => return true,
+        };
+        // Same heuristic as git, a NUL early on means binary
+        if bytes[..bytes.len().min(8000)].contains(&0) {
+            return true;
+        }
+        let content = match String::from_utf8(bytes) {
+            Ok(content) => content,
+            Err(_) => return true,
+        };
+
+        for (idx, line) in content.lines().enumerate() {
+            if let Some(found) = re.find(line) {
+                let start = line[..found.start()].chars().count();
+                let end = start + found.as_str().chars().count();
+                let grep_match = GrepMatch {
+                    path: path.to_path_buf(),
+                    line: idx + 1,
+                    columns: (start, end),
+                    text: line.to_string(),
+                };
+                if sender.send(grep_match).is_err() {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    // Take whatever the worker found since last time, true if anything changed
+    fn poll(&mut self) -> bool {
+        let mut changed = false;
+        loop {
+            match self.receiver.try_recv() {
+                Ok(grep_match) => {
+                    self.matches.push(grep_match);
+                    changed = true;
+                },
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    changed |= !self.done;
+                    self.done = true;
+                    break;
+                },
+            }
+        }
+        changed
+    }
+
+    fn display_path(&self, grep_match: &GrepMatch) -> String {
+        grep_match.path.strip_prefix(&self.root).unwrap_or(&grep_match.path).display().to_string()
+    }
+
+    // Screen rows of the results: matches grouped by file, with an empty
+    // row (None) between two files
+    fn rows(&self) -> Vec<Option<usize>> {
+        let mut rows = Vec::new();
+        for (idx, grep_match) in self.matches.iter().enumerate() {
+            if idx > 0 && self.matches[idx - 1].path != grep_match.path {
+                rows.push(None);
+            }
+            rows.push(Some(idx));
+        }
+        rows
+    }
+
+    // First match of the next (or previous) file
+    fn file_step(&self, forward: bool) -> Option<usize> {
+        let current = &self.matches.get(self.selected)?.path;
+        if forward {
+            self.matches.iter().skip(self.selected).position(|m| &m.path != current).map(|n| self.selected + n)
+        } else {
+            let previous = self.matches[..self.selected].iter().rposition(|m| &m.path != current)?;
+            let previous_path = &self.matches[previous].path;
+            Some(self.matches[..=previous].iter().rposition(|m| &m.path != previous_path).map_or(0, |n| n + 1))
+        }
+    }
+
+    fn status(&self) -> String {
+        let count = match self.matches.len() {
+            1 => "1 match".to_string(),
+            n => format!("{} matches", n),
+        };
+        if self.done { count } else { format!("{} (searching...)", count) }
+    }
+
+    fn draw(&mut self, stdout: &mut Stdout, height: u16, theme: &Theme) -> io::Result<()> {
+        let (width, _) = terminal::size()?;
+        let kind = if self.references { "References to" } else { "Grep" };
+        let header = format!("{} \"{}\" in {}: {}", kind, self.pattern, self.root.display(), self.status());
+        execute!(
+            stdout,
+            MoveTo(3, 0),
+            SetForegroundColor(theme.dired_path_color),
+            SetBackgroundColor(theme.background_color),
+            Print(header),
+            ResetColor
+        )?;
+
+        // Keep the selected match on screen
+        let rows = self.rows();
+        let visible = height.saturating_sub(2) as usize;
+        let selected_row = rows.iter().position(|row| *row == Some(self.selected)).unwrap_or(0);
+        if selected_row < self.offset {
+            self.offset = selected_row;
+        } else if visible > 0 && selected_row >= self.offset + visible {
+            self.offset = selected_row + 1 - visible;
+        }
+
+        for (screen_row, row) in rows.iter().skip(self.offset).take(visible).enumerate() {
+            let idx = match row {
+                Some(idx) => *idx,
+                None => continue,
+            };
+            let grep_match = &self.matches[idx];
+            let y = screen_row as u16 + 2;
+            let background = if idx == self.selected { theme.hl_line_color } else { theme.background_color };
+            let location = format!("{}:{}: ", self.display_path(grep_match), grep_match.line);
+            let room = (width as usize).saturating_sub(3 + location.chars().count());
+            let text: Vec<char> = grep_match.text.chars().take(room).collect();
+            let (start, end) = (grep_match.columns.0.min(text.len()), grep_match.columns.1.min(text.len()));
+
+            execute!(
+                stdout,
+                MoveTo(0, y),
+                SetBackgroundColor(background),
+                Print(" ".repeat(width as usize)),
+                MoveTo(3, y),
+                SetForegroundColor(theme.dired_dir_color),
+                Print(self.display_path(grep_match)),
+                SetForegroundColor(theme.comment_color),
+                Print(":"),
+                SetForegroundColor(theme.dired_size_color),
+                Print(grep_match.line),
+                SetForegroundColor(theme.comment_color),
+                Print(": "),
+                SetForegroundColor(theme.text_color),
+                Print(text[..start].iter().collect::<String>()),
+                SetBackgroundColor(theme.search_bg_color),
+                Print(text[start..end].iter().collect::<String>()),
+                SetBackgroundColor(background),
+                Print(text[end..].iter().collect::<String>()),
+                ResetColor
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Grep {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum DiagnosticSource {
+    Compilation,
+    Lua,
+    Lsp,
+    Formatter,
+}
+
+// A problem in a file, shown in the fringe and under the text
+struct Diagnostic {
+    source: DiagnosticSource,
+    path: PathBuf, // Canonical, to compare with the open file
+    line: usize, // 0 based
+    column: Option<usize>, // 0 based, the whole line when we don't know
+    end: Option<usize>, // End column when the range is known and on one line
+    severity: Severity,
+    message: String,
+}
+
+impl Diagnostic {
+    // Chars of `line` to underline: the word at the column, or the line
+    // without its indentation
+    fn range(&self, line: &[char]) -> (usize, usize) {
+        match (self.column, self.end) {
+            (Some(column), Some(end)) if column < end => (column.min(line.len()), end.min(line.len())),
+            (Some(column), _) if column < line.len() => {
+                let word = line[column..].iter().take_while(|c| Editor::is_word_char(**c)).count();
+                (column, column + word.max(1))
+            },
+            _ => (line.iter().take_while(|c| c.is_whitespace()).count(), line.len()),
+        }
+    }
+}
+
+// Where a formatter that failed says the problem is: rustfmt's `--> <stdin>:3:5`,
+// stylua's `line 3, character 5`, prettier's `(3:5)`. One per error, 0 based
+fn formatter_errors(stderr: &str) -> Vec<(usize, Option<usize>, String)> {
+    let locations = [
+        Regex::new(r"<stdin>:(\d+):(\d+)").unwrap(),
+        Regex::new(r"line (\d+),? (?:character|col(?:umn)?) (\d+)").unwrap(),
+        Regex::new(r"\((\d+):(\d+)\)").unwrap(),
+    ];
+    // prettier puts [error] before the code it quotes too
+    let quoted = Regex::new(r"^\[error\]\s*(>|\||\d+ \|)").unwrap();
+    let mut blocks: Vec<Vec<&str>> = Vec::new();
+    for line in stderr.lines().filter(|line| !line.trim().is_empty()) {
+        let starts_error = line.trim_start().trim_start_matches("[error]").trim_start().to_lowercase().starts_with("error")
+            || (line.starts_with("[error]") && !quoted.is_match(line));
+        match blocks.last_mut() {
+            Some(block) if !starts_error => block.push(line),
+            _ => blocks.push(vec![line]),
+        }
+    }
+
+    blocks.iter().map(|block| {
+        let message = block[0].trim().trim_start_matches("[error]").trim().trim_start_matches("error:").trim().to_string();
+        let location = block.iter().find_map(|line| locations.iter().find_map(|re| re.captures(line)));
+        let (line, column) = location.map_or((0, None), |captures| {
+            let number = |i: usize| captures[i].parse::<usize>().unwrap_or(1).saturating_sub(1);
+            (number(1), Some(number(2)))
+        });
+        (line, column, message)
+    }).collect()
+}
+
+// How many lines the two start and end with in common, not overlapping
+fn common_ends(old: &[Vec<char>], new: &[Vec<char>]) -> (usize, usize) {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+    (prefix, suffix)
+}
+
+// Where a (column, row) of `old` is in its formatted `new`: lines before
+// and after the change move as a block, inside it matching_lines pairs up
+// the lines that only differ in spacing, and the column stays on the same
+// non-blank char
+fn formatted_position(old: &[Vec<char>], new: &[Vec<char>], (column, row): (u16, u16)) -> (u16, u16) {
+    let (column, row) = (column as usize, (row as usize).min(old.len().saturating_sub(1)));
+    if old.is_empty() || new.is_empty() {
+        return (0, 0);
+    }
+    let (prefix, suffix) = common_ends(old, new);
+    let (old_len, new_len) = (old.len(), new.len());
+    let new_row = if row < prefix {
+        row
+    } else if row >= old_len - suffix {
+        row + new_len - old_len
+    } else {
+        let text = |lines: &[Vec<char>]| -> Vec<Vec<char>> {
+            lines.iter().map(|line| line.iter().filter(|c| !c.is_whitespace()).copied().collect()).collect()
+        };
+        let pairs = matching_lines(&text(&old[prefix..old_len - suffix]), &text(&new[prefix..new_len - suffix]));
+        match pairs.iter().rev().find(|(old, _)| prefix + old <= row) {
+            Some(&(old, new)) => prefix + new + (row - prefix - old),
+            None => row,
+        }
+        .min(new_len - suffix)
+    }
+    .min(new_len - 1);
+    (same_text_column(&old[row], column, &new[new_row]) as u16, new_row as u16)
+}
+
+// A `redit.completing_read` call from Lua, opened in Fzy once the Lua
+// code has returned
+struct LuaCompletingRead {
+    prompt: String,
+    items: Vec<String>,
+    on_select: mlua::RegistryKey,
+}
+
+// The `redit` table, the editor functions callable from Lua
+fn register_lua_api(lua: &Lua, completing_reads: Rc<RefCell<Vec<LuaCompletingRead>>>) -> LuaResult<()> {
+    let redit = lua.create_table()?;
+    redit.set("completing_read", lua.create_function(
+        move |lua, (prompt, items, on_select): (String, Vec<String>, mlua::Function)| {
+            let on_select = lua.create_registry_value(on_select)?;
+            completing_reads.borrow_mut().push(LuaCompletingRead { prompt, items, on_select });
+            Ok(())
+        })?)?;
+    lua.globals().set("redit", redit)?;
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Highlight {
+    start: usize,
+    end: usize,
+    color: Color,
+}
+
+struct SyntaxHighlighter {
+    parser: tree_sitter::Parser,
+    tree: Option<tree_sitter::Tree>,
+    highlights: Vec<Highlight>,
+}
+
+impl SyntaxHighlighter {
+    fn new() -> Self {
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_rust::language()).expect("Error loading Rust grammar");
+        SyntaxHighlighter {
+            parser,
+            tree: None,
+            highlights:  Vec::new()
+        }
+    }
+    
+    pub fn parse(&mut self, buffer: &[Vec<char>]) {
+        let source_code: String = buffer.iter()
+            .map(|line| line.iter().collect::<String>() + "\n")
+            .collect();
+
+        self.tree = self.parser.parse(&source_code, None);
+    }
+
+    fn get_color_for_node_kind(&self, kind: &str, theme: &Theme) -> Option<Color> {
+        match kind {
+            "let_condition" => Some(theme.dired_path_color),
+            "string_literal" => Some(theme.string_color),
+            "use_declaration" => Some(theme.use_color),
+            _ => None,
+        }
+    }
+
+
+    pub fn update_syntax_highlights(&mut self, theme: &Theme) {
+        let mut highlights = std::mem::take(&mut self.highlights); // Temporarily take highlights out
+
+        if let Some(tree) = &self.tree {
+            let root_node = tree.root_node();
+            self.collect_highlights(&root_node, &mut highlights, theme); // Work with the taken highlights
+        }
+
+        self.highlights = highlights; // Put it back
+    }
+
+
+    fn collect_highlights(&self, node: &tree_sitter::Node, highlights: &mut Vec<Highlight>, theme: &Theme) {
+        // Check if the node kind should be highlighted
+        if let Some(color) = self.get_color_for_node_kind(node.kind(), theme) {
+            highlights.push(Highlight {
+                start: node.start_byte(),
+                end: node.end_byte(),
+                color,
+            });
+        }
+
+        // Recursively visit children
+        let child_count = node.child_count();
+        for i in 0..child_count {
+            if let Some(child) = node.child(i) {
+                self.collect_highlights(&child, highlights, theme);
+            }
+        }
+    }
+
+    // The named items of the file for goto-symbol, with the position of
+    // their name. Methods are prefixed with the type of their impl.
+    pub fn symbols(&self, buffer: &[Vec<char>]) -> Vec<(String, (u16, u16))> {
+        let mut symbols = Vec::new();
+        if let Some(tree) = &self.tree {
+            let source: String = buffer.iter()
+                .map(|line| line.iter().collect::<String>() + "\n")
+                .collect();
+            self.collect_symbols(tree.root_node(), &source, buffer, None, &mut symbols);
+        }
+        symbols
+    }
+
+    fn collect_symbols(
+        &self,
+        node: tree_sitter::Node,
+        source: &str,
+        buffer: &[Vec<char>],
+        owner: Option<&str>,
+        symbols: &mut Vec<(String, (u16, u16))>,
+    ) {
+        let kind = match node.kind() {
+            "function_item" | "function_signature_item" => Some("fn"),
+            "struct_item" => Some("struct"),
+            "enum_item" => Some("enum"),
+            "union_item" => Some("union"),
+            "trait_item" => Some("trait"),
+            "mod_item" => Some("mod"),
+            "const_item" => Some("const"),
+            "static_item" => Some("static"),
+            "type_item" => Some("type"),
+            "macro_definition" => Some("macro"),
+            _ => None,
+        };
+
+        if let (Some(kind), Some(name_node)) = (kind, node.child_by_field_name("name")) {
+            let name = &source[name_node.byte_range()];
+            let label = match owner {
+                Some(owner) if kind == "fn" => format!("fn {}::{}", owner, name),
+                _ => format!("{} {}", kind, name),
+            };
+            // Tree-sitter columns are in bytes, the buffer is in chars
+            let point = name_node.start_position();
+            let column = buffer.get(point.row).map_or(0, |line| {
+                let line: String = line.iter().collect();
+                line.get(..point.column).map_or(0, |prefix| prefix.chars().count())
+            });
+            symbols.push((format!("{}:{}", label, point.row + 1), (column as u16, point.row as u16)));
+        }
+
+        let owner_name = match node.kind() {
+            "impl_item" => node.child_by_field_name("type"),
+            "trait_item" => node.child_by_field_name("name"),
+            _ => None,
+        }.map(|owner_node| source[owner_node.byte_range()].to_string());
+        let owner = owner_name.as_deref().or(owner);
+
+        for i in 0..node.child_count() {
+            if let Some(child) = node.child(i) {
+                self.collect_symbols(child, source, buffer, owner, symbols);
+            }
+        }
+    }
+
+    pub fn highlight_line(&self, line_num: usize, buffer: &[Vec<char>], theme: &Theme) -> Vec<Highlight> {
+        let mut highlights = Vec::new();
+        let line_start_byte = self.line_to_byte_index(line_num, buffer);
+        let line_end_byte = if line_num + 1 < buffer.len() {
+            self.line_to_byte_index(line_num + 1, buffer)
+        } else {
+            buffer.iter().map(|line| line.len() + 1).sum::<usize>() - 1 // Adjusting for the last line
+        };
+
+        if let Some(tree) = &self.tree {
+            let root_node = tree.root_node();
+            self.traverse_node_for_highlights(root_node, line_start_byte, line_end_byte, &mut highlights, theme);
+        }
+        highlights
+    }
+
+    fn line_to_byte_index(&self, line_num: usize, buffer: &[Vec<char>]) -> usize {
+        buffer.iter().take(line_num).map(|line| line.len() + 1).sum()
+    }
+
+    fn traverse_node_for_highlights(
+        &self, node: tree_sitter::Node,
+        line_start_byte: usize,
+        line_end_byte: usize,
+        highlights: &mut Vec<Highlight>,
+        theme: &Theme,
     ) {
         let start_byte = node.start_byte();
         let end_byte = node.end_byte();
@@ -594,30 +1699,50 @@ impl SyntaxHighlighter {
 }
 
 
-
 struct Editor {
     syntax_highlighter: SyntaxHighlighter,
-    states: Vec<UndoState>, // History of states
-    current_state: usize,
+    history: UndoHistory,
     mode: Mode,
     dired: Option<Dired>,
     cursor_pos: (u16, u16),
     offset: (u16, u16),
-    buffer: Vec<Vec<char>>,
+    buffer: Buffer,
     minibuffer_active: bool,
     minibuffer_height: u16,
     minibuffer_content: String,
-    minibuffer_prefix: String,
+    minibuffer_prompt: Option<Prompt>,
     minibuffer_cursor_pos: (u16, u16),
+    minibuffer_history: MinibufferHistory,
+    minibuffer_hint: String, // Right aligned, Tab candidates or [No match]
     current_file_path: PathBuf,
-    should_open_file: bool,
+    current_file_canonical: PathBuf, // What diagnostics and servers know the open file as
     fzy: Option<Fzy>,
     messages: Vec<String>,
     last_message_time: Option<std::time::Instant>,
     clipboard: String,
     searching: bool,
     highlight_search: bool,
-    search_query: String,
+    search: Search,
+    replace: Option<Replace>,
+    grep: Option<Grep>,
+    compilation: Option<Compilation>,
+    git: Option<GitStatus>,
+    git_commit_message: Option<(PathBuf, PathBuf)>, // Repository root and the file to go back to
+    git_gutter: Option<GitGutter>,
+    wdired: Option<Wdired>,
+    dired_job: Option<DiredJob>, // A big copy or move going on
+    dired_undo: Vec<DiredUndo>, // Oldest first
+    blame: Option<GitBlame>,
+    git_log: Option<GitLog>,
+    diagnostics: Vec<Diagnostic>,
+    diagnostics_lines: Option<Vec<Vec<char>>>, // The buffer the open file's diagnostics point into
+    lsp: HashMap<String, LspClient>, // By language id
+    lsp_failed: HashSet<String>, // Languages whose server didn't start, not retried
+    completion_popup: Option<CompletionPopup>,
+    completion_due: Option<std::time::Instant>, // When the popup opens by itself
+    buffer_words: HashMap<PathBuf, (Option<std::time::SystemTime>, Vec<String>)>, // Of the buffers not shown
+    diagnostic_echoed: Option<usize>, // Line whose diagnostic is in the minibuffer
+    lua_completing_reads: Rc<RefCell<Vec<LuaCompletingRead>>>,
     selection_start: Option<(u16, u16)>,
     selection_end: Option<(u16, u16)>,
     copied_line: bool,
@@ -629,6 +1754,7 @@ struct Editor {
     lua: Lua,
     keychords: Keychords,
     recenter_state: usize, // 0: center, 1: top, 2: bottom
+    pending_recenter: bool,
     buffer_stack: Vec<PathBuf>,
     current_buffer: usize,
 }
@@ -637,15 +1763,12 @@ impl Editor {
     fn new(config_path: Option<&str>) -> LuaResult<Editor> {
 
         let lua = Lua::new();
+        let lua_completing_reads = Rc::new(RefCell::new(Vec::new()));
+        register_lua_api(&lua, Rc::clone(&lua_completing_reads))?;
         let config = Config::new(&lua, config_path)?;
         let current_path = env::current_dir().expect("Failed to determine the current directory");
 
 
-        let initial_undo_state = UndoState {
-            buffer: vec![vec![]],
-            cursor_pos: (0, 0),
-        };
-
         let syntax_highlighter = SyntaxHighlighter::new();
         
         let buffer_stack = Vec::<PathBuf>::new();
@@ -655,27 +1778,48 @@ impl Editor {
             buffer_stack,
             current_buffer,
             syntax_highlighter,
-            states: vec![initial_undo_state],
-            current_state: 0,
+            history: UndoHistory::new(),
             mode: Mode::Normal,
             cursor_pos: (0, 0),
             minibuffer_cursor_pos: (0, 0),
             offset: (0, 0),
-            buffer: vec![vec![]],
+            buffer: Buffer { lines: vec![vec![]], edits: 0 },
             dired: None,
             minibuffer_active: false,
             minibuffer_height: 1,
             minibuffer_content: String::new(),
-            minibuffer_prefix: String::new(),
+            minibuffer_prompt: None,
+            minibuffer_history: MinibufferHistory::load(get_history_path()),
+            minibuffer_hint: String::new(),
             current_file_path: current_path.clone(),
-            should_open_file: false,
+            current_file_canonical: fs::canonicalize(&current_path).unwrap_or_else(|_| current_path.clone()),
             fzy: Some(Fzy::new(current_path)),
             messages: Vec::new(),
             last_message_time: None,
             clipboard: String::new(),
             searching: false,
             highlight_search: false,
-            search_query: String::new(),
+            search: Search::new(),
+            replace: None,
+            grep: None,
+            compilation: None,
+            git: None,
+            git_commit_message: None,
+            wdired: None,
+            dired_job: None,
+            dired_undo: Vec::new(),
+            blame: None,
+            git_log: None,
+            git_gutter: None,
+            diagnostics: Vec::new(),
+            diagnostics_lines: None,
+            lsp: HashMap::new(),
+            lsp_failed: HashSet::new(),
+            completion_popup: None,
+            completion_due: None,
+            buffer_words: HashMap::new(),
+            diagnostic_echoed: None,
+            lua_completing_reads,
             selection_start: None,
             selection_end: None,
             copied_line: false,
@@ -687,6 +1831,7 @@ impl Editor {
             config,
             keychords: Keychords::new(),
             recenter_state: 0,
+            pending_recenter: false,
         })
     }
 
@@ -700,55 +1845,29 @@ impl Editor {
     }
     
     
-    // TODO LATER Don't discard the branches build a tree like emacs does
     fn snapshot(&mut self) {
-        let is_different_from_last_snapshot = if let Some(last_state) = self.states.last() {
-            last_state.buffer != self.buffer || last_state.cursor_pos != self.cursor_pos
-        } else {
-            true
-        };
-
-        if is_different_from_last_snapshot {
-            if self.current_state + 1 < self.states.len() {
-                self.states.truncate(self.current_state + 1);
-            }
-            
-            let state = UndoState {
-                buffer: self.buffer.clone(),
-                cursor_pos: self.cursor_pos,
-            };
-            
-            self.states.push(state);
-            self.current_state = self.states.len() - 1;
-            // self.message("Snapshot took");
-        } else {
-            // self.message("Snapshot skipped; cursor_pos unchanged");
-        }
+        self.history.snapshot(&self.buffer, self.cursor_pos);
     }
 
     fn undo(&mut self) {
-        if self.current_state > 0 {
-            self.current_state -= 1;
-            let state = &self.states[self.current_state];
-            self.buffer = state.buffer.clone();
-            self.cursor_pos = state.cursor_pos;
-            self.adjust_view_to_cursor("");
-            self.message_undo_tree();
-        } else {
-            self.message("No more undos available.");
+        match self.history.undo() {
+            Some(state) => {
+                *self.buffer = state.buffer.clone();
+                self.cursor_pos = state.cursor_pos;
+                self.message_undo_tree();
+            },
+            None => self.message("No more undos available."),
         }
     }
 
     fn redo(&mut self) {
-        if self.current_state < self.states.len() - 1 {
-            self.current_state += 1;
-            let state = &self.states[self.current_state];
-            self.buffer = state.buffer.clone();
-            self.cursor_pos = state.cursor_pos;
-            self.adjust_view_to_cursor("");
-            self.message_undo_tree();
-        } else {
-            self.message("No more redos available.");
+        match self.history.redo() {
+            Some(state) => {
+                *self.buffer = state.buffer.clone();
+                self.cursor_pos = state.cursor_pos;
+                self.message_undo_tree();
+            },
+            None => self.message("No more redos available."),
         }
     }
 
@@ -767,6 +1886,19 @@ impl Editor {
                 self.config.indentation = globals.get("Indentation").unwrap_or(self.config.indentation);
                 self.config.electric_pair_mode = globals.get("Electric_pair_mode").unwrap_or(self.config.electric_pair_mode);
                 self.config.emacs_scrolling = globals.get("Emacs_scrolling").unwrap_or(self.config.emacs_scrolling);
+                self.config.search_smart_case = globals.get("Search_smart_case").unwrap_or(self.config.search_smart_case);
+                self.config.completion_auto = globals.get("Completion_auto").unwrap_or(self.config.completion_auto);
+                self.config.completion_delay = globals.get("Completion_delay").unwrap_or(self.config.completion_delay);
+                self.config.completion_min_prefix = globals.get("Completion_min_prefix").unwrap_or(self.config.completion_min_prefix);
+                self.config.format_on_save = globals.get("Format_on_save").unwrap_or(self.config.format_on_save);
+                self.config.dired_sort = globals.get::<_, String>("Dired_sort").ok().and_then(|name| DiredSort::from_name(&name)).unwrap_or(self.config.dired_sort);
+                self.config.dired_directories_first = globals.get("Dired_directories_first").unwrap_or(self.config.dired_directories_first);
+                self.config.dired_show_hidden = globals.get("Dired_show_hidden").unwrap_or(self.config.dired_show_hidden);
+                self.config.dired_preview = globals.get("Dired_preview").unwrap_or(self.config.dired_preview);
+                self.config.dired_preview_width = globals.get("Dired_preview_width").unwrap_or(self.config.dired_preview_width);
+                self.config.icons = self.config.icons.clone().with_lua(&globals);
+                self.config.dired_icons = globals.get("Dired_icons").unwrap_or(self.config.dired_icons);
+                self.config.fzy_icons = globals.get("Fzy_icons").unwrap_or(self.config.fzy_icons);
 
 
                 self.config.tree_node = globals.get::<_, String>("Tree_node")
@@ -799,6 +1931,13 @@ impl Editor {
 
                 self.config.max_minibuffer_height = globals.get("Max_minibuffer_height").unwrap_or(self.config.max_minibuffer_height);
 
+                if let Some(lsp_servers) = lua_string_table(&globals, "Lsp_servers") {
+                    self.config.lsp_servers = lsp_servers;
+                }
+                if let Some(formatters) = lua_string_table(&globals, "Formatters") {
+                    self.config.formatters = formatters;
+                }
+
                 // self.config.current_theme_name = globals.get("Theme").unwrap_or(self.config.current_theme_name.clone()); // TODO BUG
                 
                 
@@ -870,12 +2009,39 @@ impl Editor {
         }
     }
     
+    // Evaluate code taken from the buffer starting at `first_line`, an error
+    // becomes a diagnostic on the line Lua blames
+    fn eval_from_buffer(&mut self, code: &str, first_line: usize) -> std::result::Result<(), String> {
+        let path = self.current_file_canonical.clone();
+        self.diagnostics.retain(|diagnostic| diagnostic.source != DiagnosticSource::Lua || diagnostic.path != path);
+        self.diagnostic_echoed = None;
+
+        let result = self.eval(code);
+        if let Err(message) = &result {
+            // Chunks are named [string "..."], the line follows the name
+            let location = Regex::new(r#"\[string "[^\n]*?"\]:(\d+): ([^\n]*)"#).unwrap();
+            if let Some(caps) = location.captures(message) {
+                let line: usize = caps[1].parse().unwrap_or(1);
+                self.diagnostics.push(Diagnostic {
+                    source: DiagnosticSource::Lua,
+                    path,
+                    line: first_line + line.saturating_sub(1),
+                    column: None,
+                    end: None,
+                    severity: Severity::Error,
+                    message: caps[2].to_string(),
+                });
+            }
+        }
+        result
+    }
+
     pub fn eval_buffer(&mut self) {
         let buffer_content = self.buffer.iter()
             .map(|line| line.iter().collect::<String>())
             .collect::<Vec<String>>().join("\n");
 
-        match self.eval(&buffer_content) {
+        match self.eval_from_buffer(&buffer_content, 0) {
             Ok(_) => {},
             Err(err_msg) => self.message(&err_msg),
         }
@@ -884,7 +2050,11 @@ impl Editor {
     pub fn eval_region(&mut self) -> std::result::Result<(), String> {
         let selected_text = self.extract_selected_text();
         if !selected_text.is_empty() {
-            self.eval(&selected_text)?;
+            let first_line = match (self.selection_start, self.selection_end) {
+                (Some(start), Some(end)) => start.1.min(end.1) as usize,
+                _ => self.cursor_pos.1 as usize,
+            };
+            self.eval_from_buffer(&selected_text, first_line)?;
         } else {
             return Err("No text selected.".to_string());
         }
@@ -897,7 +2067,7 @@ impl Editor {
         if let Some(line) = self.buffer.get(current_line_idx) {
             // Convert the current line's characters to a String
             let line_content = line.iter().collect::<String>();
-            match self.eval(&line_content) {
+            match self.eval_from_buffer(&line_content, current_line_idx) {
                 Ok(_) => self.message("Line executed successfully."),
                 Err(err_msg) => self.message(&err_msg),
             }
@@ -923,41 +2093,125 @@ impl Editor {
         std::process::exit(0);
     }
 
-    // TODO if the minibuffer is active
-    // write M-j and M-k keybind in modeline
-    pub fn compile(&mut self) {
-        // Extract the compile command from the configuration
-        let compile_command = &self.config.compile_command;
-        
-        // Execute the compile command
-        match Command::new("sh")
+    fn word_at_cursor(&self) -> String {
+        let Some(line) = self.buffer.get(self.cursor_pos.1 as usize) else { return String::new() };
+        let column = (self.cursor_pos.0 as usize).min(line.len());
+        let start = line[..column].iter().rposition(|c| !Editor::is_word_char(*c)).map_or(0, |i| i + 1);
+        let end = column + line[column..].iter().take_while(|c| Editor::is_word_char(**c)).count();
+        line[start..end].iter().collect()
+    }
+
+    pub fn format_buffer(&mut self) {
+        self.run_formatter(false);
+    }
+
+    // Pipe the buffer through the formatter of its language. When it fails its
+    // errors become diagnostics and the buffer stays as it is. False if it failed
+    fn run_formatter(&mut self, on_save: bool) -> bool {
+        let language = language_id(&self.current_file_path);
+        let Some(command) = language.and_then(|language| self.config.formatters.get(language)).cloned() else {
+            if !on_save {
+                self.message("No formatter for this buffer");
+            }
+            return true;
+        };
+        let path = self.current_file_canonical.clone();
+        self.diagnostics.retain(|diagnostic| !(diagnostic.source == DiagnosticSource::Formatter && diagnostic.path == path));
+        self.diagnostic_echoed = None;
+
+        let directory = path.parent().filter(|dir| dir.is_dir()).map_or_else(|| env::current_dir().unwrap_or_default(), Path::to_path_buf);
+        let mut text: String = self.buffer.iter().map(|line| line.iter().collect::<String>()).collect::<Vec<_>>().join("\n");
+        text.push('\n');
+        let output = Command::new(&self.config.shell)
             .arg("-c")
-            .arg(compile_command)
-            .output() {
-                Ok(output) => {
-                    let stdout_str = String::from_utf8_lossy(&output.stdout).trim().to_string();
-                    let stderr_str = String::from_utf8_lossy(&output.stderr).trim().to_string();
-
-                    // Determine what to display in the minibuffer based on the presence of stdout or stderr
-                    self.minibuffer_content = if !stdout_str.is_empty() {
-                        stdout_str
-                    } else if !stderr_str.is_empty() {
-                        format!("Error: {}", stderr_str)
-                    } else {
-                        "Compilation succeeded with no output.".to_string()
-                    };
-                },
-                Err(e) => {
-                    // Handle the case where the command could not be executed
-                    self.minibuffer_content = format!("Failed to execute compile command: {}", e);
-                }
+            .arg(&command)
+            .current_dir(directory)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .and_then(|mut child| {
+                // From another thread, so a formatter that writes before reading everything can't block us
+                let mut stdin = child.stdin.take();
+                let writer = std::thread::spawn(move || stdin.as_mut().map(|stdin| stdin.write_all(text.as_bytes())));
+                let output = child.wait_with_output();
+                let _ = writer.join();
+                output
+            });
+
+        let output = match output {
+            Ok(output) => output,
+            Err(e) => {
+                self.error(&format!("Couldn't run {}: {}", command, e));
+                return false;
+            },
+        };
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if !output.status.success() || (stdout.is_empty() && !self.buffer.iter().all(|line| line.is_empty())) {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let mut errors = formatter_errors(&stderr);
+            if errors.is_empty() {
+                errors.push((0, None, format!("{} failed with {}", command, output.status)));
+            }
+            let count = errors.len();
+            let first = errors[0].2.clone();
+            for (line, column, message) in errors {
+                self.diagnostics.push(Diagnostic {
+                    source: DiagnosticSource::Formatter,
+                    path: path.clone(),
+                    line: line.min(self.buffer.len().saturating_sub(1)),
+                    column,
+                    end: None,
+                    severity: Severity::Error,
+                    message,
+                });
             }
+            let more = if count > 1 { format!(" (and {} more)", count - 1) } else { String::new() };
+            self.error(&format!("Formatter: {}{}", first, more));
+            return false;
+        }
 
-        // Activate the minibuffer to show the compilation results
-        self.minibuffer_active = true;
+        let formatted: Vec<Vec<char>> = stdout.lines().map(|line| line.chars().collect()).collect();
+        if self.apply_formatted(formatted) {
+            if !on_save {
+                self.message("Formatted");
+            }
+        } else if !on_save {
+            self.message("Already formatted");
+        }
+        true
+    }
+
+    // Replace the buffer with its formatted self, touching only the lines that
+    // changed and keeping the cursor on the same text. One undo step
+    fn apply_formatted(&mut self, mut formatted: Vec<Vec<char>>) -> bool {
+        if formatted.is_empty() {
+            formatted.push(Vec::new());
+        }
+        if formatted == self.buffer {
+            return false;
+        }
+        self.snapshot();
+
+        // The cursor and the selection stay on their text
+        self.cursor_pos = formatted_position(&self.buffer, &formatted, self.cursor_pos);
+        self.selection_start = self.selection_start.map(|position| formatted_position(&self.buffer, &formatted, position));
+        self.selection_end = self.selection_end.map(|position| formatted_position(&self.buffer, &formatted, position));
+
+        let (prefix, suffix) = common_ends(&self.buffer, &formatted);
+        let (old_len, new_len) = (self.buffer.len(), formatted.len());
+        self.buffer.splice(prefix..old_len - suffix, formatted[prefix..new_len - suffix].iter().cloned());
+        self.snapshot();
+        true
     }
 
     pub fn buffer_save(&mut self) -> Result<()> {
+        // There is no file behind wdired, saving renames
+        if self.editing_wdired() {
+            self.wdired_finish();
+            return Ok(());
+        }
+        let formatted = !self.config.format_on_save || self.run_formatter(true);
         let content: String = self.buffer.iter()
             .map(|line| line.iter().collect::<String>())
             .collect::<Vec<String>>().join("\n");
@@ -965,9 +2219,17 @@ impl Editor {
         // Attempt to write the buffer to the file and handle the result
         match fs::write(&self.current_file_path, content) {
             Ok(_) => {
+                // A new file has a canonical path only now
+                self.set_current_file(self.current_file_path.clone());
                 // Display a success message with the path of the file saved
-                let message = format!("Wrote {}", self.current_file_path.display());
+                let message = if formatted {
+                    format!("Wrote {}", self.current_file_path.display())
+                } else {
+                    format!("Wrote {} unformatted, the formatter failed", self.current_file_path.display())
+                };
                 self.message(&message);
+                self.lsp_did_save();
+                self.git_gutter_reload();
 
                 // Check if the saved file is 'config.lua', evaluate it if true
                 if let Some(file_name) = self.current_file_path.file_name() {
@@ -993,23 +2255,6 @@ impl Editor {
         self.buffer.insert(self.cursor_pos.1 as usize + 1, tail);
         self.cursor_pos.1 += 1;
         self.cursor_pos.0 = 0;
-
-        let (_, height) = terminal::size().unwrap();
-        let text_area_height = height - self.minibuffer_height - 1; // -1 for modeline
-
-        let effective_text_area_height = text_area_height - self.config.bottom_scroll_margin;
-
-        if self.cursor_pos.1 >= self.offset.1 + effective_text_area_height {
-            let max_offset_possible = if self.buffer.len() as u16 > text_area_height {
-                self.buffer.len() as u16 - text_area_height
-            } else {
-                0 // No scrolling needed if the document is shorter than the viewport
-            };
-
-            if self.offset.1 < max_offset_possible {
-                self.offset.1 = (self.offset.1 + 1).min(max_offset_possible); // Safely increment offset
-            }
-        }
     }
 
     fn backspace(&mut self) {
@@ -1043,11 +2288,9 @@ impl Editor {
             let current_line = self.buffer.remove(self.cursor_pos.1 as usize);
             self.buffer[self.cursor_pos.1 as usize].extend(current_line);
         }
-        self.adjust_view_to_cursor("");
     }
 
 
-
     fn delete_char(&mut self) {
         if !self.buffer[self.cursor_pos.1 as usize].is_empty() {
             if self.cursor_pos.0 < self.buffer[self.cursor_pos.1 as usize].len() as u16 {
@@ -1075,18 +2318,11 @@ impl Editor {
         self.cursor_pos.1 = self.buffer.len() as u16 - 1; // Move to the last line
         let last_line_len = self.buffer.last().map_or(0, |line| line.len());
         self.cursor_pos.0 = last_line_len as u16; // Move to the end of the last line
-        let (_, height) = size().unwrap(); // Assuming `size` returns a Result
-        let visible_lines = height - self.minibuffer_height - 1;
-        if self.buffer.len() as u16 > visible_lines {
-            self.offset.1 = self.buffer.len() as u16 - visible_lines;
-        }
     }
 
     fn first_line(&mut self) {
         self.cursor_pos.0 = 0;
         self.cursor_pos.1 = 0;
-        self.offset.0 = 0;
-        self.offset.1 = 0;
     }
 
 
@@ -1099,30 +2335,11 @@ impl Editor {
             if self.cursor_pos.0 > prev_line_len {
                 self.cursor_pos.0 = prev_line_len;
             }
-
-            if self.config.emacs_scrolling {
-                // If cursor moves above the visible area, scroll half the page
-                let (_, height) = terminal::size().unwrap();
-                let text_area_height = height - self.minibuffer_height - 1;
-                let half_page = (text_area_height / 2) as u16;
-                if self.cursor_pos.1 < self.offset.1 {
-                    self.offset.1 = self.cursor_pos.1.saturating_sub(half_page);
-                    self.offset.1 = std::cmp::max(self.offset.1, 0);  // Ensure we don't scroll above the start of the buffer
-                }
-            } else {
-                // Standard scrolling behavior
-                if self.cursor_pos.1 < self.offset.1 + self.config.top_scroll_margin && self.offset.1 > 0 {
-                    self.offset.1 -= 1;
-                }
-            }
         }
     }
 
 
     fn down(&mut self) {
-        let (_, height) = terminal::size().unwrap();
-        let text_area_height = height - self.minibuffer_height - 1;
-
         if self.cursor_pos.1 < self.buffer.len() as u16 - 1 {
             self.cursor_pos.1 += 1;
 
@@ -1131,24 +2348,6 @@ impl Editor {
             if self.cursor_pos.0 > next_line_len {
                 self.cursor_pos.0 = next_line_len;
             }
-
-            if self.config.emacs_scrolling {
-                // If cursor moves below visible area, scroll half the page
-                let half_page = (text_area_height / 2) as u16;
-                if self.cursor_pos.1 >= self.offset.1 + text_area_height {
-                    self.offset.1 = self.cursor_pos.1.saturating_sub(half_page);
-                    let max_offset = self.buffer.len() as u16 - text_area_height;
-                    self.offset.1 = std::cmp::min(self.offset.1, max_offset);
-                }
-            } else {
-                // Standard scrolling behavior
-                let effective_text_area_height = text_area_height - self.config.bottom_scroll_margin;
-                if self.cursor_pos.1 >= self.offset.1 + effective_text_area_height {
-                    if self.offset.1 < self.buffer.len() as u16 - text_area_height {
-                        self.offset.1 += 1;
-                    }
-                }
-            }
         }
     }
 
@@ -1246,9 +2445,7 @@ impl Editor {
 
     fn backward_word(&mut self) {
         self.cursor_pos = self.scan_words(self.cursor_pos, -1);
-    }
-
-
+    }
 
 
     fn forward_sentence(&mut self) {
@@ -1290,10 +2487,8 @@ impl Editor {
     }
 
 
-
     fn recenter_top_bottom(&mut self) {
-        let (_, height) = terminal::size().unwrap();
-        let text_area_height = height - self.minibuffer_height - 1;
+        let text_area_height = self.text_area_height();
 
         match self.recenter_state {
             0 => {
@@ -1357,123 +2552,962 @@ impl Editor {
         self.cursor_pos.0 = required_indentation as u16;
     }
 
-    fn adjust_view_to_cursor(&mut self, adjustment: &str) {
-        let (_, height) = terminal::size().unwrap();
-        let text_area_height = height - self.minibuffer_height - 1;
+    fn text_area_height(&self) -> u16 {
+        let (_, height) = terminal::size().unwrap_or((80, 24));
+        height.saturating_sub(self.minibuffer_height + 1) // -1 for modeline
+    }
 
-        match adjustment {
-            "center" => {
-                let new_offset = self.cursor_pos.1.saturating_sub(text_area_height / 2);
-                self.offset.1 = new_offset;
+    // Ask the next `follow_cursor` to center the view on the cursor
+    fn request_recenter(&mut self) {
+        self.pending_recenter = true;
+    }
+
+    // Runs once after every command (see `run`), so no command has to
+    // scroll by itself: clamp the cursor into the buffer, then move the
+    // view just enough to keep it visible and honor the scroll margins.
+    fn follow_cursor(&mut self) {
+        // An empty buffer has nowhere to go but the origin
+        let last_line = (self.buffer.len() as u16).saturating_sub(1);
+        self.cursor_pos.1 = self.cursor_pos.1.min(last_line);
+        let line_len = self.buffer.get(self.cursor_pos.1 as usize).map_or(0, |line| line.len() as u16);
+        self.cursor_pos.0 = self.cursor_pos.0.min(line_len);
+
+        let text_area_height = self.text_area_height();
+        if text_area_height == 0 {
+            return;
+        }
+
+        let cursor_y = self.cursor_pos.1;
+        let outside_view = cursor_y < self.offset.1 || cursor_y >= self.offset.1 + text_area_height;
+
+        if self.pending_recenter || (self.config.emacs_scrolling && outside_view) {
+            // Center, emacs style
+            self.offset.1 = cursor_y.saturating_sub(text_area_height / 2);
+        } else if !self.config.emacs_scrolling {
+            // The margins can't take more than half of the window each
+            let max_margin = text_area_height.saturating_sub(1) / 2;
+            let top_margin = self.config.top_scroll_margin.min(max_margin);
+            let bottom_margin = self.config.bottom_scroll_margin.min(max_margin);
+            let max_offset = (self.buffer.len() as u16).saturating_sub(text_area_height);
+
+            if cursor_y < self.offset.1 + top_margin {
+                self.offset.1 = cursor_y.saturating_sub(top_margin);
+            } else if cursor_y + bottom_margin >= self.offset.1 + text_area_height {
+                let wanted = cursor_y + bottom_margin + 1 - text_area_height;
+                // Don't scroll past the end of the buffer just to make room for the margin
+                self.offset.1 = wanted.min(max_offset.max(self.offset.1));
+            }
+        }
+        self.pending_recenter = false;
+
+        // Whatever happened above, the cursor line must end up on screen
+        if cursor_y < self.offset.1 {
+            self.offset.1 = cursor_y;
+        } else if cursor_y >= self.offset.1 + text_area_height {
+            self.offset.1 = cursor_y + 1 - text_area_height;
+        }
+        self.offset.1 = self.offset.1.min(last_line);
+    }
+
+    fn goto_line(&mut self, line_number: usize) {
+        if line_number == 0 || line_number > self.buffer.len() {
+            self.message(&format!("Line number {} doesn't exist.", line_number));
+            return;
+        }
+
+        self.cursor_pos.1 = (line_number - 1) as u16; // Convert to 0-based index
+        self.cursor_pos.0 = 0;
+
+        self.request_recenter();
+    }
+
+    // The matches of the query, found again if it or the buffer changed
+    fn search_refresh(&mut self) {
+        self.search.refresh(&self.buffer, self.buffer.edits, self.config.search_smart_case);
+    }
+
+    // The match after (or before) `from` in the cached matches, wrapping
+    // around the buffer. True when it wrapped
+    fn find_search_match(&self, from: (u16, u16), forward: bool, inclusive: bool) -> Option<((u16, u16), bool)> {
+        let matches = self.search.matches();
+        let from = (from.1 as usize, from.0 as usize);
+        let found = if forward {
+            let next = matches.partition_point(|&(line, start, _)| (line, start) < from || (!inclusive && (line, start) == from));
+            matches.get(next).map(|m| (m, false)).or_else(|| matches.first().map(|m| (m, true)))
+        } else {
+            let next = matches.partition_point(|&(line, start, _)| (line, start) < from);
+            next.checked_sub(1).map(|i| (&matches[i], false)).or_else(|| matches.last().map(|m| (m, true)))
+        };
+        found.map(|(&(line, start, _), wrapped)| ((start as u16, line as u16), wrapped))
+    }
+
+    // (index of the match under the cursor, total matches), for the "3/17" counter
+    fn search_match_count(&self) -> (usize, usize) {
+        let matches = self.search.matches();
+        let cursor = (self.cursor_pos.1 as usize, self.cursor_pos.0 as usize);
+        let current = matches.binary_search_by(|&(line, start, _)| (line, start).cmp(&cursor)).map_or(0, |i| i + 1);
+        (current, matches.len())
+    }
+
+    fn search_status(&self) -> String {
+        if self.search.query.is_empty() {
+            return String::new();
+        }
+        if self.search.regex().is_none() {
+            return "Invalid regexp".to_string();
+        }
+
+        let (current, total) = self.search_match_count();
+        let mut status = format!("{}/{}", current, total);
+        if self.search.failing {
+            status.push_str(" Failing");
+        } else if self.search.wrapped {
+            status.push_str(" Wrapped");
+        }
+        status
+    }
+
+    fn search_step(&mut self, forward: bool) {
+        self.search_refresh();
+        if self.search.regex().is_none() {
+            self.message("No previous search");
+            return;
+        }
+
+        match self.find_search_match(self.cursor_pos, forward, false) {
+            Some((pos, wrapped)) => {
+                self.cursor_pos = pos;
+                self.search.wrapped = wrapped;
+                self.search.failing = false;
+                if wrapped {
+                    self.request_recenter();
+                }
+                if !self.searching {
+                    let status = self.search_status();
+                    self.message(&format!("[{}] {}", status, self.search.query));
+                }
+            },
+            None => {
+                self.search.failing = true;
+                if !self.searching {
+                    self.message(&format!("Search failed: {}", self.search.query));
+                }
+            }
+        }
+    }
+
+    fn search_next(&mut self) {
+        self.search_step(true);
+    }
+
+    fn search_previous(&mut self) {
+        self.search_step(false);
+    }
+
+    fn isearch_start(&mut self) {
+        self.searching = true;
+        self.highlight_search = true;
+        self.minibuffer_active = true;
+        self.minibuffer_content.clear();
+        self.minibuffer_cursor_pos = (0, 0);
+        self.search.origin = self.cursor_pos;
+        self.search.wrapped = false;
+        self.search.failing = false;
+        self.minibuffer_history.index = None;
+        self.search.query.clear();
+        self.isearch_set_prefix();
+    }
+
+    fn isearch_set_prefix(&mut self) {
+        self.minibuffer_prompt = Some(if self.search.regex_mode { Prompt::RegexpSearch } else { Prompt::Search });
+    }
+
+    // Called after every edit of the query, jump to the first match from where the search started
+    fn isearch_update(&mut self) {
+        self.search.query = self.minibuffer_content.clone();
+        self.search.wrapped = false;
+        self.search.failing = false;
+
+        self.search_refresh();
+        if self.search.regex().is_none() {
+            self.cursor_pos = self.search.origin;
+            return;
+        }
+
+        match self.find_search_match(self.search.origin, true, true) {
+            Some((pos, wrapped)) => {
+                self.cursor_pos = pos;
+                self.search.wrapped = wrapped;
+            },
+            None => {
+                self.cursor_pos = self.search.origin;
+                self.search.failing = true;
+            }
+        }
+    }
+
+    fn isearch_set_query(&mut self, query: String) {
+        self.minibuffer_cursor_pos = (query.chars().count() as u16, 0);
+        self.minibuffer_content = query;
+        self.isearch_update();
+    }
+
+    // Keys that only make sense while searching, returns true if the key was consumed
+    fn handle_isearch_keys(&mut self, key: KeyEvent) -> bool {
+        match (key.code, key.modifiers) {
+            (KeyCode::Char('s'), KeyModifiers::CONTROL) | (KeyCode::Char('r'), KeyModifiers::CONTROL) => {
+                let forward = key.code == KeyCode::Char('s');
+                if self.minibuffer_content.is_empty() {
+                    // Empty query, reuse the last one like emacs does
+                    if let Some(last) = self.isearch_last_query() {
+                        self.isearch_set_query(last);
+                    }
+                } else {
+                    self.search_step(forward);
+                }
+            },
+            (KeyCode::Char('p'), KeyModifiers::ALT) | (KeyCode::Char('n'), KeyModifiers::ALT) => {
+                self.minibuffer_history_step(key.code == KeyCode::Char('p'));
+                self.isearch_update();
+            },
+            (KeyCode::Char('r'), KeyModifiers::ALT) => {
+                // The other mode has its own history
+                self.minibuffer_history.index = None;
+                self.search.regex_mode = !self.search.regex_mode;
+                self.isearch_set_prefix();
+                self.isearch_update();
+            },
+            (KeyCode::Char('g'), KeyModifiers::CONTROL) | (KeyCode::Esc, _) => {
+                self.isearch_cancel();
+            },
+            (KeyCode::Enter, _) => {
+                self.isearch_finish();
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    fn isearch_exit(&mut self) {
+        self.searching = false;
+        self.minibuffer_active = false;
+        self.minibuffer_content.clear();
+        self.minibuffer_prompt = None;
+        self.minibuffer_cursor_pos = (0, 0);
+    }
+
+    fn isearch_finish(&mut self) {
+        let query = self.search.query.clone();
+        self.minibuffer_remember(&query);
+        self.isearch_exit();
+        if self.search.failing {
+            self.message(&format!("Search failed: {}", query));
+        }
+    }
+
+    fn isearch_last_query(&self) -> Option<String> {
+        let name = self.minibuffer_prompt.as_ref().and_then(Prompt::history)?;
+        self.minibuffer_history.last(name).cloned()
+    }
+
+    fn isearch_cancel(&mut self) {
+        self.cursor_pos = self.search.origin;
+        self.search.query = self.isearch_last_query().unwrap_or_default();
+        self.highlight_search = false;
+        self.isearch_exit();
+    }
+
+    // Entry point for replace-string, replace-regexp and query-replace,
+    // the active visual selection (if any) becomes the region to work on.
+    fn replace_start(&mut self, regex_mode: bool, query: bool) {
+        let region = match (self.mode == Mode::Visual, self.selection_start, self.selection_end) {
+            (true, Some(start), Some(end)) => {
+                let (start, end) = if (start.1, start.0) > (end.1, end.0) { (end, start) } else { (start, end) };
+                Some((start, end))
+            },
+            _ => None,
+        };
+
+        if self.mode == Mode::Visual {
+            self.mode = Mode::Normal;
+            self.selection_start = None;
+            self.selection_end = None;
+        }
+
+        let replace = Replace::new(regex_mode, query, region);
+        self.minibuffer_open(Prompt::Replace(replace.prompt()), "");
+        self.replace = Some(replace);
+    }
+
+    pub fn replace_string(&mut self) {
+        self.replace_start(false, false);
+    }
+
+    pub fn replace_regexp(&mut self) {
+        self.replace_start(true, false);
+    }
+
+    pub fn query_replace(&mut self) {
+        self.replace_start(false, true);
+    }
+
+    pub fn query_replace_regexp(&mut self) {
+        self.replace_start(true, true);
+    }
+
+    // Keys while a replace is in progress, returns true if the key was consumed
+    fn handle_replace_keys(&mut self, key: KeyEvent) -> bool {
+        let asking = self.replace.as_ref().map_or(false, |r| r.current.is_some());
+
+        match (key.code, key.modifiers) {
+            (KeyCode::Char('g'), KeyModifiers::CONTROL) | (KeyCode::Esc, _) => {
+                self.replace_finish();
+            },
+            (KeyCode::Char(c), _) if asking => match c {
+                'y' | ' ' => self.replace_answer(true),
+                'n' => self.replace_answer(false),
+                '!' => self.replace_remaining(),
+                '^' => self.replace_back(),
+                'q' => self.replace_finish(),
+                _ => {},
+            },
+            (KeyCode::Enter, _) if asking => {
+                self.replace_finish();
+            },
+            (KeyCode::Enter, _) => {
+                let input = std::mem::take(&mut self.minibuffer_content);
+                self.minibuffer_cursor_pos = (0, 0);
+                self.minibuffer_remember(&input);
+                self.replace_submit(input);
+            },
+            _ => return asking,
+        }
+        true
+    }
+
+    fn replace_submit(&mut self, input: String) {
+        let Some(replace) = self.replace.as_mut() else { return };
+
+        if replace.from.is_none() {
+            if input.is_empty() {
+                self.replace_finish();
+                return;
+            }
+            match build_search_regex(&input, replace.regex_mode, self.config.search_smart_case) {
+                Ok(re) => replace.re = Some(re),
+                Err(e) => {
+                    self.replace = None;
+                    self.minibuffer_active = false;
+                    self.minibuffer_prompt = None;
+                    self.message(&format!("Invalid regexp: {}", e));
+                    return;
+                }
+            }
+            replace.from = Some(input);
+            self.minibuffer_prompt = Some(Prompt::Replace(replace.prompt()));
+            return;
+        }
+
+        replace.to = input;
+        let start = replace.region.map_or(self.cursor_pos, |(start, _)| start);
+        if replace.query {
+            self.replace_ask_from(start, true, true);
+        } else {
+            self.replace_all_from(start);
+            self.replace_finish();
+        }
+    }
+
+    fn find_replace_match(&self, from: (u16, u16), inclusive: bool, empty_at_from: bool) -> Option<(u16, u16, u16)> {
+        self.replace.as_ref()?.find_match(&self.buffer, from, inclusive, empty_at_from)
+    }
+
+    fn replace_match(&mut self, line_idx: u16, start: u16, end: u16) -> u16 {
+        let Some(replace) = self.replace.as_mut() else { return end };
+        replace.replace_match(&mut self.buffer, self.cursor_pos, line_idx, start, end)
+    }
+
+    fn replace_all_from(&mut self, from: (u16, u16)) {
+        let Some(replace) = self.replace.as_mut() else { return };
+        if let Some(cursor) = replace.replace_all(&mut self.buffer, from) {
+            self.cursor_pos = cursor;
+        }
+    }
+
+    fn replace_ask_from(&mut self, from: (u16, u16), inclusive: bool, empty_at_from: bool) {
+        match self.find_replace_match(from, inclusive, empty_at_from) {
+            Some((line, start, end)) => {
+                self.cursor_pos = (start, line);
+                if let Some(replace) = self.replace.as_mut() {
+                    replace.current = Some((line, start, end));
+                    self.minibuffer_prompt = Some(Prompt::Replace(format!(
+                        "Query replacing {} with {} (y/n/!/q/^): ",
+                        replace.from.as_deref().unwrap_or(""),
+                        replace.to
+                    )));
+                }
+            },
+            None => self.replace_finish(),
+        }
+    }
+
+    fn replace_answer(&mut self, replace_it: bool) {
+        let Some((line, start, end)) = self.replace.as_ref().and_then(|r| r.current) else { return };
+
+        if replace_it {
+            let new_end = self.replace_match(line, start, end);
+            self.replace_ask_from((new_end, line), true, false);
+        } else {
+            if let Some(replace) = self.replace.as_mut() {
+                replace.answers.push((line as usize, self.buffer[line as usize].clone(), self.cursor_pos, false));
+            }
+            self.replace_ask_from((start, line), false, false);
+        }
+    }
+
+    fn replace_remaining(&mut self) {
+        let Some((line, start, _)) = self.replace.as_ref().and_then(|r| r.current) else { return };
+        self.replace_all_from((start, line));
+        self.replace_finish();
+    }
+
+    // ^ goes back to the previous match, undoing it if it was replaced
+    fn replace_back(&mut self) {
+        let Some(replace) = self.replace.as_mut() else { return };
+        let Some((line_idx, line, cursor, replaced)) = replace.answers.pop() else {
+            self.message("No previous match");
+            return;
+        };
+
+        if replaced {
+            if let Some((_, region_end)) = replace.region.as_mut() {
+                if region_end.1 as usize == line_idx {
+                    let delta = line.len() as i32 - self.buffer[line_idx].len() as i32;
+                    region_end.0 = (region_end.0 as i32 + delta).max(0) as u16;
+                }
+            }
+            self.buffer[line_idx] = line;
+            replace.count -= 1;
+        }
+        self.cursor_pos = cursor;
+        self.replace_ask_from(cursor, true, true);
+    }
+
+    fn replace_finish(&mut self) {
+        let count = self.replace.take().map_or(0, |replace| replace.finish(&mut self.history, &self.buffer, self.cursor_pos));
+        self.minibuffer_active = false;
+        self.minibuffer_prompt = None;
+        self.minibuffer_content.clear();
+        self.minibuffer_cursor_pos = (0, 0);
+
+        self.message(&format!("Replaced {} occurrence{}", count, if count == 1 { "" } else { "s" }));
+    }
+
+    pub fn project_grep(&mut self) {
+        let pattern = self.grep.as_ref().map_or(String::new(), |grep| grep.pattern.clone());
+        self.minibuffer_open(Prompt::ProjectGrep, &pattern);
+    }
+
+    fn minibuffer_open(&mut self, prompt: Prompt, content: &str) {
+        self.minibuffer_active = true;
+        self.minibuffer_prompt = Some(prompt);
+        self.minibuffer_content = content.to_string();
+        let lines: Vec<&str> = content.split('\n').collect();
+        self.minibuffer_cursor_pos = (lines[lines.len() - 1].chars().count() as u16, lines.len() as u16 - 1);
+        self.minibuffer_history.index = None;
+        self.minibuffer_hint.clear();
+    }
+
+    fn minibuffer_close(&mut self) {
+        self.minibuffer_content.clear();
+        self.minibuffer_prompt = None;
+        self.minibuffer_cursor_pos = (0, 0);
+        self.minibuffer_active = false;
+        self.minibuffer_hint.clear();
+        self.searching = false;
+    }
+
+    fn minibuffer_prefix(&self) -> String {
+        self.minibuffer_prompt.as_ref().map_or(String::new(), Prompt::label)
+    }
+
+    fn minibuffer_remember(&mut self, input: &str) {
+        let Some(name) = self.minibuffer_prompt.as_ref().and_then(Prompt::history) else { return };
+        // Only logged, the minibuffer may still be reading
+        if let Err(e) = self.minibuffer_history.push(name, input) {
+            self.messages.push(format!("Couldn't save the minibuffer history: {}", e));
+        }
+    }
+
+    fn minibuffer_lines(&self) -> Vec<Vec<char>> {
+        self.minibuffer_content.split('\n').map(|line| line.chars().collect()).collect()
+    }
+
+    fn minibuffer_set_lines(&mut self, lines: &[Vec<char>]) {
+        self.minibuffer_content = lines.iter().map(|line| line.iter().collect::<String>()).collect::<Vec<_>>().join("\n");
+        if self.searching {
+            self.isearch_update();
+        }
+    }
+
+    // The line the cursor is on and the cursor column, clamped to the content
+    fn minibuffer_line(&self) -> (Vec<char>, usize) {
+        let lines = self.minibuffer_lines();
+        let y = (self.minibuffer_cursor_pos.1 as usize).min(lines.len() - 1);
+        let line = lines.into_iter().nth(y).unwrap_or_default();
+        let x = (self.minibuffer_cursor_pos.0 as usize).min(line.len());
+        (line, x)
+    }
+
+    fn minibuffer_insert(&mut self, text: &str) {
+        let mut lines = self.minibuffer_lines();
+        let y = (self.minibuffer_cursor_pos.1 as usize).min(lines.len() - 1);
+        let x = (self.minibuffer_cursor_pos.0 as usize).min(lines[y].len());
+        let tail = lines[y].split_off(x);
+
+        let mut inserted: Vec<Vec<char>> = text.split('\n').map(|line| line.chars().collect()).collect();
+        let new_y = y + inserted.len() - 1;
+        let mut last = inserted.pop().unwrap_or_default();
+        let new_x = if inserted.is_empty() { x + last.len() } else { last.len() };
+        last.extend(tail);
+        if inserted.is_empty() {
+            lines[y].extend(last);
+        } else {
+            lines[y].extend(inserted.remove(0));
+            inserted.push(last);
+            lines.splice(y + 1..y + 1, inserted);
+        }
+
+        self.minibuffer_cursor_pos = (new_x as u16, new_y as u16);
+        self.minibuffer_set_lines(&lines);
+    }
+
+    // Delete columns from..to of the cursor line, killing them when asked
+    fn minibuffer_delete(&mut self, from: usize, to: usize, kill: bool) {
+        if from >= to {
+            return;
+        }
+        let mut lines = self.minibuffer_lines();
+        let y = (self.minibuffer_cursor_pos.1 as usize).min(lines.len() - 1);
+        let removed: String = lines[y].drain(from..to).collect();
+        if kill {
+            self.clipboard = removed;
+        }
+        self.minibuffer_cursor_pos.0 = from as u16;
+        self.minibuffer_set_lines(&lines);
+    }
+
+    fn minibuffer_word_boundary(&self, forward: bool) -> usize {
+        let (line, mut x) = self.minibuffer_line();
+        if forward {
+            while x < line.len() && !Editor::is_word_char(line[x]) { x += 1; }
+            while x < line.len() && Editor::is_word_char(line[x]) { x += 1; }
+        } else {
+            while x > 0 && !Editor::is_word_char(line[x - 1]) { x -= 1; }
+            while x > 0 && Editor::is_word_char(line[x - 1]) { x -= 1; }
+        }
+        x
+    }
+
+    fn minibuffer_move_line(&mut self, down: bool) {
+        let num_lines = self.minibuffer_content.matches('\n').count() as u16 + 1;
+        if down && self.minibuffer_cursor_pos.1 + 1 < num_lines {
+            self.minibuffer_cursor_pos.1 += 1;
+        } else if !down && self.minibuffer_cursor_pos.1 > 0 {
+            self.minibuffer_cursor_pos.1 -= 1;
+        }
+        self.minibuffer_cursor_pos.0 = self.minibuffer_line().1 as u16;
+    }
+
+    // C-k kills to the end of the line, or joins the next line when already there
+    fn minibuffer_kill_line(&mut self) {
+        let (line, x) = self.minibuffer_line();
+        if x < line.len() {
+            self.minibuffer_delete(x, line.len(), true);
+            return;
+        }
+
+        if self.minibuffer_join_line(self.minibuffer_cursor_pos.1 as usize) {
+            self.clipboard = "\n".to_string();
+        }
+    }
+
+    // Join line y with the one after it, the cursor ends up at the join.
+    // False when y is the last line
+    fn minibuffer_join_line(&mut self, y: usize) -> bool {
+        let mut lines = self.minibuffer_lines();
+        if y + 1 >= lines.len() {
+            return false;
+        }
+        let next = lines.remove(y + 1);
+        self.minibuffer_cursor_pos = (lines[y].len() as u16, y as u16);
+        lines[y].extend(next);
+        self.minibuffer_set_lines(&lines);
+        true
+    }
+
+    fn minibuffer_history_step(&mut self, older: bool) {
+        let Some(name) = self.minibuffer_prompt.as_ref().and_then(Prompt::history) else { return };
+        match self.minibuffer_history.step(name, older, &self.minibuffer_content) {
+            Some(entry) => {
+                self.minibuffer_content = entry;
+                let lines = self.minibuffer_lines();
+                self.minibuffer_cursor_pos = (lines[lines.len() - 1].len() as u16, lines.len() as u16 - 1);
+            },
+            None => self.minibuffer_hint = if older { "[Beginning of history]" } else { "[End of history]" }.to_string(),
+        }
+    }
+
+    fn lua_global_names(&self) -> Vec<String> {
+        self.lua.globals().pairs::<String, mlua::Value>().filter_map(|pair| pair.ok().map(|(name, _)| name)).collect()
+    }
+
+    // Tab: complete what is before the cursor with what the prompt can take,
+    // listing the candidates when they don't agree
+    fn minibuffer_complete(&mut self) {
+        let (line, x) = self.minibuffer_line();
+        let word_start = |is_part: &dyn Fn(char) -> bool| {
+            line[..x].iter().rposition(|c| !is_part(*c)).map_or(0, |i| i + 1)
+        };
+
+        let (start, candidates) = match &self.minibuffer_prompt {
+            Some(Prompt::Eval) => (word_start(&|c| c.is_alphanumeric() || c == '_'), self.lua_global_names()),
+            Some(Prompt::ShellCommand | Prompt::DiredShell { .. }) => {
+                let start = word_start(&|c| !c.is_whitespace());
+                let word: String = line[start..x].iter().collect();
+                if line[..start].iter().all(|c| c.is_whitespace()) && !word.contains('/') {
+                    (start, path_executables())
+                } else {
+                    let dired = self.dired.as_ref().filter(|_| matches!(self.minibuffer_prompt, Some(Prompt::DiredShell { .. })));
+                    let cwd = dired.map_or_else(|| env::current_dir().unwrap_or_default(), |dired| dired.current_path.clone());
+                    (start, file_name_candidates(&cwd, &word))
+                }
+            },
+            Some(Prompt::DiredTarget { op: DiredOp::Chmod, .. }) => return,
+            Some(Prompt::DiredTouch { .. } | Prompt::DiredCreateDirectory | Prompt::DiredRename | Prompt::DiredTarget { .. }) => {
+                let Some(dired) = &self.dired else { return };
+                let typed: String = line[..x].iter().collect();
+                (0, file_name_candidates(&dired.current_path, &typed))
+            },
+            _ => return,
+        };
+
+        let typed: String = line[start..x].iter().collect();
+        let mut matches: Vec<String> = candidates.into_iter().filter(|c| c.starts_with(&typed)).collect();
+        matches.sort();
+        matches.dedup();
+
+        let common = common_prefix(&matches);
+        if common.len() > typed.len() {
+            self.minibuffer_insert(&common[typed.len()..]);
+        }
+        match matches.len() {
+            0 => self.minibuffer_hint = "[No match]".to_string(),
+            1 => {},
+            _ => self.minibuffer_hint = format!("{{{}}}", matches.join(" | ")),
+        }
+    }
+
+    // Keys for the prompts, isearch and replace get the first look
+    fn handle_minibuffer_keys(&mut self, key: KeyEvent) -> Result<bool> {
+        if key.code != KeyCode::Tab {
+            self.minibuffer_hint.clear();
+        }
+
+        match (key.code, key.modifiers) {
+            (KeyCode::Char('g'), KeyModifiers::CONTROL) | (KeyCode::Esc, _) => {
+                if let (Some(Prompt::DiredFilter(before)), Some(dired)) = (&self.minibuffer_prompt, &mut self.dired) {
+                    dired.set_filter(before);
+                }
+                self.minibuffer_close();
+            },
+            (KeyCode::Char('n'), KeyModifiers::CONTROL) | (KeyCode::Down, KeyModifiers::NONE) => self.minibuffer_move_line(true),
+            (KeyCode::Char('p'), KeyModifiers::CONTROL) | (KeyCode::Up, KeyModifiers::NONE) => self.minibuffer_move_line(false),
+            (KeyCode::Char('f'), KeyModifiers::CONTROL) | (KeyCode::Right, KeyModifiers::NONE) => {
+                let (line, x) = self.minibuffer_line();
+                self.minibuffer_cursor_pos.0 = (x + 1).min(line.len()) as u16;
+            },
+            (KeyCode::Char('b'), KeyModifiers::CONTROL) | (KeyCode::Left, KeyModifiers::NONE) => {
+                let (_, x) = self.minibuffer_line();
+                self.minibuffer_cursor_pos.0 = x.saturating_sub(1) as u16;
+            },
+            (KeyCode::Char('a'), KeyModifiers::CONTROL) | (KeyCode::Home, _) => self.minibuffer_cursor_pos.0 = 0,
+            (KeyCode::Char('e'), KeyModifiers::CONTROL) | (KeyCode::End, _) => {
+                self.minibuffer_cursor_pos.0 = self.minibuffer_line().0.len() as u16;
+            },
+            (KeyCode::Char('f'), KeyModifiers::ALT) => self.minibuffer_cursor_pos.0 = self.minibuffer_word_boundary(true) as u16,
+            (KeyCode::Char('b'), KeyModifiers::ALT) => self.minibuffer_cursor_pos.0 = self.minibuffer_word_boundary(false) as u16,
+            (KeyCode::Char('k'), KeyModifiers::CONTROL) => self.minibuffer_kill_line(),
+            (KeyCode::Char('u'), KeyModifiers::CONTROL) => {
+                let (_, x) = self.minibuffer_line();
+                self.minibuffer_delete(0, x, true);
+            },
+            (KeyCode::Char('w'), KeyModifiers::CONTROL) => {
+                let (_, x) = self.minibuffer_line();
+                let from = self.minibuffer_word_boundary(false);
+                self.minibuffer_delete(from, x, true);
+            },
+            (KeyCode::Char('d'), KeyModifiers::ALT) => {
+                let (_, x) = self.minibuffer_line();
+                let to = self.minibuffer_word_boundary(true);
+                self.minibuffer_delete(x, to, true);
+            },
+            (KeyCode::Char('d'), KeyModifiers::CONTROL) | (KeyCode::Delete, _) => {
+                let (line, x) = self.minibuffer_line();
+                if x == line.len() {
+                    self.minibuffer_join_line(self.minibuffer_cursor_pos.1 as usize);
+                } else {
+                    self.minibuffer_delete(x, x + 1, false);
+                }
+            },
+            (KeyCode::Char('y'), KeyModifiers::CONTROL) => {
+                let text = self.clipboard.clone();
+                self.minibuffer_insert(&text);
+            },
+            (KeyCode::Char('p'), KeyModifiers::ALT) => self.minibuffer_history_step(true),
+            (KeyCode::Char('n'), KeyModifiers::ALT) => self.minibuffer_history_step(false),
+            (KeyCode::Tab, _) => self.minibuffer_complete(),
+            (KeyCode::Backspace, _) => {
+                let (_, x) = self.minibuffer_line();
+                let y = self.minibuffer_cursor_pos.1 as usize;
+                if x == 0 && y > 0 {
+                    self.minibuffer_join_line(y - 1);
+                } else {
+                    self.minibuffer_delete(x.saturating_sub(1), x, false);
+                }
+            },
+            (KeyCode::Enter, _) => {
+                self.minibuffer_submit()?;
+                return Ok(true);
+            },
+            (KeyCode::Char(c), KeyModifiers::NONE | KeyModifiers::SHIFT) if self.keychords.all_fields_false() => {
+                self.minibuffer_insert(&c.to_string());
+            },
+            _ => {},
+        }
+        Ok(false)
+    }
+
+    fn minibuffer_submit(&mut self) -> Result<()> {
+        let content = std::mem::take(&mut self.minibuffer_content);
+        self.minibuffer_remember(&content);
+        let prompt = self.minibuffer_prompt.take();
+        self.minibuffer_cursor_pos = (0, 0);
+        self.minibuffer_active = false;
+
+        match prompt {
+            Some(Prompt::Eval) => match self.eval(&content) {
+                Ok(_) => self.message("Code executed successfully."),
+                Err(err) => self.message(&format!("Error executing code: {}", err)),
+            },
+            Some(Prompt::ShellCommand) => {
+                let output = Command::new(&self.config.shell)
+                    .arg("-c")
+                    .arg(&content)
+                    .output();
+
+                match output {
+                    Ok(output) => {
+                        let stdout = String::from_utf8_lossy(&output.stdout);
+                        let stderr = String::from_utf8_lossy(&output.stderr);
+
+                        if !stdout.is_empty() {
+                            self.message(&stdout);
+                        } else if !stderr.is_empty() {
+                            self.message(&format!("Error: {}", stderr));
+                        } else {
+                            self.message("(Shell command succeeded with no output)");
+                        }
+                    },
+                    Err(e) => {
+                        self.message(&format!("Failed to execute command: {}", e));
+                    }
+                }
             },
-            "enough" => {
-                if self.cursor_pos.1 < self.offset.1 {
-                    self.offset.1 = self.cursor_pos.1;
-                } else if self.cursor_pos.1 >= (self.offset.1 + text_area_height) {
-                    self.offset.1 = self.cursor_pos.1.saturating_sub(text_area_height) + 1;
+            Some(Prompt::ProjectGrep) => self.project_grep_start(&content),
+            Some(Prompt::Ex) => match content.as_str() {
+                "w" => match self.buffer_save() {
+                    Ok(_) => self.message("File saved successfully."),
+                    Err(e) => self.message(&format!("Failed to save file: {}", e)),
+                },
+                "q" => self.quit(),
+                "wq" => {
+                    self.buffer_save()?;
+                    self.quit();
+                },
+                _ => {
+                    if let Ok(line_number) = content.parse::<usize>() {
+                        self.goto_line(line_number);
+                    } else {
+                        self.message("Invalid command");
+                    }
                 }
             },
-            _ => {
-                // Default behavior respecting top and bottom scroll margins
-                if self.cursor_pos.1 < self.offset.1 + self.config.top_scroll_margin {
-                    self.offset.1 = self.cursor_pos.1.saturating_sub(self.config.top_scroll_margin);
-                } else if self.cursor_pos.1 + self.config.bottom_scroll_margin >= self.offset.1 + text_area_height {
-                    self.offset.1 = self.cursor_pos.1 + self.config.bottom_scroll_margin + 1 - text_area_height;
+            Some(Prompt::DiredCreateDirectory) => {
+                if let Some(dired) = &mut self.dired {
+                    dired.create_directory(&content)?;
+                    dired.refresh_directory_contents()?;
                 }
-            }
+            },
+            Some(Prompt::DiredFilter(_)) => {
+                if let Some(dired) = &mut self.dired {
+                    dired.set_filter(&content);
+                }
+            },
+            Some(Prompt::DiredMarkRegexp) => match Regex::new(&content) {
+                Ok(regex) => {
+                    let count = self.dired.as_mut().map_or(0, |dired| dired.mark_where(|entry| regex.is_match(&entry.name)));
+                    self.message(&format!("Marked {} file{}", count, if count == 1 { "" } else { "s" }));
+                },
+                Err(e) => self.error(&format!("Invalid regexp: {}", e)),
+            },
+            Some(Prompt::DiredTarget { op, paths }) => {
+                if !content.is_empty() {
+                    self.minibuffer_open(Prompt::DiredConfirm { op, paths, target: content }, "");
+                }
+            },
+            Some(Prompt::DiredConfirm { op, paths, target }) => {
+                if content == "y" {
+                    self.dired_execute(op, &paths, &target);
+                }
+            },
+            Some(Prompt::DiredShell { paths, background }) => {
+                if !content.is_empty() {
+                    self.dired_shell(&content, &paths, background);
+                }
+            },
+            Some(Prompt::GitDiscard(_)) => {
+                if content == "y" {
+                    self.git_discard_confirmed();
+                }
+            },
+            Some(Prompt::DiredRename) => {
+                if let Some(dired) = &mut self.dired {
+                    match dired.rename_entry(&content) {
+                        Ok((from, to)) => self.dired_undo_push(format!("Renamed {}", dired_summary(std::slice::from_ref(&from))), vec![(from, to, None)]),
+                        Err(e) => self.error(&format!("Failed to rename: {}", e)),
+                    }
+                }
+            },
+            Some(Prompt::DiredTouch { open }) => {
+                let Some(dired) = &mut self.dired else { return Ok(()) };
+                let file_path = dired.current_path.join(&content);
+                if std::fs::File::create(&file_path).is_ok() {
+                    dired.refresh_directory_contents()?;
+                    if open {
+                        self.open(&file_path, None)?;
+                    }
+                }
+            },
+            Some(Prompt::LspRename) => {
+                if !content.is_empty() {
+                    self.lsp_request("textDocument/rename", vec![("newName", content.into())], LspRequest::Rename);
+                }
+            },
+            // Search and replace read their input themselves
+            Some(Prompt::Search | Prompt::RegexpSearch | Prompt::Replace(_)) | None => {},
         }
+        Ok(())
     }
 
-    fn goto_line(&mut self, line_number: usize) {
-        if line_number == 0 || line_number > self.buffer.len() {
-            self.message(&format!("Line number {} doesn't exist.", line_number));
+    fn project_grep_start(&mut self, pattern: &str) {
+        if pattern.is_empty() {
             return;
         }
+        let re = match build_search_regex(pattern, true, self.config.search_smart_case) {
+            Ok(re) => re,
+            Err(_) => {
+                self.error(&format!("Invalid regexp: {}", pattern));
+                return;
+            },
+        };
+        let root = project_root(&self.current_file_path);
+        self.message(&format!("Searching {} for {}", root.display(), pattern));
+        self.grep = Some(Grep::start(pattern, re, root));
+        self.mode = Mode::Grep;
+    }
 
-        self.cursor_pos.1 = (line_number - 1) as u16; // Convert to 0-based index
-        self.cursor_pos.0 = 0;
+    fn grep_visit(&mut self) -> Result<()> {
+        let target = self.grep.as_ref()
+            .and_then(|grep| grep.matches.get(grep.selected))
+            .map(|grep_match| (grep_match.path.clone(), grep_match.line, grep_match.columns.0));
 
-        self.adjust_view_to_cursor("center");
+        if let Some((path, line, column)) = target {
+            self.open(&path, None)?;
+            self.cursor_pos = (column as u16, line.saturating_sub(1) as u16);
+            self.request_recenter();
+        }
+        Ok(())
     }
 
-    fn search_next(&mut self) {
-        let mut found = false;
-        let mut wrapped_around = false;
-        let (orig_line, orig_col) = (self.cursor_pos.1 as usize, self.cursor_pos.0 as usize);
-        let mut line_idx = orig_line;
-        let mut col_idx = orig_col + 1;
+    // Feed the results of background jobs into the editor, true when
+    // something changed and the screen needs a redraw
+    fn poll_jobs(&mut self) -> bool {
+        let mut changed = false;
 
-        loop {
-            if line_idx >= self.buffer.len() {
-                // Wrap to the beginning of the document
-                line_idx = 0;
-                col_idx = 0;
-                wrapped_around = true;
-            }
+        let requests: Vec<LuaCompletingRead> = self.lua_completing_reads.borrow_mut().drain(..).collect();
+        for request in requests {
+            self.lua_completing_read(request);
+            changed = true;
+        }
 
-            if let Some(line) = self.buffer.get(line_idx) {
-                if let Some(match_idx) = line.iter().skip(col_idx).collect::<String>().find(&self.search_query) {
-                    self.cursor_pos = (match_idx as u16 + col_idx as u16, line_idx as u16);
-                    found = true;
-                    break;
-                }
+        if let Some(fzy) = &mut self.fzy {
+            if fzy.active && fzy.poll_source() {
+                fzy.recalculate_positions();
+                self.minibuffer_height = fzy.calculate_minibuffer_height(fzy.max_visible_lines) as u16;
+                changed = true;
             }
-
-            // Move to the next line from the start
-            line_idx += 1;
-            col_idx = 0;
-
-            // Stop if we've wrapped around to the original position
-            if line_idx == orig_line && col_idx >= orig_col {
-                break;
+        }
+        if let Some(grep) = &mut self.grep {
+            let was_done = grep.done;
+            changed |= grep.poll();
+            if grep.done && !was_done {
+                let status = format!("Grep finished: {}", grep.status());
+                self.message(&status);
             }
         }
-
-        if !found {
-            self.message("Search query not found.");
-        } else {
-            self.adjust_view_to_cursor(if wrapped_around { "center" } else { "" });
+        if let Some(compilation) = &mut self.compilation {
+            let was_done = compilation.finished.is_some();
+            let known_errors = compilation.errors.len();
+            changed |= compilation.poll();
+            let found_errors = compilation.errors.len() != known_errors;
+            if compilation.finished.is_some() && !was_done {
+                let status = format!("{} {}", compilation.title, compilation.status());
+                self.message(&status);
+            }
+            if found_errors {
+                self.sync_compile_diagnostics();
+            }
         }
-    }
-
-    fn search_previous(&mut self) {
-        let mut found = false;
-        let mut wrapped_around = false;
-        let (orig_line, orig_col) = (self.cursor_pos.1 as usize, if self.cursor_pos.0 > 0 { self.cursor_pos.0 as usize - 1 } else { usize::MAX });
-        let mut line_idx = if orig_line == 0 { self.buffer.len() - 1 } else { orig_line - 1 };
-        let mut col_idx = if orig_col == usize::MAX { self.buffer.get(line_idx).map_or(0, |l| l.len()) } else { orig_col };
-
-        loop {
-            if let Some(line) = self.buffer.get(line_idx) {
-                let search_str: String = line.iter().take(col_idx).collect();
-                if let Some(match_idx) = search_str.rfind(&self.search_query) {
-                    self.cursor_pos = (match_idx as u16, line_idx as u16);
-                    found = true;
-                    break;
+        if let Some(job) = &mut self.dired_job {
+            changed |= job.poll();
+        }
+        if let Some(job) = self.dired_job.take_if(|job| job.finished) {
+            if let Some(dired) = &mut self.dired {
+                for (path, _) in &job.done {
+                    dired.marks.remove(path);
+                }
+                if let Err(e) = dired.refresh_directory_contents() {
+                    self.error(&format!("Failed to read the directory: {}", e));
                 }
             }
-
-            if line_idx == 0 {
-                // Wrap to the end of the document
-                line_idx = self.buffer.len() - 1;
-                col_idx = self.buffer.get(line_idx).map_or(0, |l| l.len());
-                wrapped_around = true;
-            } else {
-                line_idx -= 1;
-                col_idx = self.buffer.get(line_idx).map_or(0, |l| l.len());
-            }
-
-            // Stop if we've wrapped around to the original position
-            if line_idx == orig_line && col_idx <= orig_col {
-                break;
+            self.dired_report(job.op, job.done.len(), job.count, &job.failures);
+            if job.op == DiredOp::Move {
+                let sources: Vec<PathBuf> = job.done.iter().map(|(from, _)| from.clone()).collect();
+                self.dired_undo_push(format!("{} {}", job.op.done(), dired_summary(&sources)), job.done.into_iter().map(|(from, to)| (from, to, None)).collect());
             }
         }
-
-        if !found {
-            self.message("Search query not found.");
-        } else {
-            self.adjust_view_to_cursor(if wrapped_around { "center" } else { "" });
+        changed |= self.lsp_poll();
+        self.lsp_sync();
+
+        if self.completion_due.is_some_and(|due| std::time::Instant::now() >= due) {
+            self.completion_due = None;
+            if self.mode == Mode::Insert && !self.minibuffer_active {
+                self.completion_start(false);
+                changed = true;
+            }
         }
+        changed
     }
 
     fn extract_selected_text(&self) -> String {
@@ -1896,39 +3930,8 @@ impl Editor {
                     };
                 }
             }
-
-            // Scroll logic to ensure the cursor is visible after pasting.
-            let (_, height) = terminal::size().unwrap();
-            let text_area_height = height - self.minibuffer_height - 1;
-            let effective_text_area_height = text_area_height - self.config.bottom_scroll_margin;
-            if self.cursor_pos.1 >= self.offset.1 + effective_text_area_height {
-                let max_offset_possible = if self.buffer.len() as u16 > text_area_height {
-                    self.buffer.len() as u16 - text_area_height
-                } else {
-                    0
-                };
-                if self.offset.1 < max_offset_possible {
-                    self.offset.1 = (self.offset.1 + 1).min(max_offset_possible);
-                }
-            }
         }
 
-        fn dired_jump(&mut self) {
-            // Clone the path to avoid borrowing issues
-            let current_file_path_clone = self.current_file_path.clone();
-            let (path_to_open, focus) = if current_file_path_clone.is_file() {
-                (current_file_path_clone.parent().unwrap_or_else(|| Path::new("/")).to_path_buf(),
-                 current_file_path_clone.file_name().and_then(|n| n.to_str()))
-            } else {
-                (current_file_path_clone, None)
-            };
-            
-            // Now it's safe to call `self.open` since `self.current_file_path` is not borrowed anymore
-            if let Err(e) = self.open(&path_to_open, focus) {
-                // Handle the error, maybe show a message to the user
-                eprintln!("Error opening directory: {}", e);
-            }
-        }
 
         fn draw_selection(&self, stdout: &mut io::Stdout) -> Result<()> {
             if let (Some(start), Some(end)) = (self.selection_start, self.selection_end) {
@@ -1978,17 +3981,39 @@ impl Editor {
             let (width, height) = terminal::size()?;
 
             let cursor_pos = if self.minibuffer_active {
-                let cursor_x = 1 + self.minibuffer_prefix.len() as u16 + self.minibuffer_cursor_pos.0;
+                let cursor_x = 1 + self.minibuffer_prefix().chars().count() as u16 + self.minibuffer_cursor_pos.0;
                 let cursor_y = height - self.minibuffer_height + self.minibuffer_cursor_pos.1;
                 (cursor_x, cursor_y)
             } else if self.fzy.as_ref().map_or(false, |fzy| fzy.active) {
-                let cursor_x = 18 + self.fzy.as_ref().map_or(0, |fzy| fzy.input.len()) as u16;
+                let cursor_x = self.fzy.as_ref().map_or(0, |fzy| fzy.cursor_column());
                 let cursor_y = height - self.minibuffer_height;
                 (cursor_x, cursor_y)
             } else if self.mode == Mode::Dired {
                 self.dired.as_ref().map_or((0, 0), |dired| {
-                    let cursor_line = (dired.cursor_pos + 2).min(height - self.minibuffer_height - 1);
-                    (dired.entry_first_char_column, cursor_line)
+                    let cursor_line = (dired.cursor_pos.saturating_sub(dired.offset as u16) + 2).min(height - self.minibuffer_height - 1);
+                    let depth = dired.current_entry().map_or(0, |entry| entry.depth);
+                    ((dired.entry_first_char_column + 2 * depth as u16).min(dired.listing_width.saturating_sub(1)), cursor_line)
+                })
+            } else if self.mode == Mode::Grep {
+                self.grep.as_ref().map_or((0, 0), |grep| {
+                    let selected_row = grep.rows().iter().position(|row| *row == Some(grep.selected)).unwrap_or(0);
+                    (1, (selected_row.saturating_sub(grep.offset) as u16 + 2).min(height - self.minibuffer_height - 2))
+                })
+            } else if self.mode == Mode::Git {
+                self.git.as_ref().map_or((0, 0), |git| {
+                    (1, (git.selected.saturating_sub(git.offset) as u16 + 2).min(height - self.minibuffer_height - 2))
+                })
+            } else if self.mode == Mode::Compilation {
+                self.compilation.as_ref().map_or((0, 0), |compilation| {
+                    (1, (compilation.selected.saturating_sub(compilation.offset) as u16 + 2).min(height - self.minibuffer_height - 2))
+                })
+            } else if self.mode == Mode::Blame {
+                self.blame.as_ref().map_or((0, 0), |blame| {
+                    (1, (blame.cursor_row() as u16 + 2).min(height - self.minibuffer_height - 2))
+                })
+            } else if self.mode == Mode::GitLog {
+                self.git_log.as_ref().map_or((0, 0), |log| {
+                    (1, (log.cursor_row() as u16 + 2).min(height - self.minibuffer_height - 2))
                 })
             } else {
                 let mut start_col = 0;
@@ -2080,11 +4105,14 @@ impl Editor {
                 terminal::Clear(ClearType::All)
             )?;
 
+            if self.searching || self.highlight_search {
+                self.search_refresh();
+            }
             self.draw_minibuffer(stdout, width, height)?;
             
             // Draw text area for non-Dired modes
             // if self.mode != Mode::Dired  {
-            if self.mode != Mode::Dired && self.mode != Mode::Git {
+            if self.mode != Mode::Dired && self.mode != Mode::Git && self.mode != Mode::Grep && self.mode != Mode::Compilation && self.mode != Mode::Blame && self.mode != Mode::GitLog {
                 let mut start_col = 0;
                 if self.config.show_fringe {
                     self.draw_fringe(stdout, height)?;
@@ -2096,11 +4124,14 @@ impl Editor {
 
                 self.draw_text(stdout)?;
                 self.draw_hl_line(stdout)?;
+                self.draw_diagnostics(stdout)?;
                 self.draw_search_highlight(stdout)?;
+                self.draw_replace_highlight(stdout)?;
                 self.draw_selection(stdout)?;
                 if self.config.scroll_bar_mode {
                     self.draw_scroll_bar(stdout, width, height)?;
                 }
+                self.draw_completion(stdout, width, height)?;
             }
 
             self.draw_modeline(stdout, width, height)?;
@@ -2108,7 +4139,7 @@ impl Editor {
             if let Some(mut fzy) = self.fzy.take() { // Temporarily take `fzy` out of `self`
                 let theme = self.current_theme(); // Now it's safe to borrow `self` immutably
                 if fzy.active {
-                    fzy.draw(stdout, theme)?;
+                    fzy.draw(stdout, theme, self.config.fzy_icons.then_some(&self.config.icons))?;
                 }
                 self.fzy.replace(fzy); // Put `fzy` back into `self`
             }
@@ -2116,13 +4147,54 @@ impl Editor {
             if self.mode == Mode::Dired {
                 if let Some(mut dired) = self.dired.take() { // Temporarily take `dired` out of `self`
                     let theme = self.current_theme(); // Now it's safe to borrow `self` immutably
-                    dired.draw_dired(stdout, height, theme)?;
+                    dired.draw_dired(stdout, height, theme, self.config.dired_preview_width, self.config.dired_icons.then_some(&self.config.icons))?;
                     self.dired.replace(dired); // Put `dired` back into `self`
                 }
             }
 
             if self.mode == Mode::Git {
-                // self.draw_git(stdout)?;
+                let text_area_height = self.text_area_height();
+                if let Some(mut git) = self.git.take() {
+                    let theme = self.current_theme();
+                    git.draw(stdout, text_area_height, theme)?;
+                    self.git.replace(git);
+                }
+            }
+
+            if self.mode == Mode::Grep {
+                let text_area_height = self.text_area_height();
+                if let Some(mut grep) = self.grep.take() {
+                    let theme = self.current_theme();
+                    grep.draw(stdout, text_area_height, theme)?;
+                    self.grep.replace(grep);
+                }
+            }
+
+            if self.mode == Mode::Compilation {
+                let text_area_height = self.text_area_height();
+                if let Some(mut compilation) = self.compilation.take() {
+                    let theme = self.current_theme();
+                    compilation.draw(stdout, text_area_height, theme)?;
+                    self.compilation.replace(compilation);
+                }
+            }
+
+            if self.mode == Mode::Blame {
+                let text_area_height = self.text_area_height();
+                if let Some(mut blame) = self.blame.take() {
+                    let theme = self.current_theme();
+                    blame.draw(stdout, text_area_height, theme)?;
+                    self.blame.replace(blame);
+                }
+            }
+
+            if self.mode == Mode::GitLog {
+                let text_area_height = self.text_area_height();
+                if let Some(mut log) = self.git_log.take() {
+                    let theme = self.current_theme();
+                    log.draw(stdout, text_area_height, theme)?;
+                    self.git_log.replace(log);
+                }
             }
 
             // Reset the background color for fringe and line numbers
@@ -2340,13 +4412,11 @@ impl Editor {
 	    // }
 
 
-
         
 	    fn draw_search_highlight(&self, stdout: &mut io::Stdout) -> Result<()> {
             let (width, height) = size()?;
             let text_color = self.current_theme().text_color;
             let search_bg_color = self.current_theme().search_bg_color;
-            let background_color = self.current_theme().background_color;
             let mut start_col_base = 0;
 
             if self.config.show_fringe {
@@ -2357,41 +4427,108 @@ impl Editor {
 		        start_col_base += 4;
             }
 
-            let bottom_exclude = self.minibuffer_height + 1;
-            let search_string = if self.minibuffer_active {
-		        &self.minibuffer_content
-            } else {
-		        &self.search_query
-            };
+            if !self.highlight_search {
+                return Ok(());
+            }
+
+            // The matches are kept up to date by `draw`
+            let first_line = self.offset.1 as usize;
+            let visible_lines = self.text_area_height().min(height) as usize;
+            let matches = self.search.matches();
+            let first = matches.partition_point(|&(line, _, _)| line < first_line);
+            for &(idx, start, end) in matches[first..].iter().take_while(|&&(line, _, _)| line < first_line + visible_lines) {
+                let line_y = (idx - first_line) as u16;
+                let x = start_col_base + start as u16;
+                if x >= width {
+                    continue;
+                }
+                let part: String = self.buffer[idx][start..end].iter().take((width - x) as usize).collect();
+                execute!(stdout, MoveTo(x, line_y), SetForegroundColor(text_color), SetBackgroundColor(search_bg_color), Print(part))?;
+            }
+
+            Ok(())
+	    }
 
-            if self.highlight_search && !search_string.is_empty() {
-		        for (idx, line) in self.buffer.iter().enumerate() {
-                    if idx >= self.offset.1 as usize && idx < (self.offset.1 + height - bottom_exclude) as usize {
-			            let line_content: String = line.iter().collect();
-			            let line_y = (idx - self.offset.1 as usize) as u16;
-			            let mut current_col = start_col_base;
-			            let mut last_index = 0;
+	    fn draw_diagnostics(&self, stdout: &mut io::Stdout) -> Result<()> {
+            let (width, _) = size()?;
+            let background_color = self.current_theme().background_color;
+            let mut start_col_base = 0;
+            if self.config.show_fringe {
+                start_col_base += 2;
+            }
+            if self.config.show_line_numbers {
+                start_col_base += 4;
+            }
 
-			            for (start, part) in line_content.match_indices(search_string) {
-                            let preceding_text = &line_content[last_index..start];
-                            execute!(stdout, MoveTo(current_col, line_y), SetForegroundColor(text_color), SetBackgroundColor(background_color), Print(preceding_text))?;
-                            current_col += preceding_text.len() as u16;
+            let visible = self.offset.1 as usize..(self.offset.1 + self.text_area_height()) as usize;
+            for diagnostic in self.buffer_diagnostics() {
+                let Some(line) = self.buffer.get(diagnostic.line) else { continue };
+                if !visible.contains(&diagnostic.line) {
+                    continue;
+                }
+                let (start, end) = diagnostic.range(line);
+                let x = start_col_base + start as u16;
+                if start >= end || x >= width {
+                    continue;
+                }
+                let part: String = line[start..end].iter().take((width - x) as usize).collect();
+                execute!(
+                    stdout,
+                    MoveTo(x, (diagnostic.line - self.offset.1 as usize) as u16),
+                    SetForegroundColor(diagnostic.severity.color(self.current_theme())),
+                    SetBackgroundColor(background_color),
+                    SetAttribute(Attribute::Underlined),
+                    Print(part),
+                    SetAttribute(Attribute::NoUnderline)
+                )?;
+            }
 
-                            execute!(stdout, MoveTo(current_col, line_y), SetForegroundColor(text_color), SetBackgroundColor(search_bg_color), Print(part))?;
-                            current_col += part.len() as u16;
+            Ok(())
+	    }
 
-                            last_index = start + part.len();
-			            }
+	    fn draw_replace_highlight(&self, stdout: &mut io::Stdout) -> Result<()> {
+            let Some((line_idx, start, end)) = self.replace.as_ref().and_then(|r| r.current) else { return Ok(()) };
+            if line_idx < self.offset.1 || line_idx >= self.offset.1 + self.text_area_height() {
+                return Ok(());
+            }
 
-			            let trailing_text = &line_content[last_index..];
-			            execute!(stdout, MoveTo(current_col, line_y), SetForegroundColor(text_color), SetBackgroundColor(background_color), Print(trailing_text))?;
-                    }
-		        }
+            let mut start_col_base = 0;
+            if self.config.show_fringe {
+                start_col_base += 2;
+            }
+            if self.config.show_line_numbers {
+                start_col_base += 4;
             }
 
+            let part: String = self.buffer[line_idx as usize][start as usize..end as usize].iter().collect();
+            execute!(
+                stdout,
+                MoveTo(start_col_base + start, line_idx - self.offset.1),
+                SetForegroundColor(self.current_theme().background_color),
+                SetBackgroundColor(self.current_theme().visual_mode_color),
+                Print(part)
+            )?;
             Ok(())
 	    }
 
+	    fn draw_completion(&self, stdout: &mut io::Stdout, width: u16, height: u16) -> Result<()> {
+            let Some(popup) = self.completion_popup.as_ref().filter(|_| self.mode == Mode::Insert) else { return Ok(()) };
+            let (offset_x, offset_y) = (self.offset.0 as usize, self.offset.1 as usize);
+            if popup.line < offset_y || popup.start < offset_x {
+                return Ok(());
+            }
+            let mut start_col = 0;
+            if self.config.show_fringe {
+                start_col += 2;
+            }
+            if self.config.show_line_numbers {
+                start_col += 4;
+            }
+            let anchor = ((popup.start - offset_x) as u16 + start_col, (popup.line - offset_y) as u16);
+            let bottom = height.saturating_sub(self.minibuffer_height + 1);
+            popup.draw(stdout, anchor, bottom, width, self.current_theme())
+	    }
+
 	    fn draw_hl_line(&self, stdout: &mut io::Stdout) -> io::Result<()> {
             if self.config.show_hl_line {
 		        let (width, _height) = terminal::size()?;
@@ -2477,13 +4614,43 @@ impl Editor {
 		        let fringe_color = self.current_theme().fringe_color;
 		        let bottom_exclude = self.minibuffer_height + 1;
 
+		        // The worst diagnostic of each line gets a marker
+		        let mut markers: HashMap<usize, Severity> = HashMap::new();
+		        for diagnostic in self.buffer_diagnostics() {
+                    let severity = markers.entry(diagnostic.line).or_insert(diagnostic.severity);
+                    *severity = (*severity).min(diagnostic.severity);
+		        }
+
+		        // Changes against HEAD on the left, diagnostics on the right
 		        for y in 0..height - bottom_exclude { 
-                    execute!(
-			            stdout,
-			            MoveTo(0, y),
-			            SetForegroundColor(fringe_color),
-			            Print("||") // Wider fringe
-                    )?;
+                    let line = self.offset.1 as usize + y as usize;
+                    let mark = self.git_gutter.as_ref().filter(|_| line < self.buffer.len()).and_then(|gutter| gutter.mark(line));
+                    match mark {
+                        Some(mark) => execute!(
+                            stdout,
+                            MoveTo(0, y),
+                            SetForegroundColor(mark.color(self.current_theme())),
+                            Print(mark.symbol())
+                        )?,
+                        None => execute!(
+			                stdout,
+			                MoveTo(0, y),
+			                SetForegroundColor(fringe_color),
+			                Print("|")
+                        )?,
+                    }
+                    match markers.get(&line) {
+                        Some(severity) => execute!(
+                            stdout,
+                            SetForegroundColor(severity.color(self.current_theme())),
+                            Print("●")
+                        )?,
+                        None => execute!(
+			                stdout,
+			                SetForegroundColor(fringe_color),
+			                Print("|") // Wider fringe
+                        )?,
+                    }
 		        }
             }
             Ok(())
@@ -2504,6 +4671,11 @@ impl Editor {
                         "󰉋 Unknown".to_string()
                     }
                 },
+                Mode::Grep => "*grep*".to_string(),
+                Mode::Compilation => "*compilation*".to_string(),
+                Mode::Git => "*git-status*".to_string(),
+                Mode::Blame => "*git-blame*".to_string(),
+                Mode::GitLog => "*git-log*".to_string(),
                 // In other modes, display just the file name from `current_file_path`.
                 _ => self.current_file_path.file_name().map_or("Untitled".to_string(), |os_str| os_str.to_str().unwrap_or("Untitled").to_string()),
             };
@@ -2514,6 +4686,10 @@ impl Editor {
                 Mode::Dired  => ("DIRED",  self.current_theme().dired_mode_color,    Color::Black),
                 Mode::Visual => ("VISUAL", self.current_theme().visual_mode_color,   Color::Black),
                 Mode::Git    => (" GIT",  self.current_theme().visual_mode_color,   Color::Black),
+                Mode::Grep   => ("GREP",   self.current_theme().dired_mode_color,    Color::Black),
+                Mode::Compilation => ("COMPILE", self.current_theme().dired_mode_color, Color::Black),
+                Mode::Blame  => ("BLAME",  self.current_theme().visual_mode_color,   Color::Black),
+                Mode::GitLog => (" LOG",   self.current_theme().visual_mode_color,   Color::Black),
             };
 
             let file_bg_color = self.current_theme().modeline_lighter_color;
@@ -2527,6 +4703,32 @@ impl Editor {
             execute!(stdout, SetForegroundColor(file_text_color), Print(format!(" {} ", display_str)))?;
             execute!(stdout, SetBackgroundColor(modeline_bg_color), SetForegroundColor(file_bg_color), Print(sep_r))?;
 
+            // Branch and lines changed against HEAD
+            let vcs = self.git_gutter.as_ref().filter(|_| matches!(self.mode, Mode::Normal | Mode::Insert | Mode::Visual)).map(|gutter| {
+                let (added, removed) = gutter.counts();
+                (format!("  {} ", gutter.branch), format!("+{} ", added), format!("-{} ", removed))
+            });
+            let vcs_length = vcs.as_ref().map_or(0, |(branch, added, removed)| (branch.chars().count() + added.len() + removed.len()) as u16);
+            if let Some((branch, added, removed)) = &vcs {
+                execute!(
+                    stdout,
+                    SetBackgroundColor(modeline_bg_color),
+                    SetForegroundColor(file_text_color),
+                    Print(branch),
+                    SetForegroundColor(self.current_theme().ok_color),
+                    Print(added),
+                    SetForegroundColor(self.current_theme().error_color),
+                    Print(removed)
+                )?;
+            }
+
+            // How far a background copy got
+            let job = self.dired_job.as_ref().map(|job| format!(" 󰆏 {} ", job.progress()));
+            let job_length = job.as_ref().map_or(0, |job| job.chars().count() as u16);
+            if let Some(job) = &job {
+                execute!(stdout, SetBackgroundColor(modeline_bg_color), SetForegroundColor(self.current_theme().warning_color), Print(job))?;
+            }
+
             let pos_str = format!("{}:{}", self.cursor_pos.1 + 1, self.cursor_pos.0 + 1);
             let pos_str_length = pos_str.len() as u16 + 2;
 
@@ -2545,6 +4747,7 @@ impl Editor {
             } else {
                 width - (4 + mode_str.len() as u16 + display_str.len() as u16 + pos_str_length + custom_text_length + 3)
             };
+            let fill_length_before_pos_str = fill_length_before_pos_str.saturating_sub(vcs_length + job_length);
 
             // Print the custom text followed by the remaining space
             execute!(
@@ -2580,13 +4783,13 @@ impl Editor {
         
         fn message_undo_tree(&mut self) {
             let mut display = String::new();
-            for i in 0..self.states.len() {
-                if i == self.current_state {
+            for i in 0..self.history.states.len() {
+                if i == self.history.current {
                     display.push(self.config.current_tree_node);  // Use configured filled node character
                 } else {
                     display.push(self.config.tree_node);  // Use configured empty node character
                 }
-                if i < self.states.len() - 1 {
+                if i < self.history.states.len() - 1 {
                     display.push(self.config.tree_node_separator);  // Use configured separator character
                 }
             }
@@ -2614,6 +4817,7 @@ impl Editor {
             let minibuffer_bg = self.current_theme().minibuffer_color;
             let content_fg = self.current_theme().text_color;
             let prefix_fg = self.current_theme().dired_dir_color;
+            let prefix = self.minibuffer_prefix();
 
             let lines: Vec<&str> = self.minibuffer_content.split('\n').collect();
             let num_lines = lines.len() as u16;
@@ -2652,12 +4856,42 @@ impl Editor {
                     Print(" ".repeat(width as usize)),
                     MoveTo(0, y_position),
                     SetForegroundColor(prefix_fg),
-                    Print(&format!(" {}", self.minibuffer_prefix)),
+                    Print(&format!(" {}", prefix)),
                     SetForegroundColor(content_fg),
                     Print(line)
                 )?;
             }
 
+            if self.searching {
+                let status = self.search_status();
+                let status_x = width.saturating_sub(status.chars().count() as u16 + 1);
+                execute!(
+                    stdout,
+                    MoveTo(status_x, minibuffer_start_y),
+                    SetBackgroundColor(minibuffer_bg),
+                    SetForegroundColor(if self.search.failing { self.current_theme().error_color } else { prefix_fg }),
+                    Print(status)
+                )?;
+            }
+
+            if !self.minibuffer_hint.is_empty() {
+                let list = self.minibuffer_hint.clone();
+                let used = 1 + prefix.chars().count() + lines.first().map_or(0, |line| line.chars().count()) + 2;
+                let room = (width as usize).saturating_sub(used + 1);
+                let list: String = if list.chars().count() > room {
+                    list.chars().take(room.saturating_sub(3)).chain("...".chars()).collect()
+                } else {
+                    list
+                };
+                execute!(
+                    stdout,
+                    MoveTo(width.saturating_sub(list.chars().count() as u16 + 1), minibuffer_start_y),
+                    SetBackgroundColor(minibuffer_bg),
+                    SetForegroundColor(self.current_theme().comment_color),
+                    Print(list)
+                )?;
+            }
+
             Ok(())
         }
 
@@ -2676,18 +4910,21 @@ impl Editor {
                 self.message("Opened new buffer.");
             }
 
+            self.remember_buffer_words();
             // Update current file path
-            self.current_file_path = path.clone();  
+            self.set_current_file(path.clone());
 
             if path.is_dir() {
                 // Handle directory opening
-                self.dired = Some(Dired::new(path.clone(), focus)?);
+                let view = self.dired_view();
+                self.dired = Some(Dired::new(path.clone(), focus, view)?);
                 self.mode = Mode::Dired;
+                self.git_gutter = None;
             } else {
                 // Handle file opening
                 let contents = fs::read_to_string(path)
                     .unwrap_or_else(|_| "".to_string());
-                self.buffer = contents.lines()
+                *self.buffer = contents.lines()
                     .map(|line| line.chars().collect())
                     .collect();
 
@@ -2698,10 +4935,8 @@ impl Editor {
                 // Update editor state for the opened file
                 self.mode = Mode::Normal; 
                 self.cursor_pos = (0,0);
-                self.adjust_view_to_cursor("");
-                self.states.clear(); // Clears the undo history
-                self.snapshot(); // Creates a new initial state for undo history
-                self.current_state = 0; 
+                self.offset = (0, 0);
+                self.history.reset(&self.buffer, self.cursor_pos); // A new undo history, starting here
 
                 // Syntax highlighting updates
                 self.syntax_highlighter.parse(&self.buffer);
@@ -2709,27 +4944,43 @@ impl Editor {
                     .expect("Current theme not found");
                 self.syntax_highlighter.update_syntax_highlights(theme);
 
+                self.lsp_open_document();
+                self.git_gutter_reload();
             }
             self.message_buffers();
 
             Ok(())
         }
 
+	    // What follows anything that can move the cursor or edit, a key or a job
+	    fn post_command(&mut self) {
+	        self.follow_cursor();
+	        self.git_gutter_update();
+	        self.diagnostics_follow_edits();
+	        self.echo_diagnostic();
+	    }
+
 	    fn run(&mut self) -> Result<()> {
             let mut stdout = stdout();
             enable_raw_mode()?;
             execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide)?;
+            self.follow_cursor();
             self.draw(&mut stdout)?; // Draw the first frame
             let fzy_active = self.fzy.as_ref().map_or(false, |fzy| fzy.active);
             self.current_theme().apply_cursor_color(self.cursor_pos, &self.buffer, &self.mode, self.minibuffer_active, fzy_active);
             loop {
 		        self.draw_cursor(&mut stdout)?;
 
-		        if poll(Duration::from_millis(270))? {
+		        // Wake up in time for a completion popup waiting on its delay
+		        let timeout = self.completion_due.map_or(Duration::from_millis(270), |due| {
+                    due.saturating_duration_since(std::time::Instant::now()).min(Duration::from_millis(270))
+		        });
+		        if poll(timeout)? {
                     if let Event::Key(key) = event::read()? {
 			            self.force_show_cursor = true;
 			            self.blink_count = 0;
 			            self.handle_keys(key)?;
+			            self.post_command();
 			            self.last_cursor_toggle = std::time::Instant::now();
 			            self.draw(&mut stdout)?;
 			            self.current_theme().apply_cursor_color(self.cursor_pos, &self.buffer, &self.mode, self.minibuffer_active, fzy_active);
@@ -2743,6 +4994,12 @@ impl Editor {
 			            }
                     }
 		        }
+
+                // Background jobs report back between keys, and may have moved the cursor
+                if self.poll_jobs() {
+                    self.post_command();
+                    self.draw(&mut stdout)?;
+                }
             }
 	    }
 
@@ -2753,9 +5010,7 @@ impl Editor {
                     modifiers: KeyModifiers::ALT | KeyModifiers::SHIFT,
                     ..
 		        } => {
-                    self.minibuffer_active = true;
-                    self.minibuffer_prefix = "Eval: ".to_string();
-                    self.minibuffer_content = "".to_string();
+                    self.minibuffer_open(Prompt::Eval, "");
 		        },
 		        
                 KeyEvent {
@@ -2791,7 +5046,6 @@ impl Editor {
                 },
 
 
-
                 KeyEvent {
                     code: KeyCode::Left,
                     modifiers: KeyModifiers::NONE,
@@ -2830,14 +5084,20 @@ impl Editor {
                     }
                 },
 
+		        KeyEvent {
+                    code: KeyCode::Char('%'),
+                    modifiers,
+                    ..
+		        } if modifiers.contains(KeyModifiers::ALT) && !self.minibuffer_active => {
+                    self.query_replace();
+		        },
+
 		        KeyEvent {
                     code: KeyCode::Char('!'),
                     modifiers: KeyModifiers::ALT,
                     ..
 		        } => {
-                    self.minibuffer_active = true;
-                    self.minibuffer_prefix = "Shell command: ".to_string();
-                    self.minibuffer_content = "".to_string();
+                    self.minibuffer_open(Prompt::ShellCommand, "");
 		        },
 
 		        KeyEvent {
@@ -2845,15 +5105,8 @@ impl Editor {
                     modifiers: KeyModifiers::ALT,
                     ..
 		        } => {
-                    if let Some(fzy) = &mut self.fzy {
-			            if !fzy.m_x_active {
-                            fzy.m_x_active = true;
-                            fzy.active = true;
-                            fzy.input.clear();
-                            fzy.update_items();
-                            fzy.recalculate_positions();
-                            self.minibuffer_height = fzy.calculate_minibuffer_height(fzy.max_visible_lines) as u16;
-			            }
+                    if !self.fzy.as_ref().is_some_and(|fzy| fzy.active) {
+			            self.execute_extended_command();
                     }
 		        }
                 
@@ -2889,6 +5142,34 @@ impl Editor {
                 } => {
                     self.minibuffer_active = !self.minibuffer_active;
                 },
+                KeyEvent {
+                    code: KeyCode::Char('.'),
+                    modifiers: KeyModifiers::ALT,
+                    ..
+                } => {
+                    if !self.minibuffer_active {
+                        self.lsp_goto_definition();
+                    }
+                },
+                KeyEvent {
+                    code: KeyCode::Char('?'),
+                    modifiers,
+                    ..
+                } if modifiers.contains(KeyModifiers::ALT) => {
+                    if !self.minibuffer_active {
+                        self.lsp_find_references();
+                    }
+                },
+                KeyEvent {
+                    code: KeyCode::Char('g'),
+                    modifiers: KeyModifiers::ALT,
+                    ..
+                } => {
+                    if !self.minibuffer_active {
+                        self.keychords.meta_g_pressed = true;
+                        self.message("M-g");
+                    }
+                },
                 KeyEvent {
                     code: KeyCode::Char('k'),
                     modifiers: KeyModifiers::ALT,
@@ -2905,6 +5186,15 @@ impl Editor {
                         self.compile();
                     }
                 },
+                KeyEvent {
+                    code: KeyCode::Char('b'),
+                    modifiers: KeyModifiers::NONE,
+                    ..
+                } => {
+                    if self.keychords.ctrl_x_pressed {
+                        self.switch_to_buffer();
+                    }
+                },
                 KeyEvent {
                     code: KeyCode::Char('c'),
                     modifiers: KeyModifiers::CONTROL,
@@ -2912,7 +5202,7 @@ impl Editor {
                 } => {
                     if self.keychords.ctrl_x_pressed {
                         self.quit();
-                    } else {
+                    } else if !self.keychords.ctrl_h_pressed {
                         self.keychords.ctrl_c_pressed = true;
                         self.message("C-c");
                     }
@@ -2928,359 +5218,102 @@ impl Editor {
                     }
                 },
                 _ => {}
-            }
-
-            Ok(())
-        }
-
-        // TODO handle tab key for the minibuffer and do different things based on the prefix or the mode of the minibuffer in the future
-	    fn handle_keys(&mut self, key: crossterm::event::KeyEvent) -> Result<()> {
-            let mut event_handled = false;
-
-            if self.fzy.as_ref().map_or(false, |fzy| fzy.active) {
-		        if let Some(mut fzy) = self.fzy.take() { // Temporarily take `fzy` out
-                    event_handled = fzy.handle_input(key, self);
-                    self.fzy.replace(fzy); // Put `fzy` back
-                    if event_handled {
-			            self.minibuffer_height = 1;
-                    }
-		        }
-            }
-
-
-            // TODO make this handle_minibuffer_keys()
-            if self.minibuffer_active && !event_handled {
-                match key {
-                    
-                    KeyEvent {
-                        code: KeyCode::Char('g'),
-                        modifiers: KeyModifiers::CONTROL,
-                        ..
-		            } => {
-                        self.minibuffer_content.clear();
-                        self.minibuffer_prefix.clear();
-                        self.minibuffer_cursor_pos = (0, 0);
-                        self.minibuffer_active = false;
-		            },
-
-                    KeyEvent {
-                        code: KeyCode::Char('n'),
-                        modifiers: KeyModifiers::CONTROL,
-                        ..
-                    } => {
-                        let num_lines = self.minibuffer_content.split('\n').count() as u16;
-                        if self.minibuffer_cursor_pos.1 < num_lines - 1 {
-                            self.minibuffer_cursor_pos.1 += 1;
-                            let current_line_len = self.minibuffer_content.split('\n')
-                                .nth(self.minibuffer_cursor_pos.1 as usize)
-                                .unwrap_or("")
-                                .len() as u16;
-                            if self.minibuffer_cursor_pos.0 > current_line_len {
-                                self.minibuffer_cursor_pos.0 = current_line_len;
-                            }
-                        }
-                    },
-                    KeyEvent {
-                        code: KeyCode::Down,
-                        modifiers: KeyModifiers::NONE,
-                        ..
-                    } => {
-                        let num_lines = self.minibuffer_content.split('\n').count() as u16;
-                        if self.minibuffer_cursor_pos.1 < num_lines - 1 {
-                            self.minibuffer_cursor_pos.1 += 1;
-                            let current_line_len = self.minibuffer_content.split('\n')
-                                .nth(self.minibuffer_cursor_pos.1 as usize)
-                                .unwrap_or("")
-                                .len() as u16;
-                            if self.minibuffer_cursor_pos.0 > current_line_len {
-                                self.minibuffer_cursor_pos.0 = current_line_len;
-                            }
-                        }
-                    },
-                    KeyEvent {
-                        code: KeyCode::Char('p'),
-                        modifiers: KeyModifiers::CONTROL,
-                        ..
-                    } => {
-                        if self.minibuffer_cursor_pos.1 > 0 {
-                            self.minibuffer_cursor_pos.1 -= 1;
-                            let current_line_len = self.minibuffer_content.split('\n')
-                                .nth(self.minibuffer_cursor_pos.1 as usize)
-                                .unwrap_or("")
-                                .len() as u16;
-                            if self.minibuffer_cursor_pos.0 > current_line_len {
-                                self.minibuffer_cursor_pos.0 = current_line_len;
-                            }
-                        }
-                    },
-                    KeyEvent {
-                        code: KeyCode::Up,
-                        modifiers: KeyModifiers::NONE,
-                        ..
-                    } => {
-                        if self.minibuffer_cursor_pos.1 > 0 {
-                            self.minibuffer_cursor_pos.1 -= 1;
-                            let current_line_len = self.minibuffer_content.split('\n')
-                                .nth(self.minibuffer_cursor_pos.1 as usize)
-                                .unwrap_or("")
-                                .len() as u16;
-                            if self.minibuffer_cursor_pos.0 > current_line_len {
-                                self.minibuffer_cursor_pos.0 = current_line_len;
-                            }
-                        }
-                    },
-                    KeyEvent {
-                        code: KeyCode::Char('f'),
-                        modifiers: KeyModifiers::CONTROL,
-                        ..
-                    } => {
-                        let current_line_len = self.minibuffer_content.split('\n').nth(self.minibuffer_cursor_pos.1 as usize).unwrap_or("").len() as u16;
-                        if self.minibuffer_cursor_pos.0 < current_line_len {
-                            self.minibuffer_cursor_pos.0 += 1;
-                        }
-                    },
-                    KeyEvent {
-                        code: KeyCode::Right,
-                        modifiers: KeyModifiers::NONE,
-                        ..
-                    } => {
-                        let current_line_len = self.minibuffer_content.split('\n').nth(self.minibuffer_cursor_pos.1 as usize).unwrap_or("").len() as u16;
-                        if self.minibuffer_cursor_pos.0 < current_line_len {
-                            self.minibuffer_cursor_pos.0 += 1;
-                        }
-                    },
-                    KeyEvent {
-                        code: KeyCode::Char('b'),
-                        modifiers: KeyModifiers::CONTROL,
-                        ..
-                    } => {
-                        if self.minibuffer_cursor_pos.0 > 0 {
-                            self.minibuffer_cursor_pos.0 -= 1;
-                        }
-                    },
-                    KeyEvent {
-                        code: KeyCode::Left,
-                        modifiers: KeyModifiers::NONE,
-                        ..
-                    } => {
-                        if self.minibuffer_cursor_pos.0 > 0 {
-                            self.minibuffer_cursor_pos.0 -= 1;
-                        }
-                    },
-                    
-                    KeyEvent {
-                        code: KeyCode::Char('a'),
-                        modifiers: KeyModifiers::CONTROL,
-                        ..
-                    } => {
-                        self.minibuffer_cursor_pos.0 = 0;
-                    },
+            }
 
-                    KeyEvent {
-                        code: KeyCode::Char('e'),
-                        modifiers: KeyModifiers::CONTROL,
-                        ..
-                    } => {
-                        self.minibuffer_cursor_pos.0 = self.minibuffer_content.split('\n')
-                            .nth(self.minibuffer_cursor_pos.1 as usize)
-                            .unwrap_or("")
-                            .len() as u16;
-                    },
+            Ok(())
+        }
 
-                    _=> {}
-                }
-	            match key.code {
-                    KeyCode::Char(c) if key.modifiers.is_empty() && self.keychords.all_fields_false() => {
-                        let line_idx = self.minibuffer_cursor_pos.1 as usize;
-                        let char_idx = self.minibuffer_cursor_pos.0 as usize;
-                        
-                        // Split the minibuffer content into lines
-                        let mut lines: Vec<String> = self.minibuffer_content.split('\n').map(|s| s.to_string()).collect();
-                        
-                        // Ensure the line exists
-                        if line_idx >= lines.len() {
-                            lines.resize(line_idx + 1, String::new());
-                        }
+	    fn handle_keys(&mut self, key: crossterm::event::KeyEvent) -> Result<()> {
+            let mut event_handled = false;
 
-                        // Insert the character at the cursor position
-                        lines[line_idx].insert(char_idx, c);
-                        
-                        // Reconstruct the minibuffer content from lines
-                        self.minibuffer_content = lines.join("\n");
-                        
-                        // Move the cursor to the right
-                        self.minibuffer_cursor_pos.0 += 1;
-                    },
+            if self.fzy.as_ref().map_or(false, |fzy| fzy.active) {
+		        if let Some(mut fzy) = self.fzy.take() { // Temporarily take `fzy` out
+                    event_handled = fzy.handle_input(key, self);
+                    let chosen = fzy.chosen.take();
+                    self.fzy.replace(fzy); // Put `fzy` back
+                    if event_handled {
+			            self.minibuffer_height = 1;
+                    } else if let Some(fzy) = &mut self.fzy {
+			            fzy.recalculate_positions();
+			            self.minibuffer_height = fzy.calculate_minibuffer_height(fzy.max_visible_lines) as u16;
+                    }
+                    // The action runs once Fzy is back, so it can open Fzy again
+                    if let Some((mut action, item)) = chosen {
+			            if let Err(e) = action(self, &item) {
+                            self.error(&e.to_string());
+			            }
+                    }
+		        }
+            }
 
-                    KeyCode::Backspace => {
-                        if self.minibuffer_cursor_pos.0 > 0 {
-                            let line_idx = self.minibuffer_cursor_pos.1 as usize;
-                            let char_idx = self.minibuffer_cursor_pos.0 as usize;
-                            
-                            // Split the minibuffer content into lines
-                            let mut lines: Vec<String> = self.minibuffer_content.split('\n').map(|s| s.to_string()).collect();
-                            
-                            // Ensure the line exists
-                            if line_idx < lines.len() && char_idx > 0 {
-                                lines[line_idx].remove(char_idx - 1);
-                            }
 
-                            // Reconstruct the minibuffer content from lines
-                            self.minibuffer_content = lines.join("\n");
-                            
-                            // Move the cursor to the left
-                            self.minibuffer_cursor_pos.0 -= 1;
-                        }
+            // M-g n / M-g p, before the modes get to see n and p
+            if self.keychords.meta_g_pressed && !event_handled {
+                self.keychords.meta_g_pressed = false;
+                match key.code {
+                    KeyCode::Char('n') => {
+                        self.next_error();
+                        return Ok(());
                     },
-
-                    KeyCode::Esc => {
-                        self.minibuffer_cursor_pos = (0, 0);
-	        	        self.minibuffer_active = false;
-	        	        self.minibuffer_prefix.clear();
-	        	        self.minibuffer_content.clear();
-	        	        self.searching = false;
-	        	        self.search_query.clear();
+                    KeyCode::Char('p') => {
+                        self.previous_error();
+                        return Ok(());
                     },
+                    _ => {},
+                }
+            }
 
+            // C-c C-c / C-c C-k in a commit message or wdired
+            if self.keychords.ctrl_c_pressed && key.modifiers == KeyModifiers::CONTROL && !event_handled && (self.editing_commit_message() || self.editing_wdired()) {
+                match key.code {
+                    KeyCode::Char('c') => {
+                        self.keychords.ctrl_c_pressed = false;
+                        if self.editing_wdired() { self.wdired_finish() } else { self.git_commit_finish() }
+                        return Ok(());
+                    },
+                    KeyCode::Char('k') => {
+                        self.keychords.ctrl_c_pressed = false;
+                        if self.editing_wdired() { self.wdired_abort() } else { self.git_commit_abort() }
+                        return Ok(());
+                    },
+                    _ => {},
+                }
+            }
 
-                    
-                    KeyCode::Enter => {
-	        	        let minibuffer_content = std::mem::take(&mut self.minibuffer_content);
-	        	        if self.minibuffer_prefix == "Switch theme: " {
-                            self.switch_theme(&minibuffer_content);
-	        	        } else if self.minibuffer_prefix == "Eval: "  {
-                            match self.eval(&minibuffer_content) {
-	        		            Ok(_) => self.message("Code executed successfully."),
-	        		            Err(err) => self.message(&format!("Error executing code: {}", err)),
-                            };
-
-
-	        	        } else if self.minibuffer_prefix == "Shell command: " {
-                            let output = Command::new(&self.config.shell)
-                                .arg("-c")
-                                .arg(&minibuffer_content)
-                                .output();
-                            
-                            match output {
-                                Ok(output) => {
-                                    let stdout = String::from_utf8_lossy(&output.stdout);
-                                    let stderr = String::from_utf8_lossy(&output.stderr);
-
-                                    if !stdout.is_empty() {
-                                        self.message(&format!("{}", stdout));
-                                    } else if !stderr.is_empty() {
-                                        self.message(&format!("Error: {}", stderr));
-                                    } else {
-                                        self.message("(Shell command succeeded with no output)");
-                                    }
-                                },
-                                Err(e) => {
-                                    self.message(&format!("Failed to execute command: {}", e));
-                                }
-                            }
-	        	        } else if self.minibuffer_prefix == "Find file: " {
-                            let file_path = PathBuf::from(&minibuffer_content);
-                            self.message(&format!("Current file path: {}", file_path.display()));
+            if self.minibuffer_active && self.searching && !event_handled {
+                event_handled = self.handle_isearch_keys(key);
+            }
 
-                            if let Err(e) = self.open(&file_path, None) {
-	        		            self.message(&format!("Failed to open file: {}", e));
-                            }
+            if self.minibuffer_active && self.replace.is_some() && !event_handled {
+                event_handled = self.handle_replace_keys(key);
+            }
 
-	        	        } else if self.minibuffer_prefix == ":" {
-                            match minibuffer_content.as_str() {
-	        		            "w" => {
-                                    match self.buffer_save() {
-	        			                Ok(_) => self.message("File saved successfully."),
-	        			                Err(e) => self.message(&format!("Failed to save file: {}", e)),
-                                    }
-	        		            },
-	        		            "q" => {
-                                    self.quit();
-	        		            },
-	        		            "wq" => {
-                                    self.buffer_save()?;
-                                    self.quit();
-	        		            },
-	        		            _ => {
-                                    if let Ok(line_number) = minibuffer_content.parse::<usize>() {
-	        			                self.goto_line(line_number);
-                                    } else {
-	        			                self.message("Invalid command");
-                                    }
-	        		            }
-                            }
-	        	        } else if self.minibuffer_prefix == "Search: " {
-                            self.search_query = minibuffer_content.clone();
-
-                            // Find the next occurrence of the search query from the cursor's current position.
-                            let mut found = false;
-                            for (line_idx, line) in self.buffer.iter().enumerate().skip(self.cursor_pos.1 as usize) {
-	        		            // Determine start index for search in the current line.
-	        		            let start_search_idx = if line_idx == self.cursor_pos.1 as usize { self.cursor_pos.0 as usize + 1 } else { 0 };
-	        		            if let Some(match_idx) = line.iter().skip(start_search_idx).collect::<String>().find(&minibuffer_content) {
-                                    // Update cursor position to the start of the found match.
-                                    self.cursor_pos = (match_idx as u16, line_idx as u16);
-                                    found = true;
-                                    break;
-	        		            }
-                            }
+            if self.minibuffer_active && !event_handled {
+                event_handled = self.handle_minibuffer_keys(key)?;
+            }
 
-                            // If no match is found after the current cursor position, optionally wrap the search to the beginning of the document.
-                            if !found {
-	        		            for (line_idx, line) in self.buffer.iter().enumerate().take(self.cursor_pos.1 as usize + 1) {
-                                    if let Some(match_idx) = line.iter().collect::<String>().find(&minibuffer_content) {
-	        			                self.cursor_pos = (match_idx as u16, line_idx as u16);
-	        			                break;
-                                    }
-	        		            }
-                            }
-                            self.adjust_view_to_cursor("");
-	        	        } else if self.mode == Mode::Dired {
-                            if self.minibuffer_prefix == "Create directory: " {
-	        		            if let Some(dired) = &mut self.dired {
-                                    dired.create_directory(&minibuffer_content)?;
-                                    dired.refresh_directory_contents()?;
-	        		            }
-                            } else if self.minibuffer_prefix.starts_with("Delete ") && self.minibuffer_prefix.ends_with(" [y/n]: ") {
-	        		            if minibuffer_content == "y" {
-                                    if let Some(dired) = &mut self.dired {
-	        			                dired.delete_entry()?;
-                                    }
-	        		            }
-                            } else if self.minibuffer_prefix == "Rename: " {
-	        		            if let Some(dired) = &mut self.dired {
-                                    dired.rename_entry(&minibuffer_content)?;
-	        		            }
-                            } else {
-	        		            let file_path = self.dired.as_ref().unwrap().current_path.join(&minibuffer_content);
-	        		            if std::fs::File::create(&file_path).is_ok() {
-                                    if let Some(dired) = &mut self.dired {
-	        			                dired.refresh_directory_contents()?;
-	        			                if self.should_open_file {
-                                            self.open(&file_path, None)?;
-	        			                }
-                                    }
-	        		            }
-	        		            self.should_open_file = false;
-                            }
-	        	        }
-                        self.minibuffer_cursor_pos = (0, 0);
-	        	        self.minibuffer_active = false;
-	        	        self.minibuffer_prefix.clear();
-	        	        event_handled = true;
-                    },
-                    _ => {}
-	            }
+            // The dired filter narrows the listing as it is typed
+            if let (Some(Prompt::DiredFilter(_)), Some(dired)) = (&self.minibuffer_prompt, &mut self.dired) {
+                dired.set_filter(&self.minibuffer_content);
             }
 
 
             if !event_handled && !self.fzy.as_ref().map_or(false, |fzy| fzy.active) && !self.minibuffer_active {
 		        match self.mode {
                     Mode::Normal => { self.handle_normal_mode(key)?; },
-                    Mode::Insert => { self.handle_insert_mode(key)?; },
+                    Mode::Insert => {
+                        if !self.handle_completion_keys(key) {
+                            self.handle_insert_mode(key)?;
+                            self.completion_after_key(key);
+                        }
+                    },
                     Mode::Dired  => { self.handle_dired_mode(key)?;  },
                     Mode::Visual => { self.handle_visual_mode(key)?; },
                     Mode::Git    => { self.handle_git_mode(key)?;    },
+                    Mode::Grep   => { self.handle_grep_mode(key)?;   },
+                    Mode::Compilation => { self.handle_compilation_mode(key)?; },
+                    Mode::Blame  => { self.handle_blame_mode(key)?;  },
+                    Mode::GitLog => { self.handle_git_log_mode(key)?; },
 		        }
             }
 
@@ -3298,6 +5331,10 @@ impl Editor {
                     | Mode::Dired
                     | Mode::Visual 
                     | Mode::Git
+                    | Mode::Grep
+                    | Mode::Compilation
+                    | Mode::Blame
+                    | Mode::GitLog
                     => block,
 		        Mode::Insert => if self.config.insert_line_cursor { line } else { block },
 
@@ -3308,12 +5345,42 @@ impl Editor {
 	    }
 
 
-	    fn handle_git_mode(&mut self, key: KeyEvent) -> Result<()> {
+	    fn handle_grep_mode(&mut self, key: KeyEvent) -> Result<()> {
+            if key.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) {
+                return Ok(());
+            }
             match key.code {
 		        KeyCode::Char('q') => {
                     self.mode = Mode::Normal; // TODO to the preferred base mode instead
 		        },
-
+		        KeyCode::Char('j') | KeyCode::Char('n') | KeyCode::Down => {
+                    if let Some(grep) = &mut self.grep {
+                        if grep.selected + 1 < grep.matches.len() {
+                            grep.selected += 1;
+                        }
+                    }
+		        },
+		        KeyCode::Char('k') | KeyCode::Char('p') | KeyCode::Up => {
+                    if let Some(grep) = &mut self.grep {
+                        grep.selected = grep.selected.saturating_sub(1);
+                    }
+		        },
+		        KeyCode::Char('}') | KeyCode::Char('{') => {
+                    if let Some(grep) = &mut self.grep {
+                        if let Some(selected) = grep.file_step(key.code == KeyCode::Char('}')) {
+                            grep.selected = selected;
+                        }
+                    }
+		        },
+		        KeyCode::Char('g') => {
+                    let pattern = self.grep.as_ref().filter(|grep| !grep.references).map(|grep| grep.pattern.clone());
+                    if let Some(pattern) = pattern {
+                        self.project_grep_start(&pattern);
+                    }
+		        },
+		        KeyCode::Enter | KeyCode::Char('o') => {
+                    self.grep_visit()?;
+		        },
 		        _ => {}
             }
             Ok(())
@@ -3342,113 +5409,6 @@ impl Editor {
             Ok(())
 	    }
 
-	    fn handle_dired_mode(&mut self, key: KeyEvent) -> Result<()> {
-            match key.code {
-		        KeyCode::Char('j') | KeyCode::Char('n') |KeyCode::Down => {
-                    if let Some(dired) = &mut self.dired {
-			            let max_index = dired.entries.len() as u16 + 1;
-			            if dired.cursor_pos < max_index {
-                            dired.cursor_pos += 1;
-			            }
-                    }
-		        },
-		        KeyCode::Char('k') | KeyCode::Char('p') | KeyCode::Up => {
-                    if let Some(dired) = &mut self.dired {
-			            if dired.cursor_pos > 0 {
-                            dired.cursor_pos -= 1;
-			            }
-                    }
-		        },
-		        KeyCode::Char('h') | KeyCode::Char('b') | KeyCode::Left => {
-                    if let Some(dired) = &mut self.dired {
-			            let current_dir_name = dired.current_path.file_name()
-                            .and_then(|name| name.to_str())
-                            .unwrap_or(""); // Get the current directory name as a &str
-
-			            let parent_path = dired.current_path.parent()
-                            .unwrap_or_else(|| Path::new("/"))
-                            .to_path_buf();
-
-			            // Update Dired with the parent path, highlighting the directory we came from
-			            *dired = Dired::new(parent_path, Some(current_dir_name))?;
-                    }
-		        },
-
-		        KeyCode::Char('l') | KeyCode::Char('f') | KeyCode::Right | KeyCode::Enter => {
-                    if let Some(dired) = &mut self.dired {
-			            if dired.cursor_pos == 0 {
-                            // Do nothing for '.'
-			            } else if dired.cursor_pos == 1 {
-                            // Handle '..' the same as 'h', navigate to the parent directory
-                            let parent_path = dired.current_path.parent().unwrap_or_else(|| Path::new("/")).to_path_buf();
-                            *dired = Dired::new(parent_path, None)?;
-			            } else {
-                            let selected_entry = &dired.entries[dired.cursor_pos as usize - 2]; // Adjusting for '.' and '..'
-                            let path = selected_entry.path();
-                            if path.is_dir() {
-				                *dired = Dired::new(path.to_path_buf(), None)?;
-                            } else if path.is_file() {
-				                self.open(&path, None)?;
-                            }
-			            }
-                    }
-		        },
-		        
-		        KeyCode::Char('t') | KeyCode::Char('T') => {
-                    self.minibuffer_active = true;
-                    self.minibuffer_prefix = if matches!(key.code, KeyCode::Char('T')) {
-			            "Touch and open:".to_string()
-                    } else {
-			            "Touch:".to_string()
-                    };
-                    self.minibuffer_content = "".to_string();
-                    self.should_open_file = matches!(key.code, KeyCode::Char('T'));
-		        },
-
-		        KeyCode::Char('d') => {
-                    self.minibuffer_active = true;
-                    self.minibuffer_prefix = "Create directory: ".to_string();
-                    self.minibuffer_content = "".to_string();
-		        },
-
-		        KeyCode::Char('D') => {
-                    if let Some(dired) = &self.dired {
-			            if dired.cursor_pos > 1 && (dired.cursor_pos as usize - 2) < dired.entries.len() {
-                            let entry_to_delete = &dired.entries[dired.cursor_pos as usize - 2];
-                            let entry_name = entry_to_delete.file_name().to_string_lossy().into_owned();
-
-                            self.minibuffer_prefix = format!("Delete {} [y/n]: ", entry_name);
-                            self.minibuffer_active = true;
-                            self.minibuffer_content = "".to_string();
-			            }
-                    }
-		        },
-
-		        KeyCode::Char('r') => {
-                    if let Some(dired) = &self.dired {
-			            // Ensure the cursor is on a valid entry (not '.' or '..')
-			            if dired.cursor_pos > 1 && (dired.cursor_pos as usize - 2) < dired.entries.len() {
-                            let entry_to_rename = &dired.entries[dired.cursor_pos as usize - 2];
-                            let entry_name = entry_to_rename.file_name().to_string_lossy().into_owned();
-
-                            // Activate the minibuffer for renaming, pre-filling it with the entry's name
-                            self.minibuffer_active = true;
-                            self.minibuffer_prefix = "Rename: ".to_string();
-                            self.minibuffer_content = entry_name;
-			            }
-                    }
-		        },
-
-		        KeyCode::Char('q') => {
-                    self.mode = Mode::Normal;
-                    // self.message_buffers();
-		        },
-
-		        _ => {}
-            }
-            Ok(())
-	    }
-	    
 	    fn handle_normal_mode(&mut self, key: KeyEvent) -> Result<()> {
             match key {
 
@@ -3517,8 +5477,9 @@ impl Editor {
                     modifiers: KeyModifiers::CONTROL,
                     ..
 		        } => {
-                    self.message("C-h C-c → help commit");
-                    self.mode = Mode::Git;
+                    if self.keychords.ctrl_h_pressed {
+                        self.git_status();
+                    }
 		        },
 
 
@@ -3558,9 +5519,7 @@ impl Editor {
                     modifiers: KeyModifiers::CONTROL,
                     ..
 		        } => {
-                    self.minibuffer_active = true;
-                    self.minibuffer_prefix = "Switch theme: ".to_string();
-                    self.minibuffer_content = "".to_string();
+                    self.select_theme();
 		        },
 
 		        KeyEvent {
@@ -3635,6 +5594,13 @@ impl Editor {
                     self.last_line();
 		        },
 		        KeyEvent {
+                    code: KeyCode::Char('F'),
+                    modifiers: KeyModifiers::SHIFT,
+                    ..
+		        } => {
+                    self.find_file_in_project();
+		        },
+		        KeyEvent {
                     code: KeyCode::Char('O'),
                     modifiers: KeyModifiers::SHIFT,
                     ..
@@ -3707,22 +5673,11 @@ impl Editor {
 			            self.snapshot();
                     },
                     KeyCode::Char('f') => {
-			            if let Some(fzy) = &mut self.fzy {
-                            // fzy.current_path = self.current_file_path.parent().unwrap().to_path_buf(); // TODO
-                            fzy.active = true;
-                            fzy.input.clear();
-                            fzy.update_items();
-                            fzy.recalculate_positions();
-                            self.minibuffer_height = fzy.calculate_minibuffer_height(fzy.max_visible_lines) as u16;
-			            }
+                        self.find_file();
 
                     },
                     KeyCode::Char('/') => {
-			            self.searching = true;
-			            self.highlight_search = true;
-			            self.minibuffer_active = true;
-                        self.minibuffer_content.clear();
-			            self.minibuffer_prefix = "Search: ".to_string();
+		            self.isearch_start();
                     },
                     KeyCode::Char('n') => {
 			            self.highlight_search = true;
@@ -3781,9 +5736,10 @@ impl Editor {
 			            self.snapshot();
                     },
                     KeyCode::Char(':') => {
-			            self.minibuffer_active = true;
-			            self.minibuffer_prefix = ":".to_string();
-			            self.minibuffer_content = "".to_string();
+			            self.minibuffer_open(Prompt::Ex, "");
+                    },
+                    KeyCode::Char('K') => {
+			            self.lsp_hover();
                     },
                     KeyCode::Char('j') | KeyCode::Down => {
 			            self.down();
@@ -4004,11 +5960,7 @@ impl Editor {
                     ..
                 } => {
                     if !self.keychords.ctrl_x_pressed {
-                        self.right();
-                        self.searching = true;
-                        self.minibuffer_active = true;
-                        self.minibuffer_prefix = "Search:".to_string();
-                        self.minibuffer_content.clear();
+                        self.isearch_start();
                     }
                 },
                 KeyEvent {
@@ -4066,8 +6018,6 @@ impl Editor {
                 },
 
 
-
-
                 KeyEvent {
                     code: KeyCode::Char('y'),
                     modifiers: KeyModifiers::CONTROL,
@@ -4249,10 +6199,14 @@ impl Editor {
                     modifiers: KeyModifiers::CONTROL,
                     ..
                 } => {
-                    self.right();
-                    self.searching = true;
-                    self.minibuffer_active = true;
-                    self.minibuffer_prefix = "Search:".to_string();
+                    self.isearch_start();
+                },
+                KeyEvent {
+                    code: KeyCode::Char(' '),
+                    modifiers: KeyModifiers::CONTROL,
+                    ..
+                } => {
+                    self.completion_start(true);
                 },
                 
                 KeyEvent {
@@ -4326,7 +6280,6 @@ impl Editor {
     }
 
 
-
     struct Theme {
 	    background_color: Color,
 	    text_color: Color,
@@ -4453,6 +6406,10 @@ impl Editor {
                     Mode::Dired  => &self.normal_cursor_color,
                     Mode::Visual => &self.normal_cursor_color,
                     Mode::Git    => &self.normal_cursor_color,
+                    Mode::Grep   => &self.normal_cursor_color,
+                    Mode::Compilation => &self.normal_cursor_color,
+                    Mode::Blame  => &self.normal_cursor_color,
+                    Mode::GitLog => &self.normal_cursor_color,
 		        }
             };
 
@@ -4472,6 +6429,10 @@ impl Editor {
 
     use directories::BaseDirs;
     
+    fn get_history_path() -> Option<PathBuf> {
+	    BaseDirs::new().map(|base_dirs| base_dirs.home_dir().join(".cache/redit/minibuffer-history"))
+    }
+
     fn get_config_path() -> Option<PathBuf> {
 	    if let Some(base_dirs) = BaseDirs::new() {
             let config_dir = base_dirs.home_dir().join(".config/redit/config.lua");
@@ -4522,307 +6483,291 @@ impl Editor {
 	    }
     }
 
-    macro_rules! register_command {
-	    ($commands:expr, $name:expr, $func:expr) => {
-            $commands.insert(
-		        $name.to_string(),
-		        Box::new(move |editor: &mut Editor| {
-                    // Call the function, and wrap non-Result returning functions with Ok(())
-                    $func(editor);
-                    Ok(())
-		        }) as Box<dyn FnMut(&mut Editor) -> io::Result<()>>
-            );
-	    };
-    }
-
-    // TODO Command filterning, Fuzzy matching highlight, Change colors on selction
-    struct Fzy {
-	    active: bool,
-	    items: Vec<String>,
-	    input: String,
-	    selection_index: usize,
-	    max_visible_lines: usize,
-	    current_path: PathBuf,
-	    initial_input_line_y: Option<u16>,
-	    initial_items_start_y: Option<u16>,
-	    initial_positioning_done: bool,
-	    m_x_active: bool,
-	    commands: HashMap<String, Box<dyn FnMut(&mut Editor) -> io::Result<()>>>,
-    }
-
-    // TODO IMPORTANT automatcally add all the functions from rust at compile time
-    // and all the functions from lua at runtime
-    impl Fzy {
-	    fn new(current_path: PathBuf) -> Self {
-            let mut commands: HashMap<String, Box<dyn FnMut(&mut Editor) -> io::Result<()>>> = HashMap::new();
-            register_command!(commands, "dired-jump",  Editor::dired_jump);
-            register_command!(commands, "eval-buffer", Editor::eval_buffer);
-            register_command!(commands, "debug-ast",   Editor::debug_print_ast);
-            register_command!(commands, "compile",     Editor::compile);
-            register_command!(commands, "eval_buffer", Editor::eval_buffer);
-            register_command!(commands, "eval_region", Editor::eval_region);
-            register_command!(commands, "eval_line",   Editor::eval_line);
-            Fzy {
-		        active: false,
-		        items: Vec::new(),
-		        input: String::new(),
-		        selection_index: 0,
-		        max_visible_lines: 11,
-		        current_path,
-		        initial_input_line_y: None,
-		        initial_items_start_y: None,
-		        initial_positioning_done: false,
-		        m_x_active: false,
-		        commands,
-            }
-	    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-	    fn update_items(&mut self) {
-            // Clear existing items to prepare for new items
-            self.items.clear();
+    fn chars(text: &str) -> Vec<char> {
+        text.chars().collect()
+    }
 
-            if self.m_x_active {
-		        self.items.extend(self.commands.keys().cloned());
-            } else {
-		        let mut entries = vec![".".to_string(), "..".to_string()];
-		        let dir_entries = std::fs::read_dir(&self.current_path)
-                    .unwrap()
-                    .filter_map(|entry| {
-			            let entry = entry.ok()?;
-			            let path = entry.path();
-			            let file_name = path.file_name()?.to_str()?.to_owned();
-
-			            // Skip '.' and '..' as they are already added.
-			            if file_name == "." || file_name == ".." {
-                            return None;
-			            }
+    // A fresh directory under the system temp dir, removed by the caller
+    pub(crate) fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("redit-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
 
-			            // Add file if it matches the input filter.
-			            if self.input.is_empty() || file_name.contains(&self.input) {
-                            Some(file_name)
-			            } else {
-                            None
-			            }
-                    })
-                    .collect::<Vec<String>>();
+    fn ignore(rules: &str) -> GitIgnore {
+        GitIgnore { base: PathBuf::from("/p"), rules: rules.lines().filter_map(GitIgnore::parse_rule).collect() }
+    }
 
-		        entries.extend(dir_entries);
+    #[test]
+    fn gitignore_negation() {
+        let ignore = ignore("*.log\n!keep.log\n# comment\n\\!bang");
+        assert_eq!(ignore.is_ignored(Path::new("/p/a.log"), false), Some(true));
+        assert_eq!(ignore.is_ignored(Path::new("/p/deep/b.log"), false), Some(true));
+        assert_eq!(ignore.is_ignored(Path::new("/p/keep.log"), false), Some(false));
+        assert_eq!(ignore.is_ignored(Path::new("/p/!bang"), false), Some(true));
+        assert_eq!(ignore.is_ignored(Path::new("/p/main.rs"), false), None);
+    }
 
-		        if !self.input.is_empty() {
-                    entries.retain(|entry| entry.contains(&self.input));
-		        }
+    #[test]
+    fn gitignore_directory_only() {
+        let ignore = ignore("build/");
+        assert_eq!(ignore.is_ignored(Path::new("/p/build"), true), Some(true));
+        assert_eq!(ignore.is_ignored(Path::new("/p/src/build"), true), Some(true));
+        assert_eq!(ignore.is_ignored(Path::new("/p/build"), false), None);
+    }
 
-		        self.items = entries;
-            }
+    #[test]
+    fn gitignore_anchored() {
+        let ignore = ignore("/target\ndoc/frotz");
+        assert_eq!(ignore.is_ignored(Path::new("/p/target"), true), Some(true));
+        assert_eq!(ignore.is_ignored(Path::new("/p/src/target"), true), None);
+        assert_eq!(ignore.is_ignored(Path::new("/p/doc/frotz"), false), Some(true));
+        assert_eq!(ignore.is_ignored(Path::new("/p/a/doc/frotz"), false), None);
+        assert_eq!(ignore.is_ignored(Path::new("/elsewhere/target"), true), None);
+    }
 
-            self.selection_index = 0; // Reset selection index on each update
-	    }
+    #[test]
+    fn gitignore_double_star() {
+        let ignore = ignore("**/foo\na/**/b\nabc/**\n*.[oa]");
+        for path in ["/p/foo", "/p/x/y/foo", "/p/a/b", "/p/a/x/b", "/p/a/x/y/b", "/p/abc/x", "/p/abc/x/y", "/p/lib.a", "/p/x/main.o"] {
+            assert_eq!(ignore.is_ignored(Path::new(path), false), Some(true), "{}", path);
+        }
+        for path in ["/p/foobar", "/p/a/bc", "/p/abc", "/p/lib.so"] {
+            assert_eq!(ignore.is_ignored(Path::new(path), false), None, "{}", path);
+        }
+    }
 
-	    pub fn calculate_minibuffer_height(&self, max_height: usize) -> usize {
-            let total_items = self.items.len();
-            let visible_items = total_items.min(self.max_visible_lines) + 1; // +1 for the input line
-            visible_items.min(max_height + 1) // Ensure it does not exceed max_height
-	    }
+    #[test]
+    fn walk_project_skips_ignored_files() {
+        let dir = temp_dir("walk");
+        for file in ["src/main.rs", "src/gen/out.rs", "target/debug/redit", "notes.log", "keep.log", "sub/local.tmp", "sub/kept.rs"] {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        fs::write(dir.join(".gitignore"), "/target/\n*.log\n!keep.log\ngen/\n").unwrap();
+        fs::write(dir.join("sub/.ignore"), "*.tmp\n").unwrap();
 
+        let mut files = Vec::new();
+        walk_project(&dir, &mut |path| {
+            files.push(path.strip_prefix(&dir).unwrap().to_string_lossy().into_owned());
+            true
+        });
+        assert_eq!(files, [".gitignore", "keep.log", "src/main.rs", "sub/.ignore", "sub/kept.rs"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
 
-	    // TODO prefix, path, scroll, end scroll
-	    fn draw(&mut self, stdout: &mut Stdout, theme: &Theme) -> io::Result<()> {
-            let (width, height) = terminal::size()?;
+    #[test]
+    fn minibuffer_history_is_saved_per_prompt() {
+        let dir = temp_dir("history");
+        let path = dir.join("history");
+        let mut history = MinibufferHistory::load(Some(path.clone()));
+        history.push(Prompt::Search.history().unwrap(), "foo").unwrap();
+        history.push(Prompt::RegexpSearch.history().unwrap(), "fo+\tbar\n").unwrap();
+        history.push(Prompt::Search.history().unwrap(), "line\nbreak").unwrap();
+        history.push(Prompt::Search.history().unwrap(), "foo").unwrap();
+
+        let mut history = MinibufferHistory::load(Some(path));
+        assert_eq!(history.last("search").map(String::as_str), Some("foo"));
+        assert_eq!(history.last("regexp-search").map(String::as_str), Some("fo+\tbar\n"));
+        assert_eq!(history.step("search", true, "typed").as_deref(), Some("foo"));
+        assert_eq!(history.step("search", true, "").as_deref(), Some("line\nbreak"));
+        assert_eq!(history.step("search", true, ""), None);
+        assert_eq!(history.step("search", false, "").as_deref(), Some("foo"));
+        assert_eq!(history.step("search", false, "").as_deref(), Some("typed"));
+        fs::remove_dir_all(dir).unwrap();
+    }
 
-            if !self.initial_positioning_done {
-		        let items_to_display = self.items.len().min(self.max_visible_lines);
-		        self.initial_input_line_y = Some(height.saturating_sub(items_to_display as u16 + 1));
-		        self.initial_items_start_y = Some(self.initial_input_line_y.unwrap().saturating_add(1));
-		        self.initial_positioning_done = true;
-            }
+    #[test]
+    fn line_matches_keeps_empty_matches() {
+        let line = chars("foo bar");
+        let re = build_search_regex("^", true, false).unwrap();
+        assert_eq!(Search::line_matches(&re, &line), vec![(0, 0)]);
+        let re = build_search_regex("$", true, false).unwrap();
+        assert_eq!(Search::line_matches(&re, &line), vec![(7, 7)]);
+        let re = build_search_regex(r"\b", true, false).unwrap();
+        assert_eq!(Search::line_matches(&re, &line), vec![(0, 0), (3, 3), (4, 4), (7, 7)]);
+    }
 
-            let input_line_y = self.initial_input_line_y.unwrap_or(height.saturating_sub(self.max_visible_lines as u16 + 1));
-            let items_start_y = self.initial_items_start_y.unwrap_or(input_line_y.saturating_add(1));
+    #[test]
+    fn visible_matches_skip_empty_matches() {
+        let line = chars("aab");
+        let re = build_search_regex("a*", true, false).unwrap();
+        assert_eq!(Search::visible_matches(&re, &line), vec![(0, 2)]);
+    }
 
-            execute!(
-		        stdout,
-		        MoveTo(1, input_line_y),
-		        SetForegroundColor(theme.normal_cursor_color),
-		        Print(format!(" {:}/{:<2} ", self.selection_index + 1, self.items.len())),
-		        Print("Find file: "),
-		        SetForegroundColor(theme.text_color),
-		        Print(&self.input)
-            )?;
+    #[test]
+    fn search_refresh_follows_the_query_and_the_buffer() {
+        let mut buffer = vec![chars("foo bar"), chars(""), chars("a foo")];
+        let mut search = Search::new();
+        search.query = "foo".to_string();
+        search.refresh(&buffer, 0, true);
+        assert_eq!(search.matches(), &[(0, 0, 3), (2, 2, 5)]);
+
+        // Only an edit makes it look again
+        buffer[1] = chars("foofoo");
+        search.refresh(&buffer, 0, true);
+        assert_eq!(search.matches(), &[(0, 0, 3), (2, 2, 5)]);
+        search.refresh(&buffer, 1, true);
+        assert_eq!(search.matches(), &[(0, 0, 3), (1, 0, 3), (1, 3, 6), (2, 2, 5)]);
+
+        search.query = "Foo".to_string();
+        search.refresh(&buffer, 1, true);
+        assert!(search.matches().is_empty());
+
+        search.query = "(".to_string();
+        search.regex_mode = true;
+        search.refresh(&buffer, 1, true);
+        assert!(search.regex().is_none());
+        assert!(search.matches().is_empty());
+    }
 
-            for (index, item) in self.items.iter().enumerate() {
-		        let y_pos = items_start_y + index as u16;
-		        if y_pos >= height { break; }
-
-		        let is_dir = item == "." || item == ".." || self.current_path.join(item).is_dir();
-		        let formatted_item = if is_dir { format!("{}/", item) } else { item.clone() };
-
-		        let (icon, icon_color) = if self.m_x_active {
-                    ("", theme.text_color)
-		        } else {
-                    match item.as_str() {
-			            ".." => ("󱚁", theme.text_color),
-			            "." => ("󰉋", theme.text_color),
-			            ".git" => ("", theme.text_color),
-			            _ if item.ends_with(".rs") => ("", hex_to_rgb("#DEA584").unwrap()),
-			            _ if item.ends_with(".lua") => ("", theme.normal_cursor_color),
-			            _ if item.ends_with(".org") => ("", hex_to_rgb("#77AA99").unwrap()),
-			            _ if item.ends_with(".lock") => ("󰌾", theme.text_color), 
-			            _ if item.ends_with(".toml") => ("", theme.text_color),
-			            _ if item.ends_with(".json") => ("", hex_to_rgb("#CBCB41").unwrap()),
-			            _ => ("󰈚", theme.text_color), // Default icon for files
-                    }
-		        };
+    #[test]
+    fn search_refresh_handles_an_empty_buffer() {
+        let mut search = Search::new();
+        search.query = "x".to_string();
+        search.refresh(&[], 0, false);
+        assert!(search.regex().is_some());
+        assert!(search.matches().is_empty());
+    }
 
-		        if index == self.selection_index {
-                    execute!(
-			            stdout,
-			            MoveTo(0, y_pos),
-			            SetBackgroundColor(theme.normal_cursor_color),
-			            Print(" ".repeat(width as usize)),
-                    )?;
-		        }
+    #[test]
+    fn line_matches_are_in_chars() {
+        let line = chars("héllo wörld");
+        let re = build_search_regex("wö", false, false).unwrap();
+        assert_eq!(Search::line_matches(&re, &line), vec![(6, 8)]);
+    }
 
-		        execute!(
-                    stdout,
-                    MoveTo(1, y_pos),
-                    SetForegroundColor(icon_color),
-                    Print(format!("{} ", icon)),
-		        )?;
+    pub(crate) fn lines(text: &str) -> Vec<Vec<char>> {
+        text.split('\n').map(chars).collect()
+    }
 
-		        let item_color = if is_dir { theme.dired_dir_color } else { theme.text_color };
-		        execute!(
-                    stdout,
-                    SetForegroundColor(item_color),
-                    Print(format!(" {}", formatted_item)),
-                    SetBackgroundColor(theme.minibuffer_color)
-		        )?;
-            }
+    // Past the prompts, ready to replace
+    fn replacing(from: &str, to: &str, regex_mode: bool, region: Option<((u16, u16), (u16, u16))>) -> Replace {
+        let mut replace = Replace::new(regex_mode, false, region);
+        replace.re = Some(build_search_regex(from, regex_mode, true).unwrap());
+        replace.from = Some(from.to_string());
+        replace.to = to.to_string();
+        replace
+    }
 
-            execute!(stdout, MoveTo(0, height - 1))?;
-            Ok(())
-	    }
+    #[test]
+    fn replace_is_undone_in_one_step() {
+        let mut buffer = lines("a b a\na");
+        let mut history = UndoHistory::new();
+        history.reset(&buffer, (0, 0));
+        let mut replace = replacing("a", "x", false, None);
+        let cursor = replace.replace_all(&mut buffer, (0, 0)).unwrap();
+        assert_eq!(buffer, lines("x b x\nx"));
+        assert_eq!(cursor, (1, 1));
+        assert_eq!(replace.finish(&mut history, &buffer, cursor), 3);
+        assert_eq!(history.undo().unwrap().buffer, lines("a b a\na"));
+        assert!(history.undo().is_none());
+        assert_eq!(history.redo().unwrap().buffer, lines("x b x\nx"));
+
+        // Nothing replaced, nothing to undo
+        let replace = replacing("q", "x", false, None);
+        replace.finish(&mut history, &buffer, (0, 0));
+        assert_eq!(history.states.len(), 2);
+    }
 
-	    fn recalculate_positions(&mut self) {
-            let items_to_display = self.items.len().min(self.max_visible_lines);
-            self.initial_input_line_y = Some(terminal::size().unwrap().1.saturating_sub(items_to_display as u16 + 1));
-            self.initial_items_start_y = Some(self.initial_input_line_y.unwrap().saturating_add(1));
-	    }
+    #[test]
+    fn replace_expands_groups_and_the_whole_match() {
+        let mut buffer = lines("key = value\nname = redit");
+        let mut replace = replacing(r"(\w+) = (\w+)", r"\2: \1 (\&) \\ $1", true, None);
+        replace.replace_all(&mut buffer, (0, 0));
+        assert_eq!(buffer, lines(r"value: key (key = value) \ $1
+redit: name (name = redit) \ $1"));
+
+        // Plain strings take the text as it is
+        let mut buffer = lines("a.b");
+        replacing(".", r"\1$0", false, None).replace_all(&mut buffer, (0, 0));
+        assert_eq!(buffer, lines(r"a\1$0b"));
+    }
 
-	    
-	    pub fn handle_input(&mut self, key: KeyEvent, editor: &mut Editor) -> bool {
-            let mut state_changed = false;
+    #[test]
+    fn replace_keeps_to_the_region() {
+        let mut buffer = lines("aa aa\naa aa\naa aa");
+        // From the second a of the first line to the first a of the last
+        let mut replace = replacing("a", "bbb", false, Some(((1, 0), (0, 2))));
+        replace.replace_all(&mut buffer, (1, 0));
+        assert_eq!(buffer, lines("abbb bbbbbb\nbbbbbb bbbbbb\nbbba aa"));
+        assert_eq!(replace.count, 8);
+    }
 
-            match key {
-		        KeyEvent {
-                    code: KeyCode::Char('n'),
-                    modifiers: KeyModifiers::CONTROL,
-                    ..
-		        } => {
-                    if self.selection_index < self.items.len() - 1 { 
-			            self.selection_index += 1;
-                    }
-		        },
-		        KeyEvent {
-                    code: KeyCode::Char('p'),
-                    modifiers: KeyModifiers::CONTROL,
-                    ..
-		        } => {
-                    if self.selection_index > 0 {
-			            self.selection_index -= 1;
-                    }
-		        },
-		        KeyEvent {
-                    code: KeyCode::Char('j'),
-                    modifiers: KeyModifiers::CONTROL,
-                    ..
-		        } => {
-                    if self.selection_index < self.items.len() - 1 { 
-			            self.selection_index += 1;
-                    }
-		        },
+    #[test]
+    fn icons_go_by_name_then_extension() {
+        let icons = Icons::new();
+        let rust = hex_to_rgb("#DEA584").unwrap();
+        assert_eq!(icons.lookup("main.rs", false, Color::White), ("", rust));
+        assert_eq!(icons.lookup("MAIN.RS", false, Color::White), ("", rust)); // Any case of extension
+        assert_eq!(icons.lookup("Cargo.lock", false, Color::White), ("󰌾", Color::White)); // No color of its own
+        assert_eq!(icons.lookup("Makefile", false, Color::White), ("", hex_to_rgb("#6D8086").unwrap()));
+        assert_eq!(icons.lookup("node_modules", true, Color::Blue), ("", hex_to_rgb("#E8274B").unwrap()));
+        // Directories only go by their name, files never by the directory table
+        assert_eq!(icons.lookup("src.rs", true, Color::Blue), (Icons::DIRECTORY, Color::Blue));
+        assert_eq!(icons.lookup("target", false, Color::White), (Icons::FILE, Color::White));
+    }
 
-		        KeyEvent {
-                    code: KeyCode::Char('k'),
-                    modifiers: KeyModifiers::CONTROL,
-                    ..
-		        } => {
-                    if self.selection_index > 0 {
-			            self.selection_index -= 1;
-                    }
-		        },
-                KeyEvent {
-                    code: KeyCode::Char('g'),
-                    modifiers: KeyModifiers::CONTROL,
-                    ..
-		        } => {
-                    self.m_x_active = false;
-		            self.active = false;
-		            self.input.clear();
-		            self.items.clear();
-		            state_changed = true; // Indicate that the fuzzy finder was deactivated
-		        },
-		        KeyEvent {
-                    code,
-                    modifiers: KeyModifiers::NONE,
-                    ..
-		        } => match code {
-                    KeyCode::Char(c) => {
-			            self.input.push(c);
-			            self.update_items();
-                    },
-                    KeyCode::Backspace => {
-			            self.input.pop();
-			            self.update_items();
-                    },
-                    KeyCode::Up => {
-			            if self.selection_index > 0 {
-                            self.selection_index -= 1;
-			            }
-                    },
-                    KeyCode::Down => {
-			            if self.selection_index < self.items.len() - 1 {
-                            self.selection_index += 1;
-			            }
-                    },
-                    KeyCode::Esc => {
-			            self.m_x_active = false;
-			            self.active = false;
-			            self.input.clear();
-			            self.items.clear();
-			            state_changed = true; // Indicate that the fuzzy finder was deactivated
-                    },
-                    KeyCode::Enter => {
-			            if let Some(item) = self.items.get(self.selection_index) {
-                            if self.m_x_active {
-				                // Check if the selected item is a command
-				                if let Some(command) = self.commands.get_mut(item) {
-                                    // Execute the command
-                                    let result = command(editor);
-                                    if let Err(e) = result {
-					                    // TODO message handle the error
-                                    }
-				                }
-				                self.m_x_active = false; // Reset command mode
-                            } else {
-				                // File selection logic
-				                let full_path = self.current_path.join(item);
-				                if let Err(e) = editor.open(&full_path, None) {
-                                    // Handle potential error from opening a file
-                                    println!("Error opening file: {}", e);
-				                }
-                            }
-                            // Reset Fzy state
-                            self.active = false;
-                            self.input.clear();
-                            self.items.clear();
-                            state_changed = true;
-			            }
-                    }
-                    _ => {}
-		        },
-		        _ => {}
-            }
+    #[test]
+    fn icons_fall_back_on_a_file_and_a_directory() {
+        let mut icons = Icons::new();
+        assert_eq!(icons.lookup("notes", false, Color::White), (Icons::FILE, Color::White));
+        assert_eq!(icons.lookup("a.unknown", false, Color::White), (Icons::FILE, Color::White));
+        assert_eq!(icons.lookup("docs", true, Color::Blue), (Icons::DIRECTORY, Color::Blue));
+        // Without a Nerd Font, the colors stay
+        icons.nerd_font = false;
+        assert_eq!(icons.lookup("main.rs", false, Color::White), ("-", hex_to_rgb("#DEA584").unwrap()));
+        assert_eq!(icons.lookup("docs", true, Color::Blue), ("+", Color::Blue));
+    }
 
-            state_changed // Return whether the fuzzy finder's state has changed
-	    }
+    #[test]
+    fn icons_from_lua_go_over_the_built_in_ones() {
+        let mut icons = Icons::new();
+        let red = hex_to_rgb("#FF0000").unwrap();
+        icons.set("extensions", "rs".to_string(), ("R".to_string(), Some(red)));
+        icons.set("extensions", "zig".to_string(), ("Z".to_string(), None));
+        icons.set("filenames", "build.rs".to_string(), ("B".to_string(), None));
+        icons.set("directories", "src".to_string(), ("S".to_string(), Some(red)));
+        icons.set("nonsense", "x".to_string(), ("X".to_string(), None));
+        assert_eq!(icons.lookup("main.rs", false, Color::White), ("R", red));
+        assert_eq!(icons.lookup("a.zig", false, Color::White), ("Z", Color::White));
+        // A file name beats its extension, even an overridden one
+        assert_eq!(icons.lookup("build.rs", false, Color::White), ("B", Color::White));
+        assert_eq!(icons.lookup("src", true, Color::Blue), ("S", red));
+        assert_eq!(icons.lookup("x", false, Color::White), (Icons::FILE, Color::White));
+    }
+
+    #[test]
+    fn formatting_keeps_positions_on_their_text() {
+        let old = lines("fn f() {\n  a();\n  b(1,2);\n}\nfn g() {}");
+        let new = lines("fn f() {\n    a();\n\n    b(1, 2);\n}\nfn g() {}");
+        assert_eq!(formatted_position(&old, &new, (3, 0)), (3, 0)); // Before the change
+        assert_eq!(formatted_position(&old, &new, (2, 1)), (4, 1)); // On a, reindented
+        assert_eq!(formatted_position(&old, &new, (6, 2)), (9, 3)); // On the 2, a blank line came before
+        assert_eq!(formatted_position(&old, &new, (5, 4)), (5, 5)); // After the change
+        assert_eq!(formatted_position(&old, &new, (0, 9)), (0, 5)); // Past the end
+    }
+
+    #[test]
+    fn formatter_errors_find_the_location() {
+        let rustfmt = "error: expected one of `.`, `;`, or an operator, found `x`\n --> <stdin>:3:5\n  |\n3 |     x\n  |     ^ expected\n\nerror: this file contains an unclosed delimiter\n --> <stdin>:9:1\n";
+        assert_eq!(formatter_errors(rustfmt), vec![
+            (2, Some(4), "expected one of `.`, `;`, or an operator, found `x`".to_string()),
+            (8, Some(0), "this file contains an unclosed delimiter".to_string()),
+        ]);
+        assert_eq!(formatter_errors("error: failed to parse: unexpected token `)` at line 3, column 7\n"), vec![
+            (2, Some(6), "failed to parse: unexpected token `)` at line 3, column 7".to_string()),
+        ]);
+        assert_eq!(formatter_errors("[error] stdin: SyntaxError: Unexpected token (12:5)\n[error]   10 | let\n"), vec![
+            (11, Some(4), "stdin: SyntaxError: Unexpected token (12:5)".to_string()),
+        ]);
+        assert_eq!(formatter_errors("something went wrong\n"), vec![(0, None, "something went wrong".to_string())]);
+        assert!(formatter_errors("\n  \n").is_empty());
     }
+}